@@ -163,8 +163,13 @@ export function SheetCanvas({
 
     // Draw cell contents from engine
     if (engine) {
-      const cells = engine.get_viewport_cells();
-      if (cells) {
+      let page = engine.get_viewport_cells(false);
+      const cells = [...(page?.cells ?? [])];
+      while (page?.next && engine.get_viewport_cells_page) {
+        page = engine.get_viewport_cells_page(page.next);
+        cells.push(...(page?.cells ?? []));
+      }
+      if (cells.length > 0) {
         ctx.fillStyle = '#1e293b';
         ctx.font = `${13 * zoom}px Inter, system-ui, sans-serif`;
         ctx.textAlign = 'left';
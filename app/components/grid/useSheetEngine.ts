@@ -5,11 +5,20 @@ import { useEffect, useState, useCallback } from 'react';
 const DEFAULT_COL_WIDTH = 100;
 const DEFAULT_ROW_HEIGHT = 24;
 
+export type ViewportCell = { row: number; col: number; value: string; formula?: string; format?: any };
+
+// One page of viewport cells; `next` is set when more pages remain
+export interface ViewportPage {
+  cells?: ViewportCell[];
+  next?: string;
+}
+
 // WASM engine interface (will be loaded dynamically)
 interface SheetEngine {
   set_cell(row: number, col: number, value: string): unknown;
   get_cell(row: number, col: number): { value: string; formula?: string; format?: any } | null;
-  get_viewport_cells(): Array<{ row: number; col: number; value: string; formula?: string; format?: any }>;
+  get_viewport_cells(dense: boolean): ViewportPage;
+  get_viewport_cells_page?(token: string): ViewportPage;
   set_viewport(startRow: number, startCol: number, visibleRows: number, visibleCols: number): void;
   apply_patch(patch: unknown): unknown;
   apply_format?(startRow: number, startCol: number, endRow: number, endCol: number, format: any): void;
@@ -154,8 +163,8 @@ class FallbackEngine implements SheetEngine {
     };
   }
 
-  get_viewport_cells(): Array<{ row: number; col: number; value: string; formula?: string; format?: any }> {
-    const result: Array<{ row: number; col: number; value: string; formula?: string; format?: any }> = [];
+  get_viewport_cells(): ViewportPage {
+    const result: ViewportCell[] = [];
     
     for (const [key, cell] of this.cells) {
       const [rowStr, colStr] = key.split(',');
@@ -170,7 +179,7 @@ class FallbackEngine implements SheetEngine {
       }
     }
     
-    return result;
+    return { cells: result };
   }
 
   apply_format(startRow: number, startCol: number, endRow: number, endCol: number, format: any): void {
@@ -0,0 +1,136 @@
+//! AutoSum range detection
+//!
+//! Mirrors Excel's "Σ AutoSum": from the active cell, look upward (then
+//! leftward) for the contiguous block of numbers the user most likely wants
+//! to total.
+
+use crate::cell::{Cell, CellRef, CellValue};
+use crate::grid::Grid;
+use serde::{Deserialize, Serialize};
+
+/// A suggested AutoSum formula and the range it covers
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AutoSumSuggestion {
+    pub formula: String,
+    pub start: CellRef,
+    pub end: CellRef,
+}
+
+/// Length of the run of numeric cells to sum, scanning away from the active cell
+///
+/// `cells` is ordered nearest-first, with `None` for blank cells. The run:
+/// - extends over numeric cells;
+/// - stops at the first blank cell;
+/// - stops at a cell holding a `SUM` formula, so subtotals aren't summed twice;
+/// - stops at a text cell, which is taken to be the column's header;
+/// - stops at any other non-numeric value (booleans, errors).
+///
+/// Cells that stop the run are never part of it. Returns 0 when the cell
+/// next to the active cell doesn't start a run.
+pub fn run_length<'a>(cells: impl IntoIterator<Item = Option<&'a Cell>>) -> usize {
+    let mut len = 0;
+    for cell in cells {
+        let Some(cell) = cell else {
+            break;
+        };
+        if is_sum_formula(cell) || !matches!(cell.value, CellValue::Number(_)) {
+            break;
+        }
+        len += 1;
+    }
+    len
+}
+
+/// Suggest an AutoSum for `active`, preferring the numbers above it
+pub fn suggest(grid: &Grid, active: CellRef) -> Option<AutoSumSuggestion> {
+    let above = (0..active.row).rev().map(|row| grid.get_cell(CellRef::new(row, active.col)));
+    let len = run_length(above) as u32;
+    if len > 0 {
+        let start = CellRef::new(active.row - len, active.col);
+        let end = CellRef::new(active.row - 1, active.col);
+        return Some(AutoSumSuggestion::new(start, end));
+    }
+
+    let left = (0..active.col).rev().map(|col| grid.get_cell(CellRef::new(active.row, col)));
+    let len = run_length(left) as u32;
+    if len > 0 {
+        let start = CellRef::new(active.row, active.col - len);
+        let end = CellRef::new(active.row, active.col - 1);
+        return Some(AutoSumSuggestion::new(start, end));
+    }
+
+    None
+}
+
+impl AutoSumSuggestion {
+    fn new(start: CellRef, end: CellRef) -> Self {
+        Self {
+            formula: format!("=SUM({}:{})", start, end),
+            start,
+            end,
+        }
+    }
+}
+
+fn is_sum_formula(cell: &Cell) -> bool {
+    cell.formula
+        .as_deref()
+        .map(|f| f.trim_start_matches('=').trim_start().to_uppercase().starts_with("SUM("))
+        .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn num(n: f64) -> Option<Cell> {
+        Some(Cell::new(CellValue::Number(n)))
+    }
+
+    fn text(s: &str) -> Option<Cell> {
+        Some(Cell::new(CellValue::Text(s.to_string())))
+    }
+
+    fn sum(formula: &str, n: f64) -> Option<Cell> {
+        Some(Cell::with_formula(CellValue::Number(n), formula.to_string()))
+    }
+
+    #[test]
+    fn test_run_length() {
+        let cases: Vec<(Vec<Option<Cell>>, usize)> = vec![
+            (vec![num(1.0), num(2.0), num(3.0)], 3),
+            (vec![num(1.0), num(2.0), text("Revenue"), num(9.0)], 2),
+            (vec![num(1.0), None, num(2.0)], 1),
+            (vec![num(1.0), sum("=SUM(A1:A3)", 6.0), num(2.0)], 1),
+            (vec![num(1.0), sum("=sum(A1:A3)", 6.0)], 1),
+            (vec![num(1.0), sum("=A1*2", 2.0), num(4.0)], 3),
+            (vec![None, num(1.0)], 0),
+            (vec![text("Total"), num(1.0)], 0),
+            (vec![Some(Cell::new(CellValue::Boolean(true))), num(1.0)], 0),
+            (vec![], 0),
+        ];
+        for (cells, expected) in cases {
+            assert_eq!(run_length(cells.iter().map(Option::as_ref)), expected, "{:?}", cells);
+        }
+    }
+
+    #[test]
+    fn test_suggest_prefers_column_then_row() {
+        let mut grid = Grid::new(20, 10);
+        grid.set_value(CellRef::new(0, 1), CellValue::Text("Q1".to_string())).unwrap();
+        for row in 1..=3 {
+            grid.set_value(CellRef::new(row, 1), CellValue::Number(row as f64)).unwrap();
+        }
+        let suggestion = suggest(&grid, CellRef::new(4, 1)).unwrap();
+        assert_eq!(suggestion.formula, "=SUM(B2:B4)");
+        assert_eq!(suggestion.start, CellRef::new(1, 1));
+        assert_eq!(suggestion.end, CellRef::new(3, 1));
+
+        // Nothing above C2, so fall back to the row to its left
+        grid.set_value(CellRef::new(1, 0), CellValue::Number(5.0)).unwrap();
+        let suggestion = suggest(&grid, CellRef::new(1, 2)).unwrap();
+        assert_eq!(suggestion.formula, "=SUM(A2:B2)");
+
+        assert_eq!(suggest(&grid, CellRef::new(10, 5)), None);
+    }
+}
@@ -1,5 +1,6 @@
 //! Cell types and cell reference handling
 
+use crate::formula::number_format;
 use serde::{Deserialize, Serialize};
 use std::fmt;
 
@@ -36,7 +37,8 @@ impl CellRef {
             if !c.is_ascii_alphabetic() {
                 return None;
             }
-            col = col * 26 + (c.to_ascii_uppercase() as u32 - 'A' as u32 + 1);
+            // Long names like TEXTSPLIT are function names, not columns
+            col = col.checked_mul(26)?.checked_add(c.to_ascii_uppercase() as u32 - 'A' as u32 + 1)?;
         }
         Some(col.saturating_sub(1))
     }
@@ -66,9 +68,12 @@ impl fmt::Display for CellRef {
 }
 
 /// The value stored in a cell
-#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
-#[serde(tag = "type", content = "value")]
+///
+/// Numbers are always finite: non-finite results are `#NUM` errors.
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+#[serde(tag = "type", content = "value", from = "StoredCellValue")]
 pub enum CellValue {
+    #[default]
     Empty,
     Text(String),
     Number(f64),
@@ -76,10 +81,37 @@ pub enum CellValue {
     Error(String),
 }
 
+/// `CellValue` as saved, where older documents may hold a NaN or infinite
+/// number (written by JSON as `null`)
+#[derive(Deserialize)]
+#[serde(tag = "type", content = "value")]
+enum StoredCellValue {
+    Empty,
+    Text(String),
+    Number(Option<f64>),
+    Boolean(bool),
+    Error(String),
+}
+
+impl From<StoredCellValue> for CellValue {
+    fn from(stored: StoredCellValue) -> Self {
+        match stored {
+            StoredCellValue::Empty => Self::Empty,
+            StoredCellValue::Text(s) => Self::Text(s),
+            StoredCellValue::Number(Some(n)) if n.is_finite() => Self::Number(n),
+            StoredCellValue::Number(_) => Self::Error("NUM".to_string()),
+            StoredCellValue::Boolean(b) => Self::Boolean(b),
+            StoredCellValue::Error(e) => Self::Error(e),
+        }
+    }
+}
+
 impl CellValue {
     /// Parse a string into a cell value
+    ///
+    /// Input with nothing visible in it (see `trim_invisible`) is empty.
     pub fn parse(s: &str) -> Self {
-        let trimmed = s.trim();
+        let trimmed = trim_invisible(s);
         
         if trimmed.is_empty() {
             return Self::Empty;
@@ -93,14 +125,14 @@ impl CellValue {
             return Self::Boolean(false);
         }
         
-        // Try parsing as number
-        if let Ok(n) = trimmed.parse::<f64>() {
+        // Try parsing as number; "NaN", "inf" and overflowing literals stay text
+        if let Some(n) = parse_finite(trimmed) {
             return Self::Number(n);
         }
         
         // Try parsing percentage
-        if trimmed.ends_with('%') {
-            if let Ok(n) = trimmed[..trimmed.len()-1].parse::<f64>() {
+        if let Some(pct) = trimmed.strip_suffix('%') {
+            if let Some(n) = parse_finite(pct) {
                 return Self::Number(n / 100.0);
             }
         }
@@ -110,7 +142,7 @@ impl CellValue {
             let cleaned: String = trimmed.chars()
                 .filter(|c| c.is_ascii_digit() || *c == '.' || *c == '-')
                 .collect();
-            if let Ok(n) = cleaned.parse::<f64>() {
+            if let Some(n) = parse_finite(&cleaned) {
                 return Self::Number(n);
             }
         }
@@ -123,6 +155,7 @@ impl CellValue {
         match self {
             Self::Empty => String::new(),
             Self::Text(s) => s.clone(),
+            Self::Number(n) if !n.is_finite() => "#NUM".to_string(),
             Self::Number(n) => {
                 if n.fract() == 0.0 && n.abs() < 1e15 {
                     format!("{}", *n as i64)
@@ -135,43 +168,118 @@ impl CellValue {
         }
     }
 
-    /// Check if the value is truthy
-    pub fn is_truthy(&self) -> bool {
+    /// The kind of value held, for styling in the UI
+    pub fn value_type(&self) -> ValueType {
+        match self {
+            Self::Empty => ValueType::Empty,
+            Self::Text(_) => ValueType::Text,
+            Self::Number(_) => ValueType::Number,
+            Self::Boolean(_) => ValueType::Boolean,
+            Self::Error(_) => ValueType::Error,
+        }
+    }
+
+    /// The value as a logical condition, as IF reads it
+    ///
+    /// Blanks are FALSE and numbers are TRUE unless zero. Text only counts
+    /// when it spells TRUE or FALSE (in any case); other text and errors
+    /// have no logical value.
+    pub fn to_bool(&self) -> Option<bool> {
         match self {
-            Self::Empty => false,
-            Self::Text(s) => !s.is_empty(),
-            Self::Number(n) => *n != 0.0,
-            Self::Boolean(b) => *b,
-            Self::Error(_) => false,
+            Self::Empty => Some(false),
+            Self::Number(n) => Some(*n != 0.0),
+            Self::Boolean(b) => Some(*b),
+            Self::Text(s) if s.eq_ignore_ascii_case("true") => Some(true),
+            Self::Text(s) if s.eq_ignore_ascii_case("false") => Some(false),
+            Self::Text(_) | Self::Error(_) => None,
         }
     }
 
+    /// Check if the value is truthy, treating values without a logical value as false
+    pub fn is_truthy(&self) -> bool {
+        self.to_bool().unwrap_or(false)
+    }
+
     /// Convert to number if possible
     pub fn to_number(&self) -> Option<f64> {
         match self {
             Self::Number(n) => Some(*n),
             Self::Boolean(true) => Some(1.0),
             Self::Boolean(false) => Some(0.0),
-            Self::Text(s) => s.trim().parse().ok(),
+            Self::Text(s) => parse_finite(trim_invisible(s)),
             _ => None,
         }
     }
 }
 
-impl Default for CellValue {
-    fn default() -> Self {
-        Self::Empty
-    }
+/// Strip leading and trailing characters that draw nothing
+///
+/// That is Unicode whitespace (tabs, newlines, non-breaking spaces), control
+/// characters, and the zero-width format characters that sneak in from
+/// pasted web text: zero-width space/joiners, the word joiner and the BOM.
+pub fn trim_invisible(s: &str) -> &str {
+    s.trim_matches(|c: char| {
+        c.is_whitespace() || c.is_control() || matches!(c, '\u{200B}'..='\u{200D}' | '\u{2060}' | '\u{FEFF}')
+    })
+}
+
+/// Parse a number, refusing "NaN", "inf" and literals that overflow
+fn parse_finite(s: &str) -> Option<f64> {
+    s.parse::<f64>().ok().filter(|n| n.is_finite())
+}
+
+/// Type tag of a cell value, sent alongside display strings
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ValueType {
+    Empty,
+    Text,
+    Number,
+    Boolean,
+    Error,
+    /// A number shown with a date format
+    Date,
 }
 
 /// A complete cell with value, formula, and formatting
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Cell {
     pub value: CellValue,
+    /// Persisted copy of the formula text
+    ///
+    /// The formula engine is the source of truth for formulas; this copy is
+    /// only written by `Grid::set_cell_formula`/`Grid::set_cell_value` and is
+    /// read back when a saved sheet is loaded.
     #[serde(skip_serializing_if = "Option::is_none")]
     pub formula: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub format: Option<CellFormat>,
+    /// Free-text note attached to the cell
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub note: Option<String>,
+    /// Allowed values for list-style data validation
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub validation: Option<Vec<String>>,
+    /// Named style the cell's format is based on
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub style: Option<String>,
+    /// Formatting set on a styled cell itself
+    ///
+    /// For a styled cell `format` is the style with these overrides merged
+    /// over it, kept up to date whenever either changes.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub format_overrides: Option<CellFormat>,
+    /// `formula` can't be evaluated by this engine, so `value` is frozen (see `foreign`)
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub foreign: bool,
+    /// Number format the formula's result inherits from its operands (see
+    /// `format_inherit`), used when `format` sets none
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub implicit_format: Option<String>,
+}
+
+pub(crate) fn is_false(flag: &bool) -> bool {
+    !flag
 }
 
 impl Cell {
@@ -180,6 +288,12 @@ impl Cell {
             value,
             formula: None,
             format: None,
+            note: None,
+            validation: None,
+            style: None,
+            format_overrides: None,
+            foreign: false,
+            implicit_format: None,
         }
     }
 
@@ -188,11 +302,58 @@ impl Cell {
             value,
             formula: Some(formula),
             format: None,
+            note: None,
+            validation: None,
+            style: None,
+            format_overrides: None,
+            foreign: false,
+            implicit_format: None,
+        }
+    }
+
+    /// The number format the cell shows with: its own, or else the one its formula inherits
+    pub fn number_format(&self) -> Option<&str> {
+        self.format
+            .as_ref()
+            .and_then(|format| format.number_format.as_deref())
+            .or(self.implicit_format.as_deref())
+    }
+
+    /// The kind of value held, treating numbers with a date format as dates
+    pub fn value_type(&self) -> ValueType {
+        match (&self.value, self.number_format()) {
+            (CellValue::Number(_), Some(number_format)) if is_date_format(number_format) => ValueType::Date,
+            (value, _) => value.value_type(),
         }
     }
 
+    /// The value as shown in the cell, with its number format applied
+    ///
+    /// Codes TEXT understands (`0.00`, `0%`, `$#,##0.00`, scientific, dates
+    /// such as `m/d/yyyy` ...) format numbers; other values, and numbers
+    /// under a code it doesn't understand such as a time code, show as
+    /// `CellValue::display` does.
     pub fn display(&self) -> String {
-        self.value.display()
+        match (&self.value, self.number_format()) {
+            (CellValue::Number(n), Some(number_format)) => number_format::format_number(*n, number_format),
+            (value, _) => value.display(),
+        }
+    }
+
+    /// Whether the cell holds nothing worth storing
+    ///
+    /// Grid storage never keeps a blank cell, so whether a cell exists
+    /// doesn't depend on how it was edited. A format with nothing set
+    /// counts as no format.
+    pub fn is_blank(&self) -> bool {
+        let unset = |format: &Option<CellFormat>| format.as_ref().is_none_or(|format| *format == CellFormat::default());
+        matches!(self.value, CellValue::Empty)
+            && self.formula.is_none()
+            && unset(&self.format)
+            && unset(&self.format_overrides)
+            && self.note.is_none()
+            && self.validation.is_none()
+            && self.style.is_none()
     }
 }
 
@@ -202,8 +363,73 @@ impl Default for Cell {
     }
 }
 
+/// Format a number with a display-compacting number format
+///
+/// Understands scientific formats such as `0.00E+00` (digits after the point
+/// set the mantissa precision, zeros after the sign the minimum exponent
+/// width, and `E-` shows the sign only for negative exponents) and
+/// `compact`, which abbreviates with K/M/B/T suffixes to one decimal
+/// (`1.2T`). Other formats give `None` and the value's default display.
+pub fn format_number(value: f64, number_format: &str) -> Option<String> {
+    if !value.is_finite() {
+        return None;
+    }
+    if number_format.eq_ignore_ascii_case("compact") {
+        return Some(format_compact(value));
+    }
+    let (mantissa, exponent) = number_format.split_once(['E', 'e'])?;
+    let decimals = match mantissa.split_once('.') {
+        Some(("0", zeros)) if zeros.chars().all(|c| c == '0') => zeros.len(),
+        None if mantissa == "0" => 0,
+        _ => return None,
+    };
+    let (always_sign, width) = match exponent.split_at_checked(1)? {
+        ("+", zeros) if !zeros.is_empty() && zeros.chars().all(|c| c == '0') => (true, zeros.len()),
+        ("-", zeros) if !zeros.is_empty() && zeros.chars().all(|c| c == '0') => (false, zeros.len()),
+        _ => return None,
+    };
+    // Rust renders `1.23e12` / `1.23e-4`; rebuild the exponent in Excel's style
+    let rendered = format!("{:.*e}", decimals, value);
+    let (digits, power) = rendered.split_once('e')?;
+    let power: i32 = power.parse().ok()?;
+    let sign = if power < 0 { "-" } else if always_sign { "+" } else { "" };
+    Some(format!("{}E{}{:0width$}", digits, sign, power.unsigned_abs(), width = width))
+}
+
+/// Whether a number format shows dates, i.e. has day or year codes outside quoted text
+pub fn is_date_format(number_format: &str) -> bool {
+    if number_format.eq_ignore_ascii_case("compact") {
+        return false;
+    }
+    let mut quoted = false;
+    number_format.chars().any(|c| {
+        if c == '"' {
+            quoted = !quoted;
+        }
+        !quoted && matches!(c.to_ascii_lowercase(), 'd' | 'y')
+    })
+}
+
+fn format_compact(value: f64) -> String {
+    let one_decimal = |n: f64| (n * 10.0).round() / 10.0;
+    let mut scaled = value.abs();
+    let mut suffix = "";
+    // Step up while the rounded figure would reach 1000, so 999,960 is 1M rather than 1000K
+    for next in ["K", "M", "B", "T"] {
+        if one_decimal(scaled) < 1000.0 {
+            break;
+        }
+        scaled /= 1000.0;
+        suffix = next;
+    }
+    let scaled = one_decimal(scaled);
+    let sign = if value < 0.0 && scaled != 0.0 { "-" } else { "" };
+    let text = format!("{:.1}", scaled);
+    format!("{}{}{}", sign, text.strip_suffix(".0").unwrap_or(&text), suffix)
+}
+
 /// Cell formatting options
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
 pub struct CellFormat {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub number_format: Option<String>,
@@ -227,7 +453,27 @@ pub struct CellFormat {
     pub align_v: Option<VerticalAlign>,
 }
 
-#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+impl CellFormat {
+    /// Overwrite every field that `other` sets
+    pub fn merge(&mut self, other: CellFormat) {
+        let CellFormat {
+            number_format, font_bold, font_italic, font_underline, font_family,
+            font_size, font_color, bg_color, align_h, align_v,
+        } = other;
+        self.number_format = number_format.or(self.number_format.take());
+        self.font_bold = font_bold.or(self.font_bold);
+        self.font_italic = font_italic.or(self.font_italic);
+        self.font_underline = font_underline.or(self.font_underline);
+        self.font_family = font_family.or(self.font_family.take());
+        self.font_size = font_size.or(self.font_size);
+        self.font_color = font_color.or(self.font_color.take());
+        self.bg_color = bg_color.or(self.bg_color.take());
+        self.align_h = align_h.or(self.align_h);
+        self.align_v = align_v.or(self.align_v);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(rename_all = "lowercase")]
 pub enum HorizontalAlign {
     Left,
@@ -235,7 +481,7 @@ pub enum HorizontalAlign {
     Right,
 }
 
-#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(rename_all = "lowercase")]
 pub enum VerticalAlign {
     Top,
@@ -247,6 +493,56 @@ pub enum VerticalAlign {
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_scientific_and_compact_formats() {
+        assert_eq!(format_number(1234567890123.0, "0.00E+00").as_deref(), Some("1.23E+12"));
+        assert_eq!(format_number(0.000123456, "0.00E+00").as_deref(), Some("1.23E-04"));
+        assert_eq!(format_number(-98765.0, "0.0E-0").as_deref(), Some("-9.9E4"));
+        assert_eq!(format_number(0.0, "0E+00").as_deref(), Some("0E+00"));
+
+        assert_eq!(format_number(1234567890123.0, "compact").as_deref(), Some("1.2T"));
+        assert_eq!(format_number(1500.0, "compact").as_deref(), Some("1.5K"));
+        assert_eq!(format_number(-2_000_000.0, "compact").as_deref(), Some("-2M"));
+        assert_eq!(format_number(999_960.0, "compact").as_deref(), Some("1M"));
+        assert_eq!(format_number(999.0, "compact").as_deref(), Some("999"));
+        assert_eq!(format_number(12.34, "compact").as_deref(), Some("12.3"));
+
+        // Other formats keep the default display
+        assert_eq!(format_number(5.0, "$#,##0"), None);
+        let mut cell = Cell::new(CellValue::Number(1234567890123.0));
+        assert_eq!(cell.display(), "1234567890123");
+        cell.format = Some(CellFormat { number_format: Some("0.00E+00".to_string()), ..CellFormat::default() });
+        assert_eq!(cell.display(), "1.23E+12");
+    }
+
+    #[test]
+    fn test_display_applies_number_format() {
+        let formatted = |value: f64, number_format: &str| {
+            let mut cell = Cell::new(CellValue::Number(value));
+            cell.format = Some(CellFormat { number_format: Some(number_format.to_string()), ..CellFormat::default() });
+            cell.display()
+        };
+        assert_eq!(formatted(0.5, "0%"), "50%");
+        assert_eq!(formatted(0.1234, "0.0%"), "12.3%");
+        assert_eq!(formatted(1234567.891, "$#,##0.00"), "$1,234,567.89");
+        assert_eq!(formatted(-1234.5, "$#,##0.00"), "-$1,234.50");
+        assert_eq!(formatted(1234567.0, "#,##0"), "1,234,567");
+        assert_eq!(formatted(7.456, "0.00"), "7.46");
+        assert_eq!(formatted(2.0, "0.00"), "2.00");
+        assert_eq!(formatted(45322.0, "yyyy-mm-dd"), "2024-01-31");
+        // Codes shown some other way keep the plain display
+        assert_eq!(formatted(0.5, "hh:mm"), "0.5");
+        assert_eq!(formatted(0.5, "General"), "0.5");
+
+        // Only numbers are formatted, and an implicit format counts too
+        let mut cell = Cell::new(CellValue::Text("n/a".to_string()));
+        cell.implicit_format = Some("0%".to_string());
+        assert_eq!(cell.display(), "n/a");
+        cell.value = CellValue::Number(0.25);
+        assert_eq!(cell.display(), "25%");
+        assert_eq!(cell.value, CellValue::Number(0.25));
+    }
+
     #[test]
     fn test_cell_ref_parsing() {
         assert_eq!(CellRef::parse("A1"), Some(CellRef::new(0, 0)));
@@ -267,11 +563,42 @@ mod tests {
     fn test_cell_value_parsing() {
         assert_eq!(CellValue::parse(""), CellValue::Empty);
         assert_eq!(CellValue::parse("42"), CellValue::Number(42.0));
-        assert_eq!(CellValue::parse("3.14"), CellValue::Number(3.14));
+        assert_eq!(CellValue::parse("2.5"), CellValue::Number(2.5));
         assert_eq!(CellValue::parse("true"), CellValue::Boolean(true));
         assert_eq!(CellValue::parse("Hello"), CellValue::Text("Hello".to_string()));
         assert_eq!(CellValue::parse("50%"), CellValue::Number(0.5));
         assert_eq!(CellValue::parse("$100"), CellValue::Number(100.0));
+        assert_eq!(CellValue::parse("NaN"), CellValue::Text("NaN".to_string()));
+        assert_eq!(CellValue::parse("inf"), CellValue::Text("inf".to_string()));
+        assert_eq!(CellValue::Text("nan".to_string()).to_number(), None);
+    }
+
+    #[test]
+    fn test_invisible_input_is_empty() {
+        for blank in ["\t", "\u{00A0}", "\u{200B}\u{200B}", " \r\n", "\u{FEFF}", "\u{0007}"] {
+            assert_eq!(CellValue::parse(blank), CellValue::Empty, "{:?}", blank);
+        }
+        assert_eq!(CellValue::parse("\u{200B}42\t"), CellValue::Number(42.0));
+        assert_eq!(CellValue::parse("\u{00A0}true"), CellValue::Boolean(true));
+        assert_eq!(CellValue::Text("7\u{200B}".to_string()).to_number(), Some(7.0));
+        // Visible text keeps its characters, inner ones included
+        assert_eq!(CellValue::parse("a\u{200B}b"), CellValue::Text("a\u{200B}b".to_string()));
+    }
+
+    #[test]
+    fn test_non_finite_numbers_load_as_errors() {
+        // JSON writes NaN and infinity as null
+        let json = serde_json::to_string(&CellValue::Number(f64::NAN)).unwrap();
+        assert_eq!(json, r#"{"type":"Number","value":null}"#);
+        let loaded: CellValue = serde_json::from_str(&json).unwrap();
+        assert_eq!(loaded, CellValue::Error("NUM".to_string()));
+        let loaded: CellValue = serde_json::from_str(r#"{"type":"Number","value":2.5}"#).unwrap();
+        assert_eq!(loaded, CellValue::Number(2.5));
+
+        assert_eq!(CellValue::Number(f64::INFINITY).display(), "#NUM");
+        let mut cell = Cell::new(CellValue::Number(f64::NAN));
+        cell.format = Some(CellFormat { number_format: Some("compact".to_string()), ..CellFormat::default() });
+        assert_eq!(cell.display(), "#NUM");
     }
 }
 
@@ -0,0 +1,313 @@
+//! Chart bindings: ranges whose numeric series feed the charting layer
+//!
+//! A binding names a range and says whether its series run down columns or
+//! across rows, and whether the first row/column holds series labels. Diffs
+//! returned after an edit carry the regenerated data of every binding whose
+//! range the edit touched, so charts don't have to re-read raw cells.
+
+use crate::cell::CellRef;
+use crate::grid::{Grid, GridError, SeriesOrder};
+use indexmap::IndexMap;
+use serde::{Deserialize, Serialize};
+
+/// Which way a chart's series run through its range
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SeriesOrientation {
+    /// One series per column; the header is the first row
+    Columns,
+    /// One series per row; the header is the first column
+    Rows,
+}
+
+/// A chart bound to a range
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ChartBinding {
+    pub id: String,
+    pub start: CellRef,
+    pub end: CellRef,
+    pub orientation: SeriesOrientation,
+    pub has_header: bool,
+}
+
+/// Series extracted for a chart
+///
+/// `series[i]` is labelled `labels[i]`: the header cell when the binding has
+/// one, otherwise the column letter or 1-based row number.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ChartData {
+    pub id: String,
+    pub labels: Vec<String>,
+    /// Numeric values, `null` for anything that isn't a number
+    pub series: Vec<Vec<Option<f64>>>,
+}
+
+/// Chart bindings defined on a sheet, in creation order
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ChartStore {
+    #[serde(default)]
+    bindings: IndexMap<String, ChartBinding>,
+}
+
+impl ChartStore {
+    pub fn list(&self) -> Vec<&ChartBinding> {
+        self.bindings.values().collect()
+    }
+
+    pub fn get(&self, id: &str) -> Option<&ChartBinding> {
+        self.bindings.get(id)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bindings.is_empty()
+    }
+
+    /// Move bindings at or below `start` down by `count` rows
+    pub(crate) fn rows_inserted(&mut self, start: u32, count: u32) {
+        for binding in self.bindings.values_mut() {
+            for cell in [&mut binding.start, &mut binding.end] {
+                if cell.row >= start {
+                    cell.row += count;
+                }
+            }
+        }
+    }
+
+    /// Shift or stretch bindings for inserted columns
+    pub(crate) fn cols_inserted(&mut self, start: u32, count: u32) {
+        for binding in self.bindings.values_mut() {
+            for cell in [&mut binding.start, &mut binding.end] {
+                if cell.col >= start {
+                    cell.col += count;
+                }
+            }
+        }
+    }
+
+    /// Shrink or shift bindings for deleted rows, dropping any left empty
+    pub(crate) fn rows_deleted(&mut self, start: u32, count: u32) {
+        let end = start.saturating_add(count);
+        let shift = |row: u32| if row >= end { row - count } else { row };
+        self.bindings.retain(|_, binding| {
+            let mut surviving = (binding.start.row..=binding.end.row).filter(|row| !(start..end).contains(row));
+            let (Some(first), Some(last)) = (surviving.clone().next(), surviving.next_back()) else {
+                return false;
+            };
+            binding.start.row = shift(first);
+            binding.end.row = shift(last);
+            true
+        });
+    }
+
+    /// Shrink or shift bindings for deleted columns, dropping any left empty
+    pub(crate) fn cols_deleted(&mut self, start: u32, count: u32) {
+        let end = start.saturating_add(count);
+        let shift = |col: u32| if col >= end { col - count } else { col };
+        self.bindings.retain(|_, binding| {
+            let mut surviving = (binding.start.col..=binding.end.col).filter(|col| !(start..end).contains(col));
+            let (Some(first), Some(last)) = (surviving.clone().next(), surviving.next_back()) else {
+                return false;
+            };
+            binding.start.col = shift(first);
+            binding.end.col = shift(last);
+            true
+        });
+    }
+}
+
+impl Grid {
+    /// The sheet's chart bindings
+    pub fn charts(&self) -> &ChartStore {
+        &self.charts
+    }
+
+    /// Bind (or rebind) chart `id` to the range between `start` and `end`
+    pub fn add_chart_binding(&mut self, id: &str, start: CellRef, end: CellRef, orientation: SeriesOrientation, has_header: bool) -> Result<ChartData, GridError> {
+        self.check_bounds(start)?;
+        self.check_bounds(end)?;
+        let binding = ChartBinding {
+            id: id.to_string(),
+            start: CellRef::new(start.row.min(end.row), start.col.min(end.col)),
+            end: CellRef::new(start.row.max(end.row), start.col.max(end.col)),
+            orientation,
+            has_header,
+        };
+        let data = self.extract_chart(&binding);
+        self.charts.bindings.insert(id.to_string(), binding);
+        self.touch();
+        Ok(data)
+    }
+
+    /// Remove a chart binding, returning whether it existed
+    pub fn remove_chart_binding(&mut self, id: &str) -> bool {
+        let removed = self.charts.bindings.shift_remove(id).is_some();
+        if removed {
+            self.touch();
+        }
+        removed
+    }
+
+    /// Current series data for chart `id`
+    pub fn chart_data(&self, id: &str) -> Option<ChartData> {
+        self.charts.get(id).map(|binding| self.extract_chart(binding))
+    }
+
+    /// Data for every chart whose range contains one of `cells`
+    pub fn charts_touching(&self, cells: &[CellRef]) -> Vec<ChartData> {
+        self.charts.bindings.values()
+            .filter(|binding| cells.iter().any(|cell| {
+                (binding.start.row..=binding.end.row).contains(&cell.row)
+                    && (binding.start.col..=binding.end.col).contains(&cell.col)
+            }))
+            .map(|binding| self.extract_chart(binding))
+            .collect()
+    }
+
+    fn extract_chart(&self, binding: &ChartBinding) -> ChartData {
+        let ChartBinding { start, end, .. } = *binding;
+        let skip = u32::from(binding.has_header);
+        let (labels, series) = match binding.orientation {
+            SeriesOrientation::Columns => (start.col..=end.col)
+                .map(|col| {
+                    let label = if binding.has_header {
+                        self.display_at(CellRef::new(start.row, col))
+                    } else {
+                        CellRef::col_to_letter(col)
+                    };
+                    let values = if start.row + skip <= end.row {
+                        self.series(CellRef::new(start.row + skip, col), CellRef::new(end.row, col), SeriesOrder::ColumnMajor)
+                    } else {
+                        Vec::new()
+                    };
+                    (label, values)
+                })
+                .unzip(),
+            SeriesOrientation::Rows => (start.row..=end.row)
+                .map(|row| {
+                    let label = if binding.has_header {
+                        self.display_at(CellRef::new(row, start.col))
+                    } else {
+                        (row + 1).to_string()
+                    };
+                    let values = if start.col + skip <= end.col {
+                        self.series(CellRef::new(row, start.col + skip), CellRef::new(row, end.col), SeriesOrder::RowMajor)
+                    } else {
+                        Vec::new()
+                    };
+                    (label, values)
+                })
+                .unzip(),
+        };
+        ChartData { id: binding.id.clone(), labels, series }
+    }
+
+    fn display_at(&self, cell: CellRef) -> String {
+        self.get_cell(cell).map(|c| c.display()).unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cell::CellValue;
+    use crate::formula::FormulaEngine;
+    use crate::grid::GridDiff;
+    use crate::test_support::set;
+
+    /// B1:D4: a header row, then revenue and cost by quarter
+    fn model() -> (Grid, FormulaEngine) {
+        let mut grid = Grid::new(20, 10);
+        let mut engine = FormulaEngine::new();
+        let rows = [["Quarter", "Revenue", "Cost"], ["Q1", "100", "60"], ["Q2", "120", "n/a"], ["Q3", "150", "80"]];
+        for (r, values) in rows.iter().enumerate() {
+            for (c, value) in values.iter().enumerate() {
+                set(&mut grid, &mut engine, &format!("{}{}", CellRef::col_to_letter(1 + c as u32), r + 1), value);
+            }
+        }
+        (grid, engine)
+    }
+
+    fn cell(a1: &str) -> CellRef {
+        CellRef::parse(a1).unwrap()
+    }
+
+    #[test]
+    fn test_series_by_column() {
+        let (mut grid, _) = model();
+        let data = grid.add_chart_binding("sales", cell("C1"), cell("D4"), SeriesOrientation::Columns, true).unwrap();
+        assert_eq!(data.labels, vec!["Revenue", "Cost"]);
+        assert_eq!(data.series, vec![
+            vec![Some(100.0), Some(120.0), Some(150.0)],
+            vec![Some(60.0), None, Some(80.0)],
+        ]);
+    }
+
+    #[test]
+    fn test_series_by_row() {
+        let (mut grid, _) = model();
+        grid.add_chart_binding("quarters", cell("B2"), cell("D4"), SeriesOrientation::Rows, true).unwrap();
+        let data = grid.chart_data("quarters").unwrap();
+        assert_eq!(data.labels, vec!["Q1", "Q2", "Q3"]);
+        assert_eq!(data.series[1], vec![Some(120.0), None]);
+
+        // Without a header, rows are labelled by number
+        grid.add_chart_binding("quarters", cell("C2"), cell("D3"), SeriesOrientation::Rows, false).unwrap();
+        let data = grid.chart_data("quarters").unwrap();
+        assert_eq!(data.labels, vec!["2", "3"]);
+        assert_eq!(data.series, vec![vec![Some(100.0), Some(60.0)], vec![Some(120.0), None]]);
+    }
+
+    #[test]
+    fn test_edits_carry_regenerated_series() {
+        let (mut grid, mut engine) = model();
+        grid.add_chart_binding("sales", cell("C1"), cell("D4"), SeriesOrientation::Columns, true).unwrap();
+        set(&mut grid, &mut engine, "F1", "=C2*2");
+
+        // Editing a source cell
+        let affected = set(&mut grid, &mut engine, "D3", "70");
+        let diff = GridDiff::from_cells(&grid, &engine, &affected);
+        assert_eq!(diff.charts.len(), 1);
+        assert_eq!(diff.charts[0].series[1], vec![Some(60.0), Some(70.0), Some(80.0)]);
+
+        // Cells outside every binding produce no chart payload
+        let affected = set(&mut grid, &mut engine, "H8", "1");
+        assert!(GridDiff::from_cells(&grid, &engine, &affected).charts.is_empty());
+
+        assert!(grid.remove_chart_binding("sales"));
+        assert!(!grid.remove_chart_binding("sales"));
+        assert!(grid.chart_data("sales").is_none());
+    }
+
+    #[test]
+    fn test_binding_follows_inserted_rows() {
+        let (mut grid, mut engine) = model();
+        grid.add_chart_binding("sales", cell("C1"), cell("D4"), SeriesOrientation::Columns, true).unwrap();
+
+        grid.insert_rows(0, 2, &mut engine).unwrap();
+        let binding = grid.charts().get("sales").unwrap();
+        assert_eq!((binding.start, binding.end), (cell("C3"), cell("D6")));
+        assert_eq!(grid.chart_data("sales").unwrap().series[0], vec![Some(100.0), Some(120.0), Some(150.0)]);
+
+        // Rows inserted inside the range stretch it
+        grid.insert_rows(4, 1, &mut engine).unwrap();
+        let data = grid.chart_data("sales").unwrap();
+        assert_eq!(data.series[0], vec![Some(100.0), None, Some(120.0), Some(150.0)]);
+
+        let restored = Grid::from_json(&grid.to_json().unwrap()).unwrap();
+        assert_eq!(restored.chart_data("sales"), Some(data));
+        assert_eq!(restored.get_cell(cell("C4")).map(|c| c.value.clone()), Some(CellValue::Number(100.0)));
+    }
+
+    #[test]
+    fn test_deleted_columns_shrink_binding() {
+        let (mut grid, mut engine) = model();
+        grid.add_chart_binding("sales", cell("C1"), cell("D4"), SeriesOrientation::Columns, true).unwrap();
+        grid.delete_cols(2, 1, &mut engine).unwrap();
+        let binding = grid.charts().get("sales").unwrap();
+        assert_eq!((binding.start, binding.end), (cell("C1"), cell("C4")));
+        assert_eq!(grid.chart_data("sales").unwrap().labels, vec!["Cost"]);
+
+        grid.delete_cols(2, 1, &mut engine).unwrap();
+        assert!(grid.charts().is_empty());
+    }
+}
@@ -0,0 +1,205 @@
+//! Copy and paste between our own grids, keeping formulas
+//!
+//! Copying to other applications goes through tab-separated text
+//! (`paste_text`, the CSV export), which can only carry values. When the
+//! copy stays inside the engine, a JSON payload carries every cell's value,
+//! formula and format instead, and pasting it translates relative
+//! references by the distance moved, as Excel does.
+
+use crate::cell::{is_false, Cell, CellFormat, CellRef, CellValue};
+use crate::formula::FormulaEngine;
+use crate::grid::{Grid, GridError};
+use serde::{Deserialize, Serialize};
+
+/// A copied cell, positioned relative to the block's top-left corner
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ClipboardCell {
+    pub row: u32,
+    pub col: u32,
+    pub value: CellValue,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub formula: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub format: Option<CellFormat>,
+    /// `formula` couldn't be evaluated at the source (see `foreign`)
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub foreign: bool,
+}
+
+/// A copied block of cells
+///
+/// Cells of the block missing from `cells` were blank, and pasting clears
+/// them at the destination.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ClipboardPayload {
+    /// Where the block was copied from; formulas are written relative to it
+    pub origin: CellRef,
+    pub rows: u32,
+    pub cols: u32,
+    pub cells: Vec<ClipboardCell>,
+}
+
+impl ClipboardPayload {
+    pub fn to_json(&self) -> Result<String, GridError> {
+        serde_json::to_string(self).map_err(|e| GridError::Serialization(e.to_string()))
+    }
+
+    pub fn from_json(json: &str) -> Result<Self, GridError> {
+        serde_json::from_str(json).map_err(|e| GridError::Serialization(e.to_string()))
+    }
+}
+
+impl Grid {
+    /// Copy the cells between `start` and `end` for pasting into a grid of ours
+    pub fn copy_internal(&self, start: CellRef, end: CellRef) -> Result<ClipboardPayload, GridError> {
+        self.check_bounds(start)?;
+        self.check_bounds(end)?;
+        let origin = CellRef::new(start.row.min(end.row), start.col.min(end.col));
+        let (rows, cols) = (start.row.abs_diff(end.row) + 1, start.col.abs_diff(end.col) + 1);
+        let mut cells = Vec::new();
+        for row in 0..rows {
+            for col in 0..cols {
+                let Some(cell) = self.get_cell(CellRef::new(origin.row + row, origin.col + col)) else {
+                    continue;
+                };
+                cells.push(ClipboardCell {
+                    row,
+                    col,
+                    value: cell.value.clone(),
+                    formula: cell.formula.clone(),
+                    format: cell.format.clone(),
+                    foreign: cell.foreign,
+                });
+            }
+        }
+        Ok(ClipboardPayload { origin, rows, cols, cells })
+    }
+
+    /// Paste a copied block with its top-left corner at `target`
+    ///
+    /// Relative references move with the block. A formula whose reference
+    /// would move off the grid becomes `#REF`, and a foreign formula is
+    /// pasted as written with its copied value.
+    /// Returns every cell written or recalculated.
+    pub fn paste_internal(&mut self, target: CellRef, payload: &ClipboardPayload, formula_engine: &mut FormulaEngine) -> Result<Vec<CellRef>, GridError> {
+        let last = CellRef::new(
+            target.row.saturating_add(payload.rows.saturating_sub(1)),
+            target.col.saturating_add(payload.cols.saturating_sub(1)),
+        );
+        self.check_bounds(target)?;
+        self.check_bounds(last)?;
+        let rows = i64::from(target.row) - i64::from(payload.origin.row);
+        let cols = i64::from(target.col) - i64::from(payload.origin.col);
+        let limit = CellRef::new(self.rows, self.cols);
+
+        let mut affected = Vec::new();
+        for row in 0..payload.rows {
+            for col in 0..payload.cols {
+                let cell_ref = CellRef::new(target.row + row, target.col + col);
+                // Formats, notes and validation go too, as the block replaces the cells
+                self.set_cell_value(cell_ref, CellValue::Empty, formula_engine)?;
+                if let Some(column) = self.columns.get_mut(&cell_ref.col) {
+                    column.remove(cell_ref.row);
+                    if column.is_empty() {
+                        self.columns.remove(&cell_ref.col);
+                    }
+                }
+                affected.push(cell_ref);
+            }
+        }
+        for copied in &payload.cells {
+            let cell_ref = CellRef::new(target.row + copied.row, target.col + copied.col);
+            match &copied.formula {
+                Some(text) if copied.foreign => {
+                    self.set_cell_value(cell_ref, copied.value.clone(), formula_engine)?;
+                    if let Some(cell) = self.get_cell_mut(cell_ref) {
+                        cell.formula = Some(text.clone());
+                        cell.foreign = true;
+                    }
+                }
+                Some(text) => {
+                    let mut formula = formula_engine.parse(text)?;
+                    if formula.ast.offset(rows, cols, limit) {
+                        let moved = formula_engine.parse(&formula.ast.to_formula_string())?;
+                        self.set_cell_formula(cell_ref, moved, formula_engine)?;
+                    } else {
+                        self.set_cell_value(cell_ref, CellValue::Error("REF".to_string()), formula_engine)?;
+                    }
+                }
+                None => self.set_cell_value(cell_ref, copied.value.clone(), formula_engine)?,
+            }
+            if copied.format.is_some() {
+                let cell = self.columns.entry(cell_ref.col).or_default().get_or_insert_with(cell_ref.row, || Cell::new(CellValue::Empty));
+                cell.format = copied.format.clone();
+            }
+        }
+        self.warn_empty_ranges(&affected, formula_engine);
+
+        self.recalculate_cells(affected, formula_engine)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::set;
+
+    fn formula(grid: &Grid, a1: &str) -> Option<String> {
+        grid.get_cell(CellRef::parse(a1).unwrap()).and_then(|cell| cell.formula.clone())
+    }
+
+    fn value(grid: &Grid, a1: &str) -> CellValue {
+        grid.get_cell(CellRef::parse(a1).unwrap()).map(|cell| cell.value.clone()).unwrap_or_default()
+    }
+
+    #[test]
+    fn test_paste_translates_relative_references() {
+        let mut grid = Grid::new(20, 10);
+        let mut engine = FormulaEngine::new();
+        for (a1, input) in [("A1", "2"), ("A2", "3"), ("B1", "10"), ("B2", "20"), ("D1", "100")] {
+            set(&mut grid, &mut engine, a1, input);
+        }
+        set(&mut grid, &mut engine, "C1", "=A1*B1+$D$1");
+        set(&mut grid, &mut engine, "C2", "=SUM(A$1:B2)");
+        grid.set_format(CellRef::parse("C1").unwrap(), CellFormat { font_bold: Some(true), ..Default::default() }).unwrap();
+
+        let payload = grid.copy_internal(CellRef::parse("A1").unwrap(), CellRef::parse("C2").unwrap()).unwrap();
+        let payload = ClipboardPayload::from_json(&payload.to_json().unwrap()).unwrap();
+        grid.paste_internal(CellRef::parse("A5").unwrap(), &payload, &mut engine).unwrap();
+
+        assert_eq!(formula(&grid, "C5").as_deref(), Some("=A5*B5+$D$1"));
+        assert_eq!(formula(&grid, "C6").as_deref(), Some("=SUM(A$1:B6)"));
+        assert_eq!(value(&grid, "C5"), CellValue::Number(120.0));
+        assert_eq!(value(&grid, "A6"), CellValue::Number(3.0));
+        assert_eq!(grid.get_cell(CellRef::parse("C5").unwrap()).unwrap().format.as_ref().unwrap().font_bold, Some(true));
+
+        // The pasted formulas are live
+        set(&mut grid, &mut engine, "A5", "4");
+        assert_eq!(value(&grid, "C5"), CellValue::Number(140.0));
+    }
+
+    #[test]
+    fn test_paste_clears_blanks_and_flags_lost_references() {
+        let mut grid = Grid::new(20, 10);
+        let mut engine = FormulaEngine::new();
+        set(&mut grid, &mut engine, "A1", "1");
+        set(&mut grid, &mut engine, "B2", "=A1+1");
+        set(&mut grid, &mut engine, "F1", "old");
+
+        // Copying B1:B2 and pasting at F1: B1 was blank, and F2's A1 would be left of column A
+        let payload = grid.copy_internal(CellRef::parse("B1").unwrap(), CellRef::parse("B2").unwrap()).unwrap();
+        let moved_left = grid.copy_internal(CellRef::parse("B2").unwrap(), CellRef::parse("B2").unwrap()).unwrap();
+        grid.paste_internal(CellRef::parse("F1").unwrap(), &payload, &mut engine).unwrap();
+        assert_eq!(value(&grid, "F1"), CellValue::Empty);
+        assert_eq!(formula(&grid, "F2").as_deref(), Some("=E1+1"));
+
+        grid.paste_internal(CellRef::parse("A3").unwrap(), &moved_left, &mut engine).unwrap();
+        assert_eq!(value(&grid, "A3"), CellValue::Error("REF".to_string()));
+        assert_eq!(formula(&grid, "A3"), None);
+
+        assert!(matches!(
+            grid.paste_internal(CellRef::parse("A20").unwrap(), &payload, &mut engine),
+            Err(GridError::OutOfBounds { .. })
+        ));
+    }
+}
@@ -0,0 +1,59 @@
+//! Clock abstraction so time-dependent behavior can be tested
+
+use std::cell::Cell;
+use std::fmt;
+use std::rc::Rc;
+
+/// Source of the current time, in milliseconds since the Unix epoch
+pub trait Clock: fmt::Debug {
+    fn now_millis(&self) -> f64;
+}
+
+/// Wall-clock time (`Date.now()` in the browser)
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    #[cfg(target_arch = "wasm32")]
+    fn now_millis(&self) -> f64 {
+        js_sys::Date::now()
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    fn now_millis(&self) -> f64 {
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_millis() as f64)
+            .unwrap_or(0.0)
+    }
+}
+
+/// A clock that only moves when told to (for tests)
+#[derive(Debug, Default)]
+pub struct ManualClock {
+    now: Cell<f64>,
+}
+
+impl ManualClock {
+    pub fn new(now_millis: f64) -> Self {
+        Self { now: Cell::new(now_millis) }
+    }
+
+    pub fn set(&self, now_millis: f64) {
+        self.now.set(now_millis);
+    }
+
+    pub fn advance(&self, millis: f64) {
+        self.now.set(self.now.get() + millis);
+    }
+}
+
+impl Clock for ManualClock {
+    fn now_millis(&self) -> f64 {
+        self.now.get()
+    }
+}
+
+pub(crate) fn system_clock() -> Rc<dyn Clock> {
+    Rc::new(SystemClock)
+}
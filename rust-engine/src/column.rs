@@ -0,0 +1,449 @@
+//! Per-column cell storage with optional run-length compression
+//!
+//! Most columns are a sparse row -> cell map. Columns where the same value
+//! repeats over long stretches of rows (a status flag, a region name) can be
+//! switched to storing `(start_row, run_length, cell)` runs instead. Both
+//! layouts expose the same API, so the grid doesn't care which one is in use.
+
+use crate::cell::Cell;
+use indexmap::IndexMap;
+use serde::de::{MapAccess, Visitor};
+use serde::ser::SerializeMap;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::mem;
+
+/// Columns with fewer populated cells than this are never run-encoded
+pub const RUN_MIN_CELLS: usize = 1024;
+/// Switch to runs when the average run is at least this long
+pub const RUN_MIN_AVG_LEN: usize = 8;
+/// Switch back to a map once the average run drops below this length
+pub const RUN_FALLBACK_AVG_LEN: usize = 2;
+
+/// Storage for one column's cells
+#[derive(Debug, Clone)]
+pub enum ColumnStore {
+    /// Sparse row -> cell map
+    Map(IndexMap<u32, Cell>),
+    /// Runs of identical cells over consecutive rows
+    Runs(RunColumn),
+}
+
+/// Run-length encoded column
+#[derive(Debug, Clone, Default)]
+pub struct RunColumn {
+    /// Runs keyed by their first row
+    runs: BTreeMap<u32, Run>,
+    /// Total populated rows across all runs
+    populated: usize,
+}
+
+#[derive(Debug, Clone)]
+struct Run {
+    len: u32,
+    cell: Cell,
+}
+
+impl Default for ColumnStore {
+    fn default() -> Self {
+        Self::Map(IndexMap::new())
+    }
+}
+
+impl ColumnStore {
+    pub fn get(&self, row: u32) -> Option<&Cell> {
+        match self {
+            Self::Map(map) => map.get(&row),
+            Self::Runs(runs) => runs.get(row),
+        }
+    }
+
+    /// Mutable access to one row; in run storage the row is split out of its run first
+    pub fn get_mut(&mut self, row: u32) -> Option<&mut Cell> {
+        match self {
+            Self::Map(map) => map.get_mut(&row),
+            Self::Runs(runs) => runs.get_mut(row),
+        }
+    }
+
+    /// Get a row's cell, inserting one built by `default` if the row is empty
+    pub fn get_or_insert_with(&mut self, row: u32, default: impl FnOnce() -> Cell) -> &mut Cell {
+        if self.get(row).is_none() {
+            self.insert(row, default());
+        }
+        self.get_mut(row).expect("row was just populated")
+    }
+
+    pub fn insert(&mut self, row: u32, cell: Cell) {
+        match self {
+            Self::Map(map) => {
+                map.insert(row, cell);
+            }
+            Self::Runs(runs) => {
+                runs.insert(row, cell);
+                self.maybe_expand();
+            }
+        }
+    }
+
+    pub fn remove(&mut self, row: u32) -> Option<Cell> {
+        let removed = match self {
+            Self::Map(map) => map.shift_remove(&row),
+            Self::Runs(runs) => runs.remove(row),
+        };
+        self.maybe_expand();
+        removed
+    }
+
+    /// Number of populated rows
+    pub fn len(&self) -> usize {
+        match self {
+            Self::Map(map) => map.len(),
+            Self::Runs(runs) => runs.populated,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn is_run_encoded(&self) -> bool {
+        matches!(self, Self::Runs(_))
+    }
+
+    /// Iterate populated rows; run storage yields each row of a run
+    pub fn iter(&self) -> Box<dyn Iterator<Item = (u32, &Cell)> + '_> {
+        match self {
+            Self::Map(map) => Box::new(map.iter().map(|(&row, cell)| (row, cell))),
+            Self::Runs(runs) => Box::new(runs.runs.iter().flat_map(|(&start, run)| {
+                (start..start + run.len).map(move |row| (row, &run.cell))
+            })),
+        }
+    }
+
+    /// Switch to run storage if the column is large and repetitive enough
+    ///
+    /// Returns whether the column is run-encoded afterwards.
+    pub fn compact(&mut self) -> bool {
+        if let Self::Map(map) = self {
+            if map.len() >= RUN_MIN_CELLS {
+                let mut rows: Vec<(u32, &Cell)> = map.iter().map(|(&row, cell)| (row, cell)).collect();
+                rows.sort_unstable_by_key(|(row, _)| *row);
+                if count_runs(&rows) * RUN_MIN_AVG_LEN <= rows.len() {
+                    let map = mem::take(map);
+                    let mut runs = RunColumn::default();
+                    let mut sorted: Vec<(u32, Cell)> = map.into_iter().collect();
+                    sorted.sort_unstable_by_key(|(row, _)| *row);
+                    for (row, cell) in sorted {
+                        runs.push_back(row, cell);
+                    }
+                    *self = Self::Runs(runs);
+                }
+            }
+        }
+        self.is_run_encoded()
+    }
+
+    /// Rough heap footprint in bytes, for comparing storage modes
+    pub fn approx_heap_bytes(&self) -> usize {
+        match self {
+            // Entries plus the hash index (one usize per bucket)
+            Self::Map(map) => map.capacity() * (mem::size_of::<(u32, Cell)>() + 2 * mem::size_of::<usize>()),
+            // B-tree nodes hold keys and values with little slack
+            Self::Runs(runs) => runs.runs.len() * (mem::size_of::<u32>() + mem::size_of::<Run>()) * 3 / 2,
+        }
+    }
+
+    /// Fall back to a map once runs have been fragmented by writes
+    fn maybe_expand(&mut self) {
+        let Self::Runs(runs) = self else {
+            return;
+        };
+        let fragmented = runs.runs.len() * RUN_FALLBACK_AVG_LEN > runs.populated;
+        if runs.populated < RUN_MIN_CELLS / 2 || fragmented {
+            let mut map = IndexMap::with_capacity(runs.populated);
+            for (start, run) in mem::take(&mut runs.runs) {
+                for row in start..start + run.len {
+                    map.insert(row, run.cell.clone());
+                }
+            }
+            *self = Self::Map(map);
+        }
+    }
+}
+
+impl RunColumn {
+    fn get(&self, row: u32) -> Option<&Cell> {
+        let (&start, run) = self.runs.range(..=row).next_back()?;
+        (row < start + run.len).then_some(&run.cell)
+    }
+
+    fn get_mut(&mut self, row: u32) -> Option<&mut Cell> {
+        self.get(row)?;
+        self.split_out(row);
+        self.runs.get_mut(&row).map(|run| &mut run.cell)
+    }
+
+    fn insert(&mut self, row: u32, cell: Cell) {
+        if self.get(row).is_some() {
+            self.split_out(row);
+            self.runs.remove(&row);
+            self.populated -= 1;
+        }
+        self.runs.insert(row, Run { len: 1, cell });
+        self.populated += 1;
+        self.merge_around(row);
+    }
+
+    fn remove(&mut self, row: u32) -> Option<Cell> {
+        self.get(row)?;
+        self.split_out(row);
+        self.populated -= 1;
+        self.runs.remove(&row).map(|run| run.cell)
+    }
+
+    /// Append a cell below every existing run (rows must arrive in order)
+    fn push_back(&mut self, row: u32, cell: Cell) {
+        if let Some((&start, last)) = self.runs.iter_mut().next_back() {
+            if start + last.len == row && mergeable(&last.cell, &cell) {
+                last.len += 1;
+                self.populated += 1;
+                return;
+            }
+        }
+        self.runs.insert(row, Run { len: 1, cell });
+        self.populated += 1;
+    }
+
+    /// Split the run containing `row` so that `row` becomes a run of its own
+    fn split_out(&mut self, row: u32) {
+        let Some((&start, _)) = self.runs.range(..=row).next_back() else {
+            return;
+        };
+        let run = self.runs.remove(&start).expect("run exists");
+        let end = start + run.len;
+        if start < row {
+            self.runs.insert(start, Run { len: row - start, cell: run.cell.clone() });
+        }
+        if row + 1 < end {
+            self.runs.insert(row + 1, Run { len: end - row - 1, cell: run.cell.clone() });
+        }
+        self.runs.insert(row, Run { len: 1, cell: run.cell });
+    }
+
+    /// Merge the run starting at `row` with identical neighbors
+    fn merge_around(&mut self, row: u32) {
+        let mut start = row;
+        let prev_start = self.runs.range(..row).next_back()
+            .filter(|(&prev_start, prev)| prev_start + prev.len == row && mergeable(&prev.cell, &self.runs[&row].cell))
+            .map(|(&prev_start, _)| prev_start);
+        if let Some(prev_start) = prev_start {
+            let current = self.runs.remove(&row).expect("run exists");
+            self.runs.get_mut(&prev_start).expect("run exists").len += current.len;
+            start = prev_start;
+        }
+
+        let end = start + self.runs[&start].len;
+        let merge_next = self.runs.get(&end)
+            .is_some_and(|next| mergeable(&self.runs[&start].cell, &next.cell));
+        if merge_next {
+            let next = self.runs.remove(&end).expect("run exists");
+            self.runs.get_mut(&start).expect("run exists").len += next.len;
+        }
+    }
+}
+
+/// Whether two cells may share a run; formula cells always stand alone
+fn mergeable(a: &Cell, b: &Cell) -> bool {
+    a.formula.is_none() && b.formula.is_none() && a == b
+}
+
+/// Number of runs a row-sorted column would encode to
+fn count_runs(rows: &[(u32, &Cell)]) -> usize {
+    rows.windows(2)
+        .filter(|pair| pair[0].0 + 1 != pair[1].0 || !mergeable(pair[0].1, pair[1].1))
+        .count()
+        + usize::from(!rows.is_empty())
+}
+
+// Map storage serializes exactly as before (row -> cell). Run storage
+// serializes as `{"runs": [[start, len, cell], ...]}`; since row keys are
+// always numeric, the "runs" key unambiguously identifies the layout.
+impl Serialize for ColumnStore {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Self::Map(map) => map.serialize(serializer),
+            Self::Runs(runs) => {
+                let entries: Vec<(u32, u32, &Cell)> = runs.runs.iter()
+                    .map(|(&start, run)| (start, run.len, &run.cell))
+                    .collect();
+                let mut out = serializer.serialize_map(Some(1))?;
+                out.serialize_entry("runs", &entries)?;
+                out.end()
+            }
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for ColumnStore {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct ColumnVisitor;
+
+        impl<'de> Visitor<'de> for ColumnVisitor {
+            type Value = ColumnStore;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a map of rows to cells, or a run list")
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, mut access: A) -> Result<ColumnStore, A::Error> {
+                let mut map = IndexMap::new();
+                while let Some(key) = access.next_key::<String>()? {
+                    if key == "runs" {
+                        let entries: Vec<(u32, u32, Cell)> = access.next_value()?;
+                        let mut runs = RunColumn::default();
+                        for (start, len, cell) in entries {
+                            runs.populated += len as usize;
+                            runs.runs.insert(start, Run { len, cell });
+                        }
+                        return Ok(ColumnStore::Runs(runs));
+                    }
+                    let row: u32 = key.parse().map_err(serde::de::Error::custom)?;
+                    map.insert(row, access.next_value()?);
+                }
+                Ok(ColumnStore::Map(map))
+            }
+        }
+
+        deserializer.deserialize_map(ColumnVisitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cell::CellValue;
+
+    fn text(s: &str) -> Cell {
+        Cell::new(CellValue::Text(s.to_string()))
+    }
+
+    /// Run-encoded column with rows 0..2000 = "open"
+    fn runs_column() -> ColumnStore {
+        let mut column = ColumnStore::default();
+        for row in 0..2000 {
+            column.insert(row, text("open"));
+        }
+        assert!(column.compact());
+        column
+    }
+
+    fn run_layout(column: &ColumnStore) -> Vec<(u32, u32)> {
+        match column {
+            ColumnStore::Runs(runs) => runs.runs.iter().map(|(&s, r)| (s, r.len)).collect(),
+            ColumnStore::Map(_) => panic!("expected run storage"),
+        }
+    }
+
+    #[test]
+    fn test_write_inside_run_splits_it() {
+        let mut column = runs_column();
+        column.insert(1000, text("closed"));
+        assert_eq!(run_layout(&column), vec![(0, 1000), (1000, 1), (1001, 999)]);
+        assert_eq!(column.get(999), Some(&text("open")));
+        assert_eq!(column.get(1000), Some(&text("closed")));
+        assert_eq!(column.get(1001), Some(&text("open")));
+        assert_eq!(column.len(), 2000);
+
+        // Writing the original value back merges everything again
+        column.insert(1000, text("open"));
+        assert_eq!(run_layout(&column), vec![(0, 2000)]);
+    }
+
+    #[test]
+    fn test_writes_at_run_boundaries() {
+        let mut column = runs_column();
+
+        // Extending either end grows the run
+        column.insert(2000, text("open"));
+        assert_eq!(run_layout(&column), vec![(0, 2001)]);
+
+        // Removing the first and last rows shrinks it
+        assert_eq!(column.remove(0), Some(text("open")));
+        assert_eq!(column.remove(2000), Some(text("open")));
+        assert_eq!(run_layout(&column), vec![(1, 1999)]);
+        assert_eq!(column.get(0), None);
+        assert_eq!(column.len(), 1999);
+
+        // A different value just past the end stays separate, then bridges a gap
+        column.insert(2001, text("open"));
+        assert_eq!(run_layout(&column), vec![(1, 1999), (2001, 1)]);
+        column.insert(2000, text("open"));
+        assert_eq!(run_layout(&column), vec![(1, 2001)]);
+
+        // Mutating one row in place splits it out without touching its neighbors
+        column.get_mut(5).unwrap().value = CellValue::Text("held".to_string());
+        assert_eq!(column.get(5), Some(&text("held")));
+        assert_eq!(column.get(4), Some(&text("open")));
+        assert_eq!(column.get(6), Some(&text("open")));
+        assert_eq!(column.len(), 2001);
+    }
+
+    #[test]
+    fn test_fragmented_runs_fall_back_to_map() {
+        let mut column = runs_column();
+        for row in (0..2000).step_by(2) {
+            column.insert(row, Cell::new(CellValue::Number(row as f64)));
+        }
+        assert!(!column.is_run_encoded());
+        assert_eq!(column.len(), 2000);
+        assert_eq!(column.get(1), Some(&text("open")));
+        assert_eq!(column.get(2), Some(&Cell::new(CellValue::Number(2.0))));
+    }
+
+    #[test]
+    fn test_small_or_varied_columns_stay_maps() {
+        let mut small = ColumnStore::default();
+        for row in 0..100 {
+            small.insert(row, text("same"));
+        }
+        assert!(!small.compact());
+
+        let mut varied = ColumnStore::default();
+        for row in 0..5000 {
+            varied.insert(row, Cell::new(CellValue::Number(row as f64)));
+        }
+        assert!(!varied.compact());
+    }
+
+    #[test]
+    fn test_run_storage_round_trips() {
+        let mut column = runs_column();
+        column.insert(10, text("closed"));
+        let json = serde_json::to_string(&column).unwrap();
+        let restored: ColumnStore = serde_json::from_str(&json).unwrap();
+        assert_eq!(run_layout(&restored), run_layout(&column));
+        assert_eq!(restored.len(), 2000);
+
+        let map_json = r#"{"3":{"value":{"type":"Number","value":1.0}}}"#;
+        let restored: ColumnStore = serde_json::from_str(map_json).unwrap();
+        assert_eq!(restored.get(3), Some(&Cell::new(CellValue::Number(1.0))));
+    }
+
+    #[test]
+    fn test_memory_on_large_repetitive_column() {
+        let mut column = ColumnStore::default();
+        for row in 0..500_000 {
+            let status = if row < 250_000 { "active" } else { "churned" };
+            column.insert(row, text(status));
+        }
+        let map_bytes = column.approx_heap_bytes();
+        assert!(column.compact());
+        let run_bytes = column.approx_heap_bytes();
+
+        assert_eq!(column.len(), 500_000);
+        assert_eq!(run_layout(&column), vec![(0, 250_000), (250_000, 250_000)]);
+        assert!(run_bytes * 10_000 < map_bytes, "runs {} bytes vs map {} bytes", run_bytes, map_bytes);
+    }
+}
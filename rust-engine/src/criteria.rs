@@ -0,0 +1,168 @@
+//! Criteria for conditional aggregates such as SUMIF and COUNTIF
+//!
+//! A criterion is a value to match, optionally prefixed by a comparison
+//! (`">100"`, `"<>0"`, `"<=2024"`). Text compared for (in)equality may use
+//! wildcards: `*` for any run of characters, `?` for one character, and `~`
+//! to escape either. Matching follows Excel: ordering comparisons only match
+//! values of the same kind, and `<>` matches everything that isn't equal,
+//! blanks included.
+
+use crate::cell::CellValue;
+use crate::formula::{comparison_result, BinaryOp};
+
+#[derive(Debug, Clone)]
+pub(crate) struct Criterion {
+    op: BinaryOp,
+    target: CellValue,
+}
+
+impl Criterion {
+    /// Build a criterion from an evaluated criteria argument
+    pub(crate) fn parse(criteria: &CellValue) -> Self {
+        let CellValue::Text(text) = criteria else {
+            return Self { op: BinaryOp::Eq, target: criteria.clone() };
+        };
+        let (op, rest) = [("<=", BinaryOp::Le), (">=", BinaryOp::Ge), ("<>", BinaryOp::Ne), ("<", BinaryOp::Lt), (">", BinaryOp::Gt), ("=", BinaryOp::Eq)]
+            .into_iter()
+            .find_map(|(prefix, op)| text.strip_prefix(prefix).map(|rest| (op, rest)))
+            .unwrap_or((BinaryOp::Eq, text.as_str()));
+        // Keep text as written so `"=007"` still matches the text "007"
+        let target = match CellValue::parse(rest) {
+            CellValue::Text(_) => CellValue::Text(rest.to_string()),
+            parsed => parsed,
+        };
+        Self { op, target }
+    }
+
+    /// Whether a cell's value satisfies the criterion
+    pub(crate) fn matches(&self, value: &CellValue) -> bool {
+        match self.op {
+            BinaryOp::Eq => self.equals(value),
+            BinaryOp::Ne => !self.equals(value),
+            op => match (&self.target, value) {
+                (CellValue::Number(_), CellValue::Number(_))
+                | (CellValue::Text(_), CellValue::Text(_))
+                | (CellValue::Boolean(_), CellValue::Boolean(_)) => comparison_result(op, value, &self.target).unwrap_or(false),
+                _ => false,
+            },
+        }
+    }
+
+    fn equals(&self, value: &CellValue) -> bool {
+        match (&self.target, value) {
+            (CellValue::Empty, CellValue::Empty) => true,
+            (CellValue::Empty, CellValue::Text(text)) => text.is_empty(),
+            (CellValue::Text(pattern), CellValue::Text(text)) => wildcard_match(pattern, text),
+            (CellValue::Number(target), CellValue::Number(n)) => target == n,
+            (CellValue::Boolean(target), CellValue::Boolean(b)) => target == b,
+            (CellValue::Error(target), CellValue::Error(code)) => target == code,
+            _ => false,
+        }
+    }
+}
+
+/// Case-insensitive match of `text` against a pattern with `*`, `?` and `~` escapes
+pub(crate) fn wildcard_match(pattern: &str, text: &str) -> bool {
+    #[derive(Clone, Copy, PartialEq)]
+    enum Token {
+        Char(char),
+        One,
+        Any,
+    }
+
+    let mut tokens = Vec::new();
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        tokens.push(match c {
+            '~' => Token::Char(chars.next().unwrap_or('~')),
+            '*' => Token::Any,
+            '?' => Token::One,
+            c => Token::Char(c),
+        });
+    }
+    let text: Vec<char> = text.chars().collect();
+    let same = |a: char, b: char| a == b || a.to_lowercase().eq(b.to_lowercase());
+
+    // Greedy matching with backtracking to the most recent `*`
+    let (mut t, mut p) = (0, 0);
+    let mut backtrack: Option<(usize, usize)> = None;
+    while t < text.len() {
+        match tokens.get(p) {
+            Some(Token::Any) => {
+                backtrack = Some((p, t));
+                p += 1;
+            }
+            Some(Token::One) => {
+                p += 1;
+                t += 1;
+            }
+            Some(Token::Char(c)) if same(*c, text[t]) => {
+                p += 1;
+                t += 1;
+            }
+            _ => match backtrack {
+                Some((star, start)) => {
+                    p = star + 1;
+                    t = start + 1;
+                    backtrack = Some((star, start + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    tokens[p..].iter().all(|token| *token == Token::Any)
+}
+
+/// Whether `text` starts with a match for `pattern` (see `wildcard_match`)
+pub(crate) fn wildcard_prefix_match(pattern: &str, text: &str) -> bool {
+    // A trailing `*` accepts whatever follows; an unpaired `~` would escape it, so pair it first
+    let tildes = pattern.len() - pattern.trim_end_matches('~').len();
+    let pairing = if tildes % 2 == 1 { "~" } else { "" };
+    wildcard_match(&format!("{}{}*", pattern, pairing), text)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(criteria: CellValue, value: CellValue) -> bool {
+        Criterion::parse(&criteria).matches(&value)
+    }
+
+    fn text(s: &str) -> CellValue {
+        CellValue::Text(s.to_string())
+    }
+
+    #[test]
+    fn test_comparison_criteria() {
+        assert!(matches(text(">100"), CellValue::Number(150.0)));
+        assert!(!matches(text(">100"), CellValue::Number(100.0)));
+        assert!(!matches(text(">100"), text("abc")));
+        assert!(matches(text("<=5"), CellValue::Number(5.0)));
+        assert!(matches(text(">=5"), CellValue::Number(5.0)));
+        assert!(!matches(text(">=5"), CellValue::Number(4.9)));
+
+        // "<>" matches anything unequal, blanks and text included
+        assert!(matches(text("<>0"), CellValue::Empty));
+        assert!(matches(text("<>0"), text("n/a")));
+        assert!(!matches(text("<>0"), CellValue::Number(0.0)));
+
+        assert!(matches(CellValue::Number(3.0), CellValue::Number(3.0)));
+        assert!(matches(text("="), CellValue::Empty));
+        assert!(matches(text("<>"), CellValue::Number(1.0)));
+        assert!(!matches(text("<>"), CellValue::Empty));
+    }
+
+    #[test]
+    fn test_wildcard_criteria() {
+        assert!(matches(text("Food*"), text("food - groceries")));
+        assert!(matches(text("Food*"), text("Food")));
+        assert!(!matches(text("Food*"), text("Fast food")));
+        assert!(matches(text("*a*b?"), text("xxaYbZ")));
+        assert!(matches(text("Q?"), text("q1")));
+        assert!(!matches(text("Q?"), text("Q10")));
+        assert!(matches(text("100~*"), text("100*")));
+        assert!(!matches(text("100~*"), text("1000")));
+        assert!(matches(text("<>Food*"), text("Travel")));
+    }
+}
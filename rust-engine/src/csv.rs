@@ -0,0 +1,397 @@
+//! CSV/TSV import and export of grid ranges
+//!
+//! Exports either every row of a range or just the rows picked by a
+//! `RowFilter`, which can be a list of row indices or a formula template
+//! evaluated once per row. Imports read RFC 4180 CSV into a new grid.
+
+use crate::cell::{CellFormat, CellRef, CellValue};
+use crate::format_infer;
+use crate::formula::{FormulaEngine, FormulaError};
+use crate::grid::{Grid, GridError, SHRINK_MARGIN};
+use serde::{Deserialize, Serialize};
+
+/// Options shared by the CSV/TSV exporters
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CsvOptions {
+    /// Field separator: `,` for CSV, `\t` for TSV
+    pub delimiter: char,
+    /// Write formula text (`=A1*2`) instead of computed values
+    pub formulas: bool,
+    /// Quote every field, not only those that need it
+    pub quote_all: bool,
+}
+
+impl Default for CsvOptions {
+    fn default() -> Self {
+        Self {
+            delimiter: ',',
+            formulas: false,
+            quote_all: false,
+        }
+    }
+}
+
+/// Which rows of a range to export
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum RowFilter {
+    /// Explicit row indices; rows outside the range are ignored
+    Rows(Vec<u32>),
+    /// Formula template evaluated per row, with `{row}` replaced by the
+    /// 1-based row number (e.g. `=C{row}>1000`)
+    Formula(String),
+}
+
+impl RowFilter {
+    /// Rows between `start_row` and `end_row` that pass the filter, in sheet order
+    ///
+    /// Formula templates are parsed and evaluated without registering
+    /// anything in the dependency graph; rows whose predicate evaluates to an
+    /// error are left out.
+    pub fn matching_rows(&self, grid: &Grid, formula_engine: &FormulaEngine, start_row: u32, end_row: u32) -> Result<Vec<u32>, FormulaError> {
+        match self {
+            Self::Rows(rows) => {
+                let mut rows: Vec<u32> = rows.iter()
+                    .copied()
+                    .filter(|row| (start_row..=end_row).contains(row))
+                    .collect();
+                rows.sort_unstable();
+                rows.dedup();
+                Ok(rows)
+            }
+            Self::Formula(template) => {
+                let mut rows = Vec::new();
+                for row in start_row..=end_row {
+                    let formula = formula_engine.parse(&template.replace("{row}", &(row + 1).to_string()))?;
+                    if formula_engine.evaluate(&formula.ast, grid).is_ok_and(|value| value.is_truthy()) {
+                        rows.push(row);
+                    }
+                }
+                Ok(rows)
+            }
+        }
+    }
+}
+
+impl Grid {
+    /// A grid holding the fields of a CSV file, starting at A1
+    ///
+    /// Fields are typed with `CellValue::parse`, so numbers and booleans
+    /// become values; a field starting with `=` stays text rather than
+    /// becoming a formula. With `has_header` the first row is kept as text
+    /// throughout, so a heading like `2024` stays a label. The grid is
+    /// sized to the data plus the usual margin.
+    ///
+    /// With `infer_formats`, fields are read the way an inferring paste
+    /// reads them, so `$1,234.00` or `2024-01-31` become numbers, and each
+    /// column's fields get the `number_format` inferred from its body.
+    pub fn from_csv(data: &str, has_header: bool, infer_formats: bool) -> Result<Grid, GridError> {
+        Grid::from_records(&parse_records(data, ','), has_header, infer_formats)
+    }
+
+    /// Like `from_csv`, taking the first row as a header when `detect_header` finds one there
+    pub fn from_csv_detecting_header(data: &str, infer_formats: bool) -> Result<Grid, GridError> {
+        let records = parse_records(data, ',');
+        let grid = Grid::from_records(&records, false, infer_formats)?;
+        let Some((last_row, last_col)) = grid.used_extent() else {
+            return Ok(grid);
+        };
+        match grid.detect_header(CellRef::new(0, 0), CellRef::new(last_row, last_col)).header_row {
+            Some(0) => Grid::from_records(&records, true, infer_formats),
+            _ => Ok(grid),
+        }
+    }
+
+    fn from_records(records: &[Vec<String>], has_header: bool, infer_formats: bool) -> Result<Grid, GridError> {
+        let cols = records.iter().map(Vec::len).max().unwrap_or(0) as u32;
+        let mut grid = Grid::new(records.len() as u32 + SHRINK_MARGIN, cols + SHRINK_MARGIN);
+        for (row, record) in records.iter().enumerate() {
+            for (col, field) in record.iter().enumerate() {
+                let value = match row == 0 && has_header {
+                    true if !field.is_empty() => CellValue::Text(field.clone()),
+                    _ if infer_formats => format_infer::parse_value(field),
+                    _ => CellValue::parse(field),
+                };
+                if value != CellValue::Empty {
+                    grid.set_value(CellRef::new(row as u32, col as u32), value)?;
+                }
+            }
+        }
+
+        if infer_formats {
+            let body = &records[records.len().min(has_header as usize)..];
+            let first_row = records.len() - body.len();
+            for col in 0..cols as usize {
+                let Some(number_format) = format_infer::infer_column_format(body.iter().filter_map(|record| record.get(col)).map(String::as_str)) else {
+                    continue;
+                };
+                for (row, record) in body.iter().enumerate() {
+                    if record.get(col).and_then(|field| format_infer::classify(field)).is_none() {
+                        continue;
+                    }
+                    grid.set_format(CellRef::new((first_row + row) as u32, col as u32), CellFormat {
+                        number_format: Some(number_format.clone()),
+                        ..CellFormat::default()
+                    })?;
+                }
+            }
+        }
+        Ok(grid)
+    }
+
+    /// Export the sheet as CSV, from A1 to the last populated row and column
+    ///
+    /// Empty cells are empty fields, so blank leading rows and columns keep
+    /// the data where it was; an empty sheet is empty text.
+    pub fn used_range_to_csv(&self) -> String {
+        match self.used_extent() {
+            Some((last_row, last_col)) => self.to_csv(CellRef::new(0, 0), CellRef::new(last_row, last_col), &CsvOptions::default()),
+            None => String::new(),
+        }
+    }
+
+    /// Export a range as CSV/TSV text, one line per row
+    pub fn to_csv(&self, start: CellRef, end: CellRef, options: &CsvOptions) -> String {
+        self.rows_to_csv(start, end, start.row..=end.row, options)
+    }
+
+    /// Export the given rows of a range, in the order given
+    pub fn rows_to_csv(&self, start: CellRef, end: CellRef, rows: impl IntoIterator<Item = u32>, options: &CsvOptions) -> String {
+        let mut out = String::new();
+        for row in rows {
+            let fields: Vec<String> = (start.col..=end.col)
+                .map(|col| {
+                    let raw = match self.get_cell(CellRef::new(row, col)) {
+                        Some(cell) if options.formulas && cell.formula.is_some() => cell.formula.clone().unwrap_or_default(),
+                        Some(cell) => cell.value.display(),
+                        None => CellValue::Empty.display(),
+                    };
+                    quote_field(&raw, options)
+                })
+                .collect();
+            out.push_str(&fields.join(&options.delimiter.to_string()));
+            out.push('\n');
+        }
+        out
+    }
+}
+
+/// Split CSV text into records of fields, following RFC 4180
+///
+/// Quoted fields may hold the delimiter, line breaks and doubled quotes.
+/// Lines end in `\n` or `\r\n`. Empty lines at the end are dropped; those
+/// in between are kept as empty records so later rows keep their place.
+/// An unterminated quote runs to the end of the text.
+fn parse_records(data: &str, delimiter: char) -> Vec<Vec<String>> {
+    let mut records = Vec::new();
+    let mut record = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = data.strip_prefix('\u{feff}').unwrap_or(data).chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted => match chars.peek() {
+                Some('"') => {
+                    field.push('"');
+                    chars.next();
+                }
+                _ => quoted = false,
+            },
+            '"' if field.is_empty() => quoted = true,
+            c if quoted => field.push(c),
+            c if c == delimiter => record.push(std::mem::take(&mut field)),
+            '\r' if chars.peek() == Some(&'\n') => {}
+            '\n' => {
+                record.push(std::mem::take(&mut field));
+                records.push(std::mem::take(&mut record));
+            }
+            c => field.push(c),
+        }
+    }
+    if !field.is_empty() || !record.is_empty() {
+        record.push(field);
+        records.push(record);
+    }
+    while records.last().is_some_and(|record: &Vec<String>| record.iter().all(String::is_empty)) {
+        records.pop();
+    }
+    records
+}
+
+/// Quote a field if it contains the delimiter, a quote or a line break
+fn quote_field(raw: &str, options: &CsvOptions) -> String {
+    let needs_quotes = options.quote_all
+        || raw.contains(options.delimiter)
+        || raw.contains(['"', '\n', '\r']);
+    if needs_quotes {
+        format!("\"{}\"", raw.replace('"', "\"\""))
+    } else {
+        raw.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::grid::{CellUpdate, GridPatch};
+
+    /// Name | Region | Amount | Double, with a header row
+    fn fixture() -> (Grid, FormulaEngine) {
+        let mut grid = Grid::new(20, 10);
+        let mut engine = FormulaEngine::new();
+        let rows = [
+            ["Name", "Region", "Amount"],
+            ["Smith, Jr.", "EMEA", "1500"],
+            ["Lee", "APAC", "800"],
+            ["O\"Neil", "EMEA", "2500"],
+            ["Diaz", "AMER", "1000"],
+        ];
+        let mut updates = Vec::new();
+        for (r, row) in rows.iter().enumerate() {
+            for (c, value) in row.iter().enumerate() {
+                updates.push(CellUpdate { row: r as u32, col: c as u32, value: Some(value.to_string()), formula: None });
+            }
+            if r > 0 {
+                updates.push(CellUpdate { row: r as u32, col: 3, value: None, formula: Some(format!("=C{}*2", r + 1)) });
+            }
+        }
+        grid.apply_patch(GridPatch { updates, auto_expand: None }, &mut engine).unwrap();
+        (grid, engine)
+    }
+
+    fn export(grid: &Grid, engine: &FormulaEngine, filter: RowFilter, options: &CsvOptions) -> String {
+        let (start, end) = (CellRef::parse("A2").unwrap(), CellRef::parse("D5").unwrap());
+        let rows = filter.matching_rows(grid, engine, start.row, end.row).unwrap();
+        grid.rows_to_csv(start, end, rows, options)
+    }
+
+    #[test]
+    fn test_export_rows_by_formula() {
+        let (grid, engine) = fixture();
+        let csv = export(&grid, &engine, RowFilter::Formula("=C{row}>1000".to_string()), &CsvOptions::default());
+        assert_eq!(csv, "\"Smith, Jr.\",EMEA,1500,3000\n\"O\"\"Neil\",EMEA,2500,5000\n");
+
+        let options = CsvOptions { delimiter: '\t', formulas: true, ..CsvOptions::default() };
+        let tsv = export(&grid, &engine, RowFilter::Formula("=B{row}=\"apac\"".to_string()), &options);
+        assert_eq!(tsv, "Lee\tAPAC\t800\t=C3*2\n");
+    }
+
+    #[test]
+    fn test_export_explicit_rows_in_sheet_order() {
+        let (grid, engine) = fixture();
+        let options = CsvOptions { quote_all: true, ..CsvOptions::default() };
+        let csv = export(&grid, &engine, RowFilter::Rows(vec![4, 2, 2, 0, 9]), &options);
+        assert_eq!(csv, "\"Lee\",\"APAC\",\"800\",\"1600\"\n\"Diaz\",\"AMER\",\"1000\",\"2000\"\n");
+    }
+
+    #[test]
+    fn test_formula_filter_has_no_side_effects() {
+        let (grid, engine) = fixture();
+        let revision = grid.revision();
+        export(&grid, &engine, RowFilter::Formula("=D{row}>=2000".to_string()), &CsvOptions::default());
+        assert_eq!(grid.revision(), revision);
+        assert!(engine.formula_at(CellRef::parse("D2").unwrap()).is_some());
+        assert!(!engine.depends_on(CellRef::parse("D2").unwrap(), CellRef::parse("A2").unwrap()));
+        assert_eq!(grid.to_csv(CellRef::new(0, 0), CellRef::new(0, 2), &CsvOptions::default()), "Name,Region,Amount\n");
+    }
+
+    #[test]
+    fn test_parse_records_follows_rfc_4180() {
+        let records = parse_records("a,\"b, c\",\"say \"\"hi\"\"\"\r\n\"two\nlines\",,x\n\n3\n\n\n", ',');
+        assert_eq!(records, vec![
+            vec!["a".to_string(), "b, c".to_string(), "say \"hi\"".to_string()],
+            vec!["two\nlines".to_string(), String::new(), "x".to_string()],
+            vec![String::new()],
+            vec!["3".to_string()],
+        ]);
+        assert_eq!(parse_records("no newline,at end", ','), vec![vec!["no newline".to_string(), "at end".to_string()]]);
+        assert!(parse_records("\n\r\n", ',').is_empty());
+    }
+
+    #[test]
+    fn test_import_csv_types_fields() {
+        let data = "Name,Amount,Active,Year\n\"Smith, Jr.\",1500,TRUE,2024\nLee,-800.5,false,2023\n\"O\"\"Neil\",,true,=A1\n\n";
+        let grid = Grid::from_csv(data, true, false).unwrap();
+        let value = |a1: &str| grid.get_cell(CellRef::parse(a1).unwrap()).map(|cell| cell.value.clone());
+        assert_eq!(value("A2"), Some(CellValue::Text("Smith, Jr.".to_string())));
+        assert_eq!(value("B2"), Some(CellValue::Number(1500.0)));
+        assert_eq!(value("B3"), Some(CellValue::Number(-800.5)));
+        assert_eq!(value("B4"), None);
+        assert_eq!(value("C3"), Some(CellValue::Boolean(false)));
+        assert_eq!(value("A4"), Some(CellValue::Text("O\"Neil".to_string())));
+        assert_eq!(value("D4"), Some(CellValue::Text("=A1".to_string())));
+        assert!(grid.get_cell(CellRef::parse("D4").unwrap()).unwrap().formula.is_none());
+        assert_eq!((grid.rows, grid.cols), (4 + SHRINK_MARGIN, 4 + SHRINK_MARGIN));
+
+        // The header row stays text only when asked
+        assert_eq!(value("D1"), Some(CellValue::Text("Year".to_string())));
+        let headed = Grid::from_csv("2023,2024\n1,2\n", true, false).unwrap();
+        let plain = Grid::from_csv("2023,2024\n1,2\n", false, false).unwrap();
+        assert_eq!(headed.get_cell(CellRef::new(0, 1)).unwrap().value, CellValue::Text("2024".to_string()));
+        assert_eq!(plain.get_cell(CellRef::new(0, 1)).unwrap().value, CellValue::Number(2024.0));
+
+        // Round trip through the exporter
+        let csv = grid.to_csv(CellRef::new(0, 0), CellRef::new(3, 3), &CsvOptions::default());
+        assert!(csv.starts_with("Name,Amount,Active,Year\n\"Smith, Jr.\",1500,"));
+    }
+
+    #[test]
+    fn test_import_detects_header() {
+        let headed = Grid::from_csv_detecting_header("Region,Units,2024\nEMEA,1,100\nAPAC,2,200\n", false).unwrap();
+        assert_eq!(headed.get_cell(CellRef::new(0, 2)).unwrap().value, CellValue::Text("2024".to_string()));
+        let plain = Grid::from_csv_detecting_header("1,2024\n2,100\n", false).unwrap();
+        assert_eq!(plain.get_cell(CellRef::new(0, 1)).unwrap().value, CellValue::Number(2024.0));
+        assert_eq!(Grid::from_csv_detecting_header("", true).unwrap().cell_count(), 0);
+    }
+
+    #[test]
+    fn test_import_csv_infers_formats() {
+        let data = "Amount,Rate,Date,Code\n\"$1,234.00\",12.5%,2024-01-31,007\n$5.50,7%,2024-02-01,\n";
+        let grid = Grid::from_csv(data, true, true).unwrap();
+        let cell = |a1: &str| grid.get_cell(CellRef::parse(a1).unwrap()).unwrap();
+        assert_eq!(cell("A1").value, CellValue::Text("Amount".to_string()));
+        assert_eq!(cell("A1").format, None);
+        assert_eq!(cell("A2").value, CellValue::Number(1234.0));
+        assert_eq!(cell("A2").display(), "$1,234.00");
+        assert_eq!(cell("B3").value, CellValue::Number(0.07));
+        assert_eq!(cell("B2").display(), "12.5%");
+        assert_eq!(cell("B3").display(), "7.0%");
+        assert_eq!(cell("C2").value, CellValue::Number(45322.0));
+        assert_eq!(cell("C2").display(), "2024-01-31");
+        assert_eq!(cell("D2").value, CellValue::Number(7.0));
+        assert_eq!(cell("D2").format, None);
+
+        // Without inference dates stay text and nothing gets a format
+        let plain = Grid::from_csv(data, true, false).unwrap();
+        assert_eq!(plain.get_cell(CellRef::parse("C2").unwrap()).unwrap().value, CellValue::Text("2024-01-31".to_string()));
+        assert_eq!(plain.get_cell(CellRef::parse("A2").unwrap()).unwrap().format, None);
+    }
+
+    #[test]
+    fn test_used_range_round_trips_through_import() {
+        let mut grid = Grid::new(20, 20);
+        grid.set_value(CellRef::new(5, 5), CellValue::Number(42.0)).unwrap();
+        let csv = grid.used_range_to_csv();
+        assert_eq!(csv, ",,,,,\n".repeat(5) + ",,,,,42\n");
+        let imported = Grid::from_csv(&csv, false, false).unwrap();
+        assert_eq!(imported.get_cell(CellRef::new(5, 5)).unwrap().value, CellValue::Number(42.0));
+        assert_eq!(imported.cell_count(), 1);
+
+        let (grid, _) = fixture();
+        let csv = grid.used_range_to_csv();
+        assert!(csv.starts_with("Name,Region,Amount,\n\"Smith, Jr.\",EMEA,1500,3000\n"));
+        assert!(csv.contains("\n\"O\"\"Neil\",EMEA,2500,5000\n"));
+        let imported = Grid::from_csv(&csv, true, false).unwrap();
+        assert_eq!(imported.used_range_to_csv(), csv);
+        assert_eq!(imported.get_cell(CellRef::new(3, 0)).unwrap().value, CellValue::Text("O\"Neil".to_string()));
+        assert_eq!(imported.get_cell(CellRef::new(3, 3)).unwrap().value, CellValue::Number(5000.0));
+
+        // Line breaks inside a field survive too
+        let mut grid = Grid::new(5, 5);
+        grid.set_value(CellRef::new(0, 1), CellValue::Text("two\nlines".to_string())).unwrap();
+        let imported = Grid::from_csv(&grid.used_range_to_csv(), false, false).unwrap();
+        assert_eq!(imported.get_cell(CellRef::new(0, 1)).unwrap().value, CellValue::Text("two\nlines".to_string()));
+        assert_eq!(Grid::new(5, 5).used_range_to_csv(), "");
+    }
+}
@@ -0,0 +1,307 @@
+//! Compact, deterministic summaries of a rectangular region
+//!
+//! Used to ground agent-proposed patches: before editing `B2:F200` the agent
+//! asks for its shape, column types, header, formats, a few sample values and
+//! the formulas it contains. Output only depends on the sheet contents, so the
+//! same region always produces the same description.
+
+use crate::cell::{CellRef, CellValue, ValueType};
+use crate::formula::FormulaEngine;
+use crate::grid::{CellData, Grid};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+
+/// Summary of a range, as returned by `Grid::describe_range`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RangeDescription {
+    /// A1-style range, e.g. `B2:F200`
+    pub range: String,
+    pub rows: u32,
+    pub cols: u32,
+    /// Cells holding a value or formula
+    pub populated: usize,
+    /// `populated` as a fraction of the range's area
+    pub density: f64,
+    pub columns: Vec<ColumnSummary>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub header: Option<HeaderRow>,
+    /// Distinct number formats in use, sorted
+    pub number_formats: Vec<String>,
+    /// Representative populated cells, in row-major order
+    pub samples: Vec<SampleCell>,
+    /// Every formula cell, in row-major order
+    pub formulas: Vec<FormulaCell>,
+}
+
+/// Value-type profile of one column within the range
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ColumnSummary {
+    /// Column letter
+    pub column: String,
+    pub populated: usize,
+    pub numbers: usize,
+    pub text: usize,
+    pub booleans: usize,
+    pub errors: usize,
+    /// Most common value type; ties go to the earlier of number, text, boolean, error
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dominant: Option<ValueType>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max: Option<f64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HeaderRow {
+    /// 1-based row number, as shown in the row header
+    pub row: u32,
+    pub values: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SampleCell {
+    pub cell: String,
+    pub value: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FormulaCell {
+    pub cell: String,
+    pub formula: String,
+}
+
+impl Grid {
+    /// Describe the range between `start` and `end` (inclusive)
+    ///
+    /// Up to `max_samples` cells are sampled: first the populated cells
+    /// nearest each corner of the range, then cells from the densest rows
+    /// (most populated first, ties to the upper row), left to right.
+    pub fn describe_range(&self, start: CellRef, end: CellRef, max_samples: usize, formula_engine: &FormulaEngine) -> RangeDescription {
+        let top_left = CellRef::new(start.row.min(end.row), start.col.min(end.col));
+        let bottom_right = CellRef::new(start.row.max(end.row), start.col.max(end.col));
+        let rows = bottom_right.row - top_left.row + 1;
+        let cols = bottom_right.col - top_left.col + 1;
+
+        let mut cells: Vec<CellData> = self
+            .get_range(top_left.row, top_left.col, bottom_right.row, bottom_right.col, formula_engine)
+            .into_iter()
+            .filter(|cell| cell.formula.is_some() || cell.value_type != ValueType::Empty)
+            .collect();
+        cells.sort_by_key(|cell| (cell.row, cell.col));
+
+        let columns = (top_left.col..=bottom_right.col)
+            .map(|col| summarize_column(self, col, cells.iter().filter(|cell| cell.col == col)))
+            .collect();
+
+        let header = self.detect_header(top_left, bottom_right).header_row.map(|row| HeaderRow {
+            row: row + 1,
+            values: (top_left.col..=bottom_right.col)
+                .map(|col| self.get_cell(CellRef::new(row, col)).map(|c| c.display()).unwrap_or_default())
+                .collect(),
+        });
+
+        let number_formats: BTreeSet<String> = cells.iter()
+            .filter_map(|cell| cell.format.as_ref()?.number_format.clone())
+            .collect();
+
+        let formulas = cells.iter()
+            .filter_map(|cell| Some(FormulaCell {
+                cell: CellRef::new(cell.row, cell.col).to_a1(),
+                formula: cell.formula.clone()?,
+            }))
+            .collect();
+
+        RangeDescription {
+            range: format!("{}:{}", top_left, bottom_right),
+            rows,
+            cols,
+            populated: cells.len(),
+            density: cells.len() as f64 / (rows as f64 * cols as f64),
+            columns,
+            header,
+            number_formats: number_formats.into_iter().collect(),
+            samples: pick_samples(&cells, top_left, bottom_right, max_samples),
+            formulas,
+        }
+    }
+}
+
+/// Profile one column's `cells`, taking `min`/`max` from `grid`'s values rather than their formatted text
+fn summarize_column<'a>(grid: &Grid, col: u32, cells: impl Iterator<Item = &'a CellData>) -> ColumnSummary {
+    let mut summary = ColumnSummary {
+        column: CellRef::col_to_letter(col),
+        populated: 0,
+        numbers: 0,
+        text: 0,
+        booleans: 0,
+        errors: 0,
+        dominant: None,
+        min: None,
+        max: None,
+    };
+    for cell in cells {
+        summary.populated += 1;
+        match cell.value_type {
+            // Dates are serial numbers
+            ValueType::Number | ValueType::Date => {
+                summary.numbers += 1;
+                let value = grid.get_cell(CellRef::new(cell.row, cell.col)).map(|c| &c.value);
+                if let Some(&CellValue::Number(n)) = value {
+                    summary.min = Some(summary.min.map_or(n, |m| m.min(n)));
+                    summary.max = Some(summary.max.map_or(n, |m| m.max(n)));
+                }
+            }
+            ValueType::Text => summary.text += 1,
+            ValueType::Boolean => summary.booleans += 1,
+            ValueType::Error => summary.errors += 1,
+            ValueType::Empty => {}
+        }
+    }
+    summary.dominant = [
+        (ValueType::Number, summary.numbers),
+        (ValueType::Text, summary.text),
+        (ValueType::Boolean, summary.booleans),
+        (ValueType::Error, summary.errors),
+    ]
+    .into_iter()
+    .filter(|&(_, count)| count > 0)
+    .reduce(|best, next| if next.1 > best.1 { next } else { best })
+    .map(|(value_type, _)| value_type);
+    summary
+}
+
+/// Choose sample cells from `cells` (sorted row-major)
+fn pick_samples(cells: &[CellData], top_left: CellRef, bottom_right: CellRef, max_samples: usize) -> Vec<SampleCell> {
+    let mut chosen: Vec<usize> = Vec::new();
+    let choose = |index: usize, chosen: &mut Vec<usize>| {
+        if chosen.len() < max_samples && !chosen.contains(&index) {
+            chosen.push(index);
+        }
+    };
+
+    // Nearest populated cell to each corner; `min_by_key` keeps the first
+    // (row-major) cell on ties
+    let corners = [
+        (top_left.row, top_left.col),
+        (top_left.row, bottom_right.col),
+        (bottom_right.row, top_left.col),
+        (bottom_right.row, bottom_right.col),
+    ];
+    for (row, col) in corners {
+        let nearest = cells.iter()
+            .enumerate()
+            .min_by_key(|(_, cell)| cell.row.abs_diff(row) + cell.col.abs_diff(col))
+            .map(|(index, _)| index);
+        if let Some(index) = nearest {
+            choose(index, &mut chosen);
+        }
+    }
+
+    // Then the densest rows
+    let mut by_row: BTreeMap<u32, Vec<usize>> = BTreeMap::new();
+    for (index, cell) in cells.iter().enumerate() {
+        by_row.entry(cell.row).or_default().push(index);
+    }
+    let mut dense_rows: Vec<(u32, Vec<usize>)> = by_row.into_iter().collect();
+    dense_rows.sort_by_key(|(row, indices)| (std::cmp::Reverse(indices.len()), *row));
+    for index in dense_rows.into_iter().flat_map(|(_, indices)| indices) {
+        choose(index, &mut chosen);
+    }
+
+    chosen.sort_unstable();
+    chosen.into_iter()
+        .map(|index| {
+            let cell = &cells[index];
+            SampleCell { cell: CellRef::new(cell.row, cell.col).to_a1(), value: cell.value.clone() }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cell::CellFormat;
+    use crate::grid::{CellUpdate, GridPatch, PasteOptions};
+
+    /// B2:E6 with a header, a sparse row and a total formula
+    fn fixture() -> (Grid, FormulaEngine) {
+        let mut grid = Grid::new(50, 10);
+        let mut engine = FormulaEngine::new();
+        let rows: [(u32, [&str; 4]); 4] = [
+            (1, ["Region", "Q1", "Q2", "Total"]),
+            (2, ["EMEA", "100", "120", ""]),
+            (3, ["APAC", "80", "", ""]),
+            (5, ["AMER", "150", "170", ""]),
+        ];
+        let mut updates = Vec::new();
+        for (row, values) in rows {
+            for (offset, value) in values.iter().enumerate() {
+                if !value.is_empty() {
+                    updates.push(CellUpdate { row, col: 1 + offset as u32, value: Some(value.to_string()), formula: None });
+                }
+            }
+        }
+        updates.push(CellUpdate { row: 2, col: 4, value: None, formula: Some("=C3+D3".to_string()) });
+        grid.apply_patch(GridPatch { updates, auto_expand: None }, &mut engine).unwrap();
+        grid.set_format(CellRef::new(2, 2), CellFormat { number_format: Some("$#,##0".to_string()), ..CellFormat::default() }).unwrap();
+        (grid, engine)
+    }
+
+    #[test]
+    fn test_describe_range_snapshot() {
+        let (grid, engine) = fixture();
+        let description = grid.describe_range(CellRef::parse("B2").unwrap(), CellRef::parse("E6").unwrap(), 6, &engine);
+        let json = serde_json::to_string(&description).unwrap();
+        let expected = concat!(
+            r#"{"range":"B2:E6","rows":5,"cols":4,"populated":13,"density":0.65,"#,
+            r#""columns":["#,
+            r#"{"column":"B","populated":4,"numbers":0,"text":4,"booleans":0,"errors":0,"dominant":"text"},"#,
+            r#"{"column":"C","populated":4,"numbers":3,"text":1,"booleans":0,"errors":0,"dominant":"number","min":80.0,"max":150.0},"#,
+            r#"{"column":"D","populated":3,"numbers":2,"text":1,"booleans":0,"errors":0,"dominant":"number","min":120.0,"max":170.0},"#,
+            r#"{"column":"E","populated":2,"numbers":1,"text":1,"booleans":0,"errors":0,"dominant":"number","min":220.0,"max":220.0}],"#,
+            r#""header":{"row":2,"values":["Region","Q1","Q2","Total"]},"#,
+            r#""number_formats":["$#,##0"],"#,
+            r#""samples":[{"cell":"B2","value":"Region"},{"cell":"C2","value":"Q1"},{"cell":"D2","value":"Q2"},"#,
+            r#"{"cell":"E2","value":"Total"},{"cell":"B6","value":"AMER"},{"cell":"D6","value":"170"}],"#,
+            r#""formulas":[{"cell":"E3","formula":"=C3+D3"}]}"#,
+        );
+        assert_eq!(json, expected);
+    }
+
+    #[test]
+    fn test_describe_range_is_deterministic() {
+        let (grid, engine) = fixture();
+        let describe = || grid.describe_range(CellRef::parse("E6").unwrap(), CellRef::parse("B2").unwrap(), 5, &engine);
+        let first = describe();
+        for _ in 0..5 {
+            assert_eq!(describe(), first);
+        }
+        assert_eq!(first.samples.len(), 5);
+
+        let empty = grid.describe_range(CellRef::parse("H1").unwrap(), CellRef::parse("J3").unwrap(), 5, &engine);
+        assert_eq!(empty.populated, 0);
+        assert!(empty.samples.is_empty() && empty.header.is_none());
+    }
+
+    #[test]
+    fn test_formatted_columns_keep_their_bounds() {
+        let mut grid = Grid::new(10, 5);
+        let mut engine = FormulaEngine::new();
+        let block: Vec<Vec<String>> = [["$1,234.00", "12.5%", "2024-01-31"], ["$5.50", "7%", "2024-02-01"]]
+            .iter()
+            .map(|row| row.iter().map(|s| s.to_string()).collect())
+            .collect();
+        grid.paste_block(CellRef::new(0, 0), &block, PasteOptions { infer_formats: true }, &mut engine).unwrap();
+
+        let description = grid.describe_range(CellRef::new(0, 0), CellRef::new(1, 2), 4, &engine);
+        let bounds: Vec<(usize, Option<f64>, Option<f64>)> = description.columns.iter()
+            .map(|column| (column.numbers, column.min, column.max))
+            .collect();
+        assert_eq!(bounds, vec![
+            (2, Some(5.5), Some(1234.0)),
+            (2, Some(0.07), Some(0.125)),
+            (2, Some(45322.0), Some(45323.0)),
+        ]);
+    }
+}
@@ -0,0 +1,74 @@
+//! Document-level properties stored alongside the grid
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// Metadata about the document (title, author, timestamps, app-defined keys)
+///
+/// Timestamps are milliseconds since the Unix epoch, like JS `Date.now()`.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct DocumentProperties {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub author: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created_at: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub modified_at: Option<f64>,
+    /// Schema tag the persistence layer uses for migrations
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub schema: Option<String>,
+    /// Arbitrary app-defined key-values
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub custom: HashMap<String, serde_json::Value>,
+}
+
+impl DocumentProperties {
+    /// Get a property by name; unknown names are looked up in `custom`
+    pub fn get(&self, key: &str) -> Option<serde_json::Value> {
+        match key {
+            "title" => self.title.clone().map(Into::into),
+            "author" => self.author.clone().map(Into::into),
+            "created_at" => self.created_at.map(Into::into),
+            "modified_at" => self.modified_at.map(Into::into),
+            "schema" => self.schema.clone().map(Into::into),
+            _ => self.custom.get(key).cloned(),
+        }
+    }
+
+    /// Set a property by name; unknown names go to `custom`
+    ///
+    /// A JSON `null` clears the property.
+    pub fn set(&mut self, key: &str, value: serde_json::Value) -> Result<(), String> {
+        fn text(key: &str, value: serde_json::Value) -> Result<Option<String>, String> {
+            match value {
+                serde_json::Value::Null => Ok(None),
+                serde_json::Value::String(s) => Ok(Some(s)),
+                other => Err(format!("property '{}' must be a string, got {}", key, other)),
+            }
+        }
+        fn timestamp(key: &str, value: serde_json::Value) -> Result<Option<f64>, String> {
+            match value {
+                serde_json::Value::Null => Ok(None),
+                serde_json::Value::Number(n) => Ok(n.as_f64()),
+                other => Err(format!("property '{}' must be a number, got {}", key, other)),
+            }
+        }
+
+        match key {
+            "title" => self.title = text(key, value)?,
+            "author" => self.author = text(key, value)?,
+            "schema" => self.schema = text(key, value)?,
+            "created_at" => self.created_at = timestamp(key, value)?,
+            "modified_at" => self.modified_at = timestamp(key, value)?,
+            _ if value.is_null() => {
+                self.custom.remove(key);
+            }
+            _ => {
+                self.custom.insert(key.to_string(), value);
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,220 @@
+//! Formulas from other applications that this engine can't evaluate yet
+//!
+//! A document may contain formulas we don't support: functions we haven't
+//! implemented (`XIRR`) or syntax the parser doesn't read. Failing the
+//! import, or evaluating them to `#NAME`, would throw away the user's work
+//! on the next save. Instead such a formula is marked foreign when loaded:
+//! the cell keeps the formula text exactly as imported and its last cached
+//! value, and the engine never registers it, so the value stays frozen.
+//! Once a later version supports the formula, `reparse_foreign_formulas`
+//! turns it back into a live one.
+
+use crate::cell::CellRef;
+use crate::formula::{Formula, FormulaEngine, FormulaNode};
+use crate::grid::{Grid, GridError};
+use crate::warnings::{EngineWarning, WarningCode};
+
+/// Functions `FormulaEngine` can evaluate
+///
+/// Kept in step with the dispatch in `evaluate_function`; a test checks
+/// that every name here is dispatched.
+pub(crate) const SUPPORTED_FUNCTIONS: &[&str] = &[
+    "ABS", "AVERAGE", "AVERAGEIFS", "AVG", "CEILING", "CONCATENATE", "COUNT", "COUNTIF", "COUNTIFS",
+    "DATE", "DATEDIF", "DAY", "EDATE", "EOMONTH", "FIND", "FLOOR", "FV", "HLOOKUP", "IF", "IFERROR",
+    "IFNA", "IFS", "INDEX", "INT", "LEFT", "LEN", "LOWER", "MATCH", "MAX", "MEDIAN", "MID", "MIN",
+    "MOD", "MODE", "MONTH", "N", "NETWORKDAYS", "NOW", "NPER", "PMT", "POW", "POWER", "PROPER",
+    "PV", "RATE", "REPLACE", "RIGHT", "ROUND", "ROUNDDOWN", "ROUNDUP", "SEARCH", "SQRT",
+    "SUBSTITUTE", "SUM", "SUMIF", "SUMIFS", "SWITCH", "T", "TEXT", "TEXTJOIN", "TEXTSPLIT", "TODAY",
+    "TRIM", "UPPER", "VALUE", "VLOOKUP", "WEEKDAY", "WORKDAY", "XLOOKUP", "YEAR",
+];
+
+/// The first function called in `node` that isn't supported
+fn unsupported_function(node: &FormulaNode) -> Option<&str> {
+    match node {
+        FormulaNode::Function { name, args, .. } => {
+            if !SUPPORTED_FUNCTIONS.contains(&name.as_str()) {
+                return Some(name);
+            }
+            args.iter().find_map(unsupported_function)
+        }
+        FormulaNode::BinaryOp { left, right, .. } => unsupported_function(left).or_else(|| unsupported_function(right)),
+        FormulaNode::UnaryOp { operand, .. } => unsupported_function(operand),
+        FormulaNode::Number { .. } | FormulaNode::Text { .. } | FormulaNode::Boolean { .. }
+        | FormulaNode::CellRef { .. } | FormulaNode::Range { .. } | FormulaNode::RefError => None,
+    }
+}
+
+impl FormulaEngine {
+    /// Parse `text` if this engine can evaluate it, or say why not
+    pub(crate) fn parse_supported(&self, text: &str) -> Result<Formula, String> {
+        let formula = self.parse(text).map_err(|e| e.to_string())?;
+        match unsupported_function(&formula.ast) {
+            Some(name) => Err(format!("{} is not supported", name)),
+            None => Ok(formula),
+        }
+    }
+}
+
+impl Grid {
+    /// Whether the cell holds a formula this engine can't evaluate
+    pub fn is_foreign(&self, cell_ref: CellRef) -> bool {
+        self.get_cell(cell_ref).is_some_and(|cell| cell.foreign)
+    }
+
+    /// Freeze the formula in `cell_ref` at its cached value, with a warning
+    pub(crate) fn mark_foreign(&mut self, cell_ref: CellRef, reason: String) {
+        let Some(cell) = self.columns.get_mut(&cell_ref.col).and_then(|column| column.get_mut(cell_ref.row)) else {
+            return;
+        };
+        cell.foreign = true;
+        let formula = cell.formula.clone().unwrap_or_default();
+        self.warn(EngineWarning::new(WarningCode::ForeignFormula, "Formula can't be evaluated; its last value is kept")
+            .at(cell_ref)
+            .with_details(format!("{}: {}", formula, reason)));
+    }
+
+    /// Turn foreign formulas this engine now supports back into live formulas
+    ///
+    /// Returns the upgraded cells and every cell their recalculation changed.
+    pub fn reparse_foreign_formulas(&mut self, formula_engine: &mut FormulaEngine) -> Result<Vec<CellRef>, GridError> {
+        let mut upgraded = Vec::new();
+        for (cell_ref, text) in self.stored_formulas() {
+            if !self.is_foreign(cell_ref) {
+                continue;
+            }
+            if let Ok(formula) = formula_engine.parse_supported(&text) {
+                self.set_cell_formula(cell_ref, formula, formula_engine)?;
+                upgraded.push(cell_ref);
+            }
+        }
+        upgraded.sort_by_key(|cell| (cell.row, cell.col));
+        self.recalculate_cells(upgraded, formula_engine)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cell::{Cell, CellValue};
+    use crate::formula::FormulaError;
+    use crate::grid::{CellData, CellUpdate, GridPatch};
+    use crate::warnings::WarningCode;
+
+    /// A saved sheet: A1:A2 hold numbers, B1 a foreign XIRR, B2 a reference
+    /// to another sheet and B3 an IFS marked foreign by a version that lacked it
+    fn saved() -> String {
+        let mut grid = Grid::new(10, 10);
+        let mut put = |a1: &str, value: CellValue, formula: Option<&str>, foreign: bool| {
+            let cell_ref = CellRef::parse(a1).unwrap();
+            let mut cell = Cell::new(value);
+            cell.formula = formula.map(str::to_string);
+            cell.foreign = foreign;
+            grid.columns.entry(cell_ref.col).or_default().insert(cell_ref.row, cell);
+        };
+        put("A1", CellValue::Number(5.0), None, false);
+        put("A2", CellValue::Number(7.0), None, false);
+        put("B1", CellValue::Number(0.125), Some("=xirr( A1:A2 ,  A1:A2 )"), false);
+        put("B2", CellValue::Number(3.0), Some("=Sheet2!A1*2"), false);
+        put("B3", CellValue::Text("pos".to_string()), Some("=IFS(A1>0,\"pos\")"), true);
+        put("C1", CellValue::Number(0.0), Some("=B1*2"), false);
+        grid.to_json().unwrap()
+    }
+
+    fn load() -> (Grid, FormulaEngine) {
+        let mut grid = Grid::from_json(&saved()).unwrap();
+        let mut engine = FormulaEngine::new();
+        engine.load_formulas(&mut grid);
+        (grid, engine)
+    }
+
+    fn value(grid: &Grid, a1: &str) -> CellValue {
+        grid.get_cell(CellRef::parse(a1).unwrap()).unwrap().value.clone()
+    }
+
+    #[test]
+    fn test_foreign_formulas_round_trip_verbatim() {
+        let (mut grid, engine) = load();
+        let warnings = grid.take_warnings();
+        assert_eq!(warnings.len(), 2);
+        assert!(warnings.iter().all(|w| w.code == WarningCode::ForeignFormula));
+
+        for a1 in ["B1", "B2", "B3"] {
+            assert!(grid.is_foreign(CellRef::parse(a1).unwrap()), "{}", a1);
+        }
+        let data = CellData::from_cell(CellRef::new(0, 1), grid.get_cell(CellRef::new(0, 1)).unwrap(), &engine);
+        assert!(data.is_foreign);
+        assert_eq!(data.formula.as_deref(), Some("=xirr( A1:A2 ,  A1:A2 )"));
+
+        let exported: serde_json::Value = serde_json::from_str(&grid.to_json().unwrap()).unwrap();
+        let original: serde_json::Value = serde_json::from_str(&saved()).unwrap();
+        // Formula text and cached value come back exactly; the cells just gain the mark
+        for row in ["0", "1", "2"] {
+            let (exported, original) = (&exported["columns"]["1"][row], &original["columns"]["1"][row]);
+            assert_eq!(exported["formula"], original["formula"], "B{}", row);
+            assert_eq!(exported["value"], original["value"], "B{}", row);
+            assert_eq!(exported["foreign"], serde_json::Value::Bool(true));
+        }
+    }
+
+    #[test]
+    fn test_foreign_values_stay_frozen() {
+        let (mut grid, mut engine) = load();
+        engine.recalculate_all(&mut grid).unwrap();
+        assert_eq!(value(&grid, "B1"), CellValue::Number(0.125));
+        assert_eq!(value(&grid, "C1"), CellValue::Number(0.25));
+
+        let update = CellUpdate { row: 0, col: 0, value: Some("50".to_string()), formula: None };
+        grid.apply_patch(GridPatch { updates: vec![update], auto_expand: None }, &mut engine).unwrap();
+        assert_eq!(value(&grid, "B1"), CellValue::Number(0.125));
+        assert_eq!(value(&grid, "B3"), CellValue::Text("pos".to_string()));
+
+        // Typing over a foreign formula replaces it like any other
+        let update = CellUpdate { row: 0, col: 1, value: None, formula: Some("=A1/100".to_string()) };
+        grid.apply_patch(GridPatch { updates: vec![update], auto_expand: None }, &mut engine).unwrap();
+        assert!(!grid.is_foreign(CellRef::new(0, 1)));
+        assert_eq!(value(&grid, "C1"), CellValue::Number(1.0));
+    }
+
+    #[test]
+    fn test_reparse_upgrades_supported_formulas() {
+        let (mut grid, mut engine) = load();
+        let changed = grid.reparse_foreign_formulas(&mut engine).unwrap();
+        assert_eq!(changed, vec![CellRef::new(2, 1)]);
+        assert!(!grid.is_foreign(CellRef::new(2, 1)));
+        assert_eq!(engine.formula_at(CellRef::new(2, 1)).unwrap().raw, "=IFS(A1>0,\"pos\")");
+        assert!(grid.is_foreign(CellRef::new(0, 1)));
+        assert!(grid.is_foreign(CellRef::new(1, 1)));
+
+        // The upgraded formula now follows its inputs
+        let update = CellUpdate { row: 0, col: 0, value: Some("-1".to_string()), formula: None };
+        grid.apply_patch(GridPatch { updates: vec![update], auto_expand: None }, &mut engine).unwrap();
+        assert_eq!(value(&grid, "B3"), CellValue::Error("N/A".to_string()));
+    }
+
+    #[test]
+    fn test_foreign_marks_survive_inserted_rows() {
+        let (mut grid, mut engine) = load();
+        grid.take_warnings();
+        grid.insert_rows(0, 1, &mut engine).unwrap();
+
+        // The IFS would parse now, but stays frozen until explicitly reparsed
+        for a1 in ["B2", "B3", "B4"] {
+            assert!(grid.is_foreign(CellRef::parse(a1).unwrap()), "{}", a1);
+        }
+        assert!(engine.formula_at(CellRef::parse("B4").unwrap()).is_none());
+        assert_eq!(value(&grid, "B2"), CellValue::Number(0.125));
+        assert_eq!(value(&grid, "B4"), CellValue::Text("pos".to_string()));
+        assert_eq!(value(&grid, "C2"), CellValue::Number(0.25));
+        assert!(grid.take_warnings().is_empty());
+    }
+
+    #[test]
+    fn test_supported_functions_are_dispatched() {
+        let engine = FormulaEngine::new();
+        let grid = Grid::new(1, 1);
+        for name in SUPPORTED_FUNCTIONS {
+            let ast = FormulaNode::Function { name: name.to_string(), args: Vec::new(), written_name: None };
+            assert!(!matches!(engine.evaluate(&ast, &grid), Err(FormulaError::UnknownFunction(_))), "{}", name);
+        }
+    }
+}
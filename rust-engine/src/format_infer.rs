@@ -0,0 +1,362 @@
+//! Number format inference for pasted and imported text
+//!
+//! Raw text like `$1,234.00`, `12%`, `2024-01-31` or `(500)` is parsed into a
+//! number together with the presentation it was written in, so that a column
+//! of such values can be given a matching `number_format`.
+//!
+//! Dates typed into a single cell get the more lenient `parse_typed_date`,
+//! which also reads forms like `1/5` and `Jan 5`.
+
+use crate::cell::CellValue;
+use crate::formula::datetime::{date_to_serial, serial_to_date, unix_millis_to_serial};
+use serde::{Deserialize, Serialize};
+
+/// The presentation a single raw value was written in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum FormatKind {
+    /// Plain number, possibly with thousands separators
+    Plain,
+    /// Currency amount (`$1,234.00`)
+    Currency,
+    /// Percentage (`12%`)
+    Percent,
+    /// Date, carrying the format pattern it was written in
+    Date(&'static str),
+}
+
+/// A raw value parsed into a number plus the style it was written in
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ParsedNumber {
+    pub value: f64,
+    pub kind: FormatKind,
+    /// Negative written accounting-style, in parentheses (`(500)`)
+    pub parens: bool,
+    /// Digits written after the decimal point (`2` for `$5.50`)
+    pub decimals: usize,
+}
+
+/// Parse a raw string, recognizing currency, percent, date and accounting styles
+pub fn classify(raw: &str) -> Option<ParsedNumber> {
+    let s = raw.trim();
+    if s.is_empty() {
+        return None;
+    }
+
+    if let Some((value, pattern)) = parse_date(s) {
+        return Some(ParsedNumber { value, kind: FormatKind::Date(pattern), parens: false, decimals: 0 });
+    }
+
+    let (s, parens) = match s.strip_prefix('(').and_then(|r| r.strip_suffix(')')) {
+        Some(inner) => (inner.trim(), true),
+        None => (s, false),
+    };
+    let (s, minus) = match s.strip_prefix('-') {
+        Some(rest) => (rest.trim_start(), true),
+        None => (s, false),
+    };
+
+    let (s, kind) = if let Some(rest) = s.strip_prefix('$') {
+        (rest.trim_start(), FormatKind::Currency)
+    } else if let Some(rest) = s.strip_suffix('%') {
+        (rest.trim_end(), FormatKind::Percent)
+    } else {
+        (s, FormatKind::Plain)
+    };
+
+    let decimals = s.split_once('.').map_or(0, |(_, fraction)| fraction.len());
+    let mut value = parse_grouped(s)?;
+    if kind == FormatKind::Percent {
+        value /= 100.0;
+    }
+    if parens || minus {
+        value = -value;
+    }
+
+    Some(ParsedNumber { value, kind, parens, decimals })
+}
+
+/// Parse a value the way the paste/import paths store it when inferring formats
+pub fn parse_value(raw: &str) -> CellValue {
+    match classify(raw) {
+        Some(parsed) => CellValue::Number(parsed.value),
+        None => CellValue::parse(raw),
+    }
+}
+
+/// Pick a `number_format` for a column by majority vote over its parsed cells
+///
+/// Empty cells are ignored. A format is only returned when the winning style
+/// covers more than half of the non-empty cells, so a header row or the odd
+/// stray value does not decide the column. Plain numbers only get a format
+/// when written accounting-style. The format shows as many decimal places
+/// as the most precise value of the winning style, so `7.5%` stays `7.5%`.
+pub fn infer_column_format<'a>(raws: impl IntoIterator<Item = &'a str>) -> Option<String> {
+    let mut non_empty = 0usize;
+    let mut votes: Vec<(FormatKind, usize, bool, usize)> = Vec::new();
+
+    for raw in raws {
+        if crate::cell::trim_invisible(raw).is_empty() {
+            continue;
+        }
+        non_empty += 1;
+        if let Some(parsed) = classify(raw) {
+            match votes.iter_mut().find(|(kind, ..)| *kind == parsed.kind) {
+                Some(entry) => {
+                    entry.1 += 1;
+                    entry.2 |= parsed.parens;
+                    entry.3 = entry.3.max(parsed.decimals);
+                }
+                None => votes.push((parsed.kind, 1, parsed.parens, parsed.decimals)),
+            }
+        }
+    }
+
+    // First-seen wins ties so the result doesn't depend on hash order
+    let (kind, count, parens, decimals) = votes
+        .into_iter()
+        .reduce(|best, next| if next.1 > best.1 { next } else { best })?;
+    if count * 2 <= non_empty {
+        return None;
+    }
+
+    format_for(kind, parens, decimals)
+}
+
+/// The `number_format` string for a detected style, showing `decimals` places
+fn format_for(kind: FormatKind, parens: bool, decimals: usize) -> Option<String> {
+    let fraction = match decimals {
+        0 => String::new(),
+        n => format!(".{}", "0".repeat(n)),
+    };
+    let format = match (kind, parens) {
+        (FormatKind::Plain, false) => return None,
+        (FormatKind::Plain, true) => format!("#,##0{0};(#,##0{0})", fraction),
+        (FormatKind::Currency, false) => format!("$#,##0{}", fraction),
+        (FormatKind::Currency, true) => format!("$#,##0{0};($#,##0{0})", fraction),
+        (FormatKind::Percent, _) => format!("0{}%", fraction),
+        (FormatKind::Date(pattern), _) => pattern.to_string(),
+    };
+    Some(format)
+}
+
+/// Parse a number that may contain `,` thousands separators
+fn parse_grouped(s: &str) -> Option<f64> {
+    if s.is_empty() || !s.starts_with(|c: char| c.is_ascii_digit() || c == '.') {
+        return None;
+    }
+    let (int_part, frac_part) = match s.split_once('.') {
+        Some((i, f)) => (i, Some(f)),
+        None => (s, None),
+    };
+    if int_part.contains(',') {
+        let mut groups = int_part.split(',');
+        let first = groups.next()?;
+        if first.is_empty() || first.len() > 3 || groups.any(|g| g.len() != 3) {
+            return None;
+        }
+    }
+    if frac_part.is_some_and(|f| !f.chars().all(|c| c.is_ascii_digit())) {
+        return None;
+    }
+    let cleaned: String = s.chars().filter(|c| *c != ',').collect();
+    if !cleaned.chars().all(|c| c.is_ascii_digit() || c == '.') {
+        return None;
+    }
+    cleaned.parse().ok()
+}
+
+/// Parse `yyyy-mm-dd` or `mm/dd/yyyy` into a serial date number
+fn parse_date(s: &str) -> Option<(f64, &'static str)> {
+    let (parts, pattern): (Vec<&str>, _) = if s.contains('-') {
+        (s.split('-').collect(), "yyyy-mm-dd")
+    } else if s.contains('/') {
+        (s.split('/').collect(), "mm/dd/yyyy")
+    } else {
+        return None;
+    };
+    if parts.len() != 3 || parts.iter().any(|p| p.is_empty() || !p.chars().all(|c| c.is_ascii_digit())) {
+        return None;
+    }
+
+    let (y, m, d) = if pattern == "yyyy-mm-dd" {
+        if parts[0].len() != 4 {
+            return None;
+        }
+        (parts[0].parse().ok()?, parts[1].parse().ok()?, parts[2].parse().ok()?)
+    } else {
+        if parts[2].len() != 4 {
+            return None;
+        }
+        (parts[2].parse().ok()?, parts[0].parse().ok()?, parts[1].parse().ok()?)
+    };
+
+    date_to_serial(y, m, d).map(|serial| (serial, pattern))
+}
+
+/// Which of the first two numbers in a slashed date like `1/5/2024` is the month
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DateOrder {
+    /// `1/5/2024` is January 5 (US)
+    #[default]
+    MonthFirst,
+    /// `1/5/2024` is May 1
+    DayFirst,
+}
+
+const MONTHS: [&str; 12] = [
+    "january", "february", "march", "april", "may", "june",
+    "july", "august", "september", "october", "november", "december",
+];
+
+/// Recognize a date typed into a cell, returning its serial number and a format to show it with
+///
+/// Accepts `yyyy-mm-dd`, slashed dates with a four-digit year or none
+/// (`1/5/2024`, `1/5`) read in `order`, and dates with a month name
+/// (`Jan 5`, `5 January 2024`, `Jan 5, 2024`). Dates without a year fall in
+/// `current_year`. Anything else, two-digit years and impossible dates
+/// included, is left for the caller to treat as text.
+pub fn parse_typed_date(s: &str, order: DateOrder, current_year: i64) -> Option<(f64, &'static str)> {
+    let s = s.trim();
+    if let Some((serial, pattern)) = parse_date(s).filter(|(_, pattern)| *pattern == "yyyy-mm-dd") {
+        return Some((serial, pattern));
+    }
+
+    if s.contains('/') {
+        let parts: Vec<&str> = s.split('/').collect();
+        if parts.iter().any(|p| p.is_empty() || p.len() > 4 || !p.chars().all(|c| c.is_ascii_digit())) {
+            return None;
+        }
+        let (first, second) = (parts[0].parse().ok()?, parts[1].parse().ok()?);
+        let (month, day) = match order {
+            DateOrder::MonthFirst => (first, second),
+            DateOrder::DayFirst => (second, first),
+        };
+        return match parts.as_slice() {
+            [_, _] => date_to_serial(current_year, month, day).map(|serial| (serial, "d-mmm")),
+            [_, _, year] if year.len() == 4 => {
+                let pattern = match order {
+                    DateOrder::MonthFirst => "m/d/yyyy",
+                    DateOrder::DayFirst => "d/m/yyyy",
+                };
+                date_to_serial(year.parse().ok()?, month, day).map(|serial| (serial, pattern))
+            }
+            _ => None,
+        };
+    }
+
+    let words: Vec<&str> = s.split([' ', ',']).filter(|w| !w.is_empty()).collect();
+    let (month, day, year) = match words.as_slice() {
+        [a, b] | [a, b, _] => match (month_number(a), month_number(b)) {
+            (Some(month), None) => (month, *b, words.get(2)),
+            (None, Some(month)) => (month, *a, words.get(2)),
+            _ => return None,
+        },
+        _ => return None,
+    };
+    if day.len() > 2 || !day.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    let day = day.parse().ok()?;
+    match year {
+        None => date_to_serial(current_year, month, day).map(|serial| (serial, "d-mmm")),
+        Some(year) if year.len() == 4 && year.chars().all(|c| c.is_ascii_digit()) => {
+            date_to_serial(year.parse().ok()?, month, day).map(|serial| (serial, "d-mmm-yyyy"))
+        }
+        Some(_) => None,
+    }
+}
+
+/// 1-based month for a full or three-letter English month name
+fn month_number(word: &str) -> Option<u32> {
+    let word = word.to_ascii_lowercase();
+    MONTHS.iter()
+        .position(|name| word == *name || (word.len() == 3 && name.starts_with(word.as_str())))
+        .map(|index| index as u32 + 1)
+}
+
+/// Calendar year of a Unix timestamp in milliseconds
+pub fn year_of_unix_millis(millis: f64) -> i64 {
+    serial_to_date(unix_millis_to_serial(millis)).0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type ClassifyCase = (&'static str, Option<(f64, FormatKind, bool)>);
+
+    #[test]
+    fn test_classify() {
+        let cases: &[ClassifyCase] = &[
+            ("42", Some((42.0, FormatKind::Plain, false))),
+            ("1,234", Some((1234.0, FormatKind::Plain, false))),
+            ("(500)", Some((-500.0, FormatKind::Plain, true))),
+            ("$1,234.00", Some((1234.0, FormatKind::Currency, false))),
+            ("-$20", Some((-20.0, FormatKind::Currency, false))),
+            ("($1,000.50)", Some((-1000.5, FormatKind::Currency, true))),
+            ("12%", Some((0.12, FormatKind::Percent, false))),
+            ("2024-01-31", Some((45322.0, FormatKind::Date("yyyy-mm-dd"), false))),
+            ("01/31/2024", Some((45322.0, FormatKind::Date("mm/dd/yyyy"), false))),
+            ("1900-03-01", Some((61.0, FormatKind::Date("yyyy-mm-dd"), false))),
+            ("2024-02-30", None),
+            ("12,34", None),
+            ("Revenue", None),
+            ("", None),
+        ];
+        for (raw, expected) in cases {
+            let got = classify(raw).map(|p| (p.value, p.kind, p.parens));
+            assert_eq!(got, *expected, "classify({:?})", raw);
+        }
+    }
+
+    #[test]
+    fn test_parse_typed_date() {
+        let us = |s: &str| parse_typed_date(s, DateOrder::MonthFirst, 2024);
+        assert_eq!(us("1/5/2024"), Some((45296.0, "m/d/yyyy")));
+        assert_eq!(us("2024-01-05"), Some((45296.0, "yyyy-mm-dd")));
+        assert_eq!(us("1/5"), Some((45296.0, "d-mmm")));
+        assert_eq!(us("Jan 5"), Some((45296.0, "d-mmm")));
+        assert_eq!(us("5 january"), Some((45296.0, "d-mmm")));
+        assert_eq!(us("Jan 5, 2024"), Some((45296.0, "d-mmm-yyyy")));
+        assert_eq!(parse_typed_date("5/1/2024", DateOrder::DayFirst, 2024), Some((45296.0, "d/m/yyyy")));
+        assert_eq!(parse_typed_date("13/1", DateOrder::DayFirst, 2024), Some((45304.0, "d-mmm")));
+
+        // Not dates: stays text
+        for raw in ["1/x", "13/1", "1/5/24", "1/2/3/4", "2/30", "Ja 5", "Jan", "Jan 5 24", "May June", "n/a", "5"] {
+            assert_eq!(us(raw), None, "{:?}", raw);
+        }
+    }
+
+    #[test]
+    fn test_year_of_unix_millis() {
+        assert_eq!(year_of_unix_millis(0.0), 1970);
+        // 2024-12-31T23:59:59Z and a second later
+        assert_eq!(year_of_unix_millis(1_735_689_599_000.0), 2024);
+        assert_eq!(year_of_unix_millis(1_735_689_600_000.0), 2025);
+        assert_eq!(year_of_unix_millis(-1.0), 1969);
+    }
+
+    #[test]
+    fn test_infer_column_format() {
+        let cases: &[(&[&str], Option<&str>)] = &[
+            (&["Price", "$1,234.00", "$5.00", "$12.50"], Some("$#,##0.00")),
+            (&["$1,234.00", "($500.00)", "$12.50"], Some("$#,##0.00;($#,##0.00)")),
+            (&["12%", "7.5%", "", "100%"], Some("0.0%")),
+            (&["12%", "7%", "100%"], Some("0%")),
+            (&["1.25%", "7.5%"], Some("0.00%")),
+            (&["$1", "$20", "$300"], Some("$#,##0")),
+            (&["(1,234.5)", "20", "(7)"], Some("#,##0.0;(#,##0.0)")),
+            (&["2024-01-31", "2024-02-29"], Some("yyyy-mm-dd")),
+            (&["100", "(500)", "250"], Some("#,##0;(#,##0)")),
+            (&["1", "2", "3"], None),
+            (&["$1", "2%", "a", "b"], None),
+            (&["Name", "Alice", "Bob"], None),
+            (&["", ""], None),
+        ];
+        for (column, expected) in cases {
+            let got = infer_column_format(column.iter().copied());
+            assert_eq!(got.as_deref(), *expected, "infer_column_format({:?})", column);
+        }
+    }
+}
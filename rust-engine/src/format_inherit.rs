@@ -0,0 +1,163 @@
+//! Number formats a formula's result inherits from its operands
+//!
+//! `=B2*C2` with a currency in B2 and a percent in C2 shows as currency in
+//! Excel, without anyone formatting the cell. A formula cell with no
+//! number format of its own gets one derived the same way during
+//! recalculation: its implicit format. A number format set on the cell
+//! always wins over it, and it's worked out afresh whenever the formula
+//! is recalculated, so it follows edits to the formula.
+//!
+//! Only the kind of format matters to the rules (`FormatClass`); the
+//! result takes the exact format of the operand whose kind won.
+
+use crate::cell::{is_date_format, CellRef};
+use crate::formula::{BinaryOp, FormulaNode, UnaryOp};
+use crate::grid::Grid;
+
+/// The kind of number format an operand has, weakest first
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum FormatClass {
+    /// No number format, or one with nothing worth passing on (`0.00`)
+    Plain,
+    Percent,
+    Currency,
+    Date,
+}
+
+impl FormatClass {
+    /// Classify a number format code
+    pub fn of(number_format: &str) -> Self {
+        if is_date_format(number_format) {
+            return FormatClass::Date;
+        }
+        let mut quoted = false;
+        let mut class = FormatClass::Plain;
+        for c in number_format.chars() {
+            match c {
+                '"' => quoted = !quoted,
+                _ if quoted => {}
+                '$' | '€' | '£' | '¥' => return FormatClass::Currency,
+                '%' => class = FormatClass::Percent,
+                _ => {}
+            }
+        }
+        class
+    }
+}
+
+/// The class of `left op right`, following Excel
+///
+/// Currency beats percent, which beats plain: a currency times a percent is
+/// currency. A date plus or minus a number is still a date, but the sum of
+/// two dates and any other use of a date (a product, say) is plain, and so
+/// is a date minus a date, a count of days. Powers, comparisons and `&`
+/// give plain results.
+pub fn combine(op: BinaryOp, left: FormatClass, right: FormatClass) -> FormatClass {
+    use FormatClass::*;
+    match op {
+        BinaryOp::Add | BinaryOp::Sub | BinaryOp::Mul | BinaryOp::Div if left != Date && right != Date => left.max(right),
+        BinaryOp::Add => match (left, right) {
+            (Date, Date) => Plain,
+            _ => Date,
+        },
+        BinaryOp::Sub if right != Date => Date,
+        _ => Plain,
+    }
+}
+
+/// Functions whose result takes the format of their arguments, with the arguments that count
+fn passed_through<'a>(name: &str, args: &'a [FormulaNode]) -> Option<&'a [FormulaNode]> {
+    match name {
+        "SUM" | "AVERAGE" | "AVG" | "MIN" | "MAX" => Some(args),
+        "ABS" | "INT" | "ROUND" | "ROUNDUP" | "ROUNDDOWN" | "CEILING" | "FLOOR" => Some(args.get(..1).unwrap_or(args)),
+        "IF" | "IFERROR" => Some(args.get(1..).unwrap_or(&[])),
+        _ => None,
+    }
+}
+
+/// The number format a cell shows, set or inherited
+fn cell_format(grid: &Grid, cell: CellRef) -> Option<&str> {
+    grid.get_cell(cell).and_then(|cell| cell.number_format())
+}
+
+/// The strongest format among the cells of a range, counting only stored cells
+fn range_format(grid: &Grid, start: CellRef, end: CellRef) -> Option<&str> {
+    let rows = start.row.min(end.row)..=start.row.max(end.row);
+    (start.col.min(end.col)..=start.col.max(end.col))
+        .flat_map(|col| grid.column_rows(col).filter(|row| rows.contains(row)).map(move |row| CellRef::new(row, col)))
+        .filter_map(|cell| cell_format(grid, cell))
+        .max_by_key(|format| FormatClass::of(format))
+}
+
+/// The format of whichever operand has class `class`, preferring `left`
+fn pick<'a>(class: FormatClass, left: Option<&'a str>, right: Option<&'a str>) -> Option<&'a str> {
+    if class == FormatClass::Plain {
+        return None;
+    }
+    let class_of = |format: Option<&str>| format.map_or(FormatClass::Plain, FormatClass::of);
+    [left, right].into_iter().find(|&format| class_of(format) == class).flatten()
+}
+
+/// The number format `ast`'s result inherits, if any
+pub(crate) fn inherited_format<'a>(ast: &FormulaNode, grid: &'a Grid) -> Option<&'a str> {
+    let class_of = |format: Option<&str>| format.map_or(FormatClass::Plain, FormatClass::of);
+    match ast {
+        FormulaNode::CellRef { cell, .. } => cell_format(grid, *cell),
+        FormulaNode::Range { start, end, .. } => range_format(grid, *start, *end),
+        FormulaNode::UnaryOp { op: UnaryOp::Neg, operand } => inherited_format(operand, grid),
+        FormulaNode::BinaryOp { op, left, right } => {
+            let (left, right) = (inherited_format(left, grid), inherited_format(right, grid));
+            pick(combine(*op, class_of(left), class_of(right)), left, right)
+        }
+        FormulaNode::Function { name, args, .. } => {
+            let args = passed_through(name, args)?;
+            // SUM adds its arguments up; the rest keep the kind they're given
+            let formats: Vec<Option<&str>> = args.iter().map(|arg| inherited_format(arg, grid)).collect();
+            formats.into_iter().reduce(|left, right| {
+                let class = match name.as_str() {
+                    "SUM" => combine(BinaryOp::Add, class_of(left), class_of(right)),
+                    _ => class_of(left).max(class_of(right)),
+                };
+                pick(class, left, right)
+            })?
+        }
+        FormulaNode::UnaryOp { op: UnaryOp::Percent, .. }
+        | FormulaNode::Number { .. }
+        | FormulaNode::Text { .. }
+        | FormulaNode::Boolean { .. }
+        | FormulaNode::RefError => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use FormatClass::*;
+
+    #[test]
+    fn test_classify_formats() {
+        assert_eq!(FormatClass::of("$#,##0.00"), Currency);
+        assert_eq!(FormatClass::of("[$€-407] #,##0"), Currency);
+        assert_eq!(FormatClass::of("0.0%"), Percent);
+        assert_eq!(FormatClass::of("0.0\"%\""), Plain);
+        assert_eq!(FormatClass::of("yyyy-mm-dd"), Date);
+        assert_eq!(FormatClass::of("#,##0"), Plain);
+    }
+
+    #[test]
+    fn test_combine_rules() {
+        assert_eq!(combine(BinaryOp::Mul, Currency, Percent), Currency);
+        assert_eq!(combine(BinaryOp::Mul, Percent, Currency), Currency);
+        assert_eq!(combine(BinaryOp::Add, Plain, Percent), Percent);
+        assert_eq!(combine(BinaryOp::Div, Plain, Plain), Plain);
+        assert_eq!(combine(BinaryOp::Add, Date, Plain), Date);
+        assert_eq!(combine(BinaryOp::Add, Currency, Date), Date);
+        assert_eq!(combine(BinaryOp::Add, Date, Date), Plain);
+        assert_eq!(combine(BinaryOp::Sub, Date, Plain), Date);
+        assert_eq!(combine(BinaryOp::Sub, Date, Date), Plain);
+        assert_eq!(combine(BinaryOp::Sub, Plain, Date), Plain);
+        assert_eq!(combine(BinaryOp::Mul, Date, Plain), Plain);
+        assert_eq!(combine(BinaryOp::Pow, Currency, Plain), Plain);
+        assert_eq!(combine(BinaryOp::Concat, Currency, Currency), Plain);
+    }
+}
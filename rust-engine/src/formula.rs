@@ -2,20 +2,36 @@
 
 use crate::cell::{CellRef, CellValue};
 use crate::grid::{Grid, GridError};
+use crate::spill::Spill;
+use crate::stats::DEFAULT_LARGE_FORMULA_CELLS;
+use crate::criteria::{wildcard_match, wildcard_prefix_match, Criterion};
+use crate::format_infer;
+use crate::format_inherit::inherited_format;
+use crate::reference::{parse_anchored, Anchor};
 use petgraph::graph::{DiGraph, NodeIndex};
 use petgraph::visit::EdgeRef;
-use petgraph::algo::toposort;
+use petgraph::algo::tarjan_scc;
 use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
 use std::collections::{HashMap, HashSet};
 use thiserror::Error;
 use wasm_bindgen::JsValue;
 
+pub mod datetime;
+mod finance;
+mod graph;
+pub mod number_format;
+mod simplify;
+
+pub use graph::{GraphExportOptions, GraphFormat};
+
 #[derive(Error, Debug)]
 pub enum FormulaError {
     #[error("Parse error: {0}")]
     Parse(String),
-    #[error("Circular reference detected")]
-    CircularReference,
+    /// The cells of the cycle, in row-major order
+    #[error("Circular reference: {}", a1_list(.0))]
+    CircularReference(Vec<CellRef>),
     #[error("Invalid cell reference: {0}")]
     InvalidRef(String),
     #[error("Division by zero")]
@@ -28,6 +44,31 @@ pub enum FormulaError {
     ArgumentCount { func: String, expected: String, got: usize },
     #[error("Grid error: {0}")]
     Grid(String),
+    #[error("Goal seek failed: {0}")]
+    GoalSeek(String),
+    #[error("Range shapes don't match: {0}")]
+    ShapeMismatch(String),
+    #[error("Invalid reference mapping: {0}")]
+    InvalidMapping(String),
+    #[error("Result is not a finite number")]
+    NotFinite,
+    #[error("Invalid name: {0}")]
+    InvalidName(String),
+}
+
+impl FormulaError {
+    /// Spreadsheet error code shown in a cell when evaluation fails (without the `#`)
+    pub fn error_code(&self) -> &'static str {
+        match self {
+            FormulaError::DivisionByZero => "DIV/0",
+            FormulaError::TypeError { .. } | FormulaError::ShapeMismatch(_) => "VALUE",
+            FormulaError::InvalidRef(_) | FormulaError::Grid(_) | FormulaError::InvalidMapping(_) => "REF",
+            FormulaError::UnknownFunction(_) | FormulaError::Parse(_) | FormulaError::InvalidName(_) => "NAME",
+            FormulaError::ArgumentCount { .. } => "N/A",
+            FormulaError::CircularReference(_) => "CIRCULAR",
+            FormulaError::GoalSeek(_) | FormulaError::NotFinite => "NUM",
+        }
+    }
 }
 
 impl From<GridError> for FormulaError {
@@ -48,6 +89,21 @@ pub struct Formula {
     pub raw: String,
     pub ast: FormulaNode,
     pub dependencies: Vec<CellRef>,
+    /// Calls a function such as NOW whose result changes with no input
+    /// changing, so it's re-evaluated on every recalculation
+    #[serde(default)]
+    pub volatile: bool,
+    /// `ast` with constants folded, evaluated in its place (see `simplify`);
+    /// set on registration when the engine simplifies and it differs
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub simplified: Option<FormulaNode>,
+}
+
+impl Formula {
+    /// The tree to evaluate: the simplified one if there is one
+    pub fn evaluated(&self) -> &FormulaNode {
+        self.simplified.as_ref().unwrap_or(&self.ast)
+    }
 }
 
 /// AST node for formula expressions
@@ -57,11 +113,37 @@ pub enum FormulaNode {
     Number { value: f64 },
     Text { value: String },
     Boolean { value: bool },
-    CellRef { cell: CellRef },
-    Range { start: CellRef, end: CellRef },
+    CellRef {
+        cell: CellRef,
+        #[serde(default, skip_serializing_if = "Anchor::is_relative")]
+        anchor: Anchor,
+        /// The named range written in its place, as typed
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        name: Option<String>,
+    },
+    Range {
+        start: CellRef,
+        end: CellRef,
+        #[serde(default, skip_serializing_if = "Anchor::is_relative")]
+        start_anchor: Anchor,
+        #[serde(default, skip_serializing_if = "Anchor::is_relative")]
+        end_anchor: Anchor,
+        /// The named range written in its place, as typed
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        name: Option<String>,
+    },
     BinaryOp { op: BinaryOp, left: Box<FormulaNode>, right: Box<FormulaNode> },
     UnaryOp { op: UnaryOp, operand: Box<FormulaNode> },
-    Function { name: String, args: Vec<FormulaNode> },
+    Function {
+        /// Uppercased name used for dispatch
+        name: String,
+        args: Vec<FormulaNode>,
+        /// The name as typed, when it differs from `name`
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        written_name: Option<String>,
+    },
+    /// A reference whose cells were deleted, written `#REF!`
+    RefError,
 }
 
 #[derive(Debug, Clone, Copy, Serialize, Deserialize)]
@@ -89,225 +171,475 @@ pub enum UnaryOp {
 /// The formula evaluation engine
 pub struct FormulaEngine {
     /// Dependency graph: edges point from dependency to dependent
-    dep_graph: DiGraph<CellRef, ()>,
+    pub(crate) dep_graph: DiGraph<CellRef, ()>,
     /// Map from cell reference to node index
     cell_to_node: HashMap<CellRef, NodeIndex>,
     /// Parsed formulas by cell
-    formulas: HashMap<CellRef, Formula>,
+    pub(crate) formulas: HashMap<CellRef, Formula>,
+    /// Hypothetical cell values in effect during `evaluate_with_overrides`
+    overrides: RefCell<Option<Overrides>>,
+    /// Treat blank operands as errors instead of applying Excel's coercions
+    strict_mode: bool,
+    /// Cells found in a reference cycle by the last recalculation
+    circular: HashSet<CellRef>,
+    /// Spill areas of array formulas, keyed by anchor cell
+    pub(crate) spills: HashMap<CellRef, Spill>,
+    /// Cell count above which a formula gets a size warning
+    pub(crate) large_formula_cells: u64,
+    /// Formulas over the size threshold, with the cells they cover
+    pub(crate) large_formulas: HashMap<CellRef, u64>,
+    /// Cells whose formula is volatile
+    volatile: HashSet<CellRef>,
+    /// Named ranges by upper-cased name, as corners: the grid's, as of the
+    /// last `load_formulas`, `define_name` or `remove_name`
+    pub(crate) named_ranges: HashMap<String, (CellRef, CellRef)>,
+    /// Evaluate registered formulas through a simplified tree
+    simplify: bool,
 }
 
-impl FormulaEngine {
-    pub fn new() -> Self {
-        Self {
-            dep_graph: DiGraph::new(),
-            cell_to_node: HashMap::new(),
-            formulas: HashMap::new(),
+/// State for a single `evaluate_with_overrides` call
+struct Overrides {
+    /// Cells whose value is replaced outright
+    values: HashMap<CellRef, CellValue>,
+    /// Formula cells downstream of an override, which must be re-evaluated
+    dirty: HashSet<CellRef>,
+    /// Re-evaluated values of dirty cells, so each is computed once
+    computed: HashMap<CellRef, CellValue>,
+    /// Dirty cells currently being evaluated (cycle guard)
+    in_progress: HashSet<CellRef>,
+}
+
+/// Binary operators of one precedence level, longest spelling first
+type OperatorLevel = &'static [(&'static str, BinaryOp)];
+
+const COMPARISON: OperatorLevel = &[
+    ("<=", BinaryOp::Le),
+    ("<>", BinaryOp::Ne),
+    (">=", BinaryOp::Ge),
+    ("<", BinaryOp::Lt),
+    (">", BinaryOp::Gt),
+    ("=", BinaryOp::Eq),
+];
+const CONCAT: OperatorLevel = &[("&", BinaryOp::Concat)];
+const ADDITIVE: OperatorLevel = &[("+", BinaryOp::Add), ("-", BinaryOp::Sub)];
+const MULTIPLICATIVE: OperatorLevel = &[("*", BinaryOp::Mul), ("/", BinaryOp::Div)];
+
+/// Recursive-descent parser for a formula body (the text after `=`)
+///
+/// Each level parses its operands with the next tighter one: comparison,
+/// `&`, `+ -`, `* /`, `^`, then prefix `-`/`+` and postfix `%`. Every
+/// binary level is left-associative except `^`, which is right-associative,
+/// so `1-2+3` is `2` and `2^3^2` is `512`.
+struct ExprParser<'a> {
+    text: &'a str,
+    /// Byte offset of the next unread character
+    pos: usize,
+    /// Cells referenced, in the order they're written
+    deps: Vec<CellRef>,
+    /// Named ranges, by upper-cased name
+    names: &'a HashMap<String, (CellRef, CellRef)>,
+}
+
+impl<'a> ExprParser<'a> {
+    fn rest(&self) -> &'a str {
+        &self.text[self.pos..]
+    }
+
+    fn skip_space(&mut self) {
+        let rest = self.rest();
+        self.pos += rest.len() - rest.trim_start().len();
+    }
+
+    /// Consume `token` if it comes next
+    fn eat(&mut self, token: &str) -> bool {
+        self.skip_space();
+        let found = self.rest().starts_with(token);
+        if found {
+            self.pos += token.len();
         }
+        found
     }
 
-    /// Parse a formula string (starting with '=')
-    pub fn parse(&self, formula: &str) -> Result<Formula, FormulaError> {
-        let raw = formula.to_string();
-        let content = formula.strip_prefix('=').unwrap_or(formula).trim();
-        
-        let (ast, dependencies) = self.parse_expression(content)?;
-        
-        Ok(Formula {
-            raw,
-            ast,
-            dependencies,
-        })
+    fn error(&self) -> FormulaError {
+        FormulaError::Parse(format!("Cannot parse: {} (at position {})", self.text, self.pos))
     }
 
-    fn parse_expression(&self, expr: &str) -> Result<(FormulaNode, Vec<CellRef>), FormulaError> {
-        let expr = expr.trim();
-        let mut deps = Vec::new();
-        
-        // Handle empty expression
-        if expr.is_empty() {
-            return Ok((FormulaNode::Number { value: 0.0 }, deps));
+    fn comparison(&mut self) -> Result<FormulaNode, FormulaError> {
+        self.left_associative(COMPARISON, Self::concat)
+    }
+
+    fn concat(&mut self) -> Result<FormulaNode, FormulaError> {
+        self.left_associative(CONCAT, Self::additive)
+    }
+
+    fn additive(&mut self) -> Result<FormulaNode, FormulaError> {
+        self.left_associative(ADDITIVE, Self::multiplicative)
+    }
+
+    fn multiplicative(&mut self) -> Result<FormulaNode, FormulaError> {
+        self.left_associative(MULTIPLICATIVE, Self::power)
+    }
+
+    fn left_associative(
+        &mut self,
+        level: OperatorLevel,
+        operand: fn(&mut Self) -> Result<FormulaNode, FormulaError>,
+    ) -> Result<FormulaNode, FormulaError> {
+        let mut left = operand(self)?;
+        while let Some(&(_, op)) = level.iter().find(|(token, _)| self.eat(token)) {
+            let right = operand(self)?;
+            left = FormulaNode::BinaryOp { op, left: Box::new(left), right: Box::new(right) };
         }
+        Ok(left)
+    }
 
-        // Try to parse as number
-        if let Ok(n) = expr.parse::<f64>() {
-            return Ok((FormulaNode::Number { value: n }, deps));
+    fn power(&mut self) -> Result<FormulaNode, FormulaError> {
+        let base = self.unary()?;
+        if !self.eat("^") {
+            return Ok(base);
         }
+        let exponent = self.power()?;
+        Ok(FormulaNode::BinaryOp { op: BinaryOp::Pow, left: Box::new(base), right: Box::new(exponent) })
+    }
 
-        // Try to parse as boolean
-        if expr.eq_ignore_ascii_case("true") {
-            return Ok((FormulaNode::Boolean { value: true }, deps));
+    fn unary(&mut self) -> Result<FormulaNode, FormulaError> {
+        if self.eat("-") {
+            return Ok(match self.unary()? {
+                // A negative literal stays a single number
+                FormulaNode::Number { value } => FormulaNode::Number { value: -value },
+                operand => FormulaNode::UnaryOp { op: UnaryOp::Neg, operand: Box::new(operand) },
+            });
+        }
+        if self.eat("+") {
+            return self.unary();
         }
-        if expr.eq_ignore_ascii_case("false") {
-            return Ok((FormulaNode::Boolean { value: false }, deps));
+        let mut node = self.primary()?;
+        while self.eat("%") {
+            node = FormulaNode::UnaryOp { op: UnaryOp::Percent, operand: Box::new(node) };
         }
+        Ok(node)
+    }
 
-        // Try to parse as string literal
-        if (expr.starts_with('"') && expr.ends_with('"')) ||
-           (expr.starts_with('\'') && expr.ends_with('\'')) {
-            let value = expr[1..expr.len()-1].to_string();
-            return Ok((FormulaNode::Text { value }, deps));
+    fn primary(&mut self) -> Result<FormulaNode, FormulaError> {
+        if self.eat("(") {
+            let inner = self.comparison()?;
+            return match self.eat(")") {
+                true => Ok(inner),
+                false => Err(self.error()),
+            };
+        }
+        match self.rest().chars().next() {
+            Some('#') => self.ref_error(),
+            Some(quote @ ('"' | '\'')) => self.string(quote),
+            Some(c) if c.is_ascii_digit() || c == '.' => self.number(),
+            Some(c) if c.is_alphabetic() || c == '$' || c == '_' => self.word(),
+            _ => Err(self.error()),
         }
+    }
 
-        // Try to parse as cell reference
-        if let Some(cell_ref) = CellRef::parse(expr) {
-            deps.push(cell_ref);
-            return Ok((FormulaNode::CellRef { cell: cell_ref }, deps));
+    /// A quoted string, where a doubled quote stands for one quote character
+    fn string(&mut self, quote: char) -> Result<FormulaNode, FormulaError> {
+        let start = self.pos;
+        self.pos += 1;
+        let mut value = String::new();
+        loop {
+            let Some(end) = self.rest().find(quote) else {
+                self.pos = start;
+                return Err(self.error());
+            };
+            value.push_str(&self.rest()[..end]);
+            self.pos += end + 1;
+            if !self.rest().starts_with(quote) {
+                return Ok(FormulaNode::Text { value });
+            }
+            value.push(quote);
+            self.pos += 1;
         }
+    }
 
-        // Try to parse as range (A1:B2)
-        if let Some((start, end)) = expr.split_once(':') {
-            if let (Some(start_ref), Some(end_ref)) = (CellRef::parse(start), CellRef::parse(end)) {
-                // Add all cells in range as dependencies
-                for row in start_ref.row..=end_ref.row {
-                    for col in start_ref.col..=end_ref.col {
-                        deps.push(CellRef::new(row, col));
-                    }
-                }
-                return Ok((FormulaNode::Range { start: start_ref, end: end_ref }, deps));
+    /// `#REF!`, left where a reference's cells were deleted
+    fn ref_error(&mut self) -> Result<FormulaNode, FormulaError> {
+        let token = "#REF!";
+        match self.rest().get(..token.len()) {
+            Some(found) if found.eq_ignore_ascii_case(token) => {
+                self.pos += token.len();
+                Ok(FormulaNode::RefError)
             }
+            _ => Err(self.error()),
         }
+    }
 
-        // Try to parse as function call
-        if let Some(paren_pos) = expr.find('(') {
-            if expr.ends_with(')') {
-                let name = expr[..paren_pos].trim().to_uppercase();
-                let args_str = &expr[paren_pos+1..expr.len()-1];
-                let (args, arg_deps) = self.parse_function_args(args_str)?;
-                deps.extend(arg_deps);
-                return Ok((FormulaNode::Function { name, args }, deps));
+    fn number(&mut self) -> Result<FormulaNode, FormulaError> {
+        let rest = self.rest();
+        let mut len = rest.find(|c: char| !c.is_ascii_digit() && c != '.').unwrap_or(rest.len());
+        // An exponent needs at least one digit, so `1E` alone isn't swallowed
+        if let Some(exponent) = rest[len..].strip_prefix(['e', 'E']) {
+            let unsigned = exponent.trim_start_matches(['+', '-']);
+            let sign = exponent.len() - unsigned.len();
+            let digits = unsigned.find(|c: char| !c.is_ascii_digit()).unwrap_or(unsigned.len());
+            if sign <= 1 && digits > 0 {
+                len += 1 + sign + digits;
             }
         }
+        let value = rest[..len].parse::<f64>().map_err(|_| self.error())?;
+        self.pos += len;
+        Ok(FormulaNode::Number { value })
+    }
 
-        // Try to parse binary operations (in order of precedence)
-        // Addition/Subtraction (lowest precedence, parse last)
-        if let Some(node) = self.try_parse_binary_op(expr, &['+', '-'], &mut deps)? {
-            return Ok((node, deps));
+    /// A function call, boolean, cell reference, range or named range
+    fn word(&mut self) -> Result<FormulaNode, FormulaError> {
+        let start = self.pos;
+        let written = self.name();
+        if self.eat("(") {
+            let name = written.to_uppercase();
+            let written_name = (written != name).then(|| written.to_string());
+            let args = self.arguments()?;
+            return Ok(FormulaNode::Function { name, args, written_name });
+        }
+        if written.eq_ignore_ascii_case("true") {
+            return Ok(FormulaNode::Boolean { value: true });
         }
+        if written.eq_ignore_ascii_case("false") {
+            return Ok(FormulaNode::Boolean { value: false });
+        }
+        let Some((cell, anchor)) = parse_anchored(written) else {
+            return match self.names.get(&written.to_uppercase()) {
+                Some(&(first, last)) => Ok(self.named(written, first, last)),
+                None => {
+                    self.pos = start;
+                    Err(self.error())
+                }
+            };
+        };
+        if !self.eat(":") {
+            self.deps.push(cell);
+            return Ok(FormulaNode::CellRef { cell, anchor, name: None });
+        }
+        self.skip_space();
+        let Some((end, end_anchor)) = parse_anchored(self.name()) else {
+            return Err(self.error());
+        };
+        self.push_range(cell, end);
+        Ok(FormulaNode::Range { start: cell, end, start_anchor: anchor, end_anchor, name: None })
+    }
 
-        // Multiplication/Division
-        if let Some(node) = self.try_parse_binary_op(expr, &['*', '/'], &mut deps)? {
-            return Ok((node, deps));
+    /// Every cell in a range is a dependency
+    fn push_range(&mut self, start: CellRef, end: CellRef) {
+        for row in start.row..=end.row {
+            for col in start.col..=end.col {
+                self.deps.push(CellRef::new(row, col));
+            }
         }
+    }
 
-        // Power
-        if let Some(node) = self.try_parse_binary_op(expr, &['^'], &mut deps)? {
-            return Ok((node, deps));
+    /// The reference a named range stands for, absolute like Excel's names
+    ///
+    /// The node keeps the name, so the formula is written back with it.
+    fn named(&mut self, written: &str, start: CellRef, end: CellRef) -> FormulaNode {
+        let anchor = Anchor { col: true, row: true };
+        let name = Some(written.to_string());
+        if start == end {
+            self.deps.push(start);
+            return FormulaNode::CellRef { cell: start, anchor, name };
         }
+        self.push_range(start, end);
+        FormulaNode::Range { start, end, start_anchor: anchor, end_anchor: anchor, name }
+    }
 
-        Err(FormulaError::Parse(format!("Cannot parse: {}", expr)))
+    /// The run of name characters at the cursor
+    fn name(&mut self) -> &'a str {
+        let rest = self.rest();
+        let len = rest
+            .find(|c: char| !(c.is_alphanumeric() || matches!(c, '$' | '_' | '.')))
+            .unwrap_or(rest.len());
+        self.pos += len;
+        &rest[..len]
     }
 
-    fn try_parse_binary_op(
-        &self,
-        expr: &str,
-        ops: &[char],
-        deps: &mut Vec<CellRef>,
-    ) -> Result<Option<FormulaNode>, FormulaError> {
-        let mut paren_depth = 0;
-        let chars: Vec<char> = expr.chars().collect();
-        
-        // Scan from right to left to ensure left associativity
-        for i in (0..chars.len()).rev() {
-            let c = chars[i];
-            match c {
-                ')' => paren_depth += 1,
-                '(' => paren_depth -= 1,
-                _ if paren_depth == 0 && ops.contains(&c) => {
-                    // Don't split on negative sign at the start
-                    if i == 0 {
-                        continue;
-                    }
-                    
-                    let left = &expr[..i].trim();
-                    let right = &expr[i+1..].trim();
-                    
-                    if left.is_empty() || right.is_empty() {
-                        continue;
-                    }
-                    
-                    let (left_node, left_deps) = self.parse_expression(left)?;
-                    let (right_node, right_deps) = self.parse_expression(right)?;
-                    
-                    deps.extend(left_deps);
-                    deps.extend(right_deps);
-                    
-                    let op = match c {
-                        '+' => BinaryOp::Add,
-                        '-' => BinaryOp::Sub,
-                        '*' => BinaryOp::Mul,
-                        '/' => BinaryOp::Div,
-                        '^' => BinaryOp::Pow,
-                        _ => unreachable!(),
-                    };
-                    
-                    return Ok(Some(FormulaNode::BinaryOp {
-                        op,
-                        left: Box::new(left_node),
-                        right: Box::new(right_node),
-                    }));
-                }
-                _ => {}
+    /// Arguments after a function's opening parenthesis, through the closing one
+    fn arguments(&mut self) -> Result<Vec<FormulaNode>, FormulaError> {
+        let mut args = Vec::new();
+        if self.eat(")") {
+            return Ok(args);
+        }
+        loop {
+            self.skip_space();
+            // An omitted argument, as in `IF(A1,,1)`, reads as zero
+            let arg = match self.rest().starts_with([',', ')']) {
+                true => FormulaNode::Number { value: 0.0 },
+                false => self.comparison()?,
+            };
+            args.push(arg);
+            if self.eat(")") {
+                return Ok(args);
+            }
+            if !self.eat(",") {
+                return Err(self.error());
             }
         }
-        
-        Ok(None)
     }
+}
 
-    fn parse_function_args(&self, args_str: &str) -> Result<(Vec<FormulaNode>, Vec<CellRef>), FormulaError> {
-        let mut args = Vec::new();
-        let mut deps = Vec::new();
-        
-        if args_str.trim().is_empty() {
-            return Ok((args, deps));
+impl FormulaEngine {
+    pub fn new() -> Self {
+        Self {
+            dep_graph: DiGraph::new(),
+            cell_to_node: HashMap::new(),
+            formulas: HashMap::new(),
+            overrides: RefCell::new(None),
+            strict_mode: false,
+            circular: HashSet::new(),
+            spills: HashMap::new(),
+            large_formula_cells: DEFAULT_LARGE_FORMULA_CELLS,
+            large_formulas: HashMap::new(),
+            volatile: HashSet::new(),
+            named_ranges: HashMap::new(),
+            simplify: false,
         }
-        
-        // Simple comma split (doesn't handle nested functions with commas)
-        let mut current = String::new();
-        let mut paren_depth = 0;
-        
-        for c in args_str.chars() {
-            match c {
-                '(' => {
-                    paren_depth += 1;
-                    current.push(c);
-                }
-                ')' => {
-                    paren_depth -= 1;
-                    current.push(c);
-                }
-                ',' if paren_depth == 0 => {
-                    let (node, node_deps) = self.parse_expression(&current)?;
-                    args.push(node);
-                    deps.extend(node_deps);
-                    current.clear();
-                }
-                _ => current.push(c),
+    }
+
+    /// Switch between Excel blank-cell coercion (default) and strict mode
+    ///
+    /// In strict mode, blank operands in arithmetic are type errors and
+    /// a blank in a comparison is unordered and equal only to another blank.
+    pub fn set_strict_mode(&mut self, strict: bool) {
+        self.strict_mode = strict;
+    }
+
+    pub fn strict_mode(&self) -> bool {
+        self.strict_mode
+    }
+
+    /// Fold constants and drop identity operations in formulas before evaluating them
+    ///
+    /// Off by default. Values don't change either way, only how much work
+    /// goes into them; formulas keep their text (see `simplify`). Applies
+    /// to formulas already registered too.
+    pub fn set_simplify(&mut self, simplify: bool) {
+        self.simplify = simplify;
+        let simplified: Vec<(CellRef, Option<FormulaNode>)> = self.formulas.iter()
+            .map(|(&cell, formula)| (cell, self.simplified(&formula.ast)))
+            .collect();
+        for (cell, tree) in simplified {
+            if let Some(formula) = self.formulas.get_mut(&cell) {
+                formula.simplified = tree;
             }
         }
-        
-        if !current.trim().is_empty() {
-            let (node, node_deps) = self.parse_expression(&current)?;
-            args.push(node);
-            deps.extend(node_deps);
+    }
+
+    pub fn simplifies(&self) -> bool {
+        self.simplify
+    }
+
+    /// The simplified form of `ast` to store, if simplifying and it changes anything
+    fn simplified(&self, ast: &FormulaNode) -> Option<FormulaNode> {
+        if !self.simplify {
+            return None;
+        }
+        let simplified = self.simplify(ast);
+        (simplified.to_formula_string() != ast.to_formula_string()).then_some(simplified)
+    }
+
+    /// Name the range between `start` and `end` in `grid`, so formulas can write `=Revenue*2`
+    ///
+    /// Names are matched ignoring case and saved with the grid. Formulas keep
+    /// the name as written and follow its definition: redefining a name they
+    /// use reloads and recalculates them. A name must start with a letter or
+    /// `_`, hold only letters, digits, `_` and `.`, and not read as a cell
+    /// reference or a boolean. Returns the cells whose values changed.
+    pub fn define_name(&mut self, grid: &mut Grid, name: &str, start: CellRef, end: CellRef) -> Result<Vec<CellRef>, FormulaError> {
+        let valid = name.starts_with(|c: char| c.is_alphabetic() || c == '_')
+            && name.chars().all(|c| c.is_alphanumeric() || matches!(c, '_' | '.'))
+            && parse_anchored(name).is_none()
+            && !name.eq_ignore_ascii_case("true")
+            && !name.eq_ignore_ascii_case("false");
+        if !valid {
+            return Err(FormulaError::InvalidName(name.to_string()));
+        }
+        let corners = (
+            CellRef::new(start.row.min(end.row), start.col.min(end.col)),
+            CellRef::new(start.row.max(end.row), start.col.max(end.col)),
+        );
+        let key = name.to_uppercase();
+        grid.names.insert(key.clone(), corners);
+        grid.touch();
+        self.reload_names(grid, &key)
+    }
+
+    /// Forget a name in `grid`, or return `None` if it wasn't defined
+    ///
+    /// Formulas still using the name can no longer be read, so they're kept
+    /// as foreign formulas at their last value (see `foreign`). Returns the
+    /// cells whose values changed.
+    pub fn remove_name(&mut self, grid: &mut Grid, name: &str) -> Result<Option<Vec<CellRef>>, FormulaError> {
+        let key = name.to_uppercase();
+        if grid.names.remove(&key).is_none() {
+            return Ok(None);
+        }
+        grid.touch();
+        self.reload_names(grid, &key).map(Some)
+    }
+
+    /// Take up `grid`'s names, reloading its formulas if any uses the name `key`
+    fn reload_names(&mut self, grid: &mut Grid, key: &str) -> Result<Vec<CellRef>, FormulaError> {
+        let used = self.formulas.values().any(|formula| formula.ast.uses_name(key));
+        self.named_ranges = grid.names.clone().into_iter().collect();
+        if !used {
+            return Ok(Vec::new());
         }
+        self.clear_spills(grid)?;
+        self.load_formulas(grid);
+        self.recalculate_all(grid)
+    }
+
+    /// The corners of a named range
+    pub fn named_range(&self, name: &str) -> Option<(CellRef, CellRef)> {
+        self.named_ranges.get(&name.to_uppercase()).copied()
+    }
+
+    /// Parse a formula string (starting with '=')
+    pub fn parse(&self, formula: &str) -> Result<Formula, FormulaError> {
+        let raw = formula.to_string();
+        let content = formula.strip_prefix('=').unwrap_or(formula).trim();
+        
+        let (ast, dependencies) = self.parse_expression(content)?;
+        
+        Ok(Formula {
+            raw,
+            volatile: is_volatile(&ast),
+            ast,
+            simplified: None,
+            dependencies,
+        })
+    }
+
+    fn parse_expression(&self, expr: &str) -> Result<(FormulaNode, Vec<CellRef>), FormulaError> {
+        let expr = expr.trim();
         
-        Ok((args, deps))
+        // Handle empty expression
+        if expr.is_empty() {
+            return Ok((FormulaNode::Number { value: 0.0 }, Vec::new()));
+        }
+
+        let mut parser = ExprParser { text: expr, pos: 0, deps: Vec::new(), names: &self.named_ranges };
+        let node = parser.comparison()?;
+        parser.skip_space();
+        if parser.pos < expr.len() {
+            return Err(parser.error());
+        }
+        Ok((node, parser.deps))
     }
 
     /// Evaluate a formula node against the grid
     pub fn evaluate(&self, node: &FormulaNode, grid: &Grid) -> Result<CellValue, FormulaError> {
-        match node {
+        let value = match node {
             FormulaNode::Number { value } => Ok(CellValue::Number(*value)),
             FormulaNode::Text { value } => Ok(CellValue::Text(value.clone())),
             FormulaNode::Boolean { value } => Ok(CellValue::Boolean(*value)),
             
-            FormulaNode::CellRef { cell } => {
-                Ok(grid.get_cell(*cell)
-                    .map(|c| c.value.clone())
-                    .unwrap_or(CellValue::Empty))
-            }
+            FormulaNode::CellRef { cell, .. } => self.cell_value(*cell, grid),
             
-            FormulaNode::Range { start, end } => {
+            FormulaNode::Range { start, end, .. } => {
                 // Ranges usually need to be handled in function context
                 // Return an error for now if used directly
                 Err(FormulaError::TypeError {
@@ -327,13 +659,104 @@ impl FormulaEngine {
                 self.evaluate_unary_op(*op, val)
             }
             
-            FormulaNode::Function { name, args } => {
+            FormulaNode::Function { name, args, .. } => {
                 self.evaluate_function(name, args, grid)
             }
+
+            FormulaNode::RefError => Err(FormulaError::InvalidRef("#REF!".to_string())),
+        };
+        value.and_then(finite)
+    }
+
+    /// Evaluate a formula as if some cells held different values, without touching the grid
+    ///
+    /// Formula cells that (transitively) depend on an overridden cell are
+    /// re-evaluated on the fly, so the result matches what a full recalc would
+    /// give. This is the backend for goal-seek and what-if tables.
+    pub fn evaluate_with_overrides(
+        &self,
+        formula: &Formula,
+        grid: &Grid,
+        overrides: HashMap<CellRef, CellValue>,
+    ) -> Result<CellValue, FormulaError> {
+        let overridden: Vec<CellRef> = overrides.keys().copied().collect();
+        let dirty = self.transitive_dependents(&overridden);
+        
+        let previous = self.overrides.replace(Some(Overrides {
+            values: overrides,
+            dirty,
+            computed: HashMap::new(),
+            in_progress: HashSet::new(),
+        }));
+        let result = self.evaluate(formula.evaluated(), grid);
+        self.overrides.replace(previous);
+        result
+    }
+
+    /// Current value of a cell, honoring any active overrides
+    pub(crate) fn cell_value(&self, cell: CellRef, grid: &Grid) -> Result<CellValue, FormulaError> {
+        let needs_eval = {
+            let overrides = self.overrides.borrow();
+            match overrides.as_ref() {
+                Some(o) => {
+                    if let Some(value) = o.values.get(&cell).or_else(|| o.computed.get(&cell)) {
+                        return Ok(value.clone());
+                    }
+                    if o.in_progress.contains(&cell) {
+                        return Err(FormulaError::CircularReference(self.cycle_through(cell)));
+                    }
+                    o.dirty.contains(&cell)
+                }
+                None => false,
+            }
+        };
+        
+        if needs_eval {
+            if let Some(formula) = self.formulas.get(&cell) {
+                if let Some(o) = self.overrides.borrow_mut().as_mut() {
+                    o.in_progress.insert(cell);
+                }
+                let value = self.evaluate(formula.evaluated(), grid);
+                if let Some(o) = self.overrides.borrow_mut().as_mut() {
+                    o.in_progress.remove(&cell);
+                    if let Ok(v) = &value {
+                        o.computed.insert(cell, v.clone());
+                    }
+                }
+                return value;
+            }
         }
+        
+        Ok(grid.get_cell(cell)
+            .map(|c| c.value.clone())
+            .unwrap_or(CellValue::Empty))
+    }
+
+    /// Apply a binary operator
+    ///
+    /// Comparisons follow Excel's ordering in both modes: numbers sort before
+    /// text, text before booleans (so TRUE is greater than any number), and
+    /// values of different kinds are never equal, so the text "TRUE" isn't
+    /// the boolean TRUE. Arithmetic reads booleans as 1 and 0. An error
+    /// operand isn't ordered at all: it's the result, the left one first,
+    /// so `=A1>5` is `#DIV/0!` when A1 is.
+    pub(crate) fn evaluate_binary_op(&self, op: BinaryOp, left: CellValue, right: CellValue) -> Result<CellValue, FormulaError> {
+        for operand in [&left, &right] {
+            if let CellValue::Error(_) = operand {
+                return Ok(operand.clone());
+            }
+        }
+        let (left, right) = if self.strict_mode { (left, right) } else { coerce_empty_operands(op, left, right) };
+        if !matches!(left, CellValue::Empty) && !matches!(right, CellValue::Empty) {
+            if let Some(ordering) = comparison_result(op, &left, &right) {
+                return Ok(CellValue::Boolean(ordering));
+            }
+        }
+        self.evaluate_binary_op_strict(op, left, right).and_then(finite)
     }
 
-    fn evaluate_binary_op(&self, op: BinaryOp, left: CellValue, right: CellValue) -> Result<CellValue, FormulaError> {
+    /// Binary operators without blank coercion: blanks are non-numeric
+    fn evaluate_binary_op_strict(&self, op: BinaryOp, left: CellValue, right: CellValue) -> Result<CellValue, FormulaError> {
         let left_num = left.to_number();
         let right_num = right.to_number();
         
@@ -367,7 +790,7 @@ impl FormulaEngine {
             }
             BinaryOp::Div => {
                 match (left_num, right_num) {
-                    (Some(_), Some(r)) if r == 0.0 => Err(FormulaError::DivisionByZero),
+                    (Some(_), Some(0.0)) => Err(FormulaError::DivisionByZero),
                     (Some(l), Some(r)) => Ok(CellValue::Number(l / r)),
                     _ => Err(FormulaError::TypeError {
                         expected: "number".to_string(),
@@ -416,7 +839,11 @@ impl FormulaEngine {
         }
     }
 
-    fn evaluate_unary_op(&self, op: UnaryOp, val: CellValue) -> Result<CellValue, FormulaError> {
+    pub(crate) fn evaluate_unary_op(&self, op: UnaryOp, val: CellValue) -> Result<CellValue, FormulaError> {
+        let val = match val {
+            CellValue::Empty if !self.strict_mode => CellValue::Number(0.0),
+            other => other,
+        };
         match op {
             UnaryOp::Neg => {
                 val.to_number()
@@ -443,33 +870,75 @@ impl FormulaEngine {
             "AVERAGE" | "AVG" => self.fn_average(args, grid),
             "MIN" => self.fn_min(args, grid),
             "MAX" => self.fn_max(args, grid),
+            "MEDIAN" => self.fn_median(args, grid),
+            "MODE" => self.fn_mode(args, grid),
             "COUNT" => self.fn_count(args, grid),
             "IF" => self.fn_if(args, grid),
+            "IFERROR" | "IFNA" => self.fn_iferror(name, args, grid),
+            "IFS" => self.fn_ifs(args, grid),
+            "SWITCH" => self.fn_switch(args, grid),
             "ABS" => self.fn_abs(args, grid),
-            "ROUND" => self.fn_round(args, grid),
+            "INT" => self.fn_int(args, grid),
+            "MOD" => self.fn_mod(args, grid),
+            "ROUND" | "ROUNDUP" | "ROUNDDOWN" => self.fn_round(name, args, grid),
+            "CEILING" | "FLOOR" => self.fn_ceiling_floor(name, args, grid),
             "SQRT" => self.fn_sqrt(args, grid),
             "POWER" | "POW" => self.fn_power(args, grid),
+            "SUMIF" => self.fn_sumif(args, grid),
+            "COUNTIF" => self.fn_countif(args, grid),
+            "SUMIFS" => self.fn_sumifs(args, grid),
+            "COUNTIFS" => self.fn_countifs(args, grid),
+            "AVERAGEIFS" => self.fn_averageifs(args, grid),
+            "VLOOKUP" => self.fn_vlookup(args, grid),
+            "HLOOKUP" => self.fn_hlookup(args, grid),
+            "INDEX" => self.fn_index(args, grid),
+            "MATCH" => self.fn_match(args, grid),
+            "XLOOKUP" => self.fn_xlookup(args, grid),
+            "LEFT" | "RIGHT" => self.fn_left_right(name, args, grid),
+            "MID" => self.fn_mid(args, grid),
+            "LEN" => self.fn_len(args, grid),
+            "UPPER" | "LOWER" | "PROPER" => self.fn_change_case(name, args, grid),
+            "TRIM" => self.fn_trim(args, grid),
+            "SUBSTITUTE" => self.fn_substitute(args, grid),
+            "REPLACE" => self.fn_replace(args, grid),
+            "FIND" | "SEARCH" => self.fn_find(name, args, grid),
+            "TEXT" => self.fn_text(args, grid),
+            "VALUE" | "N" | "T" => self.fn_coerce(name, args, grid),
+            "TODAY" | "NOW" => self.fn_now(name, args, grid),
+            "DATE" => self.fn_date(args, grid),
+            "YEAR" | "MONTH" | "DAY" | "WEEKDAY" => self.fn_date_part(name, args, grid),
+            "EDATE" | "EOMONTH" => self.fn_edate(name, args, grid),
+            "DATEDIF" => self.fn_datedif(args, grid),
+            "WORKDAY" | "NETWORKDAYS" => self.fn_workday(name, args, grid),
+            "PMT" | "FV" | "PV" | "NPER" | "RATE" => self.fn_annuity(name, args, grid),
+            "CONCATENATE" => self.fn_concatenate(args, grid),
+            "TEXTJOIN" => self.fn_textjoin(args, grid),
+            // In a single-value context an array result is its top-left element
+            "TEXTSPLIT" => Ok(self.fn_textsplit(args, grid)?
+                .into_iter()
+                .next()
+                .and_then(|row| row.into_iter().next())
+                .unwrap_or_default()),
             _ => Err(FormulaError::UnknownFunction(name.to_string())),
         }
     }
 
-    fn collect_numbers(&self, args: &[FormulaNode], grid: &Grid) -> Vec<f64> {
+    /// Numbers among the arguments, skipping anything that isn't one
+    ///
+    /// A `#REF!` argument, a range whose cells were deleted, is an error
+    /// rather than something to skip.
+    fn collect_numbers(&self, args: &[FormulaNode], grid: &Grid) -> Result<Vec<f64>, FormulaError> {
         let mut numbers = Vec::new();
         for arg in args {
             match arg {
-                FormulaNode::Range { start, end } => {
-                    for row in start.row..=end.row {
-                        for col in start.col..=end.col {
-                            if let Some(cell) = grid.get_cell(CellRef::new(row, col)) {
-                                if let Some(n) = cell.value.to_number() {
-                                    numbers.push(n);
-                                }
-                            }
-                        }
-                    }
+                FormulaNode::Range { start, end, .. } => {
+                    numbers.extend(self.collect_range_cells(*start, *end, grid).iter().flatten().filter_map(CellValue::to_number));
                 }
+                FormulaNode::RefError => return Err(FormulaError::InvalidRef("#REF!".to_string())),
                 _ => {
-                    if let Ok(val) = self.evaluate(arg, grid) {
+                    if let Some(Ok(array)) = self.evaluate_array(arg, grid) {
+                        numbers.extend(array.iter().flatten().filter_map(CellValue::to_number));
+                    } else if let Ok(val) = self.evaluate(arg, grid) {
                         if let Some(n) = val.to_number() {
                             numbers.push(n);
                         }
@@ -477,104 +946,627 @@ impl FormulaEngine {
                 }
             }
         }
-        numbers
+        Ok(numbers)
     }
 
     fn fn_sum(&self, args: &[FormulaNode], grid: &Grid) -> Result<CellValue, FormulaError> {
-        let numbers = self.collect_numbers(args, grid);
+        let numbers = self.collect_numbers(args, grid)?;
         Ok(CellValue::Number(numbers.iter().sum()))
     }
 
-    fn fn_average(&self, args: &[FormulaNode], grid: &Grid) -> Result<CellValue, FormulaError> {
-        let numbers = self.collect_numbers(args, grid);
+    /// SUMIF(range, criteria, [sum_range])
+    ///
+    /// `sum_range` must be the same shape as `range`; cells pair up by offset.
+    fn fn_sumif(&self, args: &[FormulaNode], grid: &Grid) -> Result<CellValue, FormulaError> {
+        if !(2..=3).contains(&args.len()) {
+            return Err(FormulaError::ArgumentCount {
+                func: "SUMIF".to_string(),
+                expected: "2 or 3".to_string(),
+                got: args.len(),
+            });
+        }
+        let sum_range = args.get(2).unwrap_or(&args[0]);
+        let numbers = self.numbers_where("SUMIF", sum_range, &args[..2], grid)?;
+        Ok(CellValue::Number(numbers.iter().sum()))
+    }
+
+    /// SUMIFS(sum_range, range1, criteria1, [range2, criteria2], ...)
+    fn fn_sumifs(&self, args: &[FormulaNode], grid: &Grid) -> Result<CellValue, FormulaError> {
+        check_criteria_pairs("SUMIFS", args, 1)?;
+        let numbers = self.numbers_where("SUMIFS", &args[0], &args[1..], grid)?;
+        Ok(CellValue::Number(numbers.iter().sum()))
+    }
+
+    /// AVERAGEIFS(average_range, range1, criteria1, [range2, criteria2], ...)
+    fn fn_averageifs(&self, args: &[FormulaNode], grid: &Grid) -> Result<CellValue, FormulaError> {
+        check_criteria_pairs("AVERAGEIFS", args, 1)?;
+        let numbers = self.numbers_where("AVERAGEIFS", &args[0], &args[1..], grid)?;
         if numbers.is_empty() {
             return Ok(CellValue::Error("DIV/0".to_string()));
         }
         Ok(CellValue::Number(numbers.iter().sum::<f64>() / numbers.len() as f64))
     }
 
-    fn fn_min(&self, args: &[FormulaNode], grid: &Grid) -> Result<CellValue, FormulaError> {
-        let numbers = self.collect_numbers(args, grid);
-        numbers.iter().copied().reduce(f64::min)
-            .map(CellValue::Number)
-            .ok_or_else(|| FormulaError::ArgumentCount {
-                func: "MIN".to_string(),
-                expected: "at least 1".to_string(),
-                got: 0,
-            })
+    /// COUNTIFS(range1, criteria1, [range2, criteria2], ...)
+    fn fn_countifs(&self, args: &[FormulaNode], grid: &Grid) -> Result<CellValue, FormulaError> {
+        check_criteria_pairs("COUNTIFS", args, 0)?;
+        let matches = self.criteria_matches("COUNTIFS", range_bounds(&args[0])?, args, grid)?;
+        Ok(CellValue::Number(matches.len() as f64))
     }
 
-    fn fn_max(&self, args: &[FormulaNode], grid: &Grid) -> Result<CellValue, FormulaError> {
-        let numbers = self.collect_numbers(args, grid);
-        numbers.iter().copied().reduce(f64::max)
-            .map(CellValue::Number)
-            .ok_or_else(|| FormulaError::ArgumentCount {
-                func: "MAX".to_string(),
-                expected: "at least 1".to_string(),
-                got: 0,
+    /// Numbers in `target` at the offsets where every range/criteria pair matches
+    fn numbers_where(&self, func: &str, target: &FormulaNode, pairs: &[FormulaNode], grid: &Grid) -> Result<Vec<f64>, FormulaError> {
+        let (start, end) = range_bounds(target)?;
+        let mut numbers = Vec::new();
+        for (row, col) in self.criteria_matches(func, (start, end), pairs, grid)? {
+            if let CellValue::Number(n) = self.cell_value(CellRef::new(start.row + row, start.col + col), grid)? {
+                numbers.push(n);
+            }
+        }
+        Ok(numbers)
+    }
+
+    /// Offsets into a block shaped like `shape` at which every range/criteria pair matches
+    ///
+    /// Each criteria range must have the same dimensions as `shape`; cells
+    /// pair up by offset.
+    fn criteria_matches(&self, func: &str, shape: (CellRef, CellRef), pairs: &[FormulaNode], grid: &Grid) -> Result<Vec<(u32, u32)>, FormulaError> {
+        let (start, end) = shape;
+        let (height, width) = (end.row - start.row, end.col - start.col);
+        let mut criteria = Vec::new();
+        for pair in pairs.chunks(2) {
+            let (range_start, range_end) = range_bounds(&pair[0])?;
+            if (range_end.row - range_start.row, range_end.col - range_start.col) != (height, width) {
+                return Err(FormulaError::ShapeMismatch(format!(
+                    "{} range {}:{} doesn't match {}:{}", func, range_start, range_end, start, end
+                )));
+            }
+            criteria.push((range_start, Criterion::parse(&self.evaluate(&pair[1], grid)?)));
+        }
+
+        let mut matches = Vec::new();
+        for row in 0..=height {
+            for col in 0..=width {
+                let mut all = true;
+                for (range_start, criterion) in &criteria {
+                    if !criterion.matches(&self.cell_value(CellRef::new(range_start.row + row, range_start.col + col), grid)?) {
+                        all = false;
+                        break;
+                    }
+                }
+                if all {
+                    matches.push((row, col));
+                }
+            }
+        }
+        Ok(matches)
+    }
+
+    /// Values of the block between `start` and `end`, row by row
+    ///
+    /// Cells that fail to evaluate hold their error value.
+    pub(crate) fn collect_range_cells(&self, start: CellRef, end: CellRef, grid: &Grid) -> Vec<Vec<CellValue>> {
+        (start.row..=end.row)
+            .map(|row| {
+                (start.col..=end.col)
+                    .map(|col| self.cell_value(CellRef::new(row, col), grid)
+                        .unwrap_or_else(|e| CellValue::Error(e.error_code().to_string())))
+                    .collect()
             })
+            .collect()
     }
 
-    fn fn_count(&self, args: &[FormulaNode], grid: &Grid) -> Result<CellValue, FormulaError> {
-        let numbers = self.collect_numbers(args, grid);
-        Ok(CellValue::Number(numbers.len() as f64))
+    /// VLOOKUP(lookup_value, table, col_index, [range_lookup])
+    ///
+    /// With `range_lookup` TRUE (the default, as in Excel) the first column is
+    /// assumed sorted ascending and the last row whose key is at most
+    /// `lookup_value` matches. With FALSE only an equal key matches (text
+    /// compares case-insensitively). No match gives `#N/A`.
+    fn fn_vlookup(&self, args: &[FormulaNode], grid: &Grid) -> Result<CellValue, FormulaError> {
+        let (lookup, start, end, col_index, approximate) = self.lookup_args("VLOOKUP", args, grid)?;
+        if col_index > (end.col - start.col + 1) as usize {
+            return Err(FormulaError::InvalidRef(format!("column {} is outside {}:{}", col_index, start, end)));
+        }
+        let table = self.collect_range_cells(start, end, grid);
+        Ok(match lookup_position(table.iter().map(|row| &row[0]), &lookup, approximate) {
+            Some(row) => table[row][col_index - 1].clone(),
+            None => CellValue::Error("N/A".to_string()),
+        })
     }
 
-    fn fn_if(&self, args: &[FormulaNode], grid: &Grid) -> Result<CellValue, FormulaError> {
-        if args.len() < 2 || args.len() > 3 {
+    /// HLOOKUP(lookup_value, table, row_index, [range_lookup])
+    ///
+    /// VLOOKUP for tables laid out in rows: searches the first row and returns
+    /// the value `row_index` rows down. A `row_index` past the bottom of the
+    /// table gives `#REF`.
+    fn fn_hlookup(&self, args: &[FormulaNode], grid: &Grid) -> Result<CellValue, FormulaError> {
+        let (lookup, start, end, row_index, approximate) = self.lookup_args("HLOOKUP", args, grid)?;
+        if row_index > (end.row - start.row + 1) as usize {
+            return Ok(CellValue::Error("REF".to_string()));
+        }
+        let table = self.collect_range_cells(start, end, grid);
+        Ok(match lookup_position(table[0].iter(), &lookup, approximate) {
+            Some(col) => table[row_index - 1][col].clone(),
+            None => CellValue::Error("N/A".to_string()),
+        })
+    }
+
+    /// INDEX(range, row_num, [col_num])
+    ///
+    /// The cell at a 1-based offset inside `range`. With only one index on a
+    /// single-row range, the index counts columns. Offsets past the edge of
+    /// the range give `#REF`.
+    fn fn_index(&self, args: &[FormulaNode], grid: &Grid) -> Result<CellValue, FormulaError> {
+        if !(2..=3).contains(&args.len()) {
             return Err(FormulaError::ArgumentCount {
-                func: "IF".to_string(),
+                func: "INDEX".to_string(),
                 expected: "2 or 3".to_string(),
                 got: args.len(),
             });
         }
-        
-        let condition = self.evaluate(&args[0], grid)?;
-        if condition.is_truthy() {
-            self.evaluate(&args[1], grid)
-        } else if args.len() > 2 {
-            self.evaluate(&args[2], grid)
-        } else {
-            Ok(CellValue::Boolean(false))
+        let (start, end) = range_bounds(&args[0])?;
+        let mut indexes = Vec::new();
+        for arg in &args[1..] {
+            let index = self.evaluate(arg, grid)?
+                .to_number()
+                .ok_or_else(|| FormulaError::TypeError {
+                    expected: "number".to_string(),
+                    got: "non-numeric".to_string(),
+                })?
+                .trunc();
+            if index < 1.0 {
+                return Err(FormulaError::TypeError {
+                    expected: "index of at least 1".to_string(),
+                    got: index.to_string(),
+                });
+            }
+            indexes.push(index.min(u32::MAX as f64) as u32);
+        }
+        let (row, col) = match indexes[..] {
+            [index] if start.row == end.row => (1, index),
+            [index] => (index, 1),
+            [row, col, ..] => (row, col),
+            [] => unreachable!(),
+        };
+        if row > end.row - start.row + 1 || col > end.col - start.col + 1 {
+            return Ok(CellValue::Error("REF".to_string()));
         }
+        self.cell_value(CellRef::new(start.row + row - 1, start.col + col - 1), grid)
     }
 
-    fn fn_abs(&self, args: &[FormulaNode], grid: &Grid) -> Result<CellValue, FormulaError> {
-        if args.len() != 1 {
+    /// MATCH(lookup_value, range, [match_type])
+    ///
+    /// The 1-based position of `lookup_value` in a single-row or single-column
+    /// range. `match_type` 1 (the default) takes the largest value at most
+    /// `lookup_value` in ascending data, -1 the smallest value at least
+    /// `lookup_value` in descending data, and 0 an exact match. No match gives `#N/A`.
+    fn fn_match(&self, args: &[FormulaNode], grid: &Grid) -> Result<CellValue, FormulaError> {
+        if !(2..=3).contains(&args.len()) {
             return Err(FormulaError::ArgumentCount {
-                func: "ABS".to_string(),
-                expected: "1".to_string(),
+                func: "MATCH".to_string(),
+                expected: "2 or 3".to_string(),
                 got: args.len(),
             });
         }
-        let val = self.evaluate(&args[0], grid)?;
-        val.to_number()
-            .map(|n| CellValue::Number(n.abs()))
-            .ok_or_else(|| FormulaError::TypeError {
-                expected: "number".to_string(),
-                got: "non-numeric".to_string(),
-            })
+        let lookup = self.evaluate(&args[0], grid)?;
+        let (start, end) = range_bounds(&args[1])?;
+        let match_type = match args.get(2) {
+            Some(node) => self.evaluate(node, grid)?.to_number().unwrap_or(1.0),
+            None => 1.0,
+        };
+        if start.row != end.row && start.col != end.col {
+            return Ok(CellValue::Error("N/A".to_string()));
+        }
+
+        let values: Vec<CellValue> = self.collect_range_cells(start, end, grid).into_iter().flatten().collect();
+        let position = if match_type == 0.0 {
+            lookup_position(values.iter(), &lookup, false)
+        } else if match_type > 0.0 {
+            lookup_position(values.iter(), &lookup, true)
+        } else {
+            let same_kind = |value: &CellValue| std::mem::discriminant(value) == std::mem::discriminant(&lookup);
+            values.iter()
+                .enumerate()
+                .filter(|(_, value)| same_kind(value))
+                .take_while(|(_, value)| comparison_result(BinaryOp::Lt, value, &lookup) != Some(true))
+                .last()
+                .map(|(index, _)| index)
+        };
+        Ok(match position {
+            Some(index) => CellValue::Number((index + 1) as f64),
+            None => CellValue::Error("N/A".to_string()),
+        })
     }
 
-    fn fn_round(&self, args: &[FormulaNode], grid: &Grid) -> Result<CellValue, FormulaError> {
-        if args.is_empty() || args.len() > 2 {
+    /// XLOOKUP(lookup_value, lookup_range, return_range, [if_not_found], [match_mode], [search_mode])
+    ///
+    /// `match_mode` is 0 for an exact match (the default), -1 or 1 to fall
+    /// back to the next smaller or larger value, or 2 for `*`/`?` wildcards.
+    /// `search_mode` 1 searches first to last (the default) and -1 last to
+    /// first; the binary-search modes 2 and -2 search the same way. Both
+    /// ranges must be single rows or columns of the same length, and
+    /// `if_not_found` is only evaluated when nothing matches.
+    fn fn_xlookup(&self, args: &[FormulaNode], grid: &Grid) -> Result<CellValue, FormulaError> {
+        if !(3..=6).contains(&args.len()) {
             return Err(FormulaError::ArgumentCount {
-                func: "ROUND".to_string(),
-                expected: "1 or 2".to_string(),
+                func: "XLOOKUP".to_string(),
+                expected: "3 to 6".to_string(),
                 got: args.len(),
             });
         }
-        let val = self.evaluate(&args[0], grid)?;
-        let decimals = if args.len() > 1 {
-            self.evaluate(&args[1], grid)?.to_number().unwrap_or(0.0) as i32
-        } else {
+        let lookup = self.evaluate(&args[0], grid)?;
+        let (start, end) = range_bounds(&args[1])?;
+        let (return_start, return_end) = range_bounds(&args[2])?;
+        let is_line = |start: CellRef, end: CellRef| start.row == end.row || start.col == end.col;
+        let keys: Vec<CellValue> = self.collect_range_cells(start, end, grid).into_iter().flatten().collect();
+        let results: Vec<CellValue> = self.collect_range_cells(return_start, return_end, grid).into_iter().flatten().collect();
+        if !is_line(start, end) || !is_line(return_start, return_end) || keys.len() != results.len() {
+            return Err(FormulaError::ShapeMismatch(format!(
+                "XLOOKUP ranges {}:{} and {}:{} must be single rows or columns of the same length",
+                start, end, return_start, return_end
+            )));
+        }
+
+        let mode = |index: usize, default: i64| -> Result<i64, FormulaError> {
+            match args.get(index) {
+                Some(node) => self.evaluate(node, grid)?
+                    .to_number()
+                    .map(|n| n.trunc() as i64)
+                    .ok_or_else(|| FormulaError::TypeError {
+                        expected: "number".to_string(),
+                        got: "non-numeric".to_string(),
+                    }),
+                None => Ok(default),
+            }
+        };
+        let match_mode = mode(4, 0)?;
+        let search_mode = mode(5, 1)?;
+        let reverse = match (match_mode, search_mode) {
+            (-1..=2, 1 | 2) => false,
+            (-1..=2, -1 | -2) => true,
+            _ => {
+                return Err(FormulaError::TypeError {
+                    expected: "match_mode -1 to 2 and search_mode 1, -1, 2 or -2".to_string(),
+                    got: format!("{} and {}", match_mode, search_mode),
+                })
+            }
+        };
+
+        let position = if reverse {
+            xlookup_position(&keys, (0..keys.len()).rev(), &lookup, match_mode)
+        } else {
+            xlookup_position(&keys, 0..keys.len(), &lookup, match_mode)
+        };
+        match (position, args.get(3)) {
+            (Some(index), _) => Ok(results[index].clone()),
+            (None, Some(if_not_found)) => self.evaluate(if_not_found, grid),
+            (None, None) => Ok(CellValue::Error("N/A".to_string())),
+        }
+    }
+
+    /// Evaluate the arguments shared by VLOOKUP and HLOOKUP
+    ///
+    /// Returns the lookup value, the table bounds, the 1-based index into the
+    /// table and whether matching is approximate.
+    fn lookup_args(&self, func: &str, args: &[FormulaNode], grid: &Grid) -> Result<(CellValue, CellRef, CellRef, usize, bool), FormulaError> {
+        if !(3..=4).contains(&args.len()) {
+            return Err(FormulaError::ArgumentCount {
+                func: func.to_string(),
+                expected: "3 or 4".to_string(),
+                got: args.len(),
+            });
+        }
+        let lookup = self.evaluate(&args[0], grid)?;
+        let (start, end) = range_bounds(&args[1])?;
+        let index = self.evaluate(&args[2], grid)?
+            .to_number()
+            .ok_or_else(|| FormulaError::TypeError {
+                expected: "number".to_string(),
+                got: "non-numeric".to_string(),
+            })?
+            .trunc();
+        if index < 1.0 {
+            return Err(FormulaError::TypeError {
+                expected: "index of at least 1".to_string(),
+                got: index.to_string(),
+            });
+        }
+        let approximate = match args.get(3) {
+            Some(node) => match self.evaluate(node, grid)? {
+                CellValue::Boolean(b) => b,
+                other => other.to_number().is_some_and(|n| n != 0.0),
+            },
+            None => true,
+        };
+        Ok((lookup, start, end, index.min(u32::MAX as f64) as usize, approximate))
+    }
+
+    /// COUNTIF(range, criteria)
+    fn fn_countif(&self, args: &[FormulaNode], grid: &Grid) -> Result<CellValue, FormulaError> {
+        if args.len() != 2 {
+            return Err(FormulaError::ArgumentCount {
+                func: "COUNTIF".to_string(),
+                expected: "2".to_string(),
+                got: args.len(),
+            });
+        }
+        let matches = self.criteria_matches("COUNTIF", range_bounds(&args[0])?, args, grid)?;
+        Ok(CellValue::Number(matches.len() as f64))
+    }
+
+    fn fn_average(&self, args: &[FormulaNode], grid: &Grid) -> Result<CellValue, FormulaError> {
+        let numbers = self.collect_numbers(args, grid)?;
+        if numbers.is_empty() {
+            return Ok(CellValue::Error("DIV/0".to_string()));
+        }
+        Ok(CellValue::Number(numbers.iter().sum::<f64>() / numbers.len() as f64))
+    }
+
+    fn fn_min(&self, args: &[FormulaNode], grid: &Grid) -> Result<CellValue, FormulaError> {
+        let numbers = self.collect_numbers(args, grid)?;
+        numbers.iter().copied().reduce(f64::min)
+            .map(CellValue::Number)
+            .ok_or_else(|| FormulaError::ArgumentCount {
+                func: "MIN".to_string(),
+                expected: "at least 1".to_string(),
+                got: 0,
+            })
+    }
+
+    fn fn_max(&self, args: &[FormulaNode], grid: &Grid) -> Result<CellValue, FormulaError> {
+        let numbers = self.collect_numbers(args, grid)?;
+        numbers.iter().copied().reduce(f64::max)
+            .map(CellValue::Number)
+            .ok_or_else(|| FormulaError::ArgumentCount {
+                func: "MAX".to_string(),
+                expected: "at least 1".to_string(),
+                got: 0,
+            })
+    }
+
+    /// MEDIAN(number, ...): the middle number, or the mean of the middle two for an even count
+    ///
+    /// Ranges contribute their numbers, as in MIN and MAX; with no numbers at all it's #NUM.
+    fn fn_median(&self, args: &[FormulaNode], grid: &Grid) -> Result<CellValue, FormulaError> {
+        let mut numbers = self.collect_numbers(args, grid)?;
+        if numbers.is_empty() {
+            return Ok(CellValue::Error("NUM".to_string()));
+        }
+        numbers.sort_by(f64::total_cmp);
+        let middle = numbers.len() / 2;
+        Ok(CellValue::Number(if numbers.len().is_multiple_of(2) {
+            (numbers[middle - 1] + numbers[middle]) / 2.0
+        } else {
+            numbers[middle]
+        }))
+    }
+
+    /// MODE(number, ...): the most frequent number, or #N/A if none repeats
+    ///
+    /// When several numbers are equally frequent the smallest wins. With no
+    /// numbers at all it's #NUM.
+    fn fn_mode(&self, args: &[FormulaNode], grid: &Grid) -> Result<CellValue, FormulaError> {
+        let mut numbers = self.collect_numbers(args, grid)?;
+        if numbers.is_empty() {
+            return Ok(CellValue::Error("NUM".to_string()));
+        }
+        numbers.sort_by(f64::total_cmp);
+        // Runs of equal numbers in ascending order, so a later run must be strictly longer to win
+        let (mut mode, mut best) = (numbers[0], 0);
+        for run in numbers.chunk_by(|a, b| a == b) {
+            if run.len() > best {
+                (mode, best) = (run[0], run.len());
+            }
+        }
+        Ok(if best > 1 { CellValue::Number(mode) } else { CellValue::Error("N/A".to_string()) })
+    }
+
+    fn fn_count(&self, args: &[FormulaNode], grid: &Grid) -> Result<CellValue, FormulaError> {
+        let numbers = self.collect_numbers(args, grid)?;
+        Ok(CellValue::Number(numbers.len() as f64))
+    }
+
+    fn fn_if(&self, args: &[FormulaNode], grid: &Grid) -> Result<CellValue, FormulaError> {
+        if args.len() < 2 || args.len() > 3 {
+            return Err(FormulaError::ArgumentCount {
+                func: "IF".to_string(),
+                expected: "2 or 3".to_string(),
+                got: args.len(),
+            });
+        }
+        
+        let condition = match self.evaluate(&args[0], grid)? {
+            error @ CellValue::Error(_) => return Ok(error),
+            value => value.to_bool().ok_or_else(|| FormulaError::TypeError {
+                expected: "logical value".to_string(),
+                got: format!("\"{}\"", value.display()),
+            })?,
+        };
+        if condition {
+            self.evaluate(&args[1], grid)
+        } else if args.len() > 2 {
+            self.evaluate(&args[2], grid)
+        } else {
+            Ok(CellValue::Boolean(false))
+        }
+    }
+
+    /// IFS(condition1, value1, [condition2, value2], ...)
+    ///
+    /// Returns the value paired with the first true condition, or #N/A when
+    /// none is. Only that value is evaluated.
+    fn fn_ifs(&self, args: &[FormulaNode], grid: &Grid) -> Result<CellValue, FormulaError> {
+        if args.is_empty() || !args.len().is_multiple_of(2) {
+            return Err(FormulaError::ArgumentCount {
+                func: "IFS".to_string(),
+                expected: "condition/value pairs".to_string(),
+                got: args.len(),
+            });
+        }
+        for pair in args.chunks(2) {
+            let condition = match self.evaluate(&pair[0], grid)? {
+                error @ CellValue::Error(_) => return Ok(error),
+                value => value.to_bool().ok_or_else(|| FormulaError::TypeError {
+                    expected: "logical value".to_string(),
+                    got: format!("\"{}\"", value.display()),
+                })?,
+            };
+            if condition {
+                return self.evaluate(&pair[1], grid);
+            }
+        }
+        Ok(CellValue::Error("N/A".to_string()))
+    }
+
+    /// SWITCH(expression, case1, result1, [case2, result2], ..., [default])
+    ///
+    /// Cases are compared with `=`. Returns the result of the first matching
+    /// case, else the default, else #N/A. Only the chosen result is evaluated.
+    fn fn_switch(&self, args: &[FormulaNode], grid: &Grid) -> Result<CellValue, FormulaError> {
+        if args.len() < 3 {
+            return Err(FormulaError::ArgumentCount {
+                func: "SWITCH".to_string(),
+                expected: "at least 3".to_string(),
+                got: args.len(),
+            });
+        }
+        let value = match self.evaluate(&args[0], grid)? {
+            error @ CellValue::Error(_) => return Ok(error),
+            value => value,
+        };
+        let mut cases = args[1..].chunks_exact(2);
+        for pair in cases.by_ref() {
+            let case = self.evaluate(&pair[0], grid)?;
+            if self.evaluate_binary_op(BinaryOp::Eq, value.clone(), case)? == CellValue::Boolean(true) {
+                return self.evaluate(&pair[1], grid);
+            }
+        }
+        match cases.remainder() {
+            [default] => self.evaluate(default, grid),
+            _ => Ok(CellValue::Error("N/A".to_string())),
+        }
+    }
+
+    /// IFERROR(value, fallback) and IFNA(value, fallback)
+    ///
+    /// The fallback is only evaluated when `value` is an error: any error for
+    /// IFERROR, only #N/A for IFNA. Errors from the fallback itself propagate.
+    fn fn_iferror(&self, name: &str, args: &[FormulaNode], grid: &Grid) -> Result<CellValue, FormulaError> {
+        if args.len() != 2 {
+            return Err(FormulaError::ArgumentCount {
+                func: name.to_string(),
+                expected: "2".to_string(),
+                got: args.len(),
+            });
+        }
+
+        let code = match self.evaluate(&args[0], grid) {
+            Ok(CellValue::Error(code)) => code,
+            Err(e) => e.error_code().to_string(),
+            value => return value,
+        };
+        if name == "IFNA" && code != "N/A" {
+            return Ok(CellValue::Error(code));
+        }
+        self.evaluate(&args[1], grid)
+    }
+
+    /// INT(number): round down to an integer, so INT(-2.5) is -3
+    fn fn_int(&self, args: &[FormulaNode], grid: &Grid) -> Result<CellValue, FormulaError> {
+        if args.len() != 1 {
+            return Err(FormulaError::ArgumentCount {
+                func: "INT".to_string(),
+                expected: "1".to_string(),
+                got: args.len(),
+            });
+        }
+        let val = self.evaluate(&args[0], grid)?;
+        val.to_number()
+            .map(|n| CellValue::Number(n.floor()))
+            .ok_or_else(|| FormulaError::TypeError {
+                expected: "number".to_string(),
+                got: "non-numeric".to_string(),
+            })
+    }
+
+    /// MOD(number, divisor): `number - divisor*INT(number/divisor)`
+    ///
+    /// The result takes the sign of the divisor, as in Excel.
+    fn fn_mod(&self, args: &[FormulaNode], grid: &Grid) -> Result<CellValue, FormulaError> {
+        if args.len() != 2 {
+            return Err(FormulaError::ArgumentCount {
+                func: "MOD".to_string(),
+                expected: "2".to_string(),
+                got: args.len(),
+            });
+        }
+        let mut numbers = [0.0; 2];
+        for (slot, arg) in numbers.iter_mut().zip(args) {
+            *slot = self.evaluate(arg, grid)?.to_number().ok_or_else(|| FormulaError::TypeError {
+                expected: "number".to_string(),
+                got: "non-numeric".to_string(),
+            })?;
+        }
+        let [n, divisor] = numbers;
+        if divisor == 0.0 {
+            return Err(FormulaError::DivisionByZero);
+        }
+        // Snap the quotient so MOD(0.3, 0.1) is 0 rather than 0.1
+        let quotient = significant(n / divisor).floor();
+        let remainder = n - divisor * quotient;
+        if remainder.abs() <= 1e-12 * divisor.abs() {
+            return Ok(CellValue::Number(0.0));
+        }
+        Ok(CellValue::Number(significant(remainder)))
+    }
+
+    fn fn_abs(&self, args: &[FormulaNode], grid: &Grid) -> Result<CellValue, FormulaError> {
+        if args.len() != 1 {
+            return Err(FormulaError::ArgumentCount {
+                func: "ABS".to_string(),
+                expected: "1".to_string(),
+                got: args.len(),
+            });
+        }
+        let val = self.evaluate(&args[0], grid)?;
+        val.to_number()
+            .map(|n| CellValue::Number(n.abs()))
+            .ok_or_else(|| FormulaError::TypeError {
+                expected: "number".to_string(),
+                got: "non-numeric".to_string(),
+            })
+    }
+
+    /// ROUND, ROUNDUP and ROUNDDOWN(number, [digits])
+    ///
+    /// ROUNDUP rounds away from zero and ROUNDDOWN towards it; negative
+    /// `digits` round to the left of the decimal point.
+    fn fn_round(&self, name: &str, args: &[FormulaNode], grid: &Grid) -> Result<CellValue, FormulaError> {
+        if args.is_empty() || args.len() > 2 {
+            return Err(FormulaError::ArgumentCount {
+                func: name.to_string(),
+                expected: "1 or 2".to_string(),
+                got: args.len(),
+            });
+        }
+        let val = self.evaluate(&args[0], grid)?;
+        let decimals = if args.len() > 1 {
+            self.evaluate(&args[1], grid)?.to_number().unwrap_or(0.0) as i32
+        } else {
             0
         };
         
         val.to_number()
             .map(|n| {
                 let multiplier = 10_f64.powi(decimals);
-                CellValue::Number((n * multiplier).round() / multiplier)
+                // Snap first so 1.1*100 = 110.00000000000001 doesn't round up to 111
+                let scaled = significant(n * multiplier);
+                let rounded = match name {
+                    "ROUNDUP" => scaled.abs().ceil().copysign(scaled),
+                    "ROUNDDOWN" => scaled.trunc(),
+                    _ => scaled.round(),
+                };
+                CellValue::Number(rounded / multiplier)
             })
             .ok_or_else(|| FormulaError::TypeError {
                 expected: "number".to_string(),
@@ -582,6 +1574,40 @@ impl FormulaEngine {
             })
     }
 
+    /// CEILING and FLOOR(number, significance): round up or down to a multiple of `significance`
+    ///
+    /// As in Excel, a negative number with a positive significance rounds
+    /// towards +infinity for CEILING and -infinity for FLOOR; when both are
+    /// negative CEILING rounds away from zero and FLOOR towards it. A positive
+    /// number with a negative significance is #NUM.
+    fn fn_ceiling_floor(&self, name: &str, args: &[FormulaNode], grid: &Grid) -> Result<CellValue, FormulaError> {
+        if args.len() != 2 {
+            return Err(FormulaError::ArgumentCount {
+                func: name.to_string(),
+                expected: "2".to_string(),
+                got: args.len(),
+            });
+        }
+        let mut numbers = [0.0; 2];
+        for (slot, arg) in numbers.iter_mut().zip(args) {
+            *slot = self.evaluate(arg, grid)?.to_number().ok_or_else(|| FormulaError::TypeError {
+                expected: "number".to_string(),
+                got: "non-numeric".to_string(),
+            })?;
+        }
+        let [n, significance] = numbers;
+        if significance == 0.0 {
+            return Err(FormulaError::DivisionByZero);
+        }
+        if n > 0.0 && significance < 0.0 {
+            return Ok(CellValue::Error("NUM".to_string()));
+        }
+        // With both negative the quotient is positive, giving the away/towards-zero cases
+        let multiples = significant(n / significance);
+        let multiples = if name == "CEILING" { multiples.ceil() } else { multiples.floor() };
+        Ok(CellValue::Number(significant(multiples * significance)))
+    }
+
     fn fn_sqrt(&self, args: &[FormulaNode], grid: &Grid) -> Result<CellValue, FormulaError> {
         if args.len() != 1 {
             return Err(FormulaError::ArgumentCount {
@@ -619,76 +1645,2646 @@ impl FormulaEngine {
         }
     }
 
-    /// Register a formula for a cell and update the dependency graph
-    pub fn register_formula(&mut self, cell: CellRef, formula: Formula) {
-        // Get or create node for this cell
-        let cell_node = *self.cell_to_node.entry(cell).or_insert_with(|| {
-            self.dep_graph.add_node(cell)
-        });
-        
-        // Remove old dependencies
-        let old_edges: Vec<_> = self.dep_graph.edges_directed(cell_node, petgraph::Direction::Incoming)
-            .map(|e| e.id())
-            .collect();
-        for edge in old_edges {
-            self.dep_graph.remove_edge(edge);
+    /// LEFT(text, [count]) / RIGHT(text, [count]): the first or last `count` characters
+    ///
+    /// `count` defaults to 1; a negative count is `#VALUE` and one past the
+    /// end gives the whole text.
+    fn fn_left_right(&self, func: &str, args: &[FormulaNode], grid: &Grid) -> Result<CellValue, FormulaError> {
+        if args.is_empty() || args.len() > 2 {
+            return Err(FormulaError::ArgumentCount {
+                func: func.to_string(),
+                expected: "1 or 2".to_string(),
+                got: args.len(),
+            });
         }
-        
-        // Add new dependencies
-        for dep in &formula.dependencies {
-            let dep_node = *self.cell_to_node.entry(*dep).or_insert_with(|| {
-                self.dep_graph.add_node(*dep)
+        let text = match self.text_arg(&args[0], grid)? {
+            Ok(text) => text,
+            Err(error) => return Ok(error),
+        };
+        let count = match args.get(1) {
+            Some(arg) => self.count_arg(arg, grid)?,
+            None => 1,
+        };
+        let chars: Vec<char> = text.chars().collect();
+        let count = count.min(chars.len());
+        let slice = if func == "LEFT" { &chars[..count] } else { &chars[chars.len() - count..] };
+        Ok(CellValue::Text(slice.iter().collect()))
+    }
+
+    /// MID(text, start, count): `count` characters from 1-based position `start`
+    ///
+    /// A start of 0 is treated as 1 and one past the end gives empty text;
+    /// a negative start or count is `#VALUE`.
+    fn fn_mid(&self, args: &[FormulaNode], grid: &Grid) -> Result<CellValue, FormulaError> {
+        if args.len() != 3 {
+            return Err(FormulaError::ArgumentCount {
+                func: "MID".to_string(),
+                expected: "3".to_string(),
+                got: args.len(),
             });
-            self.dep_graph.add_edge(dep_node, cell_node, ());
         }
-        
-        self.formulas.insert(cell, formula);
+        let text = match self.text_arg(&args[0], grid)? {
+            Ok(text) => text,
+            Err(error) => return Ok(error),
+        };
+        let start = self.count_arg(&args[1], grid)?.max(1);
+        let count = self.count_arg(&args[2], grid)?;
+        Ok(CellValue::Text(text.chars().skip(start - 1).take(count).collect()))
     }
 
-    /// Recalculate a cell and all its dependents
-    pub fn recalculate(&mut self, grid: &mut Grid, changed: CellRef) -> Result<Vec<CellRef>, FormulaError> {
-        let mut affected = vec![changed];
-        
-        // Get all cells that depend on this one (transitively)
-        if let Some(&node) = self.cell_to_node.get(&changed) {
-            let mut to_visit = vec![node];
-            let mut visited = HashSet::new();
-            visited.insert(node);
-            
-            while let Some(current) = to_visit.pop() {
-                for neighbor in self.dep_graph.neighbors(current) {
-                    if visited.insert(neighbor) {
-                        to_visit.push(neighbor);
-                        affected.push(self.dep_graph[neighbor]);
-                    }
-                }
-            }
+    /// LEN(text): the number of characters in the text as displayed
+    fn fn_len(&self, args: &[FormulaNode], grid: &Grid) -> Result<CellValue, FormulaError> {
+        if args.len() != 1 {
+            return Err(FormulaError::ArgumentCount {
+                func: "LEN".to_string(),
+                expected: "1".to_string(),
+                got: args.len(),
+            });
         }
-        
-        // Sort by dependency order
-        if let Ok(sorted) = toposort(&self.dep_graph, None) {
-            let sorted_cells: Vec<CellRef> = sorted.into_iter()
-                .map(|idx| self.dep_graph[idx])
-                .filter(|cell| affected.contains(cell))
-                .collect();
-            
-            // Recalculate in order
-            for cell in &sorted_cells {
-                if let Some(formula) = self.formulas.get(cell).cloned() {
-                    let value = self.evaluate(&formula.ast, grid)?;
-                    grid.set_computed_value(*cell, value)?;
-                }
-            }
-            
-            Ok(sorted_cells)
-        } else {
-            Err(FormulaError::CircularReference)
+        Ok(match self.text_arg(&args[0], grid)? {
+            Ok(text) => CellValue::Number(text.chars().count() as f64),
+            Err(error) => error,
+        })
+    }
+
+    /// UPPER(text) / LOWER(text) / PROPER(text): the text with its case changed
+    ///
+    /// PROPER capitalizes the first letter of each whitespace-separated word and
+    /// lowercases the rest.
+    fn fn_change_case(&self, func: &str, args: &[FormulaNode], grid: &Grid) -> Result<CellValue, FormulaError> {
+        if args.len() != 1 {
+            return Err(FormulaError::ArgumentCount {
+                func: func.to_string(),
+                expected: "1".to_string(),
+                got: args.len(),
+            });
         }
+        let text = match self.text_arg(&args[0], grid)? {
+            Ok(text) => text,
+            Err(error) => return Ok(error),
+        };
+        Ok(CellValue::Text(match func {
+            "UPPER" => text.to_uppercase(),
+            "LOWER" => text.to_lowercase(),
+            _ => {
+                let mut word_start = true;
+                text.chars()
+                    .flat_map(|c| {
+                        let changed: Vec<char> = if word_start { c.to_uppercase().collect() } else { c.to_lowercase().collect() };
+                        word_start = c.is_whitespace();
+                        changed
+                    })
+                    .collect()
+            }
+        }))
     }
-}
 
-impl Default for FormulaEngine {
+    /// TRIM(text): the text without leading or trailing spaces, with inner runs of spaces collapsed to one
+    ///
+    /// Like Excel, only the space character is trimmed.
+    fn fn_trim(&self, args: &[FormulaNode], grid: &Grid) -> Result<CellValue, FormulaError> {
+        if args.len() != 1 {
+            return Err(FormulaError::ArgumentCount {
+                func: "TRIM".to_string(),
+                expected: "1".to_string(),
+                got: args.len(),
+            });
+        }
+        Ok(match self.text_arg(&args[0], grid)? {
+            Ok(text) => CellValue::Text(text.split(' ').filter(|word| !word.is_empty()).collect::<Vec<_>>().join(" ")),
+            Err(error) => error,
+        })
+    }
+
+    /// SUBSTITUTE(text, old, new, [instance]): `old` replaced by `new` everywhere, or only at occurrence `instance`
+    ///
+    /// Occurrences are counted left to right without overlapping. An empty
+    /// `old` or an instance past the last occurrence leaves the text as is;
+    /// an instance below 1 is `#VALUE`.
+    fn fn_substitute(&self, args: &[FormulaNode], grid: &Grid) -> Result<CellValue, FormulaError> {
+        if args.len() < 3 || args.len() > 4 {
+            return Err(FormulaError::ArgumentCount {
+                func: "SUBSTITUTE".to_string(),
+                expected: "3 or 4".to_string(),
+                got: args.len(),
+            });
+        }
+        let mut texts = Vec::with_capacity(3);
+        for arg in &args[..3] {
+            match self.text_arg(arg, grid)? {
+                Ok(text) => texts.push(text),
+                Err(error) => return Ok(error),
+            }
+        }
+        let (text, old, new) = (&texts[0], &texts[1], &texts[2]);
+        let instance = match args.get(3) {
+            Some(arg) => Some(self.position_arg(arg, grid)?),
+            None => None,
+        };
+        if old.is_empty() {
+            return Ok(CellValue::Text(text.clone()));
+        }
+        Ok(CellValue::Text(match instance {
+            None => text.replace(old.as_str(), new),
+            Some(n) => match text.match_indices(old.as_str()).nth(n - 1) {
+                Some((at, _)) => format!("{}{}{}", &text[..at], new, &text[at + old.len()..]),
+                None => text.clone(),
+            },
+        }))
+    }
+
+    /// REPLACE(text, start, count, new): `count` characters from 1-based position `start` replaced by `new`
+    ///
+    /// A start past the end appends `new`, and a count of 0 inserts it. A
+    /// start below 1 or a negative count is `#VALUE`.
+    fn fn_replace(&self, args: &[FormulaNode], grid: &Grid) -> Result<CellValue, FormulaError> {
+        if args.len() != 4 {
+            return Err(FormulaError::ArgumentCount {
+                func: "REPLACE".to_string(),
+                expected: "4".to_string(),
+                got: args.len(),
+            });
+        }
+        let text = match self.text_arg(&args[0], grid)? {
+            Ok(text) => text,
+            Err(error) => return Ok(error),
+        };
+        let start = self.position_arg(&args[1], grid)?;
+        let count = self.count_arg(&args[2], grid)?;
+        let new = match self.text_arg(&args[3], grid)? {
+            Ok(text) => text,
+            Err(error) => return Ok(error),
+        };
+        let chars: Vec<char> = text.chars().collect();
+        let from = (start - 1).min(chars.len());
+        let to = from.saturating_add(count).min(chars.len());
+        let mut replaced: String = chars[..from].iter().collect();
+        replaced.push_str(&new);
+        replaced.extend(&chars[to..]);
+        Ok(CellValue::Text(replaced))
+    }
+
+    /// FIND(needle, text, [start]) / SEARCH(needle, text, [start]): 1-based character position of `needle` in `text`
+    ///
+    /// Looks from character `start` (default 1) on. FIND is case-sensitive;
+    /// SEARCH ignores case and reads `*`, `?` and `~` as criteria do. Not
+    /// finding it, or a start below 1 or past the end of the text, is `#VALUE`.
+    fn fn_find(&self, func: &str, args: &[FormulaNode], grid: &Grid) -> Result<CellValue, FormulaError> {
+        if args.len() < 2 || args.len() > 3 {
+            return Err(FormulaError::ArgumentCount {
+                func: func.to_string(),
+                expected: "2 or 3".to_string(),
+                got: args.len(),
+            });
+        }
+        let needle = match self.text_arg(&args[0], grid)? {
+            Ok(text) => text,
+            Err(error) => return Ok(error),
+        };
+        let text = match self.text_arg(&args[1], grid)? {
+            Ok(text) => text,
+            Err(error) => return Ok(error),
+        };
+        let start = match args.get(2) {
+            Some(arg) => self.position_arg(arg, grid)?,
+            None => 1,
+        };
+        // Byte offset of each character, and of the end of the text
+        let offsets: Vec<usize> = text.char_indices().map(|(at, _)| at).chain([text.len()]).collect();
+        if start > offsets.len() {
+            return Ok(CellValue::Error("VALUE".to_string()));
+        }
+        let found = (start - 1..offsets.len()).find(|&i| {
+            let rest = &text[offsets[i]..];
+            match func {
+                "FIND" => rest.starts_with(needle.as_str()),
+                _ => wildcard_prefix_match(&needle, rest),
+            }
+        });
+        Ok(match found {
+            Some(i) => CellValue::Number((i + 1) as f64),
+            None => CellValue::Error("VALUE".to_string()),
+        })
+    }
+
+    /// TEXT(value, format): `value` shown with a number format code, as text
+    ///
+    /// Text that reads as a number is formatted as that number; other text is
+    /// returned as it is. A code `number_format` doesn't understand shows the
+    /// value plainly.
+    fn fn_text(&self, args: &[FormulaNode], grid: &Grid) -> Result<CellValue, FormulaError> {
+        if args.len() != 2 {
+            return Err(FormulaError::ArgumentCount {
+                func: "TEXT".to_string(),
+                expected: "2".to_string(),
+                got: args.len(),
+            });
+        }
+        let value = self.evaluate(&args[0], grid)?;
+        let code = match self.text_arg(&args[1], grid)? {
+            Ok(text) => text,
+            Err(error) => return Ok(error),
+        };
+        let number = match &value {
+            CellValue::Error(_) => return Ok(value),
+            CellValue::Boolean(_) => return Ok(CellValue::Text(value.display())),
+            CellValue::Text(text) => match value.to_number() {
+                Some(n) => n,
+                None => return Ok(CellValue::Text(text.clone())),
+            },
+            _ => value.to_number().unwrap_or(0.0),
+        };
+        Ok(CellValue::Text(number_format::format_number(number, &code)))
+    }
+
+    /// VALUE(text) / N(value) / T(value): a value coerced to a number or text
+    ///
+    /// VALUE reads text as a number the way pasted text is read, so
+    /// `1,234.5`, `45%`, `$12` and `(500)` all convert; anything else, TRUE
+    /// included, is `#VALUE`. N gives numbers as they are, TRUE and FALSE as
+    /// 1 and 0, and anything else as 0; T gives text as it is and anything
+    /// else as empty text. All three pass errors through.
+    fn fn_coerce(&self, func: &str, args: &[FormulaNode], grid: &Grid) -> Result<CellValue, FormulaError> {
+        if args.len() != 1 {
+            return Err(FormulaError::ArgumentCount {
+                func: func.to_string(),
+                expected: "1".to_string(),
+                got: args.len(),
+            });
+        }
+        let value = self.evaluate(&args[0], grid)?;
+        Ok(match (func, value) {
+            (_, error @ CellValue::Error(_)) => error,
+            ("VALUE", CellValue::Number(n)) => CellValue::Number(n),
+            ("VALUE", CellValue::Empty) => CellValue::Number(0.0),
+            ("VALUE", CellValue::Text(text)) => match format_infer::parse_value(&text) {
+                CellValue::Number(n) => CellValue::Number(n),
+                _ => CellValue::Error("VALUE".to_string()),
+            },
+            ("VALUE", CellValue::Boolean(_)) => CellValue::Error("VALUE".to_string()),
+            ("N", CellValue::Number(n)) => CellValue::Number(n),
+            ("N", CellValue::Boolean(b)) => CellValue::Number(if b { 1.0 } else { 0.0 }),
+            ("N", _) => CellValue::Number(0.0),
+            (_, CellValue::Text(text)) => CellValue::Text(text),
+            _ => CellValue::Text(String::new()),
+        })
+    }
+
+    /// TODAY() / NOW(): the current date, or date and time, as a serial number
+    ///
+    /// Read from the grid's clock, in UTC (see `datetime`).
+    fn fn_now(&self, func: &str, args: &[FormulaNode], grid: &Grid) -> Result<CellValue, FormulaError> {
+        if !args.is_empty() {
+            return Err(FormulaError::ArgumentCount {
+                func: func.to_string(),
+                expected: "0".to_string(),
+                got: args.len(),
+            });
+        }
+        let now = datetime::unix_millis_to_serial(grid.now_millis());
+        Ok(CellValue::Number(if func == "TODAY" { now.floor() } else { now }))
+    }
+
+    /// DATE(year, month, day): the serial number of a date
+    ///
+    /// Arguments are truncated to whole numbers, and months and days out of
+    /// range roll over into the next or previous year or month (see
+    /// `datetime::date_from_parts`). A date Excel couldn't show is `#NUM`.
+    fn fn_date(&self, args: &[FormulaNode], grid: &Grid) -> Result<CellValue, FormulaError> {
+        if args.len() != 3 {
+            return Err(FormulaError::ArgumentCount {
+                func: "DATE".to_string(),
+                expected: "3".to_string(),
+                got: args.len(),
+            });
+        }
+        let mut parts = [0_i64; 3];
+        for (part, arg) in parts.iter_mut().zip(args) {
+            match self.evaluate(arg, grid)? {
+                error @ CellValue::Error(_) => return Ok(error),
+                CellValue::Empty => {}
+                value => match value.to_number() {
+                    Some(n) if n.abs() < 1e12 => *part = n.trunc() as i64,
+                    Some(_) => return Ok(CellValue::Error("NUM".to_string())),
+                    None => return Ok(CellValue::Error("VALUE".to_string())),
+                },
+            }
+        }
+        Ok(match datetime::date_from_parts(parts[0], parts[1], parts[2]) {
+            Some(serial) => CellValue::Number(serial),
+            None => CellValue::Error("NUM".to_string()),
+        })
+    }
+
+    /// YEAR(date) / MONTH(date) / DAY(date) / WEEKDAY(date, [type]): part of a date
+    ///
+    /// `date` is a serial number or text that reads as a date or number (see
+    /// `date_serial`). WEEKDAY counts Sunday = 1 through Saturday = 7,
+    /// or with `type` 2 Monday = 1 through Sunday = 7, or with 3 Monday = 0
+    /// through Sunday = 6. Serials before 0 or past 9999-12-31 and other
+    /// types are `#NUM`; text that isn't a date is `#VALUE`.
+    fn fn_date_part(&self, func: &str, args: &[FormulaNode], grid: &Grid) -> Result<CellValue, FormulaError> {
+        let max_args = if func == "WEEKDAY" { 2 } else { 1 };
+        if args.is_empty() || args.len() > max_args {
+            return Err(FormulaError::ArgumentCount {
+                func: func.to_string(),
+                expected: if max_args == 1 { "1".to_string() } else { "1 or 2".to_string() },
+                got: args.len(),
+            });
+        }
+        let serial = match date_serial(self.evaluate(&args[0], grid)?) {
+            Ok(serial) => serial,
+            Err(error) => return Ok(error),
+        };
+        let (year, month, day) = datetime::serial_to_date(serial);
+        let part = match func {
+            "YEAR" => year as f64,
+            "MONTH" => month as f64,
+            "DAY" => day as f64,
+            _ => {
+                let kind = match args.get(1) {
+                    Some(arg) => match self.evaluate(arg, grid)? {
+                        error @ CellValue::Error(_) => return Ok(error),
+                        value => value.to_number().map(f64::trunc),
+                    },
+                    None => Some(1.0),
+                };
+                let sunday_first = datetime::weekday(serial);
+                let monday_first = (sunday_first + 6) % 7;
+                match kind {
+                    Some(1.0) => (sunday_first + 1) as f64,
+                    Some(2.0) => (monday_first + 1) as f64,
+                    Some(3.0) => monday_first as f64,
+                    _ => return Ok(CellValue::Error("NUM".to_string())),
+                }
+            }
+        };
+        Ok(CellValue::Number(part))
+    }
+
+    /// EDATE(start, months) / EOMONTH(start, months): a date some months on
+    ///
+    /// EDATE keeps the day of the month, clamped to the new month's last
+    /// day; EOMONTH gives the last day of the month. `months` is truncated
+    /// and may be negative.
+    fn fn_edate(&self, func: &str, args: &[FormulaNode], grid: &Grid) -> Result<CellValue, FormulaError> {
+        if args.len() != 2 {
+            return Err(FormulaError::ArgumentCount {
+                func: func.to_string(),
+                expected: "2".to_string(),
+                got: args.len(),
+            });
+        }
+        let start = match date_serial(self.evaluate(&args[0], grid)?) {
+            Ok(serial) => serial,
+            Err(error) => return Ok(error),
+        };
+        let months = match self.evaluate(&args[1], grid)? {
+            error @ CellValue::Error(_) => return Ok(error),
+            CellValue::Empty => 0.0,
+            value => match value.to_number() {
+                Some(n) => n.trunc().clamp(-1e6, 1e6),
+                None => return Ok(CellValue::Error("VALUE".to_string())),
+            },
+        };
+        let shifted = match func {
+            "EOMONTH" => datetime::end_of_month(start, months as i64),
+            _ => datetime::add_months(start, months as i64),
+        };
+        Ok(shifted.map_or_else(|| CellValue::Error("NUM".to_string()), CellValue::Number))
+    }
+
+    /// DATEDIF(start, end, unit): whole years, months or days between two dates
+    ///
+    /// `unit` is `"Y"`, `"M"`, `"D"`, `"MD"`, `"YM"` or `"YD"` (see
+    /// `datetime::date_difference`). An end before the start, or any other
+    /// unit, is `#NUM`.
+    fn fn_datedif(&self, args: &[FormulaNode], grid: &Grid) -> Result<CellValue, FormulaError> {
+        if args.len() != 3 {
+            return Err(FormulaError::ArgumentCount {
+                func: "DATEDIF".to_string(),
+                expected: "3".to_string(),
+                got: args.len(),
+            });
+        }
+        let mut dates = [0.0; 2];
+        for (date, arg) in dates.iter_mut().zip(args) {
+            match date_serial(self.evaluate(arg, grid)?) {
+                Ok(serial) => *date = serial,
+                Err(error) => return Ok(error),
+            }
+        }
+        let unit = match self.evaluate(&args[2], grid)? {
+            error @ CellValue::Error(_) => return Ok(error),
+            value => value.display(),
+        };
+        Ok(match datetime::date_difference(dates[0], dates[1], unit.trim()) {
+            Some(difference) => CellValue::Number(difference),
+            None => CellValue::Error("NUM".to_string()),
+        })
+    }
+
+    /// WORKDAY(start, days, [holidays]) / NETWORKDAYS(start, end, [holidays])
+    ///
+    /// WORKDAY is the date `days` working days after `start` (before it if
+    /// negative); NETWORKDAYS counts the working days from `start` to `end`,
+    /// both included, negatively if `end` comes first. Saturdays, Sundays
+    /// and the dates in `holidays` aren't working days. `holidays` is a
+    /// range or a single date; its blanks, text and booleans are skipped.
+    fn fn_workday(&self, func: &str, args: &[FormulaNode], grid: &Grid) -> Result<CellValue, FormulaError> {
+        if !(2..=3).contains(&args.len()) {
+            return Err(FormulaError::ArgumentCount {
+                func: func.to_string(),
+                expected: "2 or 3".to_string(),
+                got: args.len(),
+            });
+        }
+        let start = match date_serial(self.evaluate(&args[0], grid)?) {
+            Ok(serial) => serial,
+            Err(error) => return Ok(error),
+        };
+        let holidays = match self.text_values(&args[2..], grid)? {
+            Ok(values) => values.iter()
+                .filter_map(|value| match value {
+                    CellValue::Number(n) => Some(n.floor() as i64),
+                    _ => None,
+                })
+                .collect(),
+            Err(error) => return Ok(error),
+        };
+        if func == "NETWORKDAYS" {
+            return Ok(match date_serial(self.evaluate(&args[1], grid)?) {
+                Ok(end) => CellValue::Number(datetime::workdays_between(start, end, &holidays) as f64),
+                Err(error) => error,
+            });
+        }
+        let days = match self.evaluate(&args[1], grid)? {
+            error @ CellValue::Error(_) => return Ok(error),
+            CellValue::Empty => 0.0,
+            value => match value.to_number() {
+                Some(n) => n.trunc().clamp(-datetime::MAX_SERIAL, datetime::MAX_SERIAL),
+                None => return Ok(CellValue::Error("VALUE".to_string())),
+            },
+        };
+        Ok(datetime::add_workdays(start, days as i64, &holidays)
+            .map_or_else(|| CellValue::Error("NUM".to_string()), CellValue::Number))
+    }
+
+    /// CONCATENATE(text1, ...): the displayed text of every argument, joined
+    ///
+    /// Ranges contribute each of their cells, row by row.
+    fn fn_concatenate(&self, args: &[FormulaNode], grid: &Grid) -> Result<CellValue, FormulaError> {
+        if args.is_empty() {
+            return Err(FormulaError::ArgumentCount {
+                func: "CONCATENATE".to_string(),
+                expected: "at least 1".to_string(),
+                got: 0,
+            });
+        }
+        Ok(match self.text_values(args, grid)? {
+            Ok(values) => CellValue::Text(values.iter().map(CellValue::display).collect()),
+            Err(error) => error,
+        })
+    }
+
+    /// TEXTJOIN(delimiter, ignore_empty, text1, ...): arguments joined with `delimiter`
+    ///
+    /// Ranges contribute each of their cells, row by row. With `ignore_empty`
+    /// TRUE, blank cells and empty text are left out rather than leaving
+    /// doubled delimiters.
+    fn fn_textjoin(&self, args: &[FormulaNode], grid: &Grid) -> Result<CellValue, FormulaError> {
+        if args.len() < 3 {
+            return Err(FormulaError::ArgumentCount {
+                func: "TEXTJOIN".to_string(),
+                expected: "at least 3".to_string(),
+                got: args.len(),
+            });
+        }
+        let delimiter = match self.text_arg(&args[0], grid)? {
+            Ok(text) => text,
+            Err(error) => return Ok(error),
+        };
+        let ignore_empty = match self.evaluate(&args[1], grid)? {
+            error @ CellValue::Error(_) => return Ok(error),
+            value => value.to_bool().ok_or_else(|| FormulaError::TypeError {
+                expected: "logical value".to_string(),
+                got: format!("\"{}\"", value.display()),
+            })?,
+        };
+        let values = match self.text_values(&args[2..], grid)? {
+            Ok(values) => values,
+            Err(error) => return Ok(error),
+        };
+        let pieces: Vec<String> = values.iter()
+            .map(CellValue::display)
+            .filter(|text| !(ignore_empty && text.is_empty()))
+            .collect();
+        Ok(CellValue::Text(pieces.join(&delimiter)))
+    }
+
+    /// Every value the arguments supply, ranges and arrays flattened row by row
+    ///
+    /// The first error value found is returned instead.
+    fn text_values(&self, args: &[FormulaNode], grid: &Grid) -> Result<Result<Vec<CellValue>, CellValue>, FormulaError> {
+        let mut values = Vec::new();
+        for arg in args {
+            match arg {
+                FormulaNode::Range { start, end, .. } => {
+                    values.extend(self.collect_range_cells(*start, *end, grid).into_iter().flatten());
+                }
+                _ => match self.evaluate_array(arg, grid) {
+                    Some(array) => values.extend(array?.into_iter().flatten()),
+                    None => values.push(self.evaluate(arg, grid)?),
+                },
+            }
+        }
+        Ok(match values.iter().find(|value| matches!(value, CellValue::Error(_))) {
+            Some(error) => Err(error.clone()),
+            None => Ok(values),
+        })
+    }
+
+    /// Evaluate a text argument to its displayed text, passing errors through as the result
+    fn text_arg(&self, arg: &FormulaNode, grid: &Grid) -> Result<Result<String, CellValue>, FormulaError> {
+        Ok(match self.evaluate(arg, grid)? {
+            error @ CellValue::Error(_) => Err(error),
+            value => Ok(value.display()),
+        })
+    }
+
+    /// Evaluate a character count or position, truncated to an integer
+    ///
+    /// Negative values are a `#VALUE` error, as in Excel.
+    fn count_arg(&self, arg: &FormulaNode, grid: &Grid) -> Result<usize, FormulaError> {
+        let n = self.evaluate(arg, grid)?.to_number().ok_or_else(|| FormulaError::TypeError {
+            expected: "number".to_string(),
+            got: "non-numeric".to_string(),
+        })?;
+        if n < 0.0 {
+            return Err(FormulaError::TypeError {
+                expected: "non-negative count".to_string(),
+                got: n.to_string(),
+            });
+        }
+        Ok(n as usize)
+    }
+
+    /// Evaluate a 1-based position or occurrence number, truncated to an integer
+    ///
+    /// Anything below 1 is a `#VALUE` error.
+    fn position_arg(&self, arg: &FormulaNode, grid: &Grid) -> Result<usize, FormulaError> {
+        match self.count_arg(arg, grid)? {
+            0 => Err(FormulaError::TypeError {
+                expected: "position of at least 1".to_string(),
+                got: "0".to_string(),
+            }),
+            n => Ok(n),
+        }
+    }
+
+    /// TEXTSPLIT(text, col_delimiter, [row_delimiter]): split text into a 2D array
+    ///
+    /// Consecutive delimiters produce empty cells, as does a trailing one.
+    pub(crate) fn fn_textsplit(&self, args: &[FormulaNode], grid: &Grid) -> Result<Vec<Vec<CellValue>>, FormulaError> {
+        if args.len() < 2 || args.len() > 3 {
+            return Err(FormulaError::ArgumentCount {
+                func: "TEXTSPLIT".to_string(),
+                expected: "2 or 3".to_string(),
+                got: args.len(),
+            });
+        }
+        let text = match self.evaluate(&args[0], grid)? {
+            error @ CellValue::Error(_) => return Ok(vec![vec![error]]),
+            value => value.display(),
+        };
+        let col_delimiter = self.evaluate(&args[1], grid)?.display();
+        let row_delimiter = match args.get(2) {
+            Some(arg) => self.evaluate(arg, grid)?.display(),
+            None => String::new(),
+        };
+        if col_delimiter.is_empty() && row_delimiter.is_empty() {
+            return Err(FormulaError::TypeError {
+                expected: "delimiter".to_string(),
+                got: "empty text".to_string(),
+            });
+        }
+        
+        let split = |s: &'_ str, delimiter: &str| -> Vec<String> {
+            if delimiter.is_empty() {
+                vec![s.to_string()]
+            } else {
+                s.split(delimiter).map(str::to_string).collect()
+            }
+        };
+        Ok(split(&text, &row_delimiter)
+            .into_iter()
+            .map(|row| {
+                split(&row, &col_delimiter)
+                    .into_iter()
+                    .map(|piece| if piece.is_empty() { CellValue::Empty } else { CellValue::Text(piece) })
+                    .collect()
+            })
+            .collect())
+    }
+
+    /// Register a formula for a cell and update the dependency graph
+    pub fn register_formula(&mut self, cell: CellRef, mut formula: Formula) {
+        // Get or create node for this cell
+        let cell_node = *self.cell_to_node.entry(cell).or_insert_with(|| {
+            self.dep_graph.add_node(cell)
+        });
+        
+        // Remove old dependencies
+        let old_edges: Vec<_> = self.dep_graph.edges_directed(cell_node, petgraph::Direction::Incoming)
+            .map(|e| e.id())
+            .collect();
+        for edge in old_edges {
+            self.dep_graph.remove_edge(edge);
+        }
+        
+        // Add new dependencies
+        for dep in &formula.dependencies {
+            let dep_node = *self.cell_to_node.entry(*dep).or_insert_with(|| {
+                self.dep_graph.add_node(*dep)
+            });
+            self.dep_graph.add_edge(dep_node, cell_node, ());
+        }
+        
+        self.check_formula_size(cell, &formula);
+        formula.simplified = self.simplified(&formula.ast);
+        if formula.volatile {
+            self.volatile.insert(cell);
+        } else {
+            self.volatile.remove(&cell);
+        }
+        self.formulas.insert(cell, formula);
+    }
+
+    /// Replace every registered formula with those stored in `grid`'s cells
+    ///
+    /// Used after loading a saved sheet. Formulas that don't parse or call a
+    /// function this engine lacks are marked foreign in `grid` rather than
+    /// registered, so their cached values are kept (see `foreign`). Named
+    /// ranges come from `grid` too, so none carry over from another
+    /// document; settings do.
+    pub fn load_formulas(&mut self, grid: &mut Grid) {
+        self.named_ranges = grid.names.clone().into_iter().collect();
+        let mut parsed = Vec::new();
+        for (cell, text) in grid.stored_formulas() {
+            if grid.is_foreign(cell) {
+                continue;
+            }
+            match self.parse_supported(&text) {
+                Ok(formula) => parsed.push((cell, formula)),
+                Err(reason) => grid.mark_foreign(cell, reason),
+            }
+        }
+        let (strict_mode, large_formula_cells, simplify) = (self.strict_mode, self.large_formula_cells, self.simplify);
+        let named_ranges = std::mem::take(&mut self.named_ranges);
+        *self = FormulaEngine::new();
+        self.strict_mode = strict_mode;
+        self.large_formula_cells = large_formula_cells;
+        self.simplify = simplify;
+        self.named_ranges = named_ranges;
+        for (cell, formula) in parsed {
+            self.register_formula(cell, formula);
+        }
+    }
+
+    /// The formula registered for a cell, if any
+    pub fn formula_at(&self, cell: CellRef) -> Option<&Formula> {
+        self.formulas.get(&cell)
+    }
+
+    /// Whether `cell` depends on `precedent`, directly or transitively
+    pub fn depends_on(&self, cell: CellRef, precedent: CellRef) -> bool {
+        let (Some(&start), Some(&goal)) = (self.cell_to_node.get(&precedent), self.cell_to_node.get(&cell)) else {
+            return false;
+        };
+        petgraph::algo::has_path_connecting(&self.dep_graph, start, goal, None) && start != goal
+    }
+
+    /// Forget the formula registered for a cell (e.g. when it's overwritten by a value)
+    pub fn unregister_formula(&mut self, cell: CellRef) {
+        if self.formulas.remove(&cell).is_none() {
+            return;
+        }
+        self.large_formulas.remove(&cell);
+        self.volatile.remove(&cell);
+        if let Some(&cell_node) = self.cell_to_node.get(&cell) {
+            let old_edges: Vec<_> = self.dep_graph.edges_directed(cell_node, petgraph::Direction::Incoming)
+                .map(|e| e.id())
+                .collect();
+            for edge in old_edges {
+                self.dep_graph.remove_edge(edge);
+            }
+        }
+    }
+
+    /// Recalculate a cell and all its dependents, and every volatile formula
+    ///
+    /// Evaluation errors and reference cycles show up as error values in the
+    /// cells concerned rather than failing the whole recalculation.
+    pub fn recalculate(&mut self, grid: &mut Grid, changed: CellRef) -> Result<Vec<CellRef>, FormulaError> {
+        // Editing inside a spill area means the array formula must re-spill
+        let anchors = self.release_spill_cell(changed);
+        let mut seeds: Vec<CellRef> = self.volatile.iter().copied().collect();
+        seeds.sort_by_key(|cell| (cell.row, cell.col));
+        seeds.push(changed);
+        let mut recalculated = self.recalculate_from(grid, &seeds)?;
+        for anchor in anchors {
+            for cell in self.recalculate_from(grid, &[anchor])? {
+                if !recalculated.contains(&cell) {
+                    recalculated.push(cell);
+                }
+            }
+        }
+        Ok(recalculated)
+    }
+
+    /// Recalculate `changed` and everything depending on them, each cell once
+    pub(crate) fn recalculate_from(&mut self, grid: &mut Grid, changed: &[CellRef]) -> Result<Vec<CellRef>, FormulaError> {
+        let mut affected = self.transitive_dependents(changed);
+        affected.extend(changed.iter().copied());
+        
+        // Recalculate in dependency order
+        let mut sorted_cells = self.calculation_order_of(&affected);
+        let mut changed_cells = Vec::new();
+        for cell in sorted_cells.clone() {
+            changed_cells.extend(self.recalculate_cell(grid, cell)?);
+        }
+        
+        // Cells filled or cleared by a spill have dependents of their own
+        for cell in changed_cells {
+            if sorted_cells.contains(&cell) {
+                continue;
+            }
+            sorted_cells.push(cell);
+            for dependent in self.recalculate_from(grid, &[cell])? {
+                if !sorted_cells.contains(&dependent) {
+                    sorted_cells.push(dependent);
+                }
+            }
+        }
+        
+        Ok(sorted_cells)
+    }
+}
+
+impl FormulaEngine {
+    /// Re-evaluate every registered formula and report cached values that don't match
+    ///
+    /// Nothing is written. Formulas using volatile functions (NOW, RAND, ...)
+    /// are listed separately, since their results legitimately change between
+    /// evaluations.
+    pub fn verify_calculations(&self, grid: &Grid) -> CalculationReport {
+        let mut report = CalculationReport::default();
+        
+        let mut cells: Vec<&CellRef> = self.formulas.keys().collect();
+        cells.sort_by_key(|cell| (cell.row, cell.col));
+        
+        for cell in cells {
+            let formula = &self.formulas[cell];
+            if formula.volatile {
+                report.volatile.push(*cell);
+                continue;
+            }
+            let stored = grid.get_cell(*cell).map(|c| c.value.clone()).unwrap_or_default();
+            // Array formulas store their top-left element in the anchor
+            let recomputed = match self.evaluate_array(formula.evaluated(), grid) {
+                Some(array) => array.map(|rows| rows.into_iter().flatten().next().unwrap_or_default()),
+                None => self.evaluate(formula.evaluated(), grid),
+            }
+            .unwrap_or_else(|e| CellValue::Error(e.error_code().to_string()));
+            if !values_match(&stored, &recomputed) {
+                report.mismatches.push(CalculationMismatch { cell: *cell, stored, recomputed });
+            }
+        }
+        
+        report.checked = self.formulas.len();
+        report
+    }
+
+    /// Load `grid`'s formulas and recalculate, reporting cached values the recalculation changed
+    ///
+    /// For checking a file saved by another tool: unlike
+    /// `verify_calculations`, each formula is compared after its inputs were
+    /// recalculated too, so one wrong cached value isn't also blamed on the
+    /// formulas downstream of it. Volatile formulas are listed, not compared.
+    pub fn load_verified(&mut self, grid: &mut Grid) -> Result<CalculationReport, FormulaError> {
+        self.load_formulas(grid);
+        let mut report = CalculationReport::default();
+        let mut cells: Vec<CellRef> = self.formulas.keys().copied().collect();
+        cells.sort_by_key(|cell| (cell.row, cell.col));
+        let mut cached = Vec::new();
+        for cell in cells {
+            if self.formulas[&cell].volatile {
+                report.volatile.push(cell);
+            } else {
+                cached.push((cell, grid.get_cell(cell).map(|c| c.value.clone()).unwrap_or_default()));
+            }
+        }
+        
+        self.recalculate_all(grid)?;
+        for (cell, stored) in cached {
+            let recomputed = grid.get_cell(cell).map(|c| c.value.clone()).unwrap_or_default();
+            if !values_match(&stored, &recomputed) {
+                report.mismatches.push(CalculationMismatch { cell, stored, recomputed });
+            }
+        }
+        report.checked = self.formulas.len();
+        Ok(report)
+    }
+
+    /// Recalculate every formula in dependency order, returning the cells whose value changed
+    ///
+    /// Evaluation errors are written into the cell as error values rather than
+    /// aborting the pass.
+    pub fn recalculate_all(&mut self, grid: &mut Grid) -> Result<Vec<CellRef>, FormulaError> {
+        let mut changed = Vec::new();
+        for cell in self.calculation_order() {
+            changed.extend(self.recalculate_cell(grid, cell)?);
+        }
+        // A formula reading a spilled cell may have run before its anchor spilled
+        let spilled: Vec<CellRef> = changed.iter().copied().filter(|cell| !self.formulas.contains_key(cell)).collect();
+        for cell in spilled {
+            for dependent in self.recalculate_from(grid, &[cell])? {
+                if !changed.contains(&dependent) {
+                    changed.push(dependent);
+                }
+            }
+        }
+        Ok(changed)
+    }
+
+    /// Formula cells that would recalculate if `cell` changed, in row-major order
+    ///
+    /// Follows the dependency graph without evaluating anything, so cells a
+    /// spill would newly fill or clear aren't included.
+    pub fn impact_of(&self, cell: CellRef) -> Vec<CellRef> {
+        let mut cells: Vec<CellRef> = self.transitive_dependents(&[cell]).into_iter().collect();
+        cells.sort_by_key(|cell| (cell.row, cell.col));
+        cells
+    }
+
+    /// Cells that depend, directly or through other formulas, on any of `cells`
+    ///
+    /// A seed is only included when it depends on another seed or on itself.
+    pub(crate) fn transitive_dependents(&self, cells: &[CellRef]) -> HashSet<CellRef> {
+        let mut dependents = HashSet::new();
+        let mut to_visit: Vec<NodeIndex> = cells.iter().filter_map(|cell| self.cell_to_node.get(cell).copied()).collect();
+        let mut visited: HashSet<NodeIndex> = to_visit.iter().copied().collect();
+        while let Some(current) = to_visit.pop() {
+            for neighbor in self.dep_graph.neighbors(current) {
+                dependents.insert(self.dep_graph[neighbor]);
+                if visited.insert(neighbor) {
+                    to_visit.push(neighbor);
+                }
+            }
+        }
+        dependents
+    }
+
+    /// Every cell in the dependency graph, dependencies first
+    ///
+    /// Cells caught in a reference cycle (including formulas that refer to
+    /// themselves) are listed where the cycle as a whole falls in the order,
+    /// and remembered so `recalculate_cell` marks them instead of evaluating.
+    pub(crate) fn calculation_order(&mut self) -> Vec<CellRef> {
+        self.circular.clear();
+        order_components(&self.dep_graph, &mut self.circular)
+    }
+
+    /// The graph cells among `cells` in dependency order, like `calculation_order`
+    ///
+    /// Only the part of the graph spanned by `cells` is sorted, so an edit
+    /// costs time in proportion to its dependents rather than to the whole
+    /// sheet. `cells` must include all their own dependents: then every
+    /// cycle through one of them lies within them, and refreshing just their
+    /// `circular` marks leaves the other marks correct.
+    fn calculation_order_of(&mut self, cells: &HashSet<CellRef>) -> Vec<CellRef> {
+        let mut nodes: Vec<NodeIndex> = cells.iter().filter_map(|cell| self.cell_to_node.get(cell).copied()).collect();
+        // Keep the graph's own order between independent cells
+        nodes.sort();
+        let mut subgraph = DiGraph::with_capacity(nodes.len(), nodes.len());
+        let sub_nodes: HashMap<NodeIndex, NodeIndex> = nodes.iter()
+            .map(|&idx| (idx, subgraph.add_node(self.dep_graph[idx])))
+            .collect();
+        for &idx in &nodes {
+            for neighbor in self.dep_graph.neighbors(idx) {
+                if let Some(&target) = sub_nodes.get(&neighbor) {
+                    subgraph.add_edge(sub_nodes[&idx], target, ());
+                }
+            }
+        }
+        for cell in cells {
+            self.circular.remove(cell);
+        }
+        order_components(&subgraph, &mut self.circular)
+    }
+
+    /// Whether the last recalculation found `cell` in a reference cycle
+    pub fn is_circular(&self, cell: CellRef) -> bool {
+        self.circular.contains(&cell)
+    }
+
+    /// The cells in a reference cycle with `cell`, in row-major order, or none if it's in none
+    pub fn cycle_through(&self, cell: CellRef) -> Vec<CellRef> {
+        let Some(&node) = self.cell_to_node.get(&cell) else {
+            return Vec::new();
+        };
+        let Some(component) = tarjan_scc(&self.dep_graph).into_iter().find(|component| component.contains(&node)) else {
+            return Vec::new();
+        };
+        if component.len() == 1 && !self.dep_graph.contains_edge(node, node) {
+            return Vec::new();
+        }
+        let mut cycle: Vec<CellRef> = component.into_iter().map(|idx| self.dep_graph[idx]).collect();
+        cycle.sort_by_key(|cell| (cell.row, cell.col));
+        cycle
+    }
+
+    /// Re-evaluate one cell's formula (if it has one), returning the cells whose value changed
+    ///
+    /// Evaluation errors become error values in the cell, as do references
+    /// that loop back to the cell itself.
+    pub(crate) fn recalculate_cell(&mut self, grid: &mut Grid, cell: CellRef) -> Result<Vec<CellRef>, FormulaError> {
+        let Some(formula) = self.formulas.get(&cell).cloned() else {
+            // An array formula's spilled values go with the formula
+            return Ok(self.drop_spill(grid, cell)?);
+        };
+        let stored = grid.get_cell(cell).map(|c| c.value.clone()).unwrap_or_default();
+        if self.circular.contains(&cell) {
+            let value = CellValue::Error("CIRCULAR".to_string());
+            if stored == value {
+                return Ok(Vec::new());
+            }
+            grid.set_computed_value(cell, value)?;
+            return Ok(vec![cell]);
+        }
+        if let Some(array) = self.evaluate_array(formula.evaluated(), grid) {
+            let array = array.unwrap_or_else(|e| vec![vec![CellValue::Error(e.error_code().to_string())]]);
+            let mut changed = Vec::new();
+            let spilled = self.spill(grid, cell, array)?;
+            if grid.get_cell(cell).map(|c| &c.value) != Some(&stored) {
+                changed.push(cell);
+            }
+            changed.extend(spilled);
+            return Ok(changed);
+        }
+        let value = self.evaluate(formula.evaluated(), grid)
+            .unwrap_or_else(|e| CellValue::Error(e.error_code().to_string()));
+        let inherited = inherited_format(&formula.ast, grid).map(str::to_string);
+        let reformatted = grid.set_implicit_format(cell, inherited);
+        if stored == value {
+            return Ok(if reformatted { vec![cell] } else { Vec::new() });
+        }
+        grid.set_computed_value(cell, value)?;
+        Ok(vec![cell])
+    }
+}
+
+/// Result of `verify_calculations`
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct CalculationReport {
+    /// Number of formulas checked
+    pub checked: usize,
+    pub mismatches: Vec<CalculationMismatch>,
+    /// Formula cells skipped because they use volatile functions
+    pub volatile: Vec<CellRef>,
+}
+
+/// A formula cell whose cached value differs from a fresh evaluation
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CalculationMismatch {
+    pub cell: CellRef,
+    pub stored: CellValue,
+    pub recomputed: CellValue,
+}
+
+/// Cells of a dependency graph, dependencies first, adding cyclic ones to `circular`
+fn order_components(graph: &DiGraph<CellRef, ()>, circular: &mut HashSet<CellRef>) -> Vec<CellRef> {
+    let mut order = Vec::new();
+    // Tarjan's algorithm yields components dependents-first
+    for component in tarjan_scc(graph).into_iter().rev() {
+        let cyclic = component.len() > 1 || graph.contains_edge(component[0], component[0]);
+        for idx in component {
+            let cell = graph[idx];
+            if cyclic {
+                circular.insert(cell);
+            }
+            order.push(cell);
+        }
+    }
+    order
+}
+
+/// Cells as a comma-separated list of A1 references
+fn a1_list(cells: &[CellRef]) -> String {
+    cells.iter().map(CellRef::to_a1).collect::<Vec<_>>().join(", ")
+}
+
+/// A date argument as a serial number, or the error value to return
+///
+/// Numbers are serials; text is read the way typed-in values are, so
+/// `"2024-03-31"` is a date; blanks are 0. Errors pass through, other text
+/// is `#VALUE`, and serials before 0 or past 9999-12-31 are `#NUM`.
+fn date_serial(value: CellValue) -> Result<f64, CellValue> {
+    let serial = match value {
+        error @ CellValue::Error(_) => return Err(error),
+        CellValue::Empty => 0.0,
+        CellValue::Number(n) => n,
+        CellValue::Boolean(b) => f64::from(u8::from(b)),
+        CellValue::Text(text) => match format_infer::parse_value(&text) {
+            CellValue::Number(n) => n,
+            _ => return Err(CellValue::Error("VALUE".to_string())),
+        },
+    };
+    match (0.0..datetime::MAX_SERIAL + 1.0).contains(&serial) {
+        true => Ok(serial),
+        false => Err(CellValue::Error("NUM".to_string())),
+    }
+}
+
+/// Functions whose result can change without any input changing
+const VOLATILE_FUNCTIONS: &[&str] = &["NOW", "TODAY", "RAND", "RANDBETWEEN"];
+
+/// Whether an expression calls a volatile function anywhere
+fn is_volatile(node: &FormulaNode) -> bool {
+    match node {
+        FormulaNode::Function { name, args, .. } => {
+            VOLATILE_FUNCTIONS.contains(&name.as_str()) || args.iter().any(is_volatile)
+        }
+        FormulaNode::BinaryOp { left, right, .. } => is_volatile(left) || is_volatile(right),
+        FormulaNode::UnaryOp { operand, .. } => is_volatile(operand),
+        _ => false,
+    }
+}
+
+/// Reject non-finite numbers, which Excel reports as #NUM! rather than showing
+fn finite(value: CellValue) -> Result<CellValue, FormulaError> {
+    match value {
+        CellValue::Number(n) if !n.is_finite() => Err(FormulaError::NotFinite),
+        value => Ok(value),
+    }
+}
+
+/// Round to 15 significant digits, dropping binary noise such as 1.2500000000000002
+fn significant(x: f64) -> f64 {
+    if x == 0.0 || !x.is_finite() {
+        return x;
+    }
+    let digits = 15 - x.abs().log10().ceil() as i32;
+    let multiplier = 10_f64.powi(digits.clamp(-300, 300));
+    (x * multiplier).round() / multiplier
+}
+
+/// Compare a cached value with a recomputed one, allowing for float noise
+fn values_match(stored: &CellValue, recomputed: &CellValue) -> bool {
+    match (stored, recomputed) {
+        (CellValue::Number(a), CellValue::Number(b)) => {
+            a == b || (a - b).abs() <= 1e-9 * a.abs().max(b.abs())
+        }
+        _ => stored == recomputed,
+    }
+}
+
+/// Apply Excel's blank-cell coercions to the operands of `op`
+///
+/// Blanks are 0 in arithmetic and "" in concatenation. In comparisons a blank
+/// takes the "zero" of the other operand's type (0, "" or FALSE), so a blank
+/// sorts below any positive number and any non-empty text.
+fn coerce_empty_operands(op: BinaryOp, left: CellValue, right: CellValue) -> (CellValue, CellValue) {
+    let zero_like = |other: &CellValue| match other {
+        CellValue::Text(_) => CellValue::Text(String::new()),
+        CellValue::Boolean(_) => CellValue::Boolean(false),
+        _ => CellValue::Number(0.0),
+    };
+    let coerce = |value: CellValue, other: &CellValue| match value {
+        CellValue::Empty => match op {
+            BinaryOp::Concat => CellValue::Text(String::new()),
+            BinaryOp::Eq | BinaryOp::Ne | BinaryOp::Lt | BinaryOp::Le | BinaryOp::Gt | BinaryOp::Ge => zero_like(other),
+            _ => CellValue::Number(0.0),
+        },
+        other => other,
+    };
+    let new_left = coerce(left, &right);
+    let new_right = coerce(right, &new_left);
+    (new_left, new_right)
+}
+
+/// Check for `leading` arguments followed by one or more range/criteria pairs
+fn check_criteria_pairs(func: &str, args: &[FormulaNode], leading: usize) -> Result<(), FormulaError> {
+    let pairs = args.len().saturating_sub(leading);
+    if args.len() < leading + 2 || !pairs.is_multiple_of(2) {
+        return Err(FormulaError::ArgumentCount {
+            func: func.to_string(),
+            expected: if leading == 0 { "range/criteria pairs" } else { "a range, then range/criteria pairs" }.to_string(),
+            got: args.len(),
+        });
+    }
+    Ok(())
+}
+
+/// Index of the key matching `lookup` in a lookup table's first column or row
+///
+/// Approximate matching assumes keys sorted ascending and takes the last key
+/// of the same kind that is at most `lookup`; exact matching takes the first
+/// equal key (text compares case-insensitively).
+fn lookup_position<'a>(keys: impl Iterator<Item = &'a CellValue>, lookup: &CellValue, approximate: bool) -> Option<usize> {
+    let same_kind = |key: &CellValue| std::mem::discriminant(key) == std::mem::discriminant(lookup);
+    let mut keys = keys.enumerate().filter(|(_, key)| same_kind(key));
+    if !approximate {
+        return keys.find(|(_, key)| comparison_result(BinaryOp::Eq, key, lookup) == Some(true)).map(|(index, _)| index);
+    }
+    keys.take_while(|(_, key)| comparison_result(BinaryOp::Gt, key, lookup) != Some(true))
+        .last()
+        .map(|(index, _)| index)
+}
+
+/// Index of the key XLOOKUP settles on, visiting keys in `order`
+///
+/// The first exact match visited wins. Failing that, match modes -1 and 1
+/// take the closest smaller or larger key of the same kind, the first
+/// visited among equals. Keys needn't be sorted.
+fn xlookup_position(keys: &[CellValue], order: impl Iterator<Item = usize>, lookup: &CellValue, match_mode: i64) -> Option<usize> {
+    let same_kind = |key: &CellValue| std::mem::discriminant(key) == std::mem::discriminant(lookup);
+    let is = |op: BinaryOp, left: &CellValue, right: &CellValue| comparison_result(op, left, right) == Some(true);
+    let mut closest: Option<usize> = None;
+    for index in order {
+        let key = &keys[index];
+        if !same_kind(key) {
+            continue;
+        }
+        let exact = match (match_mode, key, lookup) {
+            (2, CellValue::Text(text), CellValue::Text(pattern)) => wildcard_match(pattern, text),
+            _ => is(BinaryOp::Eq, key, lookup),
+        };
+        if exact {
+            return Some(index);
+        }
+        let closer = match match_mode {
+            -1 => is(BinaryOp::Lt, key, lookup) && closest.is_none_or(|best| is(BinaryOp::Gt, key, &keys[best])),
+            1 => is(BinaryOp::Gt, key, lookup) && closest.is_none_or(|best| is(BinaryOp::Lt, key, &keys[best])),
+            _ => false,
+        };
+        if closer {
+            closest = Some(index);
+        }
+    }
+    closest
+}
+
+/// Corners of a range argument; a single cell counts as a 1x1 range
+fn range_bounds(node: &FormulaNode) -> Result<(CellRef, CellRef), FormulaError> {
+    match node {
+        FormulaNode::Range { start, end, .. } => Ok((*start, *end)),
+        FormulaNode::CellRef { cell, .. } => Ok((*cell, *cell)),
+        FormulaNode::RefError => Err(FormulaError::InvalidRef("#REF!".to_string())),
+        _ => Err(FormulaError::TypeError {
+            expected: "range".to_string(),
+            got: "value".to_string(),
+        }),
+    }
+}
+
+/// Evaluate a comparison operator using Excel's ordering rules
+///
+/// Numbers sort before text, text before booleans; text compares
+/// case-insensitively. Returns `None` for non-comparison operators.
+pub(crate) fn comparison_result(op: BinaryOp, left: &CellValue, right: &CellValue) -> Option<bool> {
+    use std::cmp::Ordering;
+
+    fn rank(value: &CellValue) -> u8 {
+        match value {
+            CellValue::Empty | CellValue::Number(_) => 0,
+            CellValue::Text(_) => 1,
+            CellValue::Boolean(_) => 2,
+            CellValue::Error(_) => 3,
+        }
+    }
+
+    let ordering = match (left, right) {
+        (CellValue::Number(l), CellValue::Number(r)) => l.partial_cmp(r).unwrap_or(Ordering::Equal),
+        (CellValue::Text(l), CellValue::Text(r)) => l.to_lowercase().cmp(&r.to_lowercase()),
+        (CellValue::Boolean(l), CellValue::Boolean(r)) => l.cmp(r),
+        (CellValue::Error(l), CellValue::Error(r)) => l.cmp(r),
+        _ => rank(left).cmp(&rank(right)),
+    };
+    match op {
+        BinaryOp::Eq => Some(ordering == Ordering::Equal),
+        BinaryOp::Ne => Some(ordering != Ordering::Equal),
+        BinaryOp::Lt => Some(ordering == Ordering::Less),
+        BinaryOp::Le => Some(ordering != Ordering::Greater),
+        BinaryOp::Gt => Some(ordering == Ordering::Greater),
+        BinaryOp::Ge => Some(ordering != Ordering::Less),
+        _ => None,
+    }
+}
+
+impl Default for FormulaEngine {
     fn default() -> Self {
         Self::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::grid::{CellUpdate, GridDiff, GridPatch};
+    use crate::test_support::set;
+
+    #[test]
+    fn test_evaluate_with_overrides() {
+        let mut grid = Grid::new(10, 10);
+        let mut engine = FormulaEngine::new();
+        set(&mut grid, &mut engine, "A1", "5");
+        set(&mut grid, &mut engine, "B1", "=A1*2");
+        
+        let formula = engine.parse("=A1*2").unwrap();
+        let overrides = HashMap::from([(CellRef::new(0, 0), CellValue::Number(21.0))]);
+        let value = engine.evaluate_with_overrides(&formula, &grid, overrides).unwrap();
+        
+        assert_eq!(value, CellValue::Number(42.0));
+        assert_eq!(grid.get_cell(CellRef::new(0, 0)).unwrap().value, CellValue::Number(5.0));
+        assert_eq!(engine.evaluate(&formula.ast, &grid).unwrap(), CellValue::Number(10.0));
+    }
+
+    #[test]
+    fn test_overrides_flow_through_dependent_formulas() {
+        let mut grid = Grid::new(10, 10);
+        let mut engine = FormulaEngine::new();
+        set(&mut grid, &mut engine, "A1", "5");
+        set(&mut grid, &mut engine, "A2", "=A1*2");
+        set(&mut grid, &mut engine, "A3", "=A2+1");
+        
+        let formula = engine.parse("=SUM(A2:A3)").unwrap();
+        let overrides = HashMap::from([(CellRef::new(0, 0), CellValue::Number(1.0))]);
+        let value = engine.evaluate_with_overrides(&formula, &grid, overrides).unwrap();
+        
+        // A2 = 2, A3 = 3 under the override
+        assert_eq!(value, CellValue::Number(5.0));
+        assert_eq!(grid.get_cell(CellRef::new(2, 0)).unwrap().value, CellValue::Number(11.0));
+    }
+
+    #[test]
+    fn test_empty_cell_plus_number() {
+        let mut grid = Grid::new(10, 10);
+        let mut engine = FormulaEngine::new();
+        set(&mut grid, &mut engine, "B1", "=A1+5");
+        assert_eq!(grid.get_cell(CellRef::new(0, 1)).unwrap().value, CellValue::Number(5.0));
+        
+        engine.set_strict_mode(true);
+        let formula = engine.parse("=A1+5").unwrap();
+        assert!(matches!(engine.evaluate(&formula.ast, &grid), Err(FormulaError::TypeError { .. })));
+    }
+
+    #[test]
+    fn test_empty_operand_matrix() {
+        use BinaryOp::*;
+        let empty = || CellValue::Empty;
+        let num = CellValue::Number;
+        let text = |s: &str| CellValue::Text(s.to_string());
+        let b = CellValue::Boolean;
+        
+        // (op, left, right, lenient result, strict result; None = error)
+        type Case = (BinaryOp, CellValue, CellValue, Option<CellValue>, Option<CellValue>);
+        let cases: Vec<Case> = vec![
+            (Add, empty(), num(5.0), Some(num(5.0)), None),
+            (Sub, num(5.0), empty(), Some(num(5.0)), None),
+            (Mul, empty(), num(5.0), Some(num(0.0)), None),
+            (Div, empty(), num(5.0), Some(num(0.0)), None),
+            (Div, num(5.0), empty(), None, None),
+            (Pow, num(5.0), empty(), Some(num(1.0)), None),
+            (Concat, empty(), text("x"), Some(text("x")), Some(text("x"))),
+            (Concat, num(1.0), empty(), Some(text("1")), Some(text("1"))),
+            (Eq, empty(), num(0.0), Some(b(true)), Some(b(false))),
+            (Eq, empty(), text(""), Some(b(true)), Some(b(false))),
+            (Eq, empty(), b(false), Some(b(true)), Some(b(false))),
+            (Eq, empty(), empty(), Some(b(true)), Some(b(true))),
+            (Ne, empty(), num(1.0), Some(b(true)), Some(b(true))),
+            (Lt, empty(), num(1.0), Some(b(true)), Some(b(false))),
+            (Lt, empty(), num(-1.0), Some(b(false)), Some(b(false))),
+            (Lt, empty(), text("a"), Some(b(true)), Some(b(false))),
+            (Le, empty(), empty(), Some(b(true)), Some(b(false))),
+            (Gt, num(1.0), empty(), Some(b(true)), Some(b(false))),
+            (Ge, empty(), num(0.0), Some(b(true)), Some(b(false))),
+        ];
+        
+        let mut engine = FormulaEngine::new();
+        for (op, left, right, lenient, strict) in &cases {
+            for (strict_mode, expected) in [(false, lenient), (true, strict)] {
+                engine.set_strict_mode(strict_mode);
+                let got = engine.evaluate_binary_op(*op, left.clone(), right.clone()).ok();
+                assert_eq!(&got, expected, "strict={} {:?}({:?}, {:?})", strict_mode, op, left, right);
+            }
+        }
+        
+        engine.set_strict_mode(false);
+        assert_eq!(engine.evaluate_unary_op(UnaryOp::Neg, CellValue::Empty).unwrap(), num(0.0));
+        engine.set_strict_mode(true);
+        assert!(engine.evaluate_unary_op(UnaryOp::Neg, CellValue::Empty).is_err());
+    }
+
+    #[test]
+    fn test_non_finite_results_are_num_errors() {
+        let mut grid = Grid::new(10, 10);
+        let mut engine = FormulaEngine::new();
+        let value = |grid: &Grid, a1: &str| grid.get_cell(CellRef::parse(a1).unwrap()).unwrap().value.clone();
+        let num = CellValue::Error("NUM".to_string());
+
+        set(&mut grid, &mut engine, "A1", "=SQRT(-1)");
+        assert_eq!(value(&grid, "A1"), num);
+        set(&mut grid, &mut engine, "A2", "=POWER(10, 308)*10");
+        assert_eq!(value(&grid, "A2"), num);
+        set(&mut grid, &mut engine, "A3", "=0-POWER(10, 308)*10");
+        assert_eq!(value(&grid, "A3"), num);
+        // Downstream formulas see an error, not NaN arithmetic
+        set(&mut grid, &mut engine, "A4", "=A1+1");
+        assert!(matches!(value(&grid, "A4"), CellValue::Error(_)));
+        set(&mut grid, &mut engine, "A5", "=IFERROR(SQRT(-4), 0)");
+        assert_eq!(value(&grid, "A5"), CellValue::Number(0.0));
+        assert_eq!(grid.get_cell(CellRef::parse("A1").unwrap()).unwrap().display(), "#NUM");
+
+        // Array results are checked element by element
+        set(&mut grid, &mut engine, "B1", "10");
+        set(&mut grid, &mut engine, "B2", "20");
+        set(&mut grid, &mut engine, "D1", "=POWER(10, 308)");
+        set(&mut grid, &mut engine, "C1", "=B1:B2*D1");
+        assert_eq!(value(&grid, "C1"), num);
+        assert_eq!(value(&grid, "C2"), num);
+    }
+
+    #[test]
+    fn test_directional_rounding() {
+        let mut grid = Grid::new(10, 10);
+        let mut engine = FormulaEngine::new();
+        let cases = [
+            ("=ROUNDUP(12.341, 2)", 12.35),
+            ("=ROUNDUP(0-12.341, 2)", -12.35),
+            ("=ROUNDUP(1.1, 2)", 1.1),
+            ("=ROUNDUP(1234, 0-2)", 1300.0),
+            ("=ROUNDDOWN(12.349, 2)", 12.34),
+            ("=ROUNDDOWN(0-12.349, 2)", -12.34),
+            ("=ROUNDDOWN(7.9)", 7.0),
+            ("=CEILING(1.234, 0.01)", 1.24),
+            ("=CEILING(1.23, 0.05)", 1.25),
+            ("=CEILING(1.25, 0.05)", 1.25),
+            ("=CEILING(0-2.5, 2)", -2.0),
+            ("=CEILING(0-2.5, 0-2)", -4.0),
+            ("=FLOOR(1.239, 0.01)", 1.23),
+            ("=FLOOR(1.27, 0.05)", 1.25),
+            ("=FLOOR(0-2.5, 2)", -4.0),
+            ("=FLOOR(0-2.5, 0-2)", -2.0),
+        ];
+        for (formula, expected) in cases {
+            set(&mut grid, &mut engine, "A1", formula);
+            assert_eq!(grid.get_cell(CellRef::new(0, 0)).unwrap().value, CellValue::Number(expected), "{}", formula);
+        }
+
+        set(&mut grid, &mut engine, "A1", "=CEILING(5, 0)");
+        assert_eq!(grid.get_cell(CellRef::new(0, 0)).unwrap().value, CellValue::Error("DIV/0".to_string()));
+        set(&mut grid, &mut engine, "A1", "=FLOOR(5, 0-1)");
+        assert_eq!(grid.get_cell(CellRef::new(0, 0)).unwrap().value, CellValue::Error("NUM".to_string()));
+    }
+
+    /// A grid with `n` values in column A and `=A{i}*2` beside each in column B
+    fn independent_formulas(n: u32) -> (Grid, FormulaEngine) {
+        let mut grid = Grid::new(n, 10);
+        let mut engine = FormulaEngine::new();
+        for row in 0..n {
+            grid.set_value(CellRef::new(row, 0), CellValue::Number(row as f64)).unwrap();
+            let formula = engine.parse(&format!("=A{}*2", row + 1)).unwrap();
+            grid.set_cell_formula(CellRef::new(row, 1), formula, &mut engine).unwrap();
+        }
+        engine.recalculate_all(&mut grid).unwrap();
+        (grid, engine)
+    }
+
+    /// Times single edits against a full recalculation over many independent formulas
+    ///
+    /// Run with `cargo test --release bench_ -- --ignored --nocapture`.
+    #[test]
+    #[ignore]
+    fn bench_single_edit_among_independent_formulas() {
+        const FORMULAS: u32 = 50_000;
+        const EDITS: u32 = 500;
+        let (mut grid, mut engine) = independent_formulas(FORMULAS);
+
+        let start = std::time::Instant::now();
+        engine.recalculate_all(&mut grid).unwrap();
+        let full = start.elapsed();
+
+        let start = std::time::Instant::now();
+        for i in 0..EDITS {
+            let row = (i * 7919) % FORMULAS;
+            grid.set_cell_value(CellRef::new(row, 0), CellValue::Number(-1.0), &mut engine).unwrap();
+            assert_eq!(engine.recalculate(&mut grid, CellRef::new(row, 0)).unwrap().len(), 2);
+        }
+        let per_edit = start.elapsed() / EDITS;
+
+        eprintln!("{} formulas: full recalculation {:?}, single edit {:?}", FORMULAS, full, per_edit);
+    }
+
+    #[test]
+    fn test_single_edit_recalculates_only_dependents() {
+        let (mut grid, mut engine) = independent_formulas(100);
+        // Chain C1 -> D1 off B5, and a cycle E1 <-> F1 the edit never reaches
+        grid.set_cell_formula(CellRef::new(0, 2), engine.parse("=B5+1").unwrap(), &mut engine).unwrap();
+        grid.set_cell_formula(CellRef::new(0, 3), engine.parse("=C1*10").unwrap(), &mut engine).unwrap();
+        grid.set_cell_formula(CellRef::new(0, 4), engine.parse("=F1").unwrap(), &mut engine).unwrap();
+        grid.set_cell_formula(CellRef::new(0, 5), engine.parse("=E1").unwrap(), &mut engine).unwrap();
+        engine.recalculate_all(&mut grid).unwrap();
+        assert!(engine.is_circular(CellRef::new(0, 4)));
+
+        grid.set_cell_value(CellRef::new(4, 0), CellValue::Number(50.0), &mut engine).unwrap();
+        let affected = engine.recalculate(&mut grid, CellRef::new(4, 0)).unwrap();
+        let a1: Vec<String> = affected.iter().map(CellRef::to_a1).collect();
+        assert_eq!(a1, vec!["A5", "B5", "C1", "D1"]);
+        assert_eq!(grid.get_cell(CellRef::parse("D1").unwrap()).unwrap().value, CellValue::Number(1010.0));
+        assert_eq!(grid.get_cell(CellRef::parse("B6").unwrap()).unwrap().value, CellValue::Number(10.0));
+        assert!(engine.is_circular(CellRef::new(0, 4)));
+
+        // Closing a loop through the edited cell's dependents marks it
+        grid.set_cell_formula(CellRef::new(4, 0), engine.parse("=D1").unwrap(), &mut engine).unwrap();
+        engine.recalculate(&mut grid, CellRef::new(4, 0)).unwrap();
+        assert!(engine.is_circular(CellRef::new(0, 3)));
+        assert!(engine.is_circular(CellRef::new(0, 4)));
+        assert!(!engine.is_circular(CellRef::new(5, 1)));
+    }
+
+    #[test]
+    fn test_impact_of_edit() {
+        let mut grid = Grid::new(10, 10);
+        let mut engine = FormulaEngine::new();
+        // A1 feeds B1 and B2; C1 combines them; D1 builds on C1; E1 only reads A2
+        set(&mut grid, &mut engine, "A1", "5");
+        set(&mut grid, &mut engine, "A2", "7");
+        set(&mut grid, &mut engine, "B1", "=A1*2");
+        set(&mut grid, &mut engine, "B2", "=A1+1");
+        set(&mut grid, &mut engine, "C1", "=B1+B2");
+        set(&mut grid, &mut engine, "D1", "=SUM(C1:C3)");
+        set(&mut grid, &mut engine, "E1", "=A2");
+        let revision = grid.revision();
+
+        let a1 = |cells: Vec<CellRef>| cells.iter().map(CellRef::to_a1).collect::<Vec<_>>();
+        assert_eq!(a1(engine.impact_of(CellRef::parse("A1").unwrap())), vec!["B1", "C1", "D1", "B2"]);
+        assert_eq!(a1(engine.impact_of(CellRef::parse("B2").unwrap())), vec!["C1", "D1"]);
+        // A blank cell inside a referenced range still counts
+        assert_eq!(a1(engine.impact_of(CellRef::parse("C3").unwrap())), vec!["D1"]);
+        assert!(engine.impact_of(CellRef::parse("E1").unwrap()).is_empty());
+        assert!(engine.impact_of(CellRef::parse("H8").unwrap()).is_empty());
+
+        // Nothing was recalculated or written
+        assert_eq!(grid.revision(), revision);
+        assert_eq!(grid.get_cell(CellRef::parse("D1").unwrap()).unwrap().value, CellValue::Number(16.0));
+    }
+
+    #[test]
+    fn test_ifs_and_switch() {
+        let mut grid = Grid::new(10, 10);
+        let mut engine = FormulaEngine::new();
+        let value = |grid: &Grid, a1: &str| grid.get_cell(CellRef::parse(a1).unwrap()).unwrap().value.clone();
+        set(&mut grid, &mut engine, "A1", "72");
+        set(&mut grid, &mut engine, "A2", "west");
+
+        set(&mut grid, &mut engine, "B1", "=IFS(A1>=90, \"A\", A1>=70, \"C\", TRUE, \"F\")");
+        assert_eq!(value(&grid, "B1"), CellValue::Text("C".to_string()));
+        set(&mut grid, &mut engine, "B2", "=IFS(A1>100, 1, A1<0, 2)");
+        assert_eq!(value(&grid, "B2"), CellValue::Error("N/A".to_string()));
+        // Branches that aren't chosen are never evaluated
+        set(&mut grid, &mut engine, "B3", "=IFS(A1>0, \"ok\", A1/0>1, 1/0)");
+        assert_eq!(value(&grid, "B3"), CellValue::Text("ok".to_string()));
+        set(&mut grid, &mut engine, "B4", "=IFS(A1>0, 1, 2)");
+        assert_eq!(value(&grid, "B4"), CellValue::Error("N/A".to_string()));
+
+        // Matching follows =, so text compares without case
+        set(&mut grid, &mut engine, "C1", "=SWITCH(A2, \"East\", 1, \"WEST\", 2, 0)");
+        assert_eq!(value(&grid, "C1"), CellValue::Number(2.0));
+        set(&mut grid, &mut engine, "C2", "=SWITCH(A2, \"north\", 1, 1/0)");
+        assert_eq!(value(&grid, "C2"), CellValue::Error("DIV/0".to_string()));
+        set(&mut grid, &mut engine, "C3", "=SWITCH(A1, 72, \"hit\", 1/0)");
+        assert_eq!(value(&grid, "C3"), CellValue::Text("hit".to_string()));
+        set(&mut grid, &mut engine, "C4", "=SWITCH(A1, 1, \"one\", 2, \"two\")");
+        assert_eq!(value(&grid, "C4"), CellValue::Error("N/A".to_string()));
+        set(&mut grid, &mut engine, "C5", "=SWITCH(A1, 1)");
+        assert_eq!(value(&grid, "C5"), CellValue::Error("N/A".to_string()));
+    }
+
+    #[test]
+    fn test_mod_and_int() {
+        let mut grid = Grid::new(10, 10);
+        let mut engine = FormulaEngine::new();
+        let cases = [
+            ("=MOD(7, 3)", 1.0),
+            ("=MOD(0-3, 2)", 1.0),
+            ("=MOD(3, 0-2)", -1.0),
+            ("=MOD(0-3, 0-2)", -1.0),
+            ("=MOD(5.5, 2)", 1.5),
+            ("=MOD(0.3, 0.1)", 0.0),
+            ("=INT(2.5)", 2.0),
+            ("=INT(0-2.5)", -3.0),
+            ("=INT(\"7.9\")", 7.0),
+        ];
+        for (formula, expected) in cases {
+            set(&mut grid, &mut engine, "A1", formula);
+            assert_eq!(grid.get_cell(CellRef::new(0, 0)).unwrap().value, CellValue::Number(expected), "{}", formula);
+        }
+        set(&mut grid, &mut engine, "A1", "=MOD(5, 0)");
+        assert_eq!(grid.get_cell(CellRef::new(0, 0)).unwrap().value, CellValue::Error("DIV/0".to_string()));
+    }
+
+    #[test]
+    fn test_boolean_matrix() {
+        use BinaryOp::*;
+        let num = CellValue::Number;
+        let text = |s: &str| CellValue::Text(s.to_string());
+        let b = CellValue::Boolean;
+        let error = |code: &str| CellValue::Error(code.to_string());
+        
+        // Typed input: only TRUE/FALSE spellings become booleans
+        assert_eq!(CellValue::parse("tRuE"), b(true));
+        assert_eq!(CellValue::parse(" FALSE "), b(false));
+        assert_eq!(CellValue::parse("yes"), text("yes"));
+        assert_eq!(CellValue::parse("1"), num(1.0));
+        
+        // (op, left, right, result), the same in lenient and strict mode
+        let cases = [
+            (Gt, b(true), num(1e9), b(true)),
+            (Gt, b(false), num(1e9), b(true)),
+            (Lt, num(1.0), b(true), b(true)),
+            (Gt, b(true), text("zzz"), b(true)),
+            (Gt, b(true), b(false), b(true)),
+            (Eq, b(true), text("TRUE"), b(false)),
+            (Ne, b(true), text("TRUE"), b(true)),
+            (Eq, b(true), num(1.0), b(false)),
+            (Eq, b(false), b(false), b(true)),
+            (Add, b(true), num(1.0), num(2.0)),
+            (Mul, b(false), num(5.0), num(0.0)),
+            // Errors pass through rather than being ranked
+            (Gt, error("DIV/0"), num(5.0), error("DIV/0")),
+            (Eq, error("DIV/0"), num(5.0), error("DIV/0")),
+            (Lt, num(5.0), error("N/A"), error("N/A")),
+            (Eq, error("N/A"), error("N/A"), error("N/A")),
+            (Ne, error("REF"), error("N/A"), error("REF")),
+            (Add, num(1.0), error("VALUE"), error("VALUE")),
+        ];
+        let mut engine = FormulaEngine::new();
+        for strict_mode in [false, true] {
+            engine.set_strict_mode(strict_mode);
+            for (op, left, right, expected) in &cases {
+                let got = engine.evaluate_binary_op(*op, left.clone(), right.clone()).unwrap();
+                assert_eq!(&got, expected, "strict={} {:?}({:?}, {:?})", strict_mode, op, left, right);
+            }
+            assert!(engine.evaluate_binary_op(Add, text("TRUE"), num(1.0)).is_err());
+        }
+        engine.set_strict_mode(false);
+        
+        // IF conditions: blanks and zero are FALSE, "TRUE"/"FALSE" text counts, other text is #VALUE
+        let mut grid = Grid::new(10, 10);
+        set(&mut grid, &mut engine, "A2", "0");
+        set(&mut grid, &mut engine, "A3", "2");
+        set(&mut grid, &mut engine, "A4", "=\"true\"");
+        set(&mut grid, &mut engine, "A5", "abc");
+        set(&mut grid, &mut engine, "A6", "=1/0");
+        let conditions = [
+            ("A1", text("no")),
+            ("A2", text("no")),
+            ("A3", text("yes")),
+            ("A4", text("yes")),
+            ("A5", CellValue::Error("VALUE".to_string())),
+            ("A6", CellValue::Error("DIV/0".to_string())),
+            ("A6>5", CellValue::Error("DIV/0".to_string())),
+            ("A6=5", CellValue::Error("DIV/0".to_string())),
+        ];
+        for (condition, expected) in conditions {
+            set(&mut grid, &mut engine, "B1", &format!("=IF({}, \"yes\", \"no\")", condition));
+            assert_eq!(grid.get_cell(CellRef::new(0, 1)).unwrap().value, expected, "IF({})", condition);
+        }
+    }
+
+    #[test]
+    fn test_verify_and_repair_calculations() {
+        let mut grid = Grid::new(10, 10);
+        let mut engine = FormulaEngine::new();
+        set(&mut grid, &mut engine, "A1", "4");
+        set(&mut grid, &mut engine, "A2", "=A1*2");
+        set(&mut grid, &mut engine, "A3", "=A2+1");
+        
+        let clean = engine.verify_calculations(&grid);
+        assert_eq!(clean.checked, 2);
+        assert!(clean.mismatches.is_empty(), "{:?}", clean.mismatches);
+        
+        // Corrupt A2's cached value; A3 then also disagrees with a fresh
+        // evaluation against the corrupted A2
+        grid.set_computed_value(CellRef::new(1, 0), CellValue::Number(99.0)).unwrap();
+        let report = engine.verify_calculations(&grid);
+        assert_eq!(report.mismatches.len(), 2);
+        assert_eq!(report.mismatches[0].cell, CellRef::new(1, 0));
+        assert_eq!(report.mismatches[0].stored, CellValue::Number(99.0));
+        assert_eq!(report.mismatches[0].recomputed, CellValue::Number(8.0));
+        assert_eq!(grid.get_cell(CellRef::new(1, 0)).unwrap().value, CellValue::Number(99.0));
+        
+        let changed = engine.recalculate_all(&mut grid).unwrap();
+        assert_eq!(changed, vec![CellRef::new(1, 0)]);
+        assert_eq!(grid.get_cell(CellRef::new(1, 0)).unwrap().value, CellValue::Number(8.0));
+        assert!(engine.verify_calculations(&grid).mismatches.is_empty());
+    }
+
+    #[test]
+    fn test_load_verified_reports_wrong_cached_values() {
+        let mut grid = Grid::new(10, 10);
+        let mut engine = FormulaEngine::new();
+        set(&mut grid, &mut engine, "A1", "4");
+        set(&mut grid, &mut engine, "A2", "=A1*2");
+        set(&mut grid, &mut engine, "A3", "=A2+1");
+        set(&mut grid, &mut engine, "B1", "=SUM(A1:A3)");
+        // A file saved by a tool that got A2 wrong but the rest right
+        grid.set_computed_value(CellRef::new(1, 0), CellValue::Number(99.0)).unwrap();
+        let json = grid.to_json().unwrap();
+
+        let mut imported = Grid::from_json(&json).unwrap();
+        let mut engine = FormulaEngine::new();
+        let report = engine.load_verified(&mut imported).unwrap();
+        assert_eq!(report.checked, 3);
+        assert_eq!(report.mismatches.len(), 1);
+        assert_eq!(report.mismatches[0].cell, CellRef::new(1, 0));
+        assert_eq!(report.mismatches[0].stored, CellValue::Number(99.0));
+        assert_eq!(report.mismatches[0].recomputed, CellValue::Number(8.0));
+        assert_eq!(imported.get_cell(CellRef::new(1, 0)).unwrap().value, CellValue::Number(8.0));
+
+        // The corrected save verifies clean
+        let mut resaved = Grid::from_json(&imported.to_json().unwrap()).unwrap();
+        assert!(FormulaEngine::new().load_verified(&mut resaved).unwrap().mismatches.is_empty());
+    }
+
+    #[test]
+    fn test_verify_skips_volatile_formulas() {
+        let mut grid = Grid::new(10, 10);
+        let mut engine = FormulaEngine::new();
+        let formula = engine.parse("=NOW()").unwrap();
+        grid.set_cell_formula(CellRef::new(0, 0), formula, &mut engine).unwrap();
+        
+        let report = engine.verify_calculations(&grid);
+        assert!(report.mismatches.is_empty());
+        assert_eq!(report.volatile, vec![CellRef::new(0, 0)]);
+    }
+
+    #[test]
+    fn test_parse_comparisons() {
+        let grid = Grid::new(10, 10);
+        let engine = FormulaEngine::new();
+        let cases = [
+            ("=1+2>2", true),
+            ("=3<=2", false),
+            ("=2>=2", true),
+            ("=1<>1", false),
+            ("=\"a\"=\"A\"", true),
+            ("=\"a>b\"=\"A>B\"", true),
+            ("=IF(2>1, 1, 0)=1", true),
+        ];
+        for (raw, expected) in cases {
+            let formula = engine.parse(raw).unwrap();
+            assert_eq!(engine.evaluate(&formula.ast, &grid).unwrap(), CellValue::Boolean(expected), "{}", raw);
+        }
+    }
+
+    #[test]
+    fn test_text_slicing() {
+        let mut grid = Grid::new(10, 10);
+        let mut engine = FormulaEngine::new();
+        set(&mut grid, &mut engine, "A1", "héllo 🌍");
+        set(&mut grid, &mut engine, "A2", "12.5");
+        let cases = [
+            ("=LEFT(A1, 2)", CellValue::Text("hé".to_string())),
+            ("=LEFT(A1)", CellValue::Text("h".to_string())),
+            ("=RIGHT(A1, 1)", CellValue::Text("🌍".to_string())),
+            ("=RIGHT(A1, 50)", CellValue::Text("héllo 🌍".to_string())),
+            ("=LEFT(A1, -1)", CellValue::Error("VALUE".to_string())),
+            ("=MID(A1, 2, -1)", CellValue::Error("VALUE".to_string())),
+            ("=LEFT(A1, 2.9)", CellValue::Text("hé".to_string())),
+            ("=MID(A1, 2, 3)", CellValue::Text("éll".to_string())),
+            ("=MID(A1, 0, 2)", CellValue::Text("hé".to_string())),
+            ("=MID(A1, 20, 2)", CellValue::Text(String::new())),
+            ("=LEN(A1)", CellValue::Number(7.0)),
+            ("=LEN(A2)", CellValue::Number(4.0)),
+            ("=LEFT(A2, 2)", CellValue::Text("12".to_string())),
+            ("=LEN(B9)", CellValue::Number(0.0)),
+        ];
+        for (formula, expected) in cases {
+            set(&mut grid, &mut engine, "C1", formula);
+            assert_eq!(grid.get_cell(CellRef::new(0, 2)).unwrap().value, expected, "{}", formula);
+        }
+        set(&mut grid, &mut engine, "C1", "=MID(A1, 2)");
+        assert_eq!(grid.get_cell(CellRef::new(0, 2)).unwrap().value, CellValue::Error("N/A".to_string()));
+    }
+
+    #[test]
+    fn test_concatenate_and_textjoin() {
+        let mut grid = Grid::new(10, 10);
+        let mut engine = FormulaEngine::new();
+        set(&mut grid, &mut engine, "A1", "Q1");
+        set(&mut grid, &mut engine, "C1", "Q3");
+        set(&mut grid, &mut engine, "A2", "Revenue");
+        set(&mut grid, &mut engine, "B2", "2024");
+        set(&mut grid, &mut engine, "D1", "=CONCATENATE(A2,\" \",B2,\" (\",A1:C1,\")\")");
+        set(&mut grid, &mut engine, "D2", "=TEXTJOIN(\",\",TRUE,A1:C1)");
+        set(&mut grid, &mut engine, "D3", "=TEXTJOIN(\",\",FALSE,A1:C1)");
+        set(&mut grid, &mut engine, "D4", "=TEXTJOIN(\"-\",TRUE,A1:C1,A2:B2)");
+        set(&mut grid, &mut engine, "D5", "=TEXTJOIN(\",\",TRUE,A1:C1,1/0)");
+        set(&mut grid, &mut engine, "D6", "=TEXTJOIN(\",\",TRUE)");
+
+        let value = |a1: &str| grid.get_cell(CellRef::parse(a1).unwrap()).unwrap().value.clone();
+        assert_eq!(value("D1"), CellValue::Text("Revenue 2024 (Q1Q3)".to_string()));
+        assert_eq!(value("D2"), CellValue::Text("Q1,Q3".to_string()));
+        assert_eq!(value("D3"), CellValue::Text("Q1,,Q3".to_string()));
+        assert_eq!(value("D4"), CellValue::Text("Q1-Q3-Revenue-2024".to_string()));
+        assert!(matches!(value("D5"), CellValue::Error(_)));
+        assert_eq!(value("D6"), CellValue::Error("N/A".to_string()));
+    }
+
+    #[test]
+    fn test_text_joining_mixed_values() {
+        let mut grid = Grid::new(10, 10);
+        let mut engine = FormulaEngine::new();
+        set(&mut grid, &mut engine, "A1", "West");
+        set(&mut grid, &mut engine, "A2", "1.5");
+        set(&mut grid, &mut engine, "A3", "TRUE");
+        set(&mut grid, &mut engine, "A4", "1200");
+        set(&mut grid, &mut engine, "C1", "=CONCATENATE(A1,\"-\",A2,\"-\",A3,\"-\",A4)");
+        set(&mut grid, &mut engine, "C2", "=TEXTJOIN(\",\",TRUE,A1:A4)");
+        // B1:B3 holds nothing at all
+        set(&mut grid, &mut engine, "C3", "=TEXTJOIN(\",\",TRUE,B1:B3)");
+        set(&mut grid, &mut engine, "C4", "=TEXTJOIN(\",\",FALSE,B1:B3)");
+        set(&mut grid, &mut engine, "C5", "=CONCATENATE(B1:B3)");
+
+        let value = |a1: &str| grid.get_cell(CellRef::parse(a1).unwrap()).unwrap().value.clone();
+        let display = |a1: &str| value(a1).display();
+        assert_eq!(value("C1"), CellValue::Text("West-1.5-TRUE-1200".to_string()));
+        // Numbers join exactly as the cells display them
+        let expected = ["A1", "A2", "A3", "A4"].map(display).join(",");
+        assert_eq!(value("C2"), CellValue::Text(expected));
+        assert_eq!(value("C3"), CellValue::Text(String::new()));
+        assert_eq!(value("C4"), CellValue::Text(",,".to_string()));
+        assert_eq!(value("C5"), CellValue::Text(String::new()));
+    }
+
+    #[test]
+    fn test_substitute_and_replace() {
+        let mut grid = Grid::new(10, 10);
+        let mut engine = FormulaEngine::new();
+        set(&mut grid, &mut engine, "A1", "naïve café");
+        set(&mut grid, &mut engine, "B1", "=SUBSTITUTE(\"a,a,a\", \",\", \";\", 2)");
+        set(&mut grid, &mut engine, "B2", "=SUBSTITUTE(\"a,a,a\", \",\", \";\")");
+        set(&mut grid, &mut engine, "B3", "=SUBSTITUTE(\"a,a,a\", \",\", \";\", 5)");
+        set(&mut grid, &mut engine, "B4", "=SUBSTITUTE(\"aaaa\", \"aa\", \"b\", 2)");
+        set(&mut grid, &mut engine, "B5", "=SUBSTITUTE(A1, \"é\", \"e\")");
+        set(&mut grid, &mut engine, "B6", "=SUBSTITUTE(\"abc\", \"\", \"x\")");
+        set(&mut grid, &mut engine, "B7", "=SUBSTITUTE(\"a,a\", \",\", \";\", 0)");
+        set(&mut grid, &mut engine, "C1", "=REPLACE(A1, 3, 1, \"i\")");
+        set(&mut grid, &mut engine, "C2", "=REPLACE(\"abc\", 2, 0, \"X\")");
+        set(&mut grid, &mut engine, "C3", "=REPLACE(\"abc\", 9, 2, \"!\")");
+        set(&mut grid, &mut engine, "C4", "=REPLACE(\"abcdef\", 2, 99, \"\")");
+        set(&mut grid, &mut engine, "C5", "=REPLACE(\"abc\", 0, 1, \"x\")");
+        set(&mut grid, &mut engine, "C6", "=REPLACE(\"abc\", 1, -1, \"x\")");
+        set(&mut grid, &mut engine, "C7", "=REPLACE(\"abc\", \"two\", 1, \"x\")");
+
+        let value = |a1: &str| grid.get_cell(CellRef::parse(a1).unwrap()).unwrap().value.clone();
+        let text = |s: &str| CellValue::Text(s.to_string());
+        assert_eq!(value("B1"), text("a,a;a"));
+        assert_eq!(value("B2"), text("a;a;a"));
+        assert_eq!(value("B3"), text("a,a,a"));
+        assert_eq!(value("B4"), text("aab"));
+        assert_eq!(value("B5"), text("naïve cafe"));
+        assert_eq!(value("B6"), text("abc"));
+        assert_eq!(value("B7"), CellValue::Error("VALUE".to_string()));
+        assert_eq!(value("C1"), text("naive café"));
+        assert_eq!(value("C2"), text("aXbc"));
+        assert_eq!(value("C3"), text("abc!"));
+        assert_eq!(value("C4"), text("a"));
+        for a1 in ["C5", "C6", "C7"] {
+            assert_eq!(value(a1), CellValue::Error("VALUE".to_string()), "{}", a1);
+        }
+    }
+
+    #[test]
+    fn test_find_and_search() {
+        let mut grid = Grid::new(20, 10);
+        let mut engine = FormulaEngine::new();
+        set(&mut grid, &mut engine, "A1", "SKU-1234-é");
+        let cases = [
+            ("=MID(A1, FIND(\"-\", A1)+1, 99)", CellValue::Text("1234-é".to_string())),
+            ("=LEFT(A1, SEARCH(\"-\", A1)-1)", CellValue::Text("SKU".to_string())),
+            ("=FIND(\"-\", A1, 5)", CellValue::Number(9.0)),
+            ("=FIND(\"é\", A1)", CellValue::Number(10.0)),
+            ("=FIND(\"\", A1, 3)", CellValue::Number(3.0)),
+            ("=SEARCH(\"sku\", A1)", CellValue::Number(1.0)),
+            ("=SEARCH(\"1?3\", A1)", CellValue::Number(5.0)),
+            ("=SEARCH(\"-*-\", A1, 2)", CellValue::Number(4.0)),
+            ("=SEARCH(\"~*\", \"a*b\")", CellValue::Number(2.0)),
+            ("=SEARCH(\"É\", A1)", CellValue::Number(10.0)),
+            ("=FIND(\"sku\", A1)", CellValue::Error("VALUE".to_string())),
+            ("=FIND(\"-\", A1, 10)", CellValue::Error("VALUE".to_string())),
+            ("=FIND(\"-\", A1, 0)", CellValue::Error("VALUE".to_string())),
+            ("=SEARCH(\"x\", A1, 20)", CellValue::Error("VALUE".to_string())),
+        ];
+        for (row, (text, expected)) in cases.into_iter().enumerate() {
+            let a1 = format!("B{}", row + 1);
+            set(&mut grid, &mut engine, &a1, text);
+            assert_eq!(grid.get_cell(CellRef::parse(&a1).unwrap()).unwrap().value, expected, "{}", text);
+        }
+    }
+
+    #[test]
+    fn test_case_and_trim() {
+        let mut grid = Grid::new(10, 10);
+        let mut engine = FormulaEngine::new();
+        set(&mut grid, &mut engine, "A1", "hello wORLD");
+        set(&mut grid, &mut engine, "B1", "=PROPER(\"hello world\")");
+        set(&mut grid, &mut engine, "B2", "=PROPER(A1)");
+        set(&mut grid, &mut engine, "B3", "=UPPER(A1)");
+        set(&mut grid, &mut engine, "B4", "=LOWER(A1)");
+        set(&mut grid, &mut engine, "B5", "=TRIM(\"  a   b  \")");
+        set(&mut grid, &mut engine, "B6", "=UPPER(TRUE)");
+        set(&mut grid, &mut engine, "B7", "=TRIM(1/0)");
+
+        let value = |a1: &str| grid.get_cell(CellRef::parse(a1).unwrap()).unwrap().value.clone();
+        let text = |s: &str| CellValue::Text(s.to_string());
+        assert_eq!(value("B1"), text("Hello World"));
+        assert_eq!(value("B2"), text("Hello World"));
+        assert_eq!(value("B3"), text("HELLO WORLD"));
+        assert_eq!(value("B4"), text("hello world"));
+        assert_eq!(value("B5"), text("a b"));
+        assert_eq!(value("B6"), text("TRUE"));
+        assert!(matches!(value("B7"), CellValue::Error(_)));
+    }
+
+    #[test]
+    fn test_case_and_trim_edge_cases() {
+        let mut grid = Grid::new(10, 10);
+        let mut engine = FormulaEngine::new();
+        set(&mut grid, &mut engine, "A1", "straße");
+        set(&mut grid, &mut engine, "A2", "x\t  y     z");
+        set(&mut grid, &mut engine, "A3", "élan\tvital");
+        set(&mut grid, &mut engine, "B1", "=UPPER(A1)");
+        set(&mut grid, &mut engine, "B2", "=TRIM(A2)");
+        set(&mut grid, &mut engine, "B3", "=PROPER(A3)");
+        set(&mut grid, &mut engine, "B4", "=LEN(UPPER(A1))");
+        set(&mut grid, &mut engine, "B5", "=LOWER(\"ÀÉÎ\")");
+
+        let value = |a1: &str| grid.get_cell(CellRef::parse(a1).unwrap()).unwrap().value.clone();
+        let text = |s: &str| CellValue::Text(s.to_string());
+        // Uppercasing can lengthen the text
+        assert_eq!(value("B1"), text("STRASSE"));
+        assert_eq!(value("B4"), CellValue::Number(7.0));
+        // Like Excel, TRIM only touches spaces: the tab stays
+        assert_eq!(value("B2"), text("x\t y z"));
+        // A tab still separates words for PROPER
+        assert_eq!(value("B3"), text("Élan\tVital"));
+        assert_eq!(value("B5"), text("àéî"));
+    }
+
+    #[test]
+    fn test_operator_precedence() {
+        let mut grid = Grid::new(10, 10);
+        let mut engine = FormulaEngine::new();
+        set(&mut grid, &mut engine, "A1", "3");
+        set(&mut grid, &mut engine, "A2", "4");
+
+        let cases = [
+            ("=1-2+3", CellValue::Number(2.0)),
+            ("=2^3^2", CellValue::Number(512.0)),
+            ("=2*3+4", CellValue::Number(10.0)),
+            ("=2+3*4", CellValue::Number(14.0)),
+            ("=8/4/2", CellValue::Number(1.0)),
+            ("=-A1+5", CellValue::Number(2.0)),
+            ("=-2^2", CellValue::Number(4.0)),
+            ("=2^-1", CellValue::Number(0.5)),
+            ("=-SUM(A1:A2)*2", CellValue::Number(-14.0)),
+            ("=SUM(A1:A2)+SUM(A1:A2)", CellValue::Number(14.0)),
+            ("=(1+2)*3", CellValue::Number(9.0)),
+            ("=50%*A2", CellValue::Number(2.0)),
+            ("=1+2&3", CellValue::Text("33".to_string())),
+            ("=A1+1>A2", CellValue::Boolean(false)),
+            ("=1<2=TRUE", CellValue::Boolean(true)),
+            ("=\"say \"\"hi\"\"\"&A1", CellValue::Text("say \"hi\"3".to_string())),
+        ];
+        for (text, expected) in cases {
+            let formula = engine.parse(text).unwrap();
+            assert_eq!(engine.evaluate(&formula.ast, &grid).unwrap(), expected, "{}", text);
+            // Printing keeps the grouping
+            let reparsed = engine.parse(&formula.ast.to_formula_string()).unwrap();
+            assert_eq!(engine.evaluate(&reparsed.ast, &grid).unwrap(), expected, "{}", text);
+        }
+        assert_eq!(engine.parse("=2^3^2").unwrap().ast.to_formula_string(), "=2^3^2");
+        assert_eq!(engine.parse("=(2^3)^2").unwrap().ast.to_formula_string(), "=(2^3)^2");
+        assert_eq!(engine.parse("=1-(2+3)").unwrap().ast.to_formula_string(), "=1-(2+3)");
+
+        for text in ["=1+", "=(1+2", "=SUM(A1", "=A1 A2", "=\"open", "=1.2.3"] {
+            assert!(matches!(engine.parse(text), Err(FormulaError::Parse(_))), "{}", text);
+        }
+    }
+
+    #[test]
+    fn test_unary_minus_and_percent() {
+        let mut grid = Grid::new(10, 10);
+        let mut engine = FormulaEngine::new();
+        set(&mut grid, &mut engine, "A1", "6");
+        set(&mut grid, &mut engine, "B1", "=-A1");
+        set(&mut grid, &mut engine, "B2", "=10%");
+        set(&mut grid, &mut engine, "B3", "=-(3+4)");
+        set(&mut grid, &mut engine, "B4", "=-2^2");
+        set(&mut grid, &mut engine, "B5", "=+A1%");
+        set(&mut grid, &mut engine, "B6", "=--A1");
+        set(&mut grid, &mut engine, "B7", "=200%%");
+
+        let value = |a1: &str| grid.get_cell(CellRef::parse(a1).unwrap()).unwrap().value.clone();
+        assert_eq!(value("B1"), CellValue::Number(-6.0));
+        assert_eq!(value("B2"), CellValue::Number(0.1));
+        assert_eq!(value("B3"), CellValue::Number(-7.0));
+        // As in Excel, negation binds tighter than ^
+        assert_eq!(value("B4"), CellValue::Number(4.0));
+        assert_eq!(value("B5"), CellValue::Number(0.06));
+        assert_eq!(value("B6"), CellValue::Number(6.0));
+        assert_eq!(value("B7"), CellValue::Number(0.02));
+
+        // The operators survive printing, as reference rewrites and pastes need
+        for (text, printed) in [("=-A1", "=-A1"), ("=10%", "=10%"), ("=-(3+4)", "=-(3+4)"), ("=-A1^2", "=-A1^2")] {
+            assert_eq!(engine.parse(text).unwrap().ast.to_formula_string(), printed);
+        }
+        set(&mut grid, &mut engine, "A1", "-1");
+        assert_eq!(grid.get_cell(CellRef::new(0, 1)).unwrap().value, CellValue::Number(1.0));
+    }
+
+    #[test]
+    fn test_comparison_and_concat_operators() {
+        let mut grid = Grid::new(10, 10);
+        let mut engine = FormulaEngine::new();
+        set(&mut grid, &mut engine, "A1", "3");
+        set(&mut grid, &mut engine, "A2", "5");
+        let cases = [
+            ("=1<2", CellValue::Boolean(true)),
+            ("=\"x\"&\"y\"", CellValue::Text("xy".to_string())),
+            ("=A1<>A2", CellValue::Boolean(true)),
+            ("=A1<=3", CellValue::Boolean(true)),
+            ("=A2>=6", CellValue::Boolean(false)),
+            ("=A1=3", CellValue::Boolean(true)),
+            ("=A1&\"<=\"&A2", CellValue::Text("3<=5".to_string())),
+            ("=\"total: \"&A1+A2", CellValue::Text("total: 8".to_string())),
+        ];
+        for (row, (text, expected)) in cases.into_iter().enumerate() {
+            let a1 = format!("B{}", row + 1);
+            set(&mut grid, &mut engine, &a1, text);
+            assert_eq!(grid.get_cell(CellRef::parse(&a1).unwrap()).unwrap().value, expected, "{}", text);
+        }
+        // Two-character operators aren't split
+        let ast = engine.parse("=A1<=A2").unwrap().ast;
+        assert!(matches!(ast, FormulaNode::BinaryOp { op: BinaryOp::Le, .. }));
+        assert_eq!(ast.to_formula_string(), "=A1<=A2");
+    }
+
+    #[test]
+    fn test_text_function() {
+        let mut grid = Grid::new(10, 10);
+        let mut engine = FormulaEngine::new();
+        set(&mut grid, &mut engine, "A1", "0.1234");
+        set(&mut grid, &mut engine, "A2", "1234567.891");
+        set(&mut grid, &mut engine, "B1", "=TEXT(A1, \"0.00%\")");
+        set(&mut grid, &mut engine, "B2", "=TEXT(A2, \"$#,##0.00\")");
+        set(&mut grid, &mut engine, "B3", "=TEXT(\"42\", \"0,000\")");
+        set(&mut grid, &mut engine, "B4", "=\"Total: \" & TEXT(A2, \"#,##0\")");
+        set(&mut grid, &mut engine, "B5", "=TEXT(A1, \"yyyy-mm-dd\")");
+        set(&mut grid, &mut engine, "B6", "=TEXT(\"abc\", \"0.00\")");
+        set(&mut grid, &mut engine, "B7", "=TEXT(A9, \"0.0\")");
+        let text = |a1: &str| grid.get_cell(CellRef::parse(a1).unwrap()).unwrap().value.clone();
+        assert_eq!(text("B1"), CellValue::Text("12.34%".to_string()));
+        assert_eq!(text("B2"), CellValue::Text("$1,234,567.89".to_string()));
+        assert_eq!(text("B3"), CellValue::Text("0,042".to_string()));
+        assert_eq!(text("B4"), CellValue::Text("Total: 1,234,568".to_string()));
+        assert_eq!(text("B5"), CellValue::Text("1899-12-30".to_string()));
+        assert_eq!(text("B6"), CellValue::Text("abc".to_string()));
+        assert_eq!(text("B7"), CellValue::Text("0.0".to_string()));
+    }
+
+    #[test]
+    fn test_value_n_and_t() {
+        let mut grid = Grid::new(10, 10);
+        let mut engine = FormulaEngine::new();
+        set(&mut grid, &mut engine, "A1", "abc");
+        set(&mut grid, &mut engine, "A2", "TRUE");
+        set(&mut grid, &mut engine, "A3", "12");
+        let cases = [
+            ("=VALUE(\"1,234.5\")", CellValue::Number(1234.5)),
+            ("=VALUE(\"45%\")", CellValue::Number(0.45)),
+            ("=VALUE(\" $1,200 \")", CellValue::Number(1200.0)),
+            ("=VALUE(\"(500)\")", CellValue::Number(-500.0)),
+            ("=VALUE(\"1e3\")", CellValue::Number(1000.0)),
+            ("=VALUE(A3)+1", CellValue::Number(13.0)),
+            ("=VALUE(\"12 apples\")", CellValue::Error("VALUE".to_string())),
+            ("=VALUE(A2)", CellValue::Error("VALUE".to_string())),
+            ("=N(A3)", CellValue::Number(12.0)),
+            ("=N(A2)", CellValue::Number(1.0)),
+            ("=N(\"5\")", CellValue::Number(0.0)),
+            ("=N(A9)", CellValue::Number(0.0)),
+            ("=T(A1)", CellValue::Text("abc".to_string())),
+            ("=T(A3)", CellValue::Text(String::new())),
+            ("=T(1/0)", CellValue::Error("DIV/0".to_string())),
+        ];
+        for (formula, expected) in cases {
+            set(&mut grid, &mut engine, "B1", formula);
+            assert_eq!(grid.get_cell(CellRef::parse("B1").unwrap()).map(|c| c.value.clone()).unwrap_or_default(), expected, "{}", formula);
+        }
+    }
+
+    #[test]
+    fn test_today_and_now_are_volatile() {
+        // 2024-02-29 18:00 UTC
+        let clock = std::rc::Rc::new(crate::clock::ManualClock::new(1_709_229_600_000.0));
+        let mut grid = Grid::with_clock(10, 10, clock.clone());
+        let mut engine = FormulaEngine::new();
+        set(&mut grid, &mut engine, "A1", "=TODAY()");
+        set(&mut grid, &mut engine, "A2", "=NOW()");
+        set(&mut grid, &mut engine, "A3", "=A1+1");
+        let value = |grid: &Grid, a1: &str| grid.get_cell(CellRef::parse(a1).unwrap()).unwrap().value.clone();
+        assert_eq!(value(&grid, "A1"), CellValue::Number(45_351.0));
+        assert_eq!(value(&grid, "A2"), CellValue::Number(45_351.75));
+        assert!(engine.formula_at(CellRef::parse("A1").unwrap()).unwrap().volatile);
+        assert!(!engine.formula_at(CellRef::parse("A3").unwrap()).unwrap().volatile);
+
+        // Any edit re-evaluates them, and their dependents, even though nothing they read changed
+        clock.advance(8.0 * 3_600_000.0);
+        set(&mut grid, &mut engine, "C1", "1");
+        assert_eq!(value(&grid, "A1"), CellValue::Number(45_352.0));
+        assert_eq!(value(&grid, "A3"), CellValue::Number(45_353.0));
+        assert_eq!(value(&grid, "A2"), CellValue::Number(datetime::unix_millis_to_serial(1_709_258_400_000.0)));
+
+        set(&mut grid, &mut engine, "A1", "5");
+        clock.advance(86_400_000.0);
+        set(&mut grid, &mut engine, "C1", "2");
+        assert_eq!(value(&grid, "A3"), CellValue::Number(6.0));
+        assert!(matches!(engine.parse("=TODAY(1)").map(|f| engine.evaluate(&f.ast, &grid)), Ok(Err(FormulaError::ArgumentCount { .. }))));
+    }
+
+    #[test]
+    fn test_named_ranges() {
+        let mut grid = Grid::new(20, 10);
+        let mut engine = FormulaEngine::new();
+        let value = |grid: &Grid, a1: &str| grid.get_cell(CellRef::parse(a1).unwrap()).unwrap().value.clone();
+        set(&mut grid, &mut engine, "B3", "1200");
+        set(&mut grid, &mut engine, "B4", "300");
+        engine.define_name(&mut grid, "Revenue", CellRef::new(2, 1), CellRef::new(2, 1)).unwrap();
+        engine.define_name(&mut grid, "Costs", CellRef::new(3, 1), CellRef::new(2, 1)).unwrap();
+
+        set(&mut grid, &mut engine, "C1", "=Revenue*2");
+        set(&mut grid, &mut engine, "C2", "=SUM(revenue, 5)");
+        set(&mut grid, &mut engine, "C3", "=SUM(Costs)");
+        assert_eq!(value(&grid, "C1"), CellValue::Number(2400.0));
+        assert_eq!(value(&grid, "C2"), CellValue::Number(1205.0));
+        assert_eq!(value(&grid, "C3"), CellValue::Number(1500.0));
+        assert_eq!(engine.named_range("COSTS"), Some((CellRef::new(2, 1), CellRef::new(3, 1))));
+
+        // A name is a real dependency, and the formula keeps it as written
+        set(&mut grid, &mut engine, "B3", "1000");
+        assert_eq!(value(&grid, "C1"), CellValue::Number(2000.0));
+        assert_eq!(value(&grid, "C3"), CellValue::Number(1300.0));
+        assert_eq!(engine.formula_at(CellRef::new(0, 2)).unwrap().raw, "=Revenue*2");
+
+        // Redefining a name moves the formulas using it along
+        engine.define_name(&mut grid, "REVENUE", CellRef::new(3, 1), CellRef::new(3, 1)).unwrap();
+        assert_eq!(value(&grid, "C1"), CellValue::Number(600.0));
+        assert_eq!(engine.formula_at(CellRef::new(0, 2)).unwrap().raw, "=Revenue*2");
+
+        // Once removed, formulas using it can't be read and keep their last value
+        assert!(engine.remove_name(&mut grid, "revenue").unwrap().is_some());
+        assert!(engine.remove_name(&mut grid, "revenue").unwrap().is_none());
+        assert!(engine.parse("=Revenue*2").is_err());
+        assert!(grid.is_foreign(CellRef::new(0, 2)));
+        assert_eq!(value(&grid, "C1"), CellValue::Number(600.0));
+        for invalid in ["TAX2024", "2x", "Gross margin", "true", ""] {
+            let defined = engine.define_name(&mut grid, invalid, CellRef::new(0, 0), CellRef::new(0, 0));
+            assert!(matches!(defined, Err(FormulaError::InvalidName(_))), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_date_functions() {
+        let mut grid = Grid::new(20, 10);
+        let mut engine = FormulaEngine::new();
+        set(&mut grid, &mut engine, "A1", "2024-02-29");
+        set(&mut grid, &mut engine, "A2", "45292.75");
+        set(&mut grid, &mut engine, "A3", "not a date");
+        let serial = |y, m, d| CellValue::Number(datetime::date_to_serial(y, m, d).unwrap());
+        let num = CellValue::Error("NUM".to_string());
+        for (formula, expected) in [
+            ("=DATE(2024, 1, 15)", serial(2024, 1, 15)),
+            ("=DATE(2024, 13, 1)", serial(2025, 1, 1)),
+            ("=DATE(2024, 3, 0)", serial(2024, 2, 29)),
+            ("=DATE(2023, 2, 29)", serial(2023, 3, 1)),
+            ("=DATE(2100, 2, 29)", serial(2100, 3, 1)),
+            ("=DATE(99, 12, 31.9)", serial(1999, 12, 31)),
+            ("=DATE(\"2024\", 6, 1)", serial(2024, 6, 1)),
+            ("=DATE(10000, 1, 1)", num.clone()),
+            ("=DATE(-1, 1, 1)", num.clone()),
+            ("=DATE(\"soon\", 1, 1)", CellValue::Error("VALUE".to_string())),
+            ("=YEAR(A1)", CellValue::Number(2024.0)),
+            ("=MONTH(A1)", CellValue::Number(2.0)),
+            ("=DAY(A1)", CellValue::Number(29.0)),
+            ("=DAY(A1+1)", CellValue::Number(1.0)),
+            ("=YEAR(A2)", CellValue::Number(2024.0)),
+            ("=DAY(\"2024-12-31\")", CellValue::Number(31.0)),
+            ("=MONTH(DATE(2024, 14, 1))", CellValue::Number(2.0)),
+            ("=YEAR(DATE(2100, 12, 31))", CellValue::Number(2100.0)),
+            ("=WEEKDAY(A2)", CellValue::Number(2.0)),
+            ("=WEEKDAY(A2, 2)", CellValue::Number(1.0)),
+            ("=WEEKDAY(A2, 3)", CellValue::Number(0.0)),
+            ("=WEEKDAY(DATE(2024, 3, 3))", CellValue::Number(1.0)),
+            ("=WEEKDAY(DATE(2024, 3, 3), 2)", CellValue::Number(7.0)),
+            ("=WEEKDAY(A2, 4)", num.clone()),
+            ("=YEAR(-1)", num.clone()),
+            ("=YEAR(3000000)", num.clone()),
+            ("=MONTH(A3)", CellValue::Error("VALUE".to_string())),
+        ] {
+            set(&mut grid, &mut engine, "B1", formula);
+            assert_eq!(grid.get_cell(CellRef::new(0, 1)).unwrap().value, expected, "{}", formula);
+        }
+    }
+
+    #[test]
+    fn test_month_arithmetic_functions() {
+        let mut grid = Grid::new(20, 10);
+        let mut engine = FormulaEngine::new();
+        set(&mut grid, &mut engine, "A1", "2024-01-31");
+        set(&mut grid, &mut engine, "A2", "2019-06-15");
+        let serial = |y, m, d| CellValue::Number(datetime::date_to_serial(y, m, d).unwrap());
+        let num = CellValue::Error("NUM".to_string());
+        for (formula, expected) in [
+            ("=EDATE(A1, 1)", serial(2024, 2, 29)),
+            ("=EDATE(A1, 13)", serial(2025, 2, 28)),
+            ("=EDATE(A1, -2)", serial(2023, 11, 30)),
+            ("=EDATE(A1, 1.9)", serial(2024, 2, 29)),
+            ("=EDATE(\"2023-01-31\", 1)", serial(2023, 2, 28)),
+            ("=EOMONTH(A1, 0)", serial(2024, 1, 31)),
+            ("=EOMONTH(A2, 8)", serial(2020, 2, 29)),
+            ("=EOMONTH(A2, -6)", serial(2018, 12, 31)),
+            ("=EDATE(A1, \"soon\")", CellValue::Error("VALUE".to_string())),
+            ("=EDATE(-5, 1)", num.clone()),
+            ("=DATEDIF(A2, A1, \"Y\")", CellValue::Number(4.0)),
+            ("=DATEDIF(A2, A1, \"m\")", CellValue::Number(55.0)),
+            ("=DATEDIF(A2, A1, \"YM\")", CellValue::Number(7.0)),
+            ("=DATEDIF(A2, A1, \"MD\")", CellValue::Number(16.0)),
+            ("=DATEDIF(A2, A1, \"YD\")", CellValue::Number(230.0)),
+            ("=DATEDIF(A2, A1, \"D\")", CellValue::Number(1691.0)),
+            ("=DATEDIF(A1, A2, \"D\")", num.clone()),
+            ("=DATEDIF(A2, A1, \"W\")", num.clone()),
+        ] {
+            set(&mut grid, &mut engine, "B1", formula);
+            assert_eq!(grid.get_cell(CellRef::new(0, 1)).unwrap().value, expected, "{}", formula);
+        }
+    }
+
+    #[test]
+    fn test_workday_functions() {
+        let mut grid = Grid::new(20, 10);
+        let mut engine = FormulaEngine::new();
+        // Monday 2024-07-01 to Friday 2024-07-12, with a Thursday holiday and one on a Saturday
+        set(&mut grid, &mut engine, "A1", "2024-07-01");
+        set(&mut grid, &mut engine, "A2", "2024-07-12");
+        set(&mut grid, &mut engine, "C1", "2024-07-04");
+        set(&mut grid, &mut engine, "C2", "2024-07-06");
+        set(&mut grid, &mut engine, "C3", "office party");
+        set(&mut grid, &mut engine, "D1", "2024-07-04");
+        set(&mut grid, &mut engine, "D2", "=1/0");
+        let serial = |y, m, d| CellValue::Number(datetime::date_to_serial(y, m, d).unwrap());
+        for (formula, expected) in [
+            ("=WORKDAY(A1, 5)", serial(2024, 7, 8)),
+            ("=WORKDAY(A1, 5, C1:C4)", serial(2024, 7, 9)),
+            ("=WORKDAY(A1, -1, C1:C4)", serial(2024, 6, 28)),
+            ("=WORKDAY(\"2024-07-08\", -3, C1:C4)", serial(2024, 7, 2)),
+            ("=WORKDAY(\"2024-07-06\", 0)", serial(2024, 7, 6)),
+            ("=WORKDAY(A1, 2, C1)", serial(2024, 7, 3)),
+            ("=WORKDAY(2, -5)", CellValue::Error("NUM".to_string())),
+            ("=WORKDAY(A1, \"x\")", CellValue::Error("VALUE".to_string())),
+            ("=NETWORKDAYS(A1, A2)", CellValue::Number(10.0)),
+            ("=NETWORKDAYS(A1, A2, C1:C4)", CellValue::Number(9.0)),
+            ("=NETWORKDAYS(A2, A1, C1:C4)", CellValue::Number(-9.0)),
+            ("=NETWORKDAYS(\"2024-07-06\", \"2024-07-07\", C1:C4)", CellValue::Number(0.0)),
+            ("=NETWORKDAYS(A1, A2, D1:D2)", CellValue::Error("DIV/0".to_string())),
+        ] {
+            set(&mut grid, &mut engine, "B1", formula);
+            assert_eq!(grid.get_cell(CellRef::new(0, 1)).unwrap().value, expected, "{}", formula);
+        }
+        assert!(matches!(engine.parse("=WORKDAY(A1)").map(|f| engine.evaluate(&f.ast, &grid)), Ok(Err(FormulaError::ArgumentCount { .. }))));
+    }
+
+    #[test]
+    fn test_median_and_mode() {
+        let mut grid = Grid::new(10, 10);
+        let mut engine = FormulaEngine::new();
+        for (a1, input) in [("A1", "4"), ("A2", "1"), ("A3", "3"), ("A4", "1"), ("A5", "text"), ("B1", "3")] {
+            set(&mut grid, &mut engine, a1, input);
+        }
+        let cases = [
+            ("=MEDIAN(A1:A5)", CellValue::Number(2.0)),
+            ("=MEDIAN(A1:A3)", CellValue::Number(3.0)),
+            ("=MEDIAN(7)", CellValue::Number(7.0)),
+            ("=MEDIAN(A1:A4, 10, B1)", CellValue::Number(3.0)),
+            ("=MODE(A1:A5)", CellValue::Number(1.0)),
+            // 1 and 3 both appear twice; the smaller wins
+            ("=MODE(A1:A5, B1)", CellValue::Number(1.0)),
+            ("=MODE(B1, A1:A3)", CellValue::Number(3.0)),
+            ("=MODE(1, 2, 3)", CellValue::Error("N/A".to_string())),
+            ("=MEDIAN(A5, C1:C3)", CellValue::Error("NUM".to_string())),
+            ("=MODE(C1:C3)", CellValue::Error("NUM".to_string())),
+        ];
+        for (formula, expected) in cases {
+            set(&mut grid, &mut engine, "D1", formula);
+            assert_eq!(grid.get_cell(CellRef::parse("D1").unwrap()).unwrap().value, expected, "{}", formula);
+        }
+    }
+
+    #[test]
+    fn test_textsplit_single_row() {
+        let mut grid = Grid::new(10, 10);
+        let mut engine = FormulaEngine::new();
+        set(&mut grid, &mut engine, "A1", "=TEXTSPLIT(\"a,b,c\", \",\")");
+        
+        let row: Vec<CellValue> = (0..4)
+            .map(|col| grid.get_cell(CellRef::new(0, col)).map(|c| c.value.clone()).unwrap_or_default())
+            .collect();
+        assert_eq!(row, vec![
+            CellValue::Text("a".to_string()),
+            CellValue::Text("b".to_string()),
+            CellValue::Text("c".to_string()),
+            CellValue::Empty,
+        ]);
+    }
+
+    #[test]
+    fn test_textsplit_rows_and_empty_pieces() {
+        let mut grid = Grid::new(10, 10);
+        let mut engine = FormulaEngine::new();
+        set(&mut grid, &mut engine, "A1", "=TEXTSPLIT(\"a,,b;c,d,\", \",\", \";\")");
+        
+        let text = |s: &str| CellValue::Text(s.to_string());
+        let expected = [
+            [text("a"), CellValue::Empty, text("b")],
+            [text("c"), text("d"), CellValue::Empty],
+        ];
+        for (r, row) in expected.iter().enumerate() {
+            for (c, value) in row.iter().enumerate() {
+                let got = grid.get_cell(CellRef::new(r as u32, c as u32)).map(|c| c.value.clone()).unwrap_or_default();
+                assert_eq!(&got, value, "row {} col {}", r, c);
+            }
+        }
+        
+        // Ragged rows are padded with #N/A
+        set(&mut grid, &mut engine, "A5", "=TEXTSPLIT(\"a,b;c\", \",\", \";\")");
+        assert_eq!(grid.get_cell(CellRef::new(5, 1)).unwrap().value, CellValue::Error("N/A".to_string()));
+        
+        let formula = engine.parse("=TEXTSPLIT(\"a\", \"\")").unwrap();
+        assert!(engine.evaluate(&formula.ast, &grid).is_err());
+    }
+
+    #[test]
+    fn test_sumif_and_countif() {
+        let mut grid = Grid::new(20, 10);
+        let mut engine = FormulaEngine::new();
+        let expenses = [("Food - groceries", "120"), ("Travel", "300"), ("food - dining", "45"), ("Rent", "1500"), ("Food", "n/a")];
+        for (i, (category, amount)) in expenses.iter().enumerate() {
+            set(&mut grid, &mut engine, &format!("A{}", i + 1), category);
+            set(&mut grid, &mut engine, &format!("B{}", i + 1), amount);
+        }
+        let value = |grid: &Grid, a1: &str| grid.get_cell(CellRef::parse(a1).unwrap()).unwrap().value.clone();
+        
+        set(&mut grid, &mut engine, "D1", "=SUMIF(A1:A5, \"Food*\", B1:B5)");
+        assert_eq!(value(&grid, "D1"), CellValue::Number(165.0));
+        set(&mut grid, &mut engine, "D2", "=SUMIF(B1:B5, \">100\")");
+        assert_eq!(value(&grid, "D2"), CellValue::Number(1920.0));
+        set(&mut grid, &mut engine, "D3", "=COUNTIF(B1:B5, \"<>0\")");
+        assert_eq!(value(&grid, "D3"), CellValue::Number(5.0));
+        set(&mut grid, &mut engine, "D4", "=COUNTIF(A1:A5, \"food\")");
+        assert_eq!(value(&grid, "D4"), CellValue::Number(1.0));
+        set(&mut grid, &mut engine, "D6", "=SUMIF(B1:B5, \">=300\")");
+        assert_eq!(value(&grid, "D6"), CellValue::Number(1800.0));
+        
+        // Criteria may come from a cell, and results follow edits
+        set(&mut grid, &mut engine, "F1", "300");
+        set(&mut grid, &mut engine, "D5", "=COUNTIF(B1:B5, F1)");
+        assert_eq!(value(&grid, "D5"), CellValue::Number(1.0));
+        set(&mut grid, &mut engine, "B2", "45");
+        assert_eq!(value(&grid, "D5"), CellValue::Number(0.0));
+        assert_eq!(value(&grid, "D2"), CellValue::Number(1620.0));
+        assert_eq!(value(&grid, "D6"), CellValue::Number(1500.0));
+        
+        let mismatched = engine.parse("=SUMIF(A1:A5, \"Food*\", B1:B4)").unwrap();
+        assert!(matches!(engine.evaluate(&mismatched.ast, &grid), Err(FormulaError::ShapeMismatch(_))));
+        let too_few = engine.parse("=COUNTIF(A1:A5)").unwrap();
+        assert!(matches!(engine.evaluate(&too_few.ast, &grid), Err(FormulaError::ArgumentCount { .. })));
+    }
+
+    #[test]
+    fn test_multi_criteria_aggregates() {
+        let mut grid = Grid::new(20, 10);
+        let mut engine = FormulaEngine::new();
+        // Budget lines: department, quarter, amount
+        let lines = [("Eng", "Q1", "100"), ("Eng", "Q2", "150"), ("Sales", "Q1", "80"), ("Eng", "Q1", "40"), ("Sales", "Q2", "n/a")];
+        for (i, (dept, quarter, amount)) in lines.iter().enumerate() {
+            set(&mut grid, &mut engine, &format!("A{}", i + 1), dept);
+            set(&mut grid, &mut engine, &format!("B{}", i + 1), quarter);
+            set(&mut grid, &mut engine, &format!("C{}", i + 1), amount);
+        }
+        set(&mut grid, &mut engine, "E1", "Eng");
+        set(&mut grid, &mut engine, "E2", "Q1");
+        let value = |grid: &Grid, a1: &str| grid.get_cell(CellRef::parse(a1).unwrap()).unwrap().value.clone();
+        
+        set(&mut grid, &mut engine, "F1", "=SUMIFS(C1:C5, A1:A5, E1, B1:B5, E2)");
+        assert_eq!(value(&grid, "F1"), CellValue::Number(140.0));
+        set(&mut grid, &mut engine, "F2", "=COUNTIFS(A1:A5, E1, C1:C5, \">50\")");
+        assert_eq!(value(&grid, "F2"), CellValue::Number(2.0));
+        set(&mut grid, &mut engine, "F3", "=AVERAGEIFS(C1:C5, B1:B5, E2)");
+        assert_eq!(value(&grid, "F3"), CellValue::Number(220.0 / 3.0));
+        // Text amounts are skipped, leaving nothing to average
+        set(&mut grid, &mut engine, "F4", "=AVERAGEIFS(C1:C5, A1:A5, \"Sales\", B1:B5, \"Q2\")");
+        assert_eq!(value(&grid, "F4"), CellValue::Error("DIV/0".to_string()));
+        
+        // Criteria cells drive the results
+        set(&mut grid, &mut engine, "E1", "Sales");
+        assert_eq!(value(&grid, "F1"), CellValue::Number(80.0));
+        assert_eq!(value(&grid, "F2"), CellValue::Number(1.0));
+        
+        let mismatched = engine.parse("=SUMIFS(C1:C5, A1:A5, E1, B1:B4, E2)").unwrap();
+        assert!(matches!(engine.evaluate(&mismatched.ast, &grid), Err(FormulaError::ShapeMismatch(_))));
+        for unpaired in ["=SUMIFS(C1:C5, A1:A5)", "=COUNTIFS(A1:A5, E1, B1:B5)", "=AVERAGEIFS(C1:C5)"] {
+            let formula = engine.parse(unpaired).unwrap();
+            assert!(matches!(engine.evaluate(&formula.ast, &grid), Err(FormulaError::ArgumentCount { .. })), "{}", unpaired);
+        }
+    }
+
+    #[test]
+    fn test_vlookup() {
+        let mut grid = Grid::new(20, 10);
+        let mut engine = FormulaEngine::new();
+        // Tax brackets: threshold, rate, label
+        let table = [("0", "0.1", "Low"), ("10000", "0.2", "Mid"), ("50000", "0.4", "High")];
+        for (i, (threshold, rate, label)) in table.iter().enumerate() {
+            set(&mut grid, &mut engine, &format!("A{}", i + 1), threshold);
+            set(&mut grid, &mut engine, &format!("B{}", i + 1), rate);
+            set(&mut grid, &mut engine, &format!("C{}", i + 1), label);
+        }
+        let value = |grid: &Grid, a1: &str| grid.get_cell(CellRef::parse(a1).unwrap()).unwrap().value.clone();
+        
+        // Exact match, including case-insensitive text keys
+        set(&mut grid, &mut engine, "E1", "=VLOOKUP(10000, A1:C3, 3, FALSE)");
+        assert_eq!(value(&grid, "E1"), CellValue::Text("Mid".to_string()));
+        set(&mut grid, &mut engine, "E2", "=VLOOKUP(\"high\", C1:C3, 1, FALSE)");
+        assert_eq!(value(&grid, "E2"), CellValue::Text("High".to_string()));
+        
+        // Approximate match (the default) on the sorted first column
+        set(&mut grid, &mut engine, "E3", "=VLOOKUP(25000, A1:C3, 2)");
+        assert_eq!(value(&grid, "E3"), CellValue::Number(0.2));
+        set(&mut grid, &mut engine, "E4", "=VLOOKUP(99999, A1:C3, 3, TRUE)");
+        assert_eq!(value(&grid, "E4"), CellValue::Text("High".to_string()));
+        
+        // Not found
+        set(&mut grid, &mut engine, "E5", "=VLOOKUP(25000, A1:C3, 2, FALSE)");
+        assert_eq!(value(&grid, "E5"), CellValue::Error("N/A".to_string()));
+        set(&mut grid, &mut engine, "E6", "=VLOOKUP(-1, A1:C3, 2)");
+        assert_eq!(value(&grid, "E6"), CellValue::Error("N/A".to_string()));
+        
+        let out_of_table = engine.parse("=VLOOKUP(0, A1:C3, 4, FALSE)").unwrap();
+        assert!(matches!(engine.evaluate(&out_of_table.ast, &grid), Err(FormulaError::InvalidRef(_))));
+    }
+
+    #[test]
+    fn test_hlookup() {
+        let mut grid = Grid::new(20, 10);
+        let mut engine = FormulaEngine::new();
+        // A 3x4 block: quarter headers over revenue and cost
+        let rows = [["Q1", "Q2", "Q3", "Q4"], ["100", "120", "150", "170"], ["60", "70", "80", "90"]];
+        for (r, values) in rows.iter().enumerate() {
+            for (c, value) in values.iter().enumerate() {
+                set(&mut grid, &mut engine, &format!("{}{}", CellRef::col_to_letter(c as u32), r + 1), value);
+            }
+        }
+        let value = |grid: &Grid, a1: &str| grid.get_cell(CellRef::parse(a1).unwrap()).unwrap().value.clone();
+        
+        set(&mut grid, &mut engine, "F1", "=HLOOKUP(\"q3\", A1:D3, 2, FALSE)");
+        assert_eq!(value(&grid, "F1"), CellValue::Number(150.0));
+        set(&mut grid, &mut engine, "F2", "=HLOOKUP(\"Q4\", A1:D3, 3, FALSE)");
+        assert_eq!(value(&grid, "F2"), CellValue::Number(90.0));
+        
+        // Approximate match on a numeric header row
+        set(&mut grid, &mut engine, "F3", "=HLOOKUP(130, A2:D3, 2)");
+        assert_eq!(value(&grid, "F3"), CellValue::Number(70.0));
+        
+        set(&mut grid, &mut engine, "F4", "=HLOOKUP(\"Q5\", A1:D3, 2, FALSE)");
+        assert_eq!(value(&grid, "F4"), CellValue::Error("N/A".to_string()));
+        set(&mut grid, &mut engine, "F5", "=HLOOKUP(\"Q1\", A1:D3, 4, FALSE)");
+        assert_eq!(value(&grid, "F5"), CellValue::Error("REF".to_string()));
+    }
+
+    #[test]
+    fn test_hlookup_offset_table() {
+        let mut grid = Grid::new(20, 10);
+        let mut engine = FormulaEngine::new();
+        // Months across C5:F5 with headcount below, away from the sheet's origin
+        let rows = [["Jan", "Feb", "Mar", "Apr"], ["12", "14", "15", "19"]];
+        for (r, values) in rows.iter().enumerate() {
+            for (c, value) in values.iter().enumerate() {
+                set(&mut grid, &mut engine, &format!("{}{}", CellRef::col_to_letter(2 + c as u32), r + 5), value);
+            }
+        }
+        let value = |grid: &Grid, a1: &str| grid.get_cell(CellRef::parse(a1).unwrap()).unwrap().value.clone();
+        
+        set(&mut grid, &mut engine, "A1", "=HLOOKUP(\"Mar\", C5:F6, 2, FALSE)");
+        assert_eq!(value(&grid, "A1"), CellValue::Number(15.0));
+        set(&mut grid, &mut engine, "A2", "=HLOOKUP(\"Jan\", C5:F6, 1, FALSE)");
+        assert_eq!(value(&grid, "A2"), CellValue::Text("Jan".to_string()));
+        // The block's columns, not the sheet's, are searched
+        set(&mut grid, &mut engine, "A3", "=HLOOKUP(\"Apr\", D5:E6, 2, FALSE)");
+        assert_eq!(value(&grid, "A3"), CellValue::Error("N/A".to_string()));
+        set(&mut grid, &mut engine, "A4", "=HLOOKUP(\"Feb\", C5:F6, 3, FALSE)");
+        assert_eq!(value(&grid, "A4"), CellValue::Error("REF".to_string()));
+        
+        // Follows edits to the returned row
+        set(&mut grid, &mut engine, "E6", "16");
+        assert_eq!(value(&grid, "A1"), CellValue::Number(16.0));
+    }
+
+    #[test]
+    fn test_xlookup() {
+        let mut grid = Grid::new(20, 10);
+        let mut engine = FormulaEngine::new();
+        // Unsorted ids in A1:A5 with names in C1:C5; a duplicate id 30
+        let rows = [("30", "Cara"), ("10", "Abe"), ("50", "Eve"), ("30", "Cy"), ("20", "Bo")];
+        for (r, (id, name)) in rows.iter().enumerate() {
+            set(&mut grid, &mut engine, &format!("A{}", r + 1), id);
+            set(&mut grid, &mut engine, &format!("C{}", r + 1), name);
+        }
+        let text = |s: &str| CellValue::Text(s.to_string());
+        let lookup = |grid: &mut Grid, engine: &mut FormulaEngine, formula: &str| {
+            set(grid, engine, "E1", formula);
+            grid.get_cell(CellRef::new(0, 4)).unwrap().value.clone()
+        };
+        
+        assert_eq!(lookup(&mut grid, &mut engine, "=XLOOKUP(50, A1:A5, C1:C5)"), text("Eve"));
+        assert_eq!(lookup(&mut grid, &mut engine, "=XLOOKUP(40, A1:A5, C1:C5)"), CellValue::Error("N/A".to_string()));
+        assert_eq!(lookup(&mut grid, &mut engine, "=XLOOKUP(40, A1:A5, C1:C5, \"none\")"), text("none"));
+        // Next smaller / larger don't need sorted keys
+        assert_eq!(lookup(&mut grid, &mut engine, "=XLOOKUP(45, A1:A5, C1:C5, \"none\", -1)"), text("Cara"));
+        assert_eq!(lookup(&mut grid, &mut engine, "=XLOOKUP(25, A1:A5, C1:C5, \"none\", 1)"), text("Cara"));
+        assert_eq!(lookup(&mut grid, &mut engine, "=XLOOKUP(60, A1:A5, C1:C5, \"none\", 1)"), text("none"));
+        // Reverse search finds the last duplicate
+        assert_eq!(lookup(&mut grid, &mut engine, "=XLOOKUP(30, A1:A5, C1:C5, \"none\", 0, -1)"), text("Cy"));
+        assert_eq!(lookup(&mut grid, &mut engine, "=XLOOKUP(25, A1:A5, C1:C5, \"none\", 1, -1)"), text("Cy"));
+        // Wildcards, searching names and returning ids
+        assert_eq!(lookup(&mut grid, &mut engine, "=XLOOKUP(\"e*\", C1:C5, A1:A5, 0, 2)"), CellValue::Number(50.0));
+        
+        // if_not_found isn't evaluated when there's a match
+        assert_eq!(lookup(&mut grid, &mut engine, "=XLOOKUP(10, A1:A5, C1:C5, 1/0)"), text("Abe"));
+        assert_eq!(lookup(&mut grid, &mut engine, "=XLOOKUP(11, A1:A5, C1:C5, 1/0)"), CellValue::Error("DIV/0".to_string()));
+        
+        // Ranges of different lengths, and bad modes
+        assert_eq!(lookup(&mut grid, &mut engine, "=XLOOKUP(10, A1:A5, C1:C4)"), CellValue::Error("VALUE".to_string()));
+        assert_eq!(lookup(&mut grid, &mut engine, "=XLOOKUP(10, A1:A5, C1:C5, 0, 3)"), CellValue::Error("VALUE".to_string()));
+        
+        // Follows edits to the return range
+        assert_eq!(lookup(&mut grid, &mut engine, "=XLOOKUP(20, A1:A5, C1:C5)"), text("Bo"));
+        set(&mut grid, &mut engine, "C5", "Bea");
+        assert_eq!(grid.get_cell(CellRef::new(0, 4)).unwrap().value, text("Bea"));
+    }
+
+    #[test]
+    fn test_iferror_and_ifna() {
+        let mut grid = Grid::new(20, 10);
+        let mut engine = FormulaEngine::new();
+        let value = |grid: &Grid, a1: &str| grid.get_cell(CellRef::parse(a1).unwrap()).unwrap().value.clone();
+        set(&mut grid, &mut engine, "A1", "apple");
+        set(&mut grid, &mut engine, "B1", "3");
+
+        set(&mut grid, &mut engine, "C1", "=IFERROR(1/0, 0)");
+        assert_eq!(value(&grid, "C1"), CellValue::Number(0.0));
+        set(&mut grid, &mut engine, "C2", "=IFERROR(B1*2, 0)");
+        assert_eq!(value(&grid, "C2"), CellValue::Number(6.0));
+        // Errors held by referenced cells are caught too
+        set(&mut grid, &mut engine, "C3", "=IFERROR(C4, \"none\")");
+        set(&mut grid, &mut engine, "C4", "=B1/0");
+        assert_eq!(value(&grid, "C3"), CellValue::Text("none".to_string()));
+
+        set(&mut grid, &mut engine, "D1", "=IFNA(VLOOKUP(\"pear\", A1:B1, 2, FALSE), \"missing\")");
+        assert_eq!(value(&grid, "D1"), CellValue::Text("missing".to_string()));
+        set(&mut grid, &mut engine, "D2", "=IFNA(VLOOKUP(\"apple\", A1:B1, 2, FALSE), \"missing\")");
+        assert_eq!(value(&grid, "D2"), CellValue::Number(3.0));
+        // IFNA lets other errors through
+        set(&mut grid, &mut engine, "D3", "=IFNA(1/0, \"missing\")");
+        assert_eq!(value(&grid, "D3"), CellValue::Error("DIV/0".to_string()));
+
+        // A broken fallback is still an error
+        set(&mut grid, &mut engine, "E1", "=IFERROR(1/0, NOSUCHFN(1))");
+        assert_eq!(value(&grid, "E1"), CellValue::Error("NAME".to_string()));
+    }
+
+    #[test]
+    fn test_index_and_match() {
+        let mut grid = Grid::new(20, 10);
+        let mut engine = FormulaEngine::new();
+        let rows = [("Apples", "30", "5"), ("Pears", "20", "4"), ("Plums", "10", "3")];
+        for (i, (name, price, stock)) in rows.iter().enumerate() {
+            set(&mut grid, &mut engine, &format!("A{}", i + 1), name);
+            set(&mut grid, &mut engine, &format!("B{}", i + 1), price);
+            set(&mut grid, &mut engine, &format!("C{}", i + 1), stock);
+        }
+        let value = |grid: &Grid, a1: &str| grid.get_cell(CellRef::parse(a1).unwrap()).unwrap().value.clone();
+        
+        // INDEX with only a row on a single column, and with both indexes
+        set(&mut grid, &mut engine, "E1", "=INDEX(A1:A3, 2)");
+        assert_eq!(value(&grid, "E1"), CellValue::Text("Pears".to_string()));
+        set(&mut grid, &mut engine, "E2", "=INDEX(A1:C3, 3, 2)");
+        assert_eq!(value(&grid, "E2"), CellValue::Number(10.0));
+        set(&mut grid, &mut engine, "E3", "=INDEX(A1:C1, 3)");
+        assert_eq!(value(&grid, "E3"), CellValue::Number(5.0));
+        set(&mut grid, &mut engine, "E4", "=INDEX(A1:A3, 4)");
+        assert_eq!(value(&grid, "E4"), CellValue::Error("REF".to_string()));
+        
+        set(&mut grid, &mut engine, "F1", "=MATCH(\"plums\", A1:A3, 0)");
+        assert_eq!(value(&grid, "F1"), CellValue::Number(3.0));
+        // Descending prices: smallest value at least 15
+        set(&mut grid, &mut engine, "F2", "=MATCH(15, B1:B3, -1)");
+        assert_eq!(value(&grid, "F2"), CellValue::Number(2.0));
+        // Ascending sizes in a row: largest value at most 4.5
+        for (a1, size) in [("A5", "3"), ("B5", "4"), ("C5", "5")] {
+            set(&mut grid, &mut engine, a1, size);
+        }
+        set(&mut grid, &mut engine, "F3", "=MATCH(4.5, A5:C5)");
+        assert_eq!(value(&grid, "F3"), CellValue::Number(2.0));
+        set(&mut grid, &mut engine, "F6", "=MATCH(2, A5:C5, 1)");
+        assert_eq!(value(&grid, "F6"), CellValue::Error("N/A".to_string()));
+        set(&mut grid, &mut engine, "F4", "=MATCH(\"Kiwis\", A1:A3, 0)");
+        assert_eq!(value(&grid, "F4"), CellValue::Error("N/A".to_string()));
+        set(&mut grid, &mut engine, "F5", "=MATCH(20, A1:C3, 0)");
+        assert_eq!(value(&grid, "F5"), CellValue::Error("N/A".to_string()));
+        
+        // Combined
+        set(&mut grid, &mut engine, "G1", "=INDEX(C1:C3, MATCH(\"Pears\", A1:A3, 0))");
+        assert_eq!(value(&grid, "G1"), CellValue::Number(4.0));
+    }
+
+    #[test]
+    fn test_cycle_is_isolated() {
+        let mut grid = Grid::new(10, 10);
+        let mut engine = FormulaEngine::new();
+        set(&mut grid, &mut engine, "A1", "1");
+        set(&mut grid, &mut engine, "B1", "=A1*2");
+        set(&mut grid, &mut engine, "C1", "=D1+1");
+        set(&mut grid, &mut engine, "E1", "=C1+1");
+        let value = |grid: &Grid, a1: &str| grid.get_cell(CellRef::parse(a1).unwrap()).unwrap().value.clone();
+        let circular = CellValue::Error("CIRCULAR".to_string());
+        
+        // Closing the loop marks the cycle (and what reads it) instead of failing the edit
+        let d1 = CellRef::parse("D1").unwrap();
+        let update = CellUpdate { row: d1.row, col: d1.col, value: None, formula: Some("=C1+1".to_string()) };
+        let affected = grid.apply_patch(GridPatch { updates: vec![update], auto_expand: None }, &mut engine).unwrap();
+        assert_eq!(value(&grid, "C1"), circular);
+        assert_eq!(value(&grid, "D1"), circular);
+        assert!(matches!(value(&grid, "E1"), CellValue::Error(_)));
+        let mut cycle = GridDiff::from_cells(&grid, &engine, &affected).circular;
+        cycle.sort_by_key(|cell| (cell.row, cell.col));
+        assert_eq!(cycle, vec![CellRef::parse("C1").unwrap(), d1]);
+        
+        // Unrelated formulas keep updating
+        set(&mut grid, &mut engine, "A1", "5");
+        assert_eq!(value(&grid, "B1"), CellValue::Number(10.0));
+        set(&mut grid, &mut engine, "F1", "=F1+1");
+        assert_eq!(value(&grid, "F1"), circular);
+        assert_eq!(engine.recalculate_all(&mut grid).unwrap(), Vec::new());
+        
+        // Breaking the loop recovers
+        set(&mut grid, &mut engine, "D1", "3");
+        assert_eq!(value(&grid, "C1"), CellValue::Number(4.0));
+        assert_eq!(value(&grid, "E1"), CellValue::Number(5.0));
+        assert!(!engine.is_circular(d1));
+    }
+
+    #[test]
+    fn test_circular_reference_names_its_cells() {
+        let mut grid = Grid::new(10, 10);
+        let mut engine = FormulaEngine::new();
+        set(&mut grid, &mut engine, "B1", "=A1");
+        set(&mut grid, &mut engine, "A1", "=B1+C1");
+        set(&mut grid, &mut engine, "D1", "=D1");
+        let (a1, b1) = (CellRef::parse("A1").unwrap(), CellRef::parse("B1").unwrap());
+        for cell in [a1, b1] {
+            assert_eq!(grid.get_cell(cell).unwrap().value, CellValue::Error("CIRCULAR".to_string()));
+        }
+        assert_eq!(engine.cycle_through(b1), vec![a1, b1]);
+        assert_eq!(engine.cycle_through(CellRef::parse("D1").unwrap()), vec![CellRef::parse("D1").unwrap()]);
+        assert_eq!(engine.cycle_through(CellRef::parse("C1").unwrap()), Vec::new());
+
+        // Evaluating around the cycle with overrides reports it rather than looping
+        let formula = engine.parse("=B1").unwrap();
+        let overrides = HashMap::from([(CellRef::parse("C1").unwrap(), CellValue::Number(1.0))]);
+        let error = engine.evaluate_with_overrides(&formula, &grid, overrides).unwrap_err();
+        assert!(matches!(&error, FormulaError::CircularReference(cells) if *cells == vec![a1, b1]));
+        assert_eq!(error.to_string(), "Circular reference: A1, B1");
+    }
+}
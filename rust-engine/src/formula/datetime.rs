@@ -0,0 +1,338 @@
+//! Date serial numbers, as Excel stores dates
+//!
+//! A date is the number of days since 1899-12-30, so 1 is 1899-12-31 and
+//! 45292 is 2024-01-01; the fraction is the time of day. Excel's serials
+//! count a February 29, 1900 that never was, which puts its epoch one day
+//! earlier for dates before March 1900; like most other tools we use the
+//! real calendar throughout, so those early serials differ by a day.
+//! Times are UTC, since the engine doesn't know the user's time zone.
+
+use std::collections::HashSet;
+
+/// Serial number of 1970-01-01, the Unix epoch
+const UNIX_EPOCH_SERIAL: f64 = 25_569.0;
+
+const MILLIS_PER_DAY: f64 = 86_400_000.0;
+
+/// Serial number of a calendar date, or `None` if there's no such day
+pub fn date_to_serial(year: i64, month: u32, day: u32) -> Option<f64> {
+    if !(1..=12).contains(&month) || day == 0 || day > days_in_month(year, month) {
+        return None;
+    }
+    // Days since 1970-01-01 (proleptic Gregorian), then shift to the 1899-12-30 epoch
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let mp = (month as i64 + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146_097 + doe - 719_468;
+    Some(days as f64 + UNIX_EPOCH_SERIAL)
+}
+
+/// Year, month and day of the date a serial number falls on, ignoring the time of day
+pub fn serial_to_date(serial: f64) -> (i64, u32, u32) {
+    // Inverse of the day count in `date_to_serial`
+    let days = (serial - UNIX_EPOCH_SERIAL).floor() as i64 + 719_468;
+    let era = days.div_euclid(146_097);
+    let doe = days - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    (era * 400 + yoe + i64::from(month <= 2), month, day)
+}
+
+/// Serial number of the last day Excel can show, 9999-12-31
+pub const MAX_SERIAL: f64 = 2_958_465.0;
+
+/// Serial number of a date given as DATE's arguments, which may overflow
+///
+/// Months past 12 or below 1 roll into later or earlier years and days
+/// past the end of the month (or below 1) into later or earlier months, so
+/// month 13 of 2024 is January 2025 and day 0 of March is the last day of
+/// February. Years below 1900 count from 1900, as in Excel. `None` if the
+/// year is negative or past 9999, or the date lands outside the serials
+/// Excel can show.
+pub fn date_from_parts(year: i64, month: i64, day: i64) -> Option<f64> {
+    if !(0..=9999).contains(&year) {
+        return None;
+    }
+    let year = if year < 1900 { year + 1900 } else { year };
+    let year = year.checked_add((month - 1).div_euclid(12))?;
+    let month = ((month - 1).rem_euclid(12) + 1) as u32;
+    in_range(date_to_serial(year, month, 1)? + (day - 1) as f64)
+}
+
+/// The date `months` calendar months after a serial's date, time of day dropped
+///
+/// A day past the end of the new month is clamped to its last day, so
+/// January 31 plus one month is February 28, or 29 in a leap year. `None`
+/// outside the serials Excel can show.
+pub fn add_months(serial: f64, months: i64) -> Option<f64> {
+    let (year, month, day) = serial_to_date(serial);
+    let (year, month) = shift_month(year, month, months)?;
+    in_range(date_to_serial(year, month, day.min(days_in_month(year, month)))?)
+}
+
+/// The last day of the month `months` months after a serial's date
+pub fn end_of_month(serial: f64, months: i64) -> Option<f64> {
+    let (year, month, _) = serial_to_date(serial);
+    let (year, month) = shift_month(year, month, months)?;
+    in_range(date_to_serial(year, month, days_in_month(year, month))?)
+}
+
+/// The year and month `months` months on from `year` and `month`
+fn shift_month(year: i64, month: u32, months: i64) -> Option<(i64, u32)> {
+    let index = (year.checked_mul(12)? + i64::from(month) - 1).checked_add(months)?;
+    Some((index.div_euclid(12), (index.rem_euclid(12) + 1) as u32))
+}
+
+fn in_range(serial: f64) -> Option<f64> {
+    (0.0..=MAX_SERIAL).contains(&serial).then_some(serial)
+}
+
+/// Whole units from `start` to `end`, as DATEDIF counts them; `None` if
+/// `end` comes first or `unit` isn't one DATEDIF knows
+///
+/// `Y`, `M` and `D` count whole years, whole months and days. `YM` is the
+/// months left over after the whole years, `YD` the days since the last
+/// anniversary of `start`, and `MD` the days left over after the whole
+/// months. Where Excel's `MD` can go negative after a long month (from
+/// January 31 to March 1), this counts from the end of the short month.
+pub fn date_difference(start: f64, end: f64, unit: &str) -> Option<f64> {
+    let (start, end) = (start.floor(), end.floor());
+    if start > end {
+        return None;
+    }
+    let (y1, m1, d1) = serial_to_date(start);
+    let (y2, m2, d2) = serial_to_date(end);
+    let months = (y2 - y1) * 12 + i64::from(m2) - i64::from(m1) - i64::from(d2 < d1);
+    Some(match unit.to_ascii_uppercase().as_str() {
+        "Y" => (months / 12) as f64,
+        "M" => months as f64,
+        "D" => end - start,
+        "YM" => (months % 12) as f64,
+        "YD" => end - add_months(start, months / 12 * 12)?,
+        "MD" if d2 >= d1 => f64::from(d2 - d1),
+        "MD" => {
+            let (year, month) = shift_month(y2, m2, -1)?;
+            let last = days_in_month(year, month);
+            f64::from(last - d1.min(last) + d2)
+        }
+        _ => return None,
+    })
+}
+
+/// Day of the week of a serial number, 0 for Sunday through 6 for Saturday
+pub fn weekday(serial: f64) -> u32 {
+    // Serial 1, 1899-12-31, was a Sunday
+    (serial.floor() as i64 - 1).rem_euclid(7) as u32
+}
+
+/// Whether a serial's date is a Saturday or a Sunday
+pub fn is_weekend(serial: f64) -> bool {
+    matches!(weekday(serial), 0 | 6)
+}
+
+/// The working day `days` working days after `start`, or before it if
+/// `days` is negative; `None` outside the serials Excel can show
+///
+/// Weekends and the serials in `holidays` aren't working days. With `days`
+/// zero the answer is `start` itself, working day or not.
+pub fn add_workdays(start: f64, days: i64, holidays: &HashSet<i64>) -> Option<f64> {
+    let step = days.signum();
+    let mut date = start.floor() as i64;
+    let mut remaining = days.abs();
+    while remaining > 0 {
+        date += step;
+        in_range(date as f64)?;
+        if !is_weekend(date as f64) && !holidays.contains(&date) {
+            remaining -= 1;
+        }
+    }
+    Some(date as f64)
+}
+
+/// Working days from `start` to `end`, both included, negative if `end`
+/// comes first
+///
+/// Weekends and the serials in `holidays` aren't counted.
+pub fn workdays_between(start: f64, end: f64, holidays: &HashSet<i64>) -> i64 {
+    let (start, end) = (start.floor() as i64, end.floor() as i64);
+    if start > end {
+        return -workdays_between(end as f64, start as f64, holidays);
+    }
+    let off = holidays.iter()
+        .filter(|&&day| (start..=end).contains(&day) && !is_weekend(day as f64))
+        .count() as i64;
+    weekdays_before(end + 1) - weekdays_before(start) - off
+}
+
+/// Monday-to-Friday serials from 0 up to, but not including, `serial`
+fn weekdays_before(serial: i64) -> i64 {
+    // Shifted so that the remainder by 7 is the day of the week, 0 for Sunday
+    let count = |shifted: i64| shifted.div_euclid(7) * 5 + (shifted.rem_euclid(7) - 1).clamp(0, 5);
+    count(serial + 6) - count(6)
+}
+
+/// Serial number of a Unix timestamp in milliseconds, time of day included
+pub fn unix_millis_to_serial(millis: f64) -> f64 {
+    millis / MILLIS_PER_DAY + UNIX_EPOCH_SERIAL
+}
+
+pub fn days_in_month(year: i64, month: u32) -> u32 {
+    match month {
+        2 if (year % 4 == 0 && year % 100 != 0) || year % 400 == 0 => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_serials_match_excel() {
+        assert_eq!(date_to_serial(1900, 3, 1), Some(61.0));
+        assert_eq!(date_to_serial(1970, 1, 1), Some(25_569.0));
+        assert_eq!(date_to_serial(2024, 1, 1), Some(45_292.0));
+        assert_eq!(date_to_serial(2024, 2, 29), Some(45_351.0));
+        assert_eq!(date_to_serial(2024, 3, 1), Some(45_352.0));
+        assert_eq!(date_to_serial(2023, 2, 29), None);
+        assert_eq!(date_to_serial(1900, 2, 29), None);
+        assert_eq!(date_to_serial(2024, 13, 1), None);
+    }
+
+    #[test]
+    fn test_serials_round_trip_across_leap_years() {
+        let (first, last) = (date_to_serial(1896, 1, 1).unwrap(), date_to_serial(2104, 12, 31).unwrap());
+        let mut serial = first;
+        while serial <= last {
+            let (year, month, day) = serial_to_date(serial);
+            assert_eq!(date_to_serial(year, month, day), Some(serial), "{}", serial);
+            // The time of day doesn't change the date
+            assert_eq!(serial_to_date(serial + 0.999), (year, month, day));
+            serial += 1.0;
+        }
+        assert_eq!(serial_to_date(45_351.0), (2024, 2, 29));
+        assert_eq!(serial_to_date(73_109.0), (2100, 2, 28));
+        assert_eq!(serial_to_date(73_110.0), (2100, 3, 1));
+    }
+
+    #[test]
+    fn test_date_parts_overflow_like_excel() {
+        assert_eq!(date_from_parts(2024, 13, 1), date_to_serial(2025, 1, 1));
+        assert_eq!(date_from_parts(2024, 0, 1), date_to_serial(2023, 12, 1));
+        assert_eq!(date_from_parts(2024, -11, 1), date_to_serial(2023, 1, 1));
+        assert_eq!(date_from_parts(2024, 2, 30), date_to_serial(2024, 3, 1));
+        assert_eq!(date_from_parts(2023, 2, 29), date_to_serial(2023, 3, 1));
+        assert_eq!(date_from_parts(2024, 3, 0), date_to_serial(2024, 2, 29));
+        assert_eq!(date_from_parts(2100, 3, 0), date_to_serial(2100, 2, 28));
+        assert_eq!(date_from_parts(2024, 1, -30), date_to_serial(2023, 12, 1));
+        assert_eq!(date_from_parts(2024, 1, 400), date_to_serial(2025, 2, 3));
+        // End of month from "day 0 of next month"
+        for (month, last) in [(1, 31), (2, 29), (4, 30), (12, 31)] {
+            assert_eq!(date_from_parts(2024, month + 1, 0), date_to_serial(2024, month as u32, last), "{}", month);
+        }
+        // Two-digit and small years count from 1900
+        assert_eq!(date_from_parts(24, 1, 1), date_to_serial(1924, 1, 1));
+        assert_eq!(date_from_parts(1900, 1, 1), Some(2.0));
+        assert_eq!(date_from_parts(9999, 12, 31), Some(MAX_SERIAL));
+        assert_eq!(date_from_parts(9999, 12, 32), None);
+        assert_eq!(date_from_parts(10_000, 1, 1), None);
+        assert_eq!(date_from_parts(-1, 1, 1), None);
+        assert_eq!(date_from_parts(1900, 1, -5), None);
+    }
+
+    #[test]
+    fn test_month_arithmetic_clamps_to_month_end() {
+        let date = |y, m, d| date_to_serial(y, m, d).unwrap();
+        assert_eq!(add_months(date(2024, 1, 31), 1), Some(date(2024, 2, 29)));
+        assert_eq!(add_months(date(2023, 1, 31), 1), Some(date(2023, 2, 28)));
+        assert_eq!(add_months(date(2100, 1, 31), 1), Some(date(2100, 2, 28)));
+        assert_eq!(add_months(date(2000, 1, 31), 1), Some(date(2000, 2, 29)));
+        assert_eq!(add_months(date(2024, 3, 31), -1), Some(date(2024, 2, 29)));
+        assert_eq!(add_months(date(2024, 5, 31), 1), Some(date(2024, 6, 30)));
+        assert_eq!(add_months(date(2024, 2, 29), 12), Some(date(2025, 2, 28)));
+        assert_eq!(add_months(date(2024, 11, 15) + 0.5, 2), Some(date(2025, 1, 15)));
+        assert_eq!(add_months(date(1900, 1, 1), -1), None);
+        assert_eq!(add_months(date(9999, 12, 1), 1), None);
+        assert_eq!(end_of_month(date(2024, 1, 15), 1), Some(date(2024, 2, 29)));
+        assert_eq!(end_of_month(date(2024, 1, 15), 0), Some(date(2024, 1, 31)));
+        assert_eq!(end_of_month(date(2024, 1, 15), -2), Some(date(2023, 11, 30)));
+        assert_eq!(end_of_month(date(2099, 12, 31), 2), Some(date(2100, 2, 28)));
+    }
+
+    #[test]
+    fn test_date_difference_units() {
+        let date = |y, m, d| date_to_serial(y, m, d).unwrap();
+        let diff = |start, end, unit| date_difference(start, end, unit);
+        let (start, end) = (date(2020, 2, 29), date(2024, 2, 28));
+        assert_eq!(diff(start, end, "Y"), Some(3.0));
+        assert_eq!(diff(start, end, "M"), Some(47.0));
+        assert_eq!(diff(start, end, "D"), Some(end - start));
+        assert_eq!(diff(start, end, "YM"), Some(11.0));
+        assert_eq!(diff(start, end, "MD"), Some(30.0));
+        assert_eq!(diff(start, end, "YD"), Some(end - date(2023, 2, 28)));
+        assert_eq!(diff(start, date(2024, 2, 29), "y"), Some(4.0));
+        assert_eq!(diff(date(2024, 1, 31), date(2024, 2, 29), "M"), Some(0.0));
+        assert_eq!(diff(date(2024, 1, 31), date(2024, 3, 1), "MD"), Some(1.0));
+        assert_eq!(diff(date(2024, 1, 15), date(2024, 1, 15), "D"), Some(0.0));
+        assert_eq!(diff(end, start, "D"), None);
+        assert_eq!(diff(start, end, "W"), None);
+    }
+
+    #[test]
+    fn test_weekday() {
+        assert_eq!(weekday(1.0), 0);
+        assert_eq!(weekday(0.0), 6);
+        // 2024-01-01 was a Monday, 2100-03-01 a Monday
+        assert_eq!(weekday(45_292.0), 1);
+        assert_eq!(weekday(45_292.75), 1);
+        assert_eq!(weekday(73_110.0), 1);
+    }
+
+    #[test]
+    fn test_workdays() {
+        let date = |y, m, d| date_to_serial(y, m, d).unwrap();
+        let none = HashSet::new();
+        // 2024-01-05 was a Friday
+        assert_eq!(add_workdays(date(2024, 1, 5), 1, &none), Some(date(2024, 1, 8)));
+        assert_eq!(add_workdays(date(2024, 1, 8), -1, &none), Some(date(2024, 1, 5)));
+        assert_eq!(add_workdays(date(2024, 1, 6), 0, &none), Some(date(2024, 1, 6)));
+        assert_eq!(add_workdays(date(2024, 1, 6), 1, &none), Some(date(2024, 1, 8)));
+        assert_eq!(add_workdays(date(2024, 1, 6), -1, &none), Some(date(2024, 1, 5)));
+        let new_year = HashSet::from([date(2024, 1, 1) as i64]);
+        assert_eq!(add_workdays(date(2023, 12, 29), 1, &new_year), Some(date(2024, 1, 2)));
+        assert_eq!(add_workdays(2.0, -5, &none), None);
+
+        // The counting shortcut agrees with walking day by day
+        let holidays = HashSet::from([date(2024, 1, 1) as i64, date(2024, 1, 13) as i64, date(2024, 1, 17) as i64]);
+        let walked = |start: f64, end: f64| {
+            (start as i64..=end as i64)
+                .filter(|&day| !is_weekend(day as f64) && !holidays.contains(&day))
+                .count() as i64
+        };
+        for start in 0..20 {
+            for end in start..40 {
+                let (start, end) = (date(2023, 12, 25) + f64::from(start), date(2023, 12, 25) + f64::from(end));
+                assert_eq!(workdays_between(start, end, &holidays), walked(start, end), "{} to {}", start, end);
+                if start < end {
+                    assert_eq!(workdays_between(end, start, &holidays), -walked(start, end));
+                }
+            }
+        }
+        assert_eq!(workdays_between(0.0, 6.0, &none), 5);
+    }
+
+    #[test]
+    fn test_unix_millis_to_serial() {
+        assert_eq!(unix_millis_to_serial(0.0), 25_569.0);
+        // 2024-02-29 18:00 UTC
+        assert_eq!(unix_millis_to_serial(1_709_229_600_000.0), 45_351.75);
+    }
+}
@@ -0,0 +1,227 @@
+//! Annuity functions: PMT, FV, PV, NPER and RATE
+//!
+//! All five solve the same equation relating a loan or savings plan's
+//! present value, future value, payment per period, number of periods and
+//! rate per period:
+//!
+//! ```text
+//! pv*(1+rate)^nper + pmt*(1+rate*type)*((1+rate)^nper - 1)/rate + fv = 0
+//! ```
+//!
+//! which at a rate of 0 becomes `pv + pmt*nper + fv = 0`. As in Excel, cash
+//! paid out is negative and cash received positive, and `type` is 0 for
+//! payments at the end of each period or 1 for payments at the start.
+//! PMT, FV, PV and NPER have closed forms; RATE is found by Newton's method.
+
+use super::{FormulaEngine, FormulaError, FormulaNode};
+use crate::cell::CellValue;
+use crate::grid::Grid;
+
+/// Newton steps RATE takes before giving up with #NUM
+const MAX_RATE_ITERATIONS: usize = 100;
+
+/// RATE has converged once a step moves the rate by less than this
+const RATE_TOLERANCE: f64 = 1e-12;
+
+/// `(1+rate)^nper` and `(1+rate)^nper - 1`, the latter accurate for small rates
+fn growth(rate: f64, nper: f64) -> (f64, f64) {
+    let growth_minus_one = (nper * rate.ln_1p()).exp_m1();
+    (growth_minus_one + 1.0, growth_minus_one)
+}
+
+/// The payment each period
+pub fn pmt(rate: f64, nper: f64, pv: f64, fv: f64, due: bool) -> f64 {
+    if rate == 0.0 {
+        return -(pv + fv) / nper;
+    }
+    let (g, g1) = growth(rate, nper);
+    let due = if due { 1.0 + rate } else { 1.0 };
+    -(pv * g + fv) * rate / (due * g1)
+}
+
+/// The value after the last period
+pub fn fv(rate: f64, nper: f64, pmt: f64, pv: f64, due: bool) -> f64 {
+    if rate == 0.0 {
+        return -(pv + pmt * nper);
+    }
+    let (g, g1) = growth(rate, nper);
+    let due = if due { 1.0 + rate } else { 1.0 };
+    -(pv * g + pmt * due * g1 / rate)
+}
+
+/// The value now of the payments and final value
+pub fn pv(rate: f64, nper: f64, pmt: f64, fv: f64, due: bool) -> f64 {
+    if rate == 0.0 {
+        return -(fv + pmt * nper);
+    }
+    let (g, g1) = growth(rate, nper);
+    let due = if due { 1.0 + rate } else { 1.0 };
+    -(fv + pmt * due * g1 / rate) / g
+}
+
+/// The number of periods, or `None` if no number of them balances
+pub fn nper(rate: f64, pmt: f64, pv: f64, fv: f64, due: bool) -> Option<f64> {
+    if rate == 0.0 {
+        return (pmt != 0.0).then(|| -(pv + fv) / pmt);
+    }
+    if rate <= -1.0 {
+        return None;
+    }
+    let annuity = pmt * if due { 1.0 + rate } else { 1.0 } / rate;
+    let ratio = (annuity - fv) / (annuity + pv);
+    (ratio > 0.0).then(|| ratio.ln() / rate.ln_1p())
+}
+
+/// The rate per period, starting the search at `guess`, or `None` if it doesn't converge
+pub fn rate(nper: f64, pmt: f64, pv: f64, fv: f64, due: bool, guess: f64) -> Option<f64> {
+    let t = if due { 1.0 } else { 0.0 };
+    let mut rate = guess;
+    for _ in 0..MAX_RATE_ITERATIONS {
+        if rate <= -1.0 || !rate.is_finite() {
+            return None;
+        }
+        // The balance equation and its derivative in the rate
+        let (value, slope) = if rate == 0.0 {
+            (pv + pmt * nper + fv, pv * nper + pmt * (t * nper + nper * (nper - 1.0) / 2.0))
+        } else {
+            let (g, g1) = growth(rate, nper);
+            let dg = nper * g / (1.0 + rate);
+            let due = 1.0 + rate * t;
+            let value = pv * g + pmt * due * g1 / rate + fv;
+            let slope = pv * dg + pmt * (t * g1 / rate + due * (dg * rate - g1) / (rate * rate));
+            (value, slope)
+        };
+        if slope == 0.0 || !slope.is_finite() {
+            return None;
+        }
+        let step = value / slope;
+        rate -= step;
+        if step.abs() < RATE_TOLERANCE {
+            return (rate > -1.0).then_some(rate);
+        }
+    }
+    None
+}
+
+impl FormulaEngine {
+    /// PMT(rate, nper, pv, [fv], [type]), FV(rate, nper, pmt, [pv], [type]),
+    /// PV(rate, nper, pmt, [fv], [type]), NPER(rate, pmt, pv, [fv], [type])
+    /// and RATE(nper, pmt, pv, [fv], [type], [guess])
+    ///
+    /// Omitted values are 0, except RATE's `guess`, which is 10%. A `type`
+    /// other than 0 means payments at the start of each period. Anything
+    /// with no answer, including a RATE that doesn't converge, is #NUM.
+    pub(super) fn fn_annuity(&self, func: &str, args: &[FormulaNode], grid: &Grid) -> Result<CellValue, FormulaError> {
+        let max_args = if func == "RATE" { 6 } else { 5 };
+        if !(3..=max_args).contains(&args.len()) {
+            return Err(FormulaError::ArgumentCount {
+                func: func.to_string(),
+                expected: format!("3 to {}", max_args),
+                got: args.len(),
+            });
+        }
+        let numbers = match self.number_args(args, grid)? {
+            Ok(numbers) => numbers,
+            Err(error) => return Ok(error),
+        };
+        let [a, b, c] = [numbers[0], numbers[1], numbers[2]];
+        let d = numbers.get(3).copied().unwrap_or(0.0);
+        let due = numbers.get(4).is_some_and(|&t| t != 0.0);
+        let result = match func {
+            "PMT" => Some(pmt(a, b, c, d, due)),
+            "FV" => Some(fv(a, b, c, d, due)),
+            "PV" => Some(pv(a, b, c, d, due)),
+            "NPER" => nper(a, b, c, d, due),
+            _ => rate(a, b, c, d, due, numbers.get(5).copied().unwrap_or(0.1)),
+        };
+        Ok(match result {
+            Some(n) if n.is_finite() => CellValue::Number(n),
+            _ => CellValue::Error("NUM".to_string()),
+        })
+    }
+
+    /// Each argument as a number: blanks are 0, errors come back as they
+    /// are and text that isn't a number is #VALUE
+    fn number_args(&self, args: &[FormulaNode], grid: &Grid) -> Result<Result<Vec<f64>, CellValue>, FormulaError> {
+        let mut numbers = Vec::with_capacity(args.len());
+        for arg in args {
+            match self.evaluate(arg, grid)? {
+                error @ CellValue::Error(_) => return Ok(Err(error)),
+                CellValue::Empty => numbers.push(0.0),
+                value => match value.to_number() {
+                    Some(n) => numbers.push(n),
+                    None => return Ok(Err(CellValue::Error("VALUE".to_string()))),
+                },
+            }
+        }
+        Ok(Ok(numbers))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cell::CellRef;
+    use crate::grid::{CellUpdate, GridPatch};
+
+    /// Reference values computed by Excel, from its function documentation
+    #[test]
+    fn test_closed_forms_match_excel() {
+        let close = |actual: f64, expected: f64| (actual - expected).abs() <= 1e-9 * expected.abs();
+        assert!(close(pmt(0.08 / 12.0, 10.0, 10_000.0, 0.0, false), -1037.0320893591636));
+        assert!(close(pmt(0.08 / 12.0, 10.0, 10_000.0, 0.0, true), -1030.1643271779772));
+        assert!(close(fv(0.06 / 12.0, 10.0, -200.0, -500.0, true), 2581.4033740601362));
+        assert!(close(fv(0.005, 120.0, -100.0, 0.0, false), 16387.9346806458));
+        assert!(close(pv(0.08 / 12.0, 240.0, 500.0, 0.0, false), -59777.14585118777));
+        assert!(close(nper(0.01, -100.0, -1000.0, 10_000.0, true).unwrap(), 59.67386567429457));
+        assert!(close(nper(0.01, -100.0, -1000.0, 10_000.0, false).unwrap(), 60.08212285376166));
+        assert!(close(nper(0.01, -100.0, -1000.0, 0.0, false).unwrap(), -9.578594039813161));
+        assert!(close(rate(48.0, -200.0, 8000.0, 0.0, false, 0.1).unwrap(), 0.007701472488201622));
+    }
+
+    #[test]
+    fn test_zero_rate_is_linear() {
+        assert_eq!(pmt(0.0, 10.0, 1000.0, 0.0, true), -100.0);
+        assert_eq!(fv(0.0, 10.0, -100.0, -500.0, false), 1500.0);
+        assert_eq!(pv(0.0, 10.0, -100.0, 0.0, false), 1000.0);
+        assert_eq!(nper(0.0, -100.0, 1000.0, 0.0, false), Some(10.0));
+        assert_eq!(nper(0.0, 0.0, 1000.0, 0.0, false), None);
+        assert!(rate(10.0, -100.0, 1000.0, 0.0, false, 0.1).unwrap().abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_annuity_functions_in_formulas() {
+        let mut grid = Grid::new(10, 10);
+        let mut engine = FormulaEngine::new();
+        let value = |grid: &mut Grid, engine: &mut FormulaEngine, formula: &str| {
+            let update = CellUpdate { row: 0, col: 0, value: None, formula: Some(formula.to_string()) };
+            grid.apply_patch(GridPatch { updates: vec![update], auto_expand: None }, engine).unwrap();
+            grid.get_cell(CellRef::new(0, 0)).unwrap().value.clone()
+        };
+        let round = |value: CellValue| match value {
+            CellValue::Number(n) => CellValue::Number((n * 1e6).round() / 1e6),
+            other => other,
+        };
+        let error = |code: &str| CellValue::Error(code.to_string());
+
+        assert_eq!(round(value(&mut grid, &mut engine, "=PMT(8%/12, 10, 10000)")), CellValue::Number(-1037.032089));
+        assert_eq!(round(value(&mut grid, &mut engine, "=PMT(8%/12, 10, 10000, 0, 1)")), CellValue::Number(-1030.164327));
+        assert_eq!(round(value(&mut grid, &mut engine, "=FV(6%/12, 10, -200, -500, 1)")), CellValue::Number(2581.403374));
+        assert_eq!(round(value(&mut grid, &mut engine, "=PV(8%/12, 240, 500)")), CellValue::Number(-59777.145851));
+        assert_eq!(round(value(&mut grid, &mut engine, "=NPER(1%, -100, -1000, 10000, 1)")), CellValue::Number(59.673866));
+        assert_eq!(round(value(&mut grid, &mut engine, "=RATE(48, -200, 8000)*12")), CellValue::Number(0.092418));
+        assert_eq!(round(value(&mut grid, &mut engine, "=RATE(48, -200, 8000, , , 0.5)*12")), CellValue::Number(0.092418));
+
+        // No rate makes receiving both the loan and the payments balance
+        assert_eq!(value(&mut grid, &mut engine, "=RATE(10, 100, 1000)"), error("NUM"));
+        // Payments smaller than the interest never pay the loan off
+        assert_eq!(value(&mut grid, &mut engine, "=NPER(1%, -5, 1000)"), error("NUM"));
+        assert_eq!(value(&mut grid, &mut engine, "=PMT(5%, 0, 1000)"), error("NUM"));
+        assert_eq!(value(&mut grid, &mut engine, "=PMT(\"five\", 10, 1000)"), error("VALUE"));
+        assert_eq!(value(&mut grid, &mut engine, "=PV(1/0, 10, 100)"), error("DIV/0"));
+        assert!(matches!(
+            engine.parse("=PMT(1%, 10)").and_then(|f| engine.evaluate(&f.ast, &grid)),
+            Err(FormulaError::ArgumentCount { .. })
+        ));
+    }
+}
@@ -0,0 +1,312 @@
+//! Export of the dependency graph, for drawing a model's calculation structure
+//!
+//! Nodes are the cells formulas read or live in, labeled by A1 reference
+//! and annotated with the formula (shortened), whether the cell shows an
+//! error and how many formulas read it directly. Edges point from a cell to
+//! the formulas that read it. The output is Graphviz DOT or a JSON node and
+//! edge list, in row-major order so the same sheet always exports the same
+//! text. Past `max_nodes` cells the export is cut short and says so.
+
+use super::{FormulaEngine, FormulaError};
+use crate::cell::{CellRef, CellValue};
+use crate::grid::Grid;
+use petgraph::graph::NodeIndex;
+use petgraph::Direction;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashSet};
+
+/// Default for `GraphExportOptions::max_nodes`
+pub const DEFAULT_MAX_GRAPH_NODES: usize = 2_000;
+
+/// Formula text longer than this many characters is shortened with `…`
+const FORMULA_LABEL_CHARS: usize = 40;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum GraphFormat {
+    #[default]
+    Dot,
+    Json,
+}
+
+/// Options for `FormulaEngine::export_graph`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct GraphExportOptions {
+    #[serde(default)]
+    pub format: GraphFormat,
+    /// An A1-style range such as `B2:D10`: only its cells, the cells they
+    /// depend on and the formulas depending on them are exported
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub range: Option<String>,
+    /// Most cells exported; the first ones in row-major order are kept
+    #[serde(default = "default_max_nodes")]
+    pub max_nodes: usize,
+}
+
+impl Default for GraphExportOptions {
+    fn default() -> Self {
+        Self { format: GraphFormat::default(), range: None, max_nodes: DEFAULT_MAX_GRAPH_NODES }
+    }
+}
+
+fn default_max_nodes() -> usize {
+    DEFAULT_MAX_GRAPH_NODES
+}
+
+/// The JSON form of an export
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct GraphExport {
+    nodes: Vec<GraphNode>,
+    edges: Vec<GraphEdge>,
+    /// Cells the export would hold without the cap
+    total_nodes: usize,
+    truncated: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct GraphNode {
+    /// A1 reference
+    id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    formula: Option<String>,
+    error: bool,
+    /// Formulas reading the cell directly
+    dependents: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct GraphEdge {
+    from: String,
+    to: String,
+}
+
+impl FormulaEngine {
+    /// The dependency graph as DOT or JSON text, as `options` asks
+    ///
+    /// Fails only when `options.range` isn't an A1-style range.
+    pub fn export_graph(&self, grid: &Grid, options: &GraphExportOptions) -> Result<String, FormulaError> {
+        let export = self.graph_export(grid, options)?;
+        Ok(match options.format {
+            GraphFormat::Dot => to_dot(&export),
+            GraphFormat::Json => serde_json::to_string(&export).expect("graph export serializes"),
+        })
+    }
+
+    fn graph_export(&self, grid: &Grid, options: &GraphExportOptions) -> Result<GraphExport, FormulaError> {
+        let graph = &self.dep_graph;
+        // Cells left without formula or edges once their formula was removed don't belong
+        let connected = |node: NodeIndex| {
+            self.formulas.contains_key(&graph[node]) || graph.neighbors_undirected(node).next().is_some()
+        };
+        let mut nodes: Vec<NodeIndex> = match &options.range {
+            None => graph.node_indices().filter(|&node| connected(node)).collect(),
+            Some(range) => {
+                let (start, end) = parse_range(range)?;
+                let inside = |cell: CellRef| {
+                    (start.row..=end.row).contains(&cell.row) && (start.col..=end.col).contains(&cell.col)
+                };
+                let seeds: Vec<NodeIndex> = graph.node_indices()
+                    .filter(|&node| connected(node) && inside(graph[node]))
+                    .collect();
+                let mut reached: HashSet<NodeIndex> = seeds.iter().copied().collect();
+                for direction in [Direction::Outgoing, Direction::Incoming] {
+                    let mut to_visit = seeds.clone();
+                    while let Some(node) = to_visit.pop() {
+                        for next in graph.neighbors_directed(node, direction) {
+                            if reached.insert(next) {
+                                to_visit.push(next);
+                            }
+                        }
+                    }
+                }
+                reached.into_iter().collect()
+            }
+        };
+        nodes.sort_by_key(|&node| (graph[node].row, graph[node].col));
+        let total_nodes = nodes.len();
+        nodes.truncate(options.max_nodes);
+        let kept: HashSet<NodeIndex> = nodes.iter().copied().collect();
+
+        let mut edges = BTreeSet::new();
+        for &node in &nodes {
+            for dependent in graph.neighbors(node) {
+                if kept.contains(&dependent) {
+                    let (from, to) = (graph[node], graph[dependent]);
+                    edges.insert(((from.row, from.col), (to.row, to.col)));
+                }
+            }
+        }
+        Ok(GraphExport {
+            nodes: nodes.iter()
+                .map(|&node| {
+                    let cell = graph[node];
+                    GraphNode {
+                        id: cell.to_a1(),
+                        formula: self.formulas.get(&cell).map(|formula| shorten(&formula.raw)),
+                        error: grid.get_cell(cell).is_some_and(|c| matches!(c.value, CellValue::Error(_))),
+                        dependents: graph.neighbors(node).collect::<HashSet<_>>().len(),
+                    }
+                })
+                .collect(),
+            edges: edges.into_iter()
+                .map(|((from_row, from_col), (to_row, to_col))| GraphEdge {
+                    from: CellRef::new(from_row, from_col).to_a1(),
+                    to: CellRef::new(to_row, to_col).to_a1(),
+                })
+                .collect(),
+            total_nodes,
+            truncated: total_nodes > options.max_nodes,
+        })
+    }
+}
+
+/// Corners of an A1-style range such as `B2:D10`, top-left first
+fn parse_range(range: &str) -> Result<(CellRef, CellRef), FormulaError> {
+    let (start, end) = range.split_once(':').unwrap_or((range, range));
+    let corner = |a1: &str| CellRef::parse(a1.trim()).ok_or_else(|| FormulaError::InvalidRef(range.to_string()));
+    let (start, end) = (corner(start)?, corner(end)?);
+    Ok((
+        CellRef::new(start.row.min(end.row), start.col.min(end.col)),
+        CellRef::new(start.row.max(end.row), start.col.max(end.col)),
+    ))
+}
+
+fn shorten(formula: &str) -> String {
+    match formula.char_indices().nth(FORMULA_LABEL_CHARS) {
+        Some((at, _)) => format!("{}…", &formula[..at]),
+        None => formula.to_string(),
+    }
+}
+
+fn to_dot(export: &GraphExport) -> String {
+    let mut out = String::from("digraph dependencies {\n    rankdir=LR;\n    node [shape=box];\n");
+    for node in &export.nodes {
+        let mut label = node.id.clone();
+        if let Some(formula) = &node.formula {
+            label.push_str("\\n");
+            label.push_str(&escape(formula));
+        }
+        if node.dependents > 0 {
+            let plural = if node.dependents == 1 { "" } else { "s" };
+            label.push_str(&format!("\\n{} dependent{}", node.dependents, plural));
+        }
+        let color = if node.error { ", color=red" } else { "" };
+        out.push_str(&format!("    \"{}\" [label=\"{}\"{}];\n", node.id, label, color));
+    }
+    for edge in &export.edges {
+        out.push_str(&format!("    \"{}\" -> \"{}\";\n", edge.from, edge.to));
+    }
+    if export.truncated {
+        out.push_str(&format!(
+            "    truncated [shape=note, label=\"Showing {} of {} cells\"];\n",
+            export.nodes.len(), export.total_nodes
+        ));
+    }
+    out.push_str("}\n");
+    out
+}
+
+/// `text` inside a DOT quoted string
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::set;
+
+    /// A1 and A2 feed B1, which feeds C1 and C2; E1 and F1 are a separate chain
+    fn fixture() -> (Grid, FormulaEngine) {
+        let mut grid = Grid::new(10, 10);
+        let mut engine = FormulaEngine::new();
+        set(&mut grid, &mut engine, "A1", "2");
+        set(&mut grid, &mut engine, "A2", "0");
+        set(&mut grid, &mut engine, "B1", "=A1/A2");
+        set(&mut grid, &mut engine, "C1", "=B1*2");
+        set(&mut grid, &mut engine, "C2", "=IFERROR(B1, \"none\")");
+        set(&mut grid, &mut engine, "E1", "5");
+        set(&mut grid, &mut engine, "F1", "=E1*2");
+        (grid, engine)
+    }
+
+    fn export(engine: &FormulaEngine, grid: &Grid, options: GraphExportOptions) -> GraphExport {
+        let json = engine.export_graph(grid, &GraphExportOptions { format: GraphFormat::Json, ..options }).unwrap();
+        serde_json::from_str(&json).unwrap()
+    }
+
+    #[test]
+    fn test_dot_output() {
+        let (grid, engine) = fixture();
+        let dot = engine.export_graph(&grid, &GraphExportOptions::default()).unwrap();
+        assert_eq!(dot, concat!(
+            "digraph dependencies {\n",
+            "    rankdir=LR;\n",
+            "    node [shape=box];\n",
+            "    \"A1\" [label=\"A1\\n1 dependent\"];\n",
+            "    \"B1\" [label=\"B1\\n=A1/A2\\n2 dependents\", color=red];\n",
+            "    \"C1\" [label=\"C1\\n=B1*2\", color=red];\n",
+            "    \"E1\" [label=\"E1\\n1 dependent\"];\n",
+            "    \"F1\" [label=\"F1\\n=E1*2\"];\n",
+            "    \"A2\" [label=\"A2\\n1 dependent\"];\n",
+            "    \"C2\" [label=\"C2\\n=IFERROR(B1, \\\"none\\\")\"];\n",
+            "    \"A1\" -> \"B1\";\n",
+            "    \"B1\" -> \"C1\";\n",
+            "    \"B1\" -> \"C2\";\n",
+            "    \"E1\" -> \"F1\";\n",
+            "    \"A2\" -> \"B1\";\n",
+            "}\n",
+        ));
+    }
+
+    #[test]
+    fn test_range_filter_follows_both_directions() {
+        let (grid, engine) = fixture();
+        let ids = |range: &str| -> Vec<String> {
+            let options = GraphExportOptions { range: Some(range.to_string()), ..GraphExportOptions::default() };
+            export(&engine, &grid, options).nodes.into_iter().map(|node| node.id).collect()
+        };
+        assert_eq!(ids("B1"), ["A1", "B1", "C1", "A2", "C2"]);
+        assert_eq!(ids("C2"), ["A1", "B1", "A2", "C2"]);
+        assert_eq!(ids("A2:A10"), ["B1", "C1", "A2", "C2"]);
+        assert_eq!(ids("F1:E1"), ["E1", "F1"]);
+        assert!(ids("H8").is_empty());
+
+        let options = GraphExportOptions { range: Some("C2".to_string()), ..GraphExportOptions::default() };
+        let filtered = export(&engine, &grid, options);
+        assert_eq!(filtered.edges.len(), 3);
+        // Dependent counts describe the whole sheet, not the filtered part
+        assert_eq!(filtered.nodes[1].dependents, 2);
+
+        let bad = GraphExportOptions { range: Some("nowhere".to_string()), ..GraphExportOptions::default() };
+        assert!(matches!(engine.export_graph(&grid, &bad), Err(FormulaError::InvalidRef(_))));
+    }
+
+    #[test]
+    fn test_node_cap_truncates_with_notice() {
+        let mut grid = Grid::new(300, 5);
+        let mut engine = FormulaEngine::new();
+        set(&mut grid, &mut engine, "A1", "1");
+        for row in 2..=300 {
+            set(&mut grid, &mut engine, &format!("A{}", row), &format!("=A{}+1", row - 1));
+        }
+        set(&mut grid, &mut engine, "B1", &format!("=\"{}\"&A300", "long text ".repeat(10)));
+
+        let capped = GraphExportOptions { max_nodes: 50, ..GraphExportOptions::default() };
+        let json = export(&engine, &grid, capped.clone());
+        assert!(json.truncated);
+        assert_eq!((json.nodes.len(), json.total_nodes), (50, 301));
+        assert_eq!(json.edges.len(), 48);
+        assert!(json.edges.iter().all(|edge| json.nodes.iter().any(|node| node.id == edge.to)));
+        let long = json.nodes.iter().find(|node| node.id == "B1").unwrap();
+        assert_eq!(long.formula.as_ref().unwrap().chars().count(), FORMULA_LABEL_CHARS + 1);
+
+        let dot = engine.export_graph(&grid, &capped).unwrap();
+        assert!(dot.contains("truncated [shape=note, label=\"Showing 50 of 301 cells\"];"));
+        assert_eq!(dot.matches(" -> ").count(), 48);
+
+        let full = export(&engine, &grid, GraphExportOptions::default());
+        assert!(!full.truncated);
+        assert_eq!(full.nodes.len(), 301);
+    }
+}
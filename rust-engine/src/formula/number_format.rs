@@ -0,0 +1,312 @@
+//! Number format codes, as used by TEXT and for showing cell values
+//!
+//! Covers the parts of Excel's format codes that labels use: `0` and `#`
+//! digit placeholders, a decimal point, `,` as a thousands separator (or,
+//! after the last digit, scaling by 1000), `%` scaling, literal text in
+//! quotes or after `\`, symbols Excel shows as typed (`$`, `-`, `(` ...),
+//! `_x` spacing, `[$€-407]` currency tags and up to three `;`-separated
+//! sections for positive, negative and zero values. Scientific and
+//! `compact` codes go through `cell::format_number`. Date codes built from
+//! `y`, `m` and `d` parts, such as `m/d/yyyy` or `d-mmm`, show the date a
+//! serial number falls on. Anything else, such as a time code, falls back
+//! to the value's plain display.
+
+use super::{datetime, significant};
+use crate::cell::{self, CellValue};
+
+const MONTH_NAMES: [&str; 12] = [
+    "January", "February", "March", "April", "May", "June",
+    "July", "August", "September", "October", "November", "December",
+];
+
+/// Format `value` with a number format code, or display it plainly if the code isn't understood
+pub fn format_number(value: f64, code: &str) -> String {
+    try_format(value, code).unwrap_or_else(|| CellValue::Number(value).display())
+}
+
+fn try_format(value: f64, code: &str) -> Option<String> {
+    if !value.is_finite() || code.eq_ignore_ascii_case("general") {
+        return None;
+    }
+    if let Some(text) = cell::format_number(value, code) {
+        return Some(text);
+    }
+    if let Some(text) = format_date(value, code) {
+        return Some(text);
+    }
+    let sections = split_sections(code);
+    // Only the first section adds a minus sign; a negative section writes its own
+    let (section, value, signed) = match sections.len() {
+        2.. if value < 0.0 => (sections[1], -value, false),
+        3.. if value == 0.0 => (sections[2], value, false),
+        _ => (sections[0], value, true),
+    };
+    Some(Pattern::parse(section)?.render(value, signed))
+}
+
+/// A serial number shown with a date code of `y`, `m` and `d` runs
+///
+/// `yy`/`yyyy` are the year, `m`/`mm` the month number, `mmm`/`mmmm` its
+/// name, and `d`/`dd` the day; `/`, `-`, `.`, `,` and spaces are kept as
+/// written. Codes without a part or with anything else, and serials
+/// outside the dates Excel can show, aren't handled.
+fn format_date(value: f64, code: &str) -> Option<String> {
+    if !(0.0..datetime::MAX_SERIAL + 1.0).contains(&value) {
+        return None;
+    }
+    let (year, month, day) = datetime::serial_to_date(value);
+    let month_name = MONTH_NAMES[month as usize - 1];
+    let (mut out, mut has_part) = (String::new(), false);
+    let mut chars = code.chars().peekable();
+    while let Some(c) = chars.next() {
+        let part = c.to_ascii_lowercase();
+        if !matches!(part, 'y' | 'm' | 'd') {
+            match c {
+                '/' | '-' | '.' | ',' | ' ' => out.push(c),
+                _ => return None,
+            }
+            continue;
+        }
+        let mut run = 1;
+        while chars.next_if(|next| next.to_ascii_lowercase() == part).is_some() {
+            run += 1;
+        }
+        let text = match (part, run) {
+            ('y', 1..=2) => format!("{:02}", year.rem_euclid(100)),
+            ('y', _) => format!("{:04}", year),
+            ('m', 1) => month.to_string(),
+            ('m', 2) => format!("{:02}", month),
+            ('m', 3) => month_name[..3].to_string(),
+            ('m', _) => month_name.to_string(),
+            ('d', 1) => day.to_string(),
+            ('d', 2) => format!("{:02}", day),
+            _ => return None,
+        };
+        out.push_str(&text);
+        has_part = true;
+    }
+    has_part.then_some(out)
+}
+
+/// Split on `;` outside quotes and escapes
+fn split_sections(code: &str) -> Vec<&str> {
+    let mut sections = Vec::new();
+    let (mut start, mut quoted, mut escaped) = (0, false, false);
+    for (at, c) in code.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if !quoted => escaped = true,
+            '"' => quoted = !quoted,
+            ';' if !quoted => {
+                sections.push(&code[start..at]);
+                start = at + 1;
+            }
+            _ => {}
+        }
+    }
+    sections.push(&code[start..]);
+    sections
+}
+
+/// One section of a format code
+#[derive(Debug, Default)]
+struct Pattern {
+    /// Literal text before the first digit placeholder
+    prefix: String,
+    /// Literal text after the last digit placeholder
+    suffix: String,
+    /// Any `0` or `#` placeholder at all; without one the number isn't shown
+    has_digits: bool,
+    /// `0`s before the point: the minimum number of integer digits
+    integer_zeros: usize,
+    /// A `,` between integer placeholders
+    grouping: bool,
+    has_point: bool,
+    /// `0`s after the point: decimals always shown
+    min_decimals: usize,
+    /// `0`s and `#`s after the point: decimals shown at most
+    max_decimals: usize,
+    /// Each `%` multiplies by 100
+    percents: i32,
+    /// Each `,` right after the last digit divides by 1000
+    thousands: i32,
+}
+
+impl Pattern {
+    fn parse(section: &str) -> Option<Self> {
+        let mut pattern = Pattern::default();
+        let mut literal_done = false; // true once digits are followed by literal text
+        let mut pending_commas = 0;
+        let mut chars = section.chars().peekable();
+        while let Some(c) = chars.next() {
+            let literal = match c {
+                '0' | '#' => {
+                    if literal_done {
+                        return None;
+                    }
+                    pattern.has_digits = true;
+                    if pattern.has_point {
+                        // Scaling commas only go after the last placeholder
+                        if pending_commas > 0 {
+                            return None;
+                        }
+                        pattern.max_decimals += 1;
+                        pattern.min_decimals += usize::from(c == '0' && pattern.min_decimals + 1 == pattern.max_decimals);
+                    } else {
+                        pattern.grouping |= pending_commas > 0;
+                        pending_commas = 0;
+                        pattern.integer_zeros += usize::from(c == '0');
+                    }
+                    continue;
+                }
+                '.' if !pattern.has_point && !literal_done => {
+                    pattern.has_point = true;
+                    pattern.has_digits = true;
+                    pattern.thousands += pending_commas;
+                    pending_commas = 0;
+                    continue;
+                }
+                ',' if pattern.has_digits && !literal_done => {
+                    pending_commas += 1;
+                    continue;
+                }
+                '%' => {
+                    pattern.percents += 1;
+                    "%".to_string()
+                }
+                '"' => {
+                    let mut text = String::new();
+                    loop {
+                        match chars.next()? {
+                            '"' => break text,
+                            c => text.push(c),
+                        }
+                    }
+                }
+                '\\' => chars.next()?.to_string(),
+                // `_x` leaves the width of `x`, as a space here; `*x` fills, which text can't
+                '_' => {
+                    chars.next()?;
+                    " ".to_string()
+                }
+                '*' => {
+                    chars.next()?;
+                    continue;
+                }
+                '[' => {
+                    let mut tag = String::new();
+                    loop {
+                        match chars.next()? {
+                            ']' => break,
+                            c => tag.push(c),
+                        }
+                    }
+                    match tag.strip_prefix('$') {
+                        Some(currency) => currency.split('-').next().unwrap_or("").to_string(),
+                        // Colors don't apply to text
+                        None if tag.chars().all(|c| c.is_ascii_alphabetic()) => continue,
+                        None => return None,
+                    }
+                }
+                c if c.is_alphanumeric() || matches!(c, '?' | '@' | '.' | ',') => return None,
+                c => c.to_string(),
+            };
+            if pattern.has_digits {
+                pattern.thousands += pending_commas;
+                pending_commas = 0;
+                literal_done = true;
+                pattern.suffix.push_str(&literal);
+            } else {
+                pattern.prefix.push_str(&literal);
+            }
+        }
+        pattern.thousands += pending_commas;
+        Some(pattern)
+    }
+
+    fn render(&self, value: f64, signed: bool) -> String {
+        if !self.has_digits {
+            return format!("{}{}", self.prefix, self.suffix);
+        }
+        let scaled = value.abs() * 100_f64.powi(self.percents) / 1000_f64.powi(self.thousands);
+        // Snap before rounding so 2.675 shows as 2.68, as typed, not 2.67
+        let factor = 10_f64.powi(self.max_decimals as i32);
+        let rounded = significant(significant(scaled) * factor).round() / factor;
+        let text = format!("{:.*}", self.max_decimals, rounded);
+        let (integer, decimals) = text.split_once('.').unwrap_or((&text, ""));
+
+        let mut out = String::new();
+        if signed && value < 0.0 && rounded != 0.0 {
+            out.push('-');
+        }
+        out.push_str(&self.prefix);
+        let integer = match integer.trim_start_matches('0') {
+            "" => "0".repeat(self.integer_zeros),
+            digits => format!("{:0>width$}", digits, width = self.integer_zeros),
+        };
+        for (i, digit) in integer.chars().enumerate() {
+            if self.grouping && i > 0 && (integer.len() - i) % 3 == 0 {
+                out.push(',');
+            }
+            out.push(digit);
+        }
+        if self.has_point {
+            out.push('.');
+            let optional = decimals.len() - self.min_decimals;
+            let trimmed = decimals[self.min_decimals..].trim_end_matches('0');
+            out.push_str(&decimals[..self.min_decimals]);
+            out.push_str(&trimmed[..trimmed.len().min(optional)]);
+        }
+        out.push_str(&self.suffix);
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_codes() {
+        let cases = [
+            (0.1234, "0.00%", "12.34%"),
+            (1234567.891, "$#,##0.00", "$1,234,567.89"),
+            (-1234.5, "$#,##0.00", "-$1,234.50"),
+            (1234.0, "0,000", "1,234"),
+            (12.0, "0,000", "0,012"),
+            (2.675, "0.00", "2.68"),
+            (0.5, "#.##", ".5"),
+            (3.0, "0.0#", "3.0"),
+            (3.25159, "0.0#", "3.25"),
+            (42.0, "0 \"units\"", "42 units"),
+            (42.0, "\\#0", "#42"),
+            (1500000.0, "#,##0.0,,\"M\"", "1.5M"),
+            (-5.0, "0.00;(0.00)", "(5.00)"),
+            (0.0, "0;-0;\"zero\"", "zero"),
+            (7.0, "[Red]0.0", "7.0"),
+            (7.5, "[$€-407] #,##0.00", "€ 7.50"),
+            (9.0, "_(0_)", " 9 "),
+            (-0.001, "0.00", "0.00"),
+            (1.2345e12, "0.00E+00", "1.23E+12"),
+            (45296.0, "m/d/yyyy", "1/5/2024"),
+            (45296.0, "d/m/yyyy", "5/1/2024"),
+            (45322.75, "yyyy-mm-dd", "2024-01-31"),
+            (45296.0, "d-mmm", "5-Jan"),
+            (45296.0, "d-mmm-yyyy", "5-Jan-2024"),
+            (45296.0, "dd.mm.yy", "05.01.24"),
+            (45296.0, "mmmm d, yyyy", "January 5, 2024"),
+        ];
+        for (value, code, expected) in cases {
+            assert_eq!(format_number(value, code), expected, "{} with {}", value, code);
+        }
+    }
+
+    #[test]
+    fn test_unknown_codes_fall_back_to_plain_display() {
+        for code in ["General", "hh:mm", "dddd", "??/??", "@", "0.00 abc", "[>100]0", "0-0"] {
+            assert_eq!(format_number(1234.5, code), "1234.5", "{}", code);
+        }
+        assert_eq!(format_number(-1.0, "yyyy-mm-dd"), "-1");
+        assert_eq!(format_number(f64::NAN, "0.00"), "#NUM");
+    }
+}
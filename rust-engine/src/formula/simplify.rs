@@ -0,0 +1,217 @@
+//! Constant folding and identity removal for formula ASTs
+//!
+//! Generated formulas often carry structure that does nothing, such as
+//! `=((A1*2)*1)+0`, or constant arithmetic such as `=B2*(12*30)`. With
+//! `FormulaEngine::set_simplify` on, each registered formula also keeps a
+//! simplified tree, which is what gets evaluated. The text the user wrote
+//! and the tree it parses to are untouched, so display and reference
+//! rewriting never see the simplified form.
+//!
+//! Every rewrite gives the same value as the original for any grid:
+//!
+//! - An operator on literals is replaced by its value, unless that value is
+//!   an error, so `=1/0` still reads as a division.
+//! - `*1`, `/1`, `^1`, `+0`, `-0` and `--` are dropped only around an
+//!   expression that is already a number: arithmetic or a literal. Around a
+//!   cell reference they coerce text, blanks and booleans, so `=A1*1` stays.
+//! - Function calls are never folded, and volatile ones are left alone
+//!   entirely, arguments included.
+//!
+//! Operands are never reordered, so float rounding is the same as well.
+
+use super::{is_volatile, BinaryOp, FormulaEngine, FormulaNode, UnaryOp};
+use crate::cell::CellValue;
+
+impl FormulaEngine {
+    /// `node` with constant operations folded and identity operations removed
+    pub(crate) fn simplify(&self, node: &FormulaNode) -> FormulaNode {
+        match node {
+            FormulaNode::BinaryOp { op, left, right } => {
+                let (left, right) = (self.simplify(left), self.simplify(right));
+                if let (Some(l), Some(r)) = (literal(&left), literal(&right)) {
+                    if let Some(folded) = self.evaluate_binary_op(*op, l, r).ok().and_then(to_literal) {
+                        return folded;
+                    }
+                }
+                match (op, &left, &right) {
+                    (BinaryOp::Mul | BinaryOp::Div | BinaryOp::Pow, x, one) | (BinaryOp::Mul, one, x)
+                        if is_number(one, 1.0) && is_numeric(x) => x.clone(),
+                    (BinaryOp::Add | BinaryOp::Sub, x, zero) | (BinaryOp::Add, zero, x)
+                        if is_number(zero, 0.0) && is_numeric(x) => x.clone(),
+                    _ => FormulaNode::BinaryOp { op: *op, left: Box::new(left), right: Box::new(right) },
+                }
+            }
+            FormulaNode::UnaryOp { op, operand } => {
+                let operand = self.simplify(operand);
+                if let Some(value) = literal(&operand) {
+                    if let Some(folded) = self.evaluate_unary_op(*op, value).ok().and_then(to_literal) {
+                        return folded;
+                    }
+                }
+                match (op, operand) {
+                    (UnaryOp::Neg, FormulaNode::UnaryOp { op: UnaryOp::Neg, operand: inner }) if is_numeric(&inner) => *inner,
+                    (op, operand) => FormulaNode::UnaryOp { op: *op, operand: Box::new(operand) },
+                }
+            }
+            FormulaNode::Function { .. } if is_volatile(node) => node.clone(),
+            FormulaNode::Function { name, args, written_name } => FormulaNode::Function {
+                name: name.clone(),
+                args: args.iter().map(|arg| self.simplify(arg)).collect(),
+                written_name: written_name.clone(),
+            },
+            FormulaNode::Number { .. }
+            | FormulaNode::Text { .. }
+            | FormulaNode::Boolean { .. }
+            | FormulaNode::CellRef { .. }
+            | FormulaNode::Range { .. }
+            | FormulaNode::RefError => node.clone(),
+        }
+    }
+}
+
+/// The value of a literal node
+fn literal(node: &FormulaNode) -> Option<CellValue> {
+    match node {
+        FormulaNode::Number { value } => Some(CellValue::Number(*value)),
+        FormulaNode::Text { value } => Some(CellValue::Text(value.clone())),
+        FormulaNode::Boolean { value } => Some(CellValue::Boolean(*value)),
+        _ => None,
+    }
+}
+
+/// A literal node for a folded value; errors and blanks aren't folded
+fn to_literal(value: CellValue) -> Option<FormulaNode> {
+    match value {
+        CellValue::Number(value) if value.is_finite() => Some(FormulaNode::Number { value }),
+        CellValue::Text(value) => Some(FormulaNode::Text { value }),
+        CellValue::Boolean(value) => Some(FormulaNode::Boolean { value }),
+        _ => None,
+    }
+}
+
+fn is_number(node: &FormulaNode, expected: f64) -> bool {
+    matches!(node, FormulaNode::Number { value } if *value == expected)
+}
+
+/// Whether `node` always evaluates to a number (or an error), never to text, a boolean or a blank
+fn is_numeric(node: &FormulaNode) -> bool {
+    match node {
+        FormulaNode::Number { .. } | FormulaNode::UnaryOp { .. } => true,
+        FormulaNode::BinaryOp { op, .. } => {
+            matches!(op, BinaryOp::Add | BinaryOp::Sub | BinaryOp::Mul | BinaryOp::Div | BinaryOp::Pow)
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cell::CellRef;
+    use crate::grid::Grid;
+
+    fn simplified(engine: &FormulaEngine, text: &str) -> String {
+        engine.simplify(&engine.parse(text).unwrap().ast).to_formula_string()
+    }
+
+    #[test]
+    fn test_folding_corpus() {
+        let engine = FormulaEngine::new();
+        for (text, expected) in [
+            ("=B2*(12*30)", "=B2*360"),
+            ("=((A1*2)*1)+0", "=A1*2"),
+            ("=((A1)*1)+0", "=A1*1"),
+            ("=0+(A1-B1)/1", "=A1-B1"),
+            ("=1*(A1^2)^1", "=A1^2"),
+            ("=--(A1+1)", "=A1+1"),
+            ("=--A1", "=--A1"),
+            ("=A1+0", "=A1+0"),
+            ("=-(-(A1%))", "=A1%"),
+            ("=SUM(A1:A3, 2*3, B1*1)", "=SUM(A1:A3,6,B1*1)"),
+            ("=IF(A1>2+3, \"a\"&\"b\", 50%)", "=IF(A1>5,\"ab\",0.5)"),
+            ("=1/0", "=1/0"),
+            ("=(1/0)*(2+2)", "=1/0*4"),
+            ("=\"x\"*2", "=\"x\"*2"),
+            ("=2^3^2", "=512"),
+            ("=1=1", "=TRUE"),
+            ("=NOW()*1", "=NOW()*1"),
+            ("=RANDBETWEEN(1, 2*5)+0", "=RANDBETWEEN(1,2*5)+0"),
+            ("=TODAY()+(1+1)", "=TODAY()+2"),
+        ] {
+            assert_eq!(simplified(&engine, text), expected, "{}", text);
+        }
+    }
+
+    /// Small deterministic generator, so runs are repeatable
+    struct Lcg(u64);
+
+    impl Lcg {
+        fn next(&mut self, bound: u64) -> u64 {
+            self.0 = self.0.wrapping_mul(6_364_136_223_846_793_005).wrapping_add(1_442_695_040_888_963_407);
+            (self.0 >> 33) % bound
+        }
+    }
+
+    #[test]
+    fn test_simplified_formulas_evaluate_the_same() {
+        let corpus = [
+            "=((A1)*1)+0", "=((A1*2)*1)+0", "=--A2", "=--(A2*3)", "=B1*(12*30)", "=A1/1+0",
+            "=(A1+B1)^1*1", "=0+A3-0", "=A1&(\"-\"&\"x\")", "=IF(A2, 1+1, 2*2)", "=SUM(A1:B3, 1*1)+0",
+            "=A1>(1+1)", "=-(-(B2+0))", "=(1/0)+A1*1", "=B3*1%", "=MAX(A1:A3)*1", "=LEN(A1)+0*1",
+        ];
+        let mut random = Lcg(7);
+        for strict in [false, true] {
+            let mut engine = FormulaEngine::new();
+            engine.set_strict_mode(strict);
+            for _ in 0..50 {
+                let mut grid = Grid::new(5, 3);
+                for row in 0..3 {
+                    for col in 0..2 {
+                        let value = match random.next(5) {
+                            0 => CellValue::Empty,
+                            1 => CellValue::Text(["abc", "12", "TRUE", ""][random.next(4) as usize].to_string()),
+                            2 => CellValue::Boolean(random.next(2) == 1),
+                            3 => CellValue::Number(0.0),
+                            _ => CellValue::Number((random.next(2000) as f64 - 1000.0) / 7.0),
+                        };
+                        grid.set_value(CellRef::new(row, col), value).unwrap();
+                    }
+                }
+                for text in corpus {
+                    let ast = engine.parse(text).unwrap().ast;
+                    let outcome = |node: &FormulaNode| {
+                        engine.evaluate(node, &grid).map_err(|e| e.error_code())
+                    };
+                    assert_eq!(outcome(&ast), outcome(&engine.simplify(&ast)), "{} on {:?}", text, grid.get_range(0, 0, 2, 1, &engine));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_engine_evaluates_simplified_tree_and_keeps_text() {
+        let mut grid = Grid::new(10, 5);
+        let mut engine = FormulaEngine::new();
+        grid.set_cell_value(CellRef::new(1, 1), CellValue::Number(2.0), &mut engine).unwrap();
+        let formula = engine.parse("=B2*(12*30)+0").unwrap();
+        grid.set_cell_formula(CellRef::new(0, 0), formula, &mut engine).unwrap();
+        assert!(engine.formula_at(CellRef::new(0, 0)).unwrap().simplified.is_none());
+
+        engine.set_simplify(true);
+        let formula = engine.formula_at(CellRef::new(0, 0)).unwrap();
+        assert_eq!(formula.evaluated().to_formula_string(), "=B2*360");
+        assert_eq!(formula.raw, "=B2*(12*30)+0");
+        engine.recalculate_all(&mut grid).unwrap();
+        assert_eq!(grid.get_cell(CellRef::new(0, 0)).unwrap().value, CellValue::Number(720.0));
+
+        // Rewrites start from the text as written, and the setting survives a reload
+        grid.insert_rows(1, 1, &mut engine).unwrap();
+        assert_eq!(grid.get_cell(CellRef::new(0, 0)).unwrap().formula.as_deref(), Some("=B3*(12*30)+0"));
+        let formula = engine.formula_at(CellRef::new(0, 0)).unwrap();
+        assert_eq!(formula.evaluated().to_formula_string(), "=B3*360");
+        assert_eq!(grid.get_cell(CellRef::new(0, 0)).unwrap().value, CellValue::Number(720.0));
+
+        engine.set_simplify(false);
+        assert!(engine.formula_at(CellRef::new(0, 0)).unwrap().simplified.is_none());
+    }
+}
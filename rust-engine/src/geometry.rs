@@ -0,0 +1,413 @@
+//! Where rows, columns and cells land on the canvas
+//!
+//! The renderer draws from a `SheetLayout`, and `SheetEngine::get_cell_rect`
+//! answers from one built the same way, so an overlay anchored to a cell
+//! lines up with the pixels drawn for it at any zoom, scroll position or
+//! frozen pane split.
+//!
+//! Each axis is laid out the same way: the header band, then the frozen
+//! lines, then the scrolled lines starting at the viewport's first line and
+//! shifted back by its pixel offset. A scrolled line slides under the frozen
+//! pane rather than over it.
+
+use crate::cell::CellRef;
+use crate::formula::FormulaEngine;
+use crate::grid::{CellData, Grid};
+use crate::viewport::Viewport;
+use serde::{Deserialize, Serialize};
+use std::ops::Range;
+
+/// Width of the row header band at 100% zoom
+pub const HEADER_WIDTH: f64 = 50.0;
+
+/// Height of the column header band at 100% zoom
+pub const HEADER_HEIGHT: f64 = 24.0;
+
+/// A cell's rectangle in canvas pixels
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CellRect {
+    pub x: f64,
+    pub y: f64,
+    pub width: f64,
+    pub height: f64,
+}
+
+/// A cell or range's rectangle in canvas pixels, for drawing overlays
+///
+/// The rectangle is never trimmed; `clipped` says part of it is out of
+/// view, under the headers or a frozen pane, or past the canvas edge.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct ScreenRect {
+    pub x: f64,
+    pub y: f64,
+    pub width: f64,
+    pub height: f64,
+    pub clipped: bool,
+}
+
+/// The cells in view, one list per pane, for laying the panes out in JS
+///
+/// Without frozen panes everything is in `main`. Hidden rows and columns,
+/// and cells scrolled under a frozen pane or past the canvas edge, are left
+/// out, just as the renderer leaves them undrawn.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct VisibleCells {
+    /// Frozen rows and frozen columns
+    pub top_left: Vec<CellData>,
+    /// Frozen rows, scrolled columns
+    pub top: Vec<CellData>,
+    /// Scrolled rows, frozen columns
+    pub left: Vec<CellData>,
+    /// Scrolled rows and columns
+    pub main: Vec<CellData>,
+    pub panes: PaneGeometry,
+}
+
+/// Where the panes meet, in canvas pixels
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct PaneGeometry {
+    /// Left edge of the cells, past the row headers
+    pub cells_x: f64,
+    /// Top edge of the cells, below the column headers
+    pub cells_y: f64,
+    /// Where the frozen columns end and the scrolled ones start (`cells_x` when none are frozen)
+    pub split_x: f64,
+    /// Where the frozen rows end and the scrolled ones start (`cells_y` when none are frozen)
+    pub split_y: f64,
+}
+
+/// A row or column in view
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Line {
+    pub index: u32,
+    /// Leading edge in canvas pixels
+    pub start: f64,
+    /// Zoomed size in pixels (0 when hidden)
+    pub size: f64,
+    /// Start of the part not covered by the headers or a frozen pane
+    pub visible_start: f64,
+    /// End of the part inside the canvas
+    pub visible_end: f64,
+}
+
+impl Line {
+    fn end(&self) -> f64 {
+        self.start + self.size
+    }
+
+    /// Whether any of the line shows
+    pub fn is_drawn(&self) -> bool {
+        self.visible_end > self.visible_start
+    }
+
+    fn is_whole(&self) -> bool {
+        self.visible_start <= self.start && self.visible_end >= self.end()
+    }
+}
+
+/// The rows or the columns of a layout
+#[derive(Debug, Clone)]
+struct Axis {
+    /// Every line in view, frozen ones first, so in index order
+    lines: Vec<Line>,
+    frozen: Range<u32>,
+    scrolled: Range<u32>,
+    /// Where the scrolled lines' pane starts: the header band plus the frozen lines
+    split: f64,
+}
+
+impl Axis {
+    fn new(frozen: Range<u32>, scrolled: Range<u32>, offset: f64, header: f64, extent: f64, size: impl Fn(u32) -> f64) -> Self {
+        let mut lines = Vec::new();
+        let mut edge = header;
+        let mut pane = header;
+        let split = header + frozen.clone().map(&size).sum::<f64>();
+        for index in frozen.clone().chain(scrolled.clone()) {
+            if index == scrolled.start {
+                pane = edge;
+                edge -= offset;
+            }
+            let size = size(index);
+            lines.push(Line {
+                index,
+                start: edge,
+                size,
+                visible_start: edge.max(pane),
+                visible_end: (edge + size).min(extent),
+            });
+            edge += size;
+        }
+        Self { lines, frozen, scrolled, split }
+    }
+
+    fn line(&self, index: u32) -> Option<&Line> {
+        self.lines.binary_search_by_key(&index, |line| line.index).ok().map(|i| &self.lines[i])
+    }
+
+    /// Leading edge, trailing edge and whether part is out of view, for lines `first..=last`
+    fn span(&self, first: u32, last: u32) -> Option<(f64, f64, bool)> {
+        let in_view: Vec<&Line> = self.lines.iter().filter(|line| (first..=last).contains(&line.index)).collect();
+        let mut drawn = in_view.iter().filter(|line| line.is_drawn());
+        let lead = drawn.next()?;
+        let tail = drawn.next_back().unwrap_or(lead);
+        let all_in_view = in_view.len() as u64 == u64::from(last - first) + 1;
+        let clipped = !all_in_view || in_view.iter().any(|line| line.size > 0.0 && !line.is_whole());
+        Some((lead.start, tail.end(), clipped))
+    }
+
+    /// The in-view index ranges: frozen, then scrolled
+    fn ranges(&self) -> impl Iterator<Item = Range<u32>> {
+        [self.frozen.clone(), self.scrolled.clone()].into_iter().filter(|range| !range.is_empty())
+    }
+}
+
+/// Canvas positions of everything in view
+#[derive(Debug, Clone)]
+pub struct SheetLayout {
+    zoom: f64,
+    rows: Axis,
+    cols: Axis,
+}
+
+impl SheetLayout {
+    /// Lay out `viewport` over `grid` on a canvas of `width` × `height` pixels
+    ///
+    /// Pass infinite sizes when there's no canvas; only the viewport then
+    /// limits what's in view.
+    pub fn new(grid: &Grid, viewport: &Viewport, width: f64, height: f64) -> Self {
+        let zoom = viewport.zoom as f64;
+        let in_view = |frozen: u32, start: u32, visible: u32, count: u32| {
+            let frozen = frozen.min(count);
+            (0..frozen, start.max(frozen)..start.saturating_add(visible).min(count))
+        };
+        let (frozen, scrolled) = in_view(viewport.frozen_rows, viewport.start_row, viewport.visible_rows, grid.rows);
+        let rows = Axis::new(frozen, scrolled, viewport.offset_y as f64 * zoom, HEADER_HEIGHT * zoom, height, |row| {
+            grid.get_row_height(row) as f64 * zoom
+        });
+        let (frozen, scrolled) = in_view(viewport.frozen_cols, viewport.start_col, viewport.visible_cols, grid.cols);
+        let cols = Axis::new(frozen, scrolled, viewport.offset_x as f64 * zoom, HEADER_WIDTH * zoom, width, |col| {
+            grid.get_col_width(col) as f64 * zoom
+        });
+        Self { zoom, rows, cols }
+    }
+
+    pub fn zoom(&self) -> f64 {
+        self.zoom
+    }
+
+    pub fn header_width(&self) -> f64 {
+        HEADER_WIDTH * self.zoom
+    }
+
+    pub fn header_height(&self) -> f64 {
+        HEADER_HEIGHT * self.zoom
+    }
+
+    /// Rows with some part showing, top to bottom
+    pub fn rows(&self) -> impl Iterator<Item = &Line> {
+        self.rows.lines.iter().filter(|line| line.is_drawn())
+    }
+
+    /// Columns with some part showing, left to right
+    pub fn cols(&self) -> impl Iterator<Item = &Line> {
+        self.cols.lines.iter().filter(|line| line.is_drawn())
+    }
+
+    /// The blocks of cells in view, as inclusive corners
+    ///
+    /// One block without frozen panes, up to four with them.
+    pub fn blocks(&self) -> Vec<(CellRef, CellRef)> {
+        let mut blocks = Vec::new();
+        for rows in self.rows.ranges() {
+            for cols in self.cols.ranges() {
+                blocks.push((CellRef::new(rows.start, cols.start), CellRef::new(rows.end - 1, cols.end - 1)));
+            }
+        }
+        blocks
+    }
+
+    /// The cells in view, split by pane
+    pub fn visible_cells(&self, grid: &Grid, formula_engine: &FormulaEngine) -> VisibleCells {
+        let mut visible = VisibleCells {
+            panes: PaneGeometry {
+                cells_x: self.header_width(),
+                cells_y: self.header_height(),
+                split_x: self.cols.split,
+                split_y: self.rows.split,
+            },
+            ..Default::default()
+        };
+        let (rows, cols) = (&self.rows, &self.cols);
+        let panes = [
+            (&rows.frozen, &cols.frozen, &mut visible.top_left),
+            (&rows.frozen, &cols.scrolled, &mut visible.top),
+            (&rows.scrolled, &cols.frozen, &mut visible.left),
+            (&rows.scrolled, &cols.scrolled, &mut visible.main),
+        ];
+        for (rows, cols, cells) in panes {
+            if rows.is_empty() || cols.is_empty() {
+                continue;
+            }
+            *cells = grid.get_range(rows.start, cols.start, rows.end - 1, cols.end - 1, formula_engine)
+                .into_iter()
+                .filter(|cell| self.placement(cell.row, cell.col).is_some())
+                .collect();
+        }
+        visible
+    }
+
+    /// Where a cell is drawn, and the part of that not covered by a header or frozen pane
+    pub fn placement(&self, row: u32, col: u32) -> Option<(CellRect, CellRect)> {
+        let (row, col) = (self.rows.line(row)?, self.cols.line(col)?);
+        if !row.is_drawn() || !col.is_drawn() {
+            return None;
+        }
+        let rect = CellRect { x: col.start, y: row.start, width: col.size, height: row.size };
+        let visible = CellRect {
+            x: col.visible_start,
+            y: row.visible_start,
+            width: col.visible_end - col.visible_start,
+            height: row.visible_end - row.visible_start,
+        };
+        Some((rect, visible))
+    }
+
+    /// A cell's rectangle, or `None` when it's hidden or out of view
+    pub fn cell_rect(&self, row: u32, col: u32) -> Option<ScreenRect> {
+        self.range_rect(CellRef::new(row, col), CellRef::new(row, col))
+    }
+
+    /// The rectangle around the part of a range in view, or `None` when none of it is
+    pub fn range_rect(&self, start: CellRef, end: CellRef) -> Option<ScreenRect> {
+        let (top, bottom, rows_clipped) = self.rows.span(start.row.min(end.row), start.row.max(end.row))?;
+        let (left, right, cols_clipped) = self.cols.span(start.col.min(end.col), start.col.max(end.col))?;
+        Some(ScreenRect {
+            x: left,
+            y: top,
+            width: right - left,
+            height: bottom - top,
+            clipped: rows_clipped || cols_clipped,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cell::CellValue;
+
+    fn rect(x: f64, y: f64, width: f64, height: f64, clipped: bool) -> Option<ScreenRect> {
+        Some(ScreenRect { x, y, width, height, clipped })
+    }
+
+    #[test]
+    fn test_cell_rects_follow_zoom_scroll_and_hidden_lines() {
+        let mut grid = Grid::new(100, 20);
+        grid.set_col_width(1, 150.0).unwrap();
+        grid.set_row_hidden(3, true);
+        let mut viewport = Viewport::new(0, 0, 10, 5);
+
+        let layout = SheetLayout::new(&grid, &viewport, 800.0, 600.0);
+        assert_eq!(layout.cell_rect(0, 0), rect(50.0, 24.0, 100.0, 24.0, false));
+        assert_eq!(layout.cell_rect(4, 2), rect(300.0, 96.0, 100.0, 24.0, false));
+        assert_eq!(layout.cell_rect(3, 0), None);
+        assert_eq!(layout.cell_rect(10, 0), None);
+
+        viewport.set_zoom(2.0);
+        viewport.start_row = 2;
+        viewport.offset_y = 6.0;
+        let layout = SheetLayout::new(&grid, &viewport, 800.0, 600.0);
+        // Row 2 is partly scrolled under the header
+        assert_eq!(layout.cell_rect(2, 0), rect(100.0, 36.0, 200.0, 48.0, true));
+        assert_eq!(layout.cell_rect(4, 0), rect(100.0, 84.0, 200.0, 48.0, false));
+        assert_eq!(layout.cell_rect(1, 0), None);
+        // Column 2 ends at the right edge of the 800px canvas, and column 3 is past it
+        assert_eq!(layout.cell_rect(4, 2), rect(600.0, 84.0, 200.0, 48.0, false));
+        assert_eq!(layout.cell_rect(4, 3), None);
+        let narrow = SheetLayout::new(&grid, &viewport, 700.0, 600.0);
+        assert_eq!(narrow.cell_rect(4, 2), rect(600.0, 84.0, 200.0, 48.0, true));
+    }
+
+    #[test]
+    fn test_frozen_panes_stay_put() {
+        let grid = Grid::new(100, 20);
+        let mut viewport = Viewport::new(10, 5, 10, 5);
+        viewport.frozen_rows = 2;
+        viewport.frozen_cols = 1;
+        viewport.offset_x = 30.0;
+
+        let layout = SheetLayout::new(&grid, &viewport, 800.0, 600.0);
+        assert_eq!(layout.cell_rect(0, 0), rect(50.0, 24.0, 100.0, 24.0, false));
+        assert_eq!(layout.cell_rect(1, 5), rect(120.0, 48.0, 100.0, 24.0, true));
+        assert_eq!(layout.cell_rect(10, 6), rect(220.0, 72.0, 100.0, 24.0, false));
+        // Rows between the frozen pane and the scroll position are out of view
+        assert_eq!(layout.cell_rect(5, 0), None);
+        assert_eq!(layout.blocks().len(), 4);
+
+        // A range across the panes covers both, and is clipped by the rows scrolled away
+        assert_eq!(layout.range_rect(CellRef::new(0, 0), CellRef::new(10, 0)), rect(50.0, 24.0, 100.0, 72.0, true));
+        assert_eq!(layout.range_rect(CellRef::new(10, 6), CellRef::new(11, 7)), rect(220.0, 72.0, 200.0, 48.0, false));
+        assert_eq!(layout.range_rect(CellRef::new(3, 0), CellRef::new(9, 0)), None);
+    }
+
+    /// Fill every cell in the first `rows` × `cols` with its A1 name
+    fn labelled(rows: u32, cols: u32) -> (Grid, FormulaEngine) {
+        let mut grid = Grid::new(100, 20);
+        let mut engine = FormulaEngine::new();
+        for row in 0..rows {
+            for col in 0..cols {
+                let cell_ref = CellRef::new(row, col);
+                grid.set_cell_value(cell_ref, CellValue::Text(cell_ref.to_a1()), &mut engine).unwrap();
+            }
+        }
+        (grid, engine)
+    }
+
+    fn names(cells: &[CellData]) -> Vec<String> {
+        let mut names: Vec<(u32, u32, String)> = cells.iter().map(|cell| (cell.row, cell.col, cell.value.clone())).collect();
+        names.sort();
+        names.into_iter().map(|(_, _, name)| name).collect()
+    }
+
+    #[test]
+    fn test_visible_cells_without_panes() {
+        let (mut grid, engine) = labelled(4, 3);
+        grid.set_row_hidden(1, true);
+        grid.set_col_hidden(2, true);
+        let layout = SheetLayout::new(&grid, &Viewport::new(0, 0, 3, 5), 800.0, 600.0);
+        let visible = layout.visible_cells(&grid, &engine);
+        assert_eq!(names(&visible.main), ["A1", "B1", "A3", "B3"]);
+        assert!(visible.top_left.is_empty() && visible.top.is_empty() && visible.left.is_empty());
+        assert_eq!(visible.panes, PaneGeometry { cells_x: 50.0, cells_y: 24.0, split_x: 50.0, split_y: 24.0 });
+    }
+
+    #[test]
+    fn test_visible_cells_by_pane() {
+        let (mut grid, engine) = labelled(14, 8);
+        // One hidden row inside the frozen band and one in the scrolled rows
+        grid.set_row_hidden(1, true);
+        grid.set_row_hidden(11, true);
+        let mut viewport = Viewport::new(10, 5, 3, 2);
+        viewport.frozen_rows = 3;
+        viewport.frozen_cols = 1;
+
+        let visible = SheetLayout::new(&grid, &viewport, 800.0, 600.0).visible_cells(&grid, &engine);
+        assert_eq!(names(&visible.top_left), ["A1", "A3"]);
+        assert_eq!(names(&visible.top), ["F1", "G1", "F3", "G3"]);
+        assert_eq!(names(&visible.left), ["A11", "A13"]);
+        assert_eq!(names(&visible.main), ["F11", "G11", "F13", "G13"]);
+        assert_eq!(visible.panes, PaneGeometry { cells_x: 50.0, cells_y: 24.0, split_x: 150.0, split_y: 72.0 });
+
+        // Frozen rows only, at 200% on a narrower canvas: column C starts at its right edge
+        grid.set_row_hidden(1, false);
+        viewport.frozen_cols = 0;
+        viewport.start_col = 0;
+        viewport.visible_cols = 5;
+        viewport.set_zoom(2.0);
+        let visible = SheetLayout::new(&grid, &viewport, 500.0, 600.0).visible_cells(&grid, &engine);
+        assert!(visible.top_left.is_empty() && visible.left.is_empty());
+        assert_eq!(names(&visible.top), ["A1", "B1", "A2", "B2", "A3", "B3"]);
+        assert_eq!(names(&visible.main), ["A11", "B11", "A13", "B13"]);
+        assert_eq!(visible.panes, PaneGeometry { cells_x: 100.0, cells_y: 48.0, split_x: 100.0, split_y: 192.0 });
+    }
+}
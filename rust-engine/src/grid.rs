@@ -1,21 +1,47 @@
 //! Grid data structure for cell storage
 
-use crate::cell::{Cell, CellRef, CellValue};
+use crate::cell::{Cell, CellFormat, CellRef, CellValue, ValueType};
+use crate::chart::{ChartData, ChartStore};
+use crate::format_infer::{self, DateOrder};
+use crate::clock::{system_clock, Clock};
+use crate::column::ColumnStore;
+use crate::document::DocumentProperties;
+use crate::paging::PageOptions;
+use crate::reference::Axis;
+use crate::scenario::ScenarioStore;
+use crate::style::{restyle, StyleSheet};
+use crate::viewport::Viewport;
 use crate::formula::{Formula, FormulaEngine, FormulaError};
-use indexmap::IndexMap;
+use crate::warnings::{self, EngineWarning, WarningCode};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::rc::Rc;
 use wasm_bindgen::JsValue;
 
 /// Error type for grid operations
 #[derive(Debug, thiserror::Error)]
 pub enum GridError {
-    #[error("Cell reference out of bounds: {0}")]
-    OutOfBounds(CellRef),
+    #[error("Cell reference out of bounds: {cell} (grid is {rows}x{cols})")]
+    OutOfBounds {
+        cell: CellRef,
+        /// Grid dimensions when the write was refused
+        rows: u32,
+        cols: u32,
+    },
     #[error("Serialization error: {0}")]
     Serialization(String),
     #[error("Formula error: {0}")]
     Formula(String),
+    #[error("Scenario error: {0}")]
+    Scenario(String),
+    #[error("Invalid property: {0}")]
+    Property(String),
+    #[error("Invalid size: {0}")]
+    InvalidSize(String),
+    #[error("Pagination error: {0}")]
+    Pagination(String),
+    #[error("Unknown style: {0}")]
+    UnknownStyle(String),
 }
 
 impl From<FormulaError> for GridError {
@@ -24,8 +50,25 @@ impl From<FormulaError> for GridError {
     }
 }
 
+/// Error object handed to JS for out-of-bounds writes, so callers can resize and retry
+#[derive(Serialize)]
+struct OutOfBoundsPayload {
+    kind: &'static str,
+    message: String,
+    row: u32,
+    col: u32,
+    rows: u32,
+    cols: u32,
+}
+
 impl From<GridError> for JsValue {
     fn from(e: GridError) -> Self {
+        if let GridError::OutOfBounds { cell, rows, cols } = e {
+            let payload = OutOfBoundsPayload { kind: "out_of_bounds", message: e.to_string(), row: cell.row, col: cell.col, rows, cols };
+            if let Ok(value) = serde_wasm_bindgen::to_value(&payload) {
+                return value;
+            }
+        }
         JsValue::from_str(&e.to_string())
     }
 }
@@ -38,9 +81,10 @@ pub struct Grid {
     pub cols: u32,
     
     /// Sparse cell storage: column -> row -> cell
-    /// Using columnar layout for better cache performance on column operations
+    /// Using columnar layout for better cache performance on column operations;
+    /// repetitive columns may be run-length encoded (see `compact_columns`)
     #[serde(default)]
-    columns: HashMap<u32, IndexMap<u32, Cell>>,
+    pub(crate) columns: HashMap<u32, ColumnStore>,
     
     /// Column widths (in pixels)
     #[serde(default)]
@@ -57,13 +101,87 @@ pub struct Grid {
     /// Default row height
     #[serde(default = "default_row_height")]
     default_row_height: f32,
+    
+    /// Allowed range for column widths and row heights
+    #[serde(default)]
+    size_limits: SizeLimits,
+    
+    /// How far auto-expanding writes may grow the grid
+    #[serde(default)]
+    dimension_limits: DimensionLimits,
+    
+    /// How typed slashed dates such as `1/5/2024` are read
+    #[serde(default)]
+    date_order: DateOrder,
+    
+    /// Hidden columns (rendered with zero width)
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    hidden_cols: BTreeSet<u32>,
+    
+    /// Hidden rows (rendered with zero height)
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    hidden_rows: BTreeSet<u32>,
+    
+    /// Cached total width, tagged with the column count it was computed for
+    #[serde(skip)]
+    content_width: std::cell::Cell<Option<(u32, f64)>>,
+    
+    /// Cached total height, tagged with the row count it was computed for
+    #[serde(skip)]
+    content_height: std::cell::Cell<Option<(u32, f64)>>,
+    
+    /// Named input scenarios
+    #[serde(default, skip_serializing_if = "ScenarioStore::is_empty")]
+    pub(crate) scenarios: ScenarioStore,
+    
+    /// Warnings from the edit in progress, drained by `take_warnings`
+    #[serde(skip)]
+    pub(crate) warnings: Vec<EngineWarning>,
+    
+    /// Charts bound to ranges of the sheet
+    #[serde(default, skip_serializing_if = "ChartStore::is_empty")]
+    pub(crate) charts: ChartStore,
+    
+    /// Named cell styles
+    #[serde(default, skip_serializing_if = "StyleSheet::is_empty")]
+    pub(crate) styles: StyleSheet,
+    
+    /// Named ranges by upper-cased name, as corners; the formula engine
+    /// reads them from here when it loads the grid's formulas
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub(crate) names: BTreeMap<String, (CellRef, CellRef)>,
+    
+    /// Document metadata (title, author, timestamps, custom keys)
+    #[serde(default)]
+    properties: DocumentProperties,
+    
+    /// Where the sheet was scrolled to when it was saved
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    view_state: Option<Viewport>,
+    
+    /// Time source for `modified_at` bumps
+    #[serde(skip, default = "system_clock")]
+    clock: Rc<dyn Clock>,
+    
+    /// Count of mutations since load, for cheap change detection
+    #[serde(skip)]
+    revision: u64,
 }
 
+/// Blank rows and columns kept past the used range by `shrink_to_fit`
+pub(crate) const SHRINK_MARGIN: u32 = 10;
+
 fn default_col_width() -> f32 { 100.0 }
 fn default_row_height() -> f32 { 24.0 }
 
 impl Grid {
     pub fn new(rows: u32, cols: u32) -> Self {
+        Self::with_clock(rows, cols, system_clock())
+    }
+
+    /// Create a grid that reads time from `clock`
+    pub fn with_clock(rows: u32, cols: u32, clock: Rc<dyn Clock>) -> Self {
+        let now = clock.now_millis();
         Self {
             rows,
             cols,
@@ -72,106 +190,301 @@ impl Grid {
             row_heights: HashMap::new(),
             default_col_width: default_col_width(),
             default_row_height: default_row_height(),
+            size_limits: SizeLimits::default(),
+            dimension_limits: DimensionLimits::default(),
+            date_order: DateOrder::default(),
+            hidden_cols: BTreeSet::new(),
+            hidden_rows: BTreeSet::new(),
+            content_width: std::cell::Cell::new(None),
+            content_height: std::cell::Cell::new(None),
+            scenarios: ScenarioStore::default(),
+            warnings: Vec::new(),
+            charts: ChartStore::default(),
+            styles: StyleSheet::default(),
+            names: BTreeMap::new(),
+            properties: DocumentProperties {
+                created_at: Some(now),
+                modified_at: Some(now),
+                ..DocumentProperties::default()
+            },
+            view_state: None,
+            clock,
+            revision: 0,
         }
     }
 
+    /// Replace the time source (e.g. after loading a document)
+    pub fn set_clock(&mut self, clock: Rc<dyn Clock>) {
+        self.clock = clock;
+    }
+
+    /// Current time from the grid's clock
+    pub(crate) fn now_millis(&self) -> f64 {
+        self.clock.now_millis()
+    }
+
+    /// Document properties
+    pub fn properties(&self) -> &DocumentProperties {
+        &self.properties
+    }
+
+    /// Set a document property; explicitly setting `modified_at` is honored as given
+    pub fn set_property(&mut self, key: &str, value: serde_json::Value) -> Result<(), GridError> {
+        self.properties.set(key, value).map_err(GridError::Property)?;
+        if key != "modified_at" {
+            self.touch();
+        }
+        Ok(())
+    }
+
+    /// Scroll position, zoom and frozen panes saved with the document
+    pub fn view_state(&self) -> Option<&Viewport> {
+        self.view_state.as_ref()
+    }
+
+    /// Remember the viewport so it's saved with the document
+    ///
+    /// Scrolling isn't an edit, so this doesn't bump `modified_at`.
+    pub fn set_view_state(&mut self, viewport: Viewport) {
+        self.view_state = Some(viewport);
+    }
+
+    /// Record that the document was modified
+    pub(crate) fn touch(&mut self) {
+        self.properties.modified_at = Some(self.clock.now_millis());
+        self.revision += 1;
+    }
+
+    /// Mutation counter; changes whenever cell contents, formats or sizes do
+    pub fn revision(&self) -> u64 {
+        self.revision
+    }
+
     /// Get a cell by reference
     pub fn get_cell(&self, cell_ref: CellRef) -> Option<&Cell> {
-        self.columns.get(&cell_ref.col)?.get(&cell_ref.row)
+        self.columns.get(&cell_ref.col)?.get(cell_ref.row)
+    }
+
+    /// The formula in a cell as the user wrote it, if it holds one
+    pub fn get_cell_formula(&self, cell_ref: CellRef, formula_engine: &FormulaEngine) -> Option<String> {
+        shown_formula(cell_ref, self.get_cell(cell_ref)?, formula_engine)
     }
 
     /// Get a mutable cell by reference
     pub fn get_cell_mut(&mut self, cell_ref: CellRef) -> Option<&mut Cell> {
-        self.columns.get_mut(&cell_ref.col)?.get_mut(&cell_ref.row)
+        self.columns.get_mut(&cell_ref.col)?.get_mut(cell_ref.row)
     }
 
-    /// Set a raw value (not a formula)
-    pub fn set_value(&mut self, cell_ref: CellRef, value: CellValue) -> Result<(), GridError> {
+    /// Put a plain value in a cell, dropping any formula it held
+    ///
+    /// This and `set_cell_formula` are the only ways cell contents change, so
+    /// stored values and the engine's registered formulas always agree.
+    pub fn set_cell_value(&mut self, cell_ref: CellRef, value: CellValue, formula_engine: &mut FormulaEngine) -> Result<(), GridError> {
+        self.set_value(cell_ref, value)?;
+        formula_engine.unregister_formula(cell_ref);
+        Ok(())
+    }
+
+    /// Store text typed into a cell, recognizing dates
+    ///
+    /// A date is stored as its serial number and, unless the cell already
+    /// has a number format, gets a date format matching how it was typed.
+    /// Other input is parsed with `CellValue::parse`.
+    pub fn enter_value(&mut self, cell_ref: CellRef, raw: &str, formula_engine: &mut FormulaEngine) -> Result<(), GridError> {
+        let current_year = format_infer::year_of_unix_millis(self.now_millis());
+        let Some((serial, number_format)) = format_infer::parse_typed_date(raw, self.date_order, current_year) else {
+            return self.set_cell_value(cell_ref, CellValue::parse(raw), formula_engine);
+        };
+        self.set_cell_value(cell_ref, CellValue::Number(serial), formula_engine)?;
+        if !self.has_number_format(cell_ref) {
+            self.set_format(cell_ref, CellFormat {
+                number_format: Some(number_format.to_string()),
+                ..CellFormat::default()
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Whether typed slashed dates read month or day first
+    pub fn date_order(&self) -> DateOrder {
+        self.date_order
+    }
+
+    pub fn set_date_order(&mut self, order: DateOrder) {
+        self.date_order = order;
+    }
+
+    fn has_number_format(&self, cell_ref: CellRef) -> bool {
+        self.get_cell(cell_ref)
+            .and_then(|cell| cell.format.as_ref())
+            .is_some_and(|format| format.number_format.is_some())
+    }
+
+    /// Put a formula in a cell and register it with the engine
+    ///
+    /// An empty formula (`""` or a bare `=`) clears the cell instead.
+    pub fn set_cell_formula(&mut self, cell_ref: CellRef, formula: Formula, formula_engine: &mut FormulaEngine) -> Result<(), GridError> {
+        if formula.raw.trim().trim_start_matches('=').trim().is_empty() {
+            return self.set_cell_value(cell_ref, CellValue::Empty, formula_engine);
+        }
         self.check_bounds(cell_ref)?;
+        self.touch();
         
-        let column = self.columns.entry(cell_ref.col).or_insert_with(IndexMap::new);
+        let column = self.columns.entry(cell_ref.col).or_default();
+        let cell = column.get_or_insert_with(cell_ref.row, Cell::default);
+        cell.value = CellValue::Empty;
+        cell.formula = Some(formula.raw.clone());
+        cell.foreign = false;
+        cell.implicit_format = None;
+        formula_engine.register_formula(cell_ref, formula);
         
-        if matches!(value, CellValue::Empty) {
-            column.shift_remove(&cell_ref.row);
-            if column.is_empty() {
-                self.columns.remove(&cell_ref.col);
+        Ok(())
+    }
+
+    /// Rows with a stored cell in `col`, in storage order
+    pub(crate) fn column_rows(&self, col: u32) -> impl Iterator<Item = u32> + '_ {
+        self.columns.get(&col).into_iter().flat_map(|column| column.iter().map(|(row, _)| row))
+    }
+
+    /// Every formula cell with its formula text, in row-major order
+    pub fn list_formulas(&self) -> Vec<FormulaListing> {
+        let mut formulas = self.stored_formulas();
+        formulas.sort_by_key(|(cell, _)| (cell.row, cell.col));
+        formulas.into_iter()
+            .map(|(cell, formula)| FormulaListing { reference: cell.to_a1(), formula })
+            .collect()
+    }
+
+    /// Formula text stored in each cell, as read from a saved sheet
+    pub(crate) fn stored_formulas(&self) -> Vec<(CellRef, String)> {
+        self.columns.iter()
+            .flat_map(|(&col, column)| {
+                column.iter().filter_map(move |(row, cell)| Some((CellRef::new(row, col), cell.formula.clone()?)))
+            })
+            .collect()
+    }
+
+    /// Write a raw value, keeping any existing formatting, note and validation
+    ///
+    /// Doesn't touch the formula engine: only for cells that can't hold a
+    /// formula, such as spill targets. Everything else goes through `set_cell_value`.
+    pub(crate) fn set_value(&mut self, cell_ref: CellRef, value: CellValue) -> Result<(), GridError> {
+        self.check_bounds(cell_ref)?;
+        self.touch();
+        
+        match self.get_cell_mut(cell_ref) {
+            Some(cell) => {
+                cell.value = value;
+                cell.formula = None;
+                cell.foreign = false;
+                cell.implicit_format = None;
             }
-        } else {
-            column.insert(cell_ref.row, Cell::new(value));
+            None if matches!(value, CellValue::Empty) => {}
+            None => self.columns.entry(cell_ref.col).or_default().insert(cell_ref.row, Cell::new(value)),
         }
+        self.remove_if_blank(cell_ref);
         
         Ok(())
     }
 
-    /// Set a formula on a cell
-    pub fn set_formula(&mut self, cell_ref: CellRef, formula: Formula) -> Result<(), GridError> {
+    /// Attach a note to a cell, or remove it with `None`
+    pub fn set_note(&mut self, cell_ref: CellRef, note: Option<String>) -> Result<(), GridError> {
+        self.update_attributes(cell_ref, |cell| cell.note = note)
+    }
+
+    /// Restrict a cell to a list of allowed values, or lift the restriction with `None`
+    pub fn set_validation(&mut self, cell_ref: CellRef, options: Option<Vec<String>>) -> Result<(), GridError> {
+        self.update_attributes(cell_ref, |cell| cell.validation = options)
+    }
+
+    fn update_attributes(&mut self, cell_ref: CellRef, update: impl FnOnce(&mut Cell)) -> Result<(), GridError> {
         self.check_bounds(cell_ref)?;
+        self.touch();
         
-        let column = self.columns.entry(cell_ref.col).or_insert_with(IndexMap::new);
-        column.insert(cell_ref.row, Cell::with_formula(CellValue::Empty, formula.raw.clone()));
+        let column = self.columns.entry(cell_ref.col).or_default();
+        update(column.get_or_insert_with(cell_ref.row, Cell::default));
+        self.remove_if_blank(cell_ref);
         
         Ok(())
     }
 
+    /// Drop the cell from storage if it's blank (see `Cell::is_blank`)
+    ///
+    /// Every write that can leave a cell empty ends here, so a cell is
+    /// stored exactly when it holds something, whatever edits led there.
+    pub(crate) fn remove_if_blank(&mut self, cell_ref: CellRef) {
+        let Some(column) = self.columns.get_mut(&cell_ref.col) else {
+            return;
+        };
+        if column.get(cell_ref.row).is_some_and(Cell::is_blank) {
+            column.remove(cell_ref.row);
+        }
+        if column.is_empty() {
+            self.columns.remove(&cell_ref.col);
+        }
+    }
+
     /// Set a computed value (from formula evaluation)
     pub fn set_computed_value(&mut self, cell_ref: CellRef, value: CellValue) -> Result<(), GridError> {
         self.check_bounds(cell_ref)?;
         
         if let Some(cell) = self.get_cell_mut(cell_ref) {
             cell.value = value;
+            self.revision += 1;
         }
+        self.remove_if_blank(cell_ref);
         
         Ok(())
     }
 
+    /// Record the number format a formula cell inherits, returning whether it changed
+    pub(crate) fn set_implicit_format(&mut self, cell_ref: CellRef, number_format: Option<String>) -> bool {
+        let Some(cell) = self.get_cell_mut(cell_ref) else {
+            return false;
+        };
+        if cell.implicit_format == number_format {
+            return false;
+        }
+        cell.implicit_format = number_format;
+        self.revision += 1;
+        true
+    }
+
     /// Set formatting for a cell (merges with existing format)
-    pub fn set_format(&mut self, cell_ref: CellRef, format: crate::cell::CellFormat) -> Result<(), GridError> {
+    ///
+    /// Out-of-range font sizes are clamped, with a warning.
+    pub fn set_format(&mut self, cell_ref: CellRef, mut format: crate::cell::CellFormat) -> Result<(), GridError> {
         self.check_bounds(cell_ref)?;
+        if let Some(details) = warnings::clamp_format(&mut format) {
+            self.warn(EngineWarning::new(WarningCode::FormatClamped, "Format was adjusted to fit the allowed range").at(cell_ref).with_details(details));
+        }
+        self.touch();
         
-        let column = self.columns.entry(cell_ref.col).or_insert_with(IndexMap::new);
-        let cell = column.entry(cell_ref.row).or_insert_with(|| Cell::new(CellValue::Empty));
+        let column = self.columns.entry(cell_ref.col).or_default();
+        let cell = column.get_or_insert_with(cell_ref.row, || Cell::new(CellValue::Empty));
         
-        // Merge with existing format
-        if let Some(existing_format) = &mut cell.format {
-            if format.number_format.is_some() {
-                existing_format.number_format = format.number_format;
-            }
-            if format.font_bold.is_some() {
-                existing_format.font_bold = format.font_bold;
-            }
-            if format.font_italic.is_some() {
-                existing_format.font_italic = format.font_italic;
-            }
-            if format.font_underline.is_some() {
-                existing_format.font_underline = format.font_underline;
-            }
-            if format.font_family.is_some() {
-                existing_format.font_family = format.font_family;
-            }
-            if format.font_size.is_some() {
-                existing_format.font_size = format.font_size;
-            }
-            if format.font_color.is_some() {
-                existing_format.font_color = format.font_color;
-            }
-            if format.bg_color.is_some() {
-                existing_format.bg_color = format.bg_color;
-            }
-            if format.align_h.is_some() {
-                existing_format.align_h = format.align_h;
-            }
-            if format.align_v.is_some() {
-                existing_format.align_v = format.align_v;
+        // Merge with existing format; on a styled cell the change is an override
+        match cell.style.clone() {
+            Some(style) => {
+                cell.format_overrides.get_or_insert_with(CellFormat::default).merge(format);
+                restyle(cell, self.styles.get(&style));
             }
-        } else {
-            cell.format = Some(format);
+            None => cell.format.get_or_insert_with(CellFormat::default).merge(format),
         }
+        self.remove_if_blank(cell_ref);
         
         Ok(())
     }
 
     /// Apply formatting to a range of cells
-    pub fn apply_format_to_range(&mut self, start_row: u32, start_col: u32, end_row: u32, end_col: u32, format: crate::cell::CellFormat) -> Result<(), GridError> {
+    pub fn apply_format_to_range(&mut self, start_row: u32, start_col: u32, end_row: u32, end_col: u32, mut format: crate::cell::CellFormat) -> Result<(), GridError> {
+        // Clamp once so the range gets a single warning rather than one per cell
+        if let Some(details) = warnings::clamp_format(&mut format) {
+            let end = CellRef::new(end_row.min(self.rows - 1), end_col.min(self.cols - 1));
+            self.warn(EngineWarning::new(WarningCode::FormatClamped, "Format was adjusted to fit the allowed range")
+                .over(CellRef::new(start_row, start_col), end)
+                .with_details(details));
+        }
         for row in start_row..=end_row.min(self.rows - 1) {
             for col in start_col..=end_col.min(self.cols - 1) {
                 self.set_format(CellRef::new(row, col), format.clone())?;
@@ -181,28 +494,58 @@ impl Grid {
     }
 
     /// Check if a cell reference is within bounds
-    fn check_bounds(&self, cell_ref: CellRef) -> Result<(), GridError> {
+    pub(crate) fn check_bounds(&self, cell_ref: CellRef) -> Result<(), GridError> {
         if cell_ref.row >= self.rows || cell_ref.col >= self.cols {
-            return Err(GridError::OutOfBounds(cell_ref));
+            return Err(GridError::OutOfBounds { cell: cell_ref, rows: self.rows, cols: self.cols });
+        }
+        Ok(())
+    }
+
+    /// Grow the grid once so every one of `cells` fits
+    ///
+    /// Fails without changing anything if a cell lies past the dimension limits.
+    pub fn expand_to_fit(&mut self, cells: impl Iterator<Item = CellRef>) -> Result<(), GridError> {
+        let (mut rows, mut cols) = (self.rows, self.cols);
+        for cell in cells {
+            if cell.row >= self.dimension_limits.max_rows || cell.col >= self.dimension_limits.max_cols {
+                return Err(GridError::OutOfBounds { cell, rows: self.rows, cols: self.cols });
+            }
+            rows = rows.max(cell.row + 1);
+            cols = cols.max(cell.col + 1);
+        }
+        if (rows, cols) != (self.rows, self.cols) {
+            self.rows = rows;
+            self.cols = cols;
+            self.touch();
         }
         Ok(())
     }
 
-    /// Get cells in a range (inclusive)
-    pub fn get_range(&self, start_row: u32, start_col: u32, end_row: u32, end_col: u32) -> Vec<CellData> {
+    /// Current row and column count
+    pub fn dimensions(&self) -> GridDimensions {
+        GridDimensions { rows: self.rows, cols: self.cols }
+    }
+
+    /// Change how far auto-expanding writes may grow the grid
+    ///
+    /// A grid already larger than the new limits keeps its size.
+    pub fn set_dimension_limits(&mut self, max_rows: u32, max_cols: u32) -> Result<(), GridError> {
+        if max_rows == 0 || max_cols == 0 {
+            return Err(GridError::InvalidSize(format!("invalid dimension limits {}x{}", max_rows, max_cols)));
+        }
+        self.dimension_limits = DimensionLimits { max_rows, max_cols };
+        Ok(())
+    }
+
+    /// Get cells in a range (inclusive), with formulas as the engine has them
+    pub fn get_range(&self, start_row: u32, start_col: u32, end_row: u32, end_col: u32, formula_engine: &FormulaEngine) -> Vec<CellData> {
         let mut cells = Vec::new();
         
         for col in start_col..=end_col.min(self.cols - 1) {
             if let Some(column) = self.columns.get(&col) {
-                for (&row, cell) in column.iter() {
+                for (row, cell) in column.iter() {
                     if row >= start_row && row <= end_row {
-                        cells.push(CellData {
-                            row,
-                            col,
-                            value: cell.value.display(),
-                            formula: cell.formula.clone(),
-                            format: cell.format.clone(),
-                        });
+                        cells.push(CellData::from_cell(CellRef::new(row, col), cell, formula_engine));
                     }
                 }
             }
@@ -211,36 +554,445 @@ impl Grid {
         cells
     }
 
-    /// Get column width
+    /// Numeric values of a range as a flat series (for sparklines)
+    ///
+    /// Cells are read row by row or column by column depending on `order`;
+    /// anything that isn't a number (including blanks) becomes `None`.
+    pub fn series(&self, start: CellRef, end: CellRef, order: SeriesOrder) -> Vec<Option<f64>> {
+        let (top, bottom) = (start.row.min(end.row), start.row.max(end.row));
+        let (left, right) = (start.col.min(end.col), start.col.max(end.col));
+        let value_at = |row, col| match self.get_cell(CellRef::new(row, col)).map(|c| &c.value) {
+            Some(CellValue::Number(n)) => Some(*n),
+            _ => None,
+        };
+        
+        let mut series = Vec::new();
+        match order {
+            SeriesOrder::RowMajor => {
+                for row in top..=bottom {
+                    for col in left..=right {
+                        series.push(value_at(row, col));
+                    }
+                }
+            }
+            SeriesOrder::ColumnMajor => {
+                for col in left..=right {
+                    for row in top..=bottom {
+                        series.push(value_at(row, col));
+                    }
+                }
+            }
+        }
+        series
+    }
+
+    /// Guess whether a range starts with a header row
+    ///
+    /// The candidate is the range's first non-empty row. It counts as a header
+    /// when most of its non-empty cells are text while most of the non-empty
+    /// cells below it are numbers (dates are stored as numbers too).
+    pub fn detect_header(&self, start: CellRef, end: CellRef) -> HeaderDetection {
+        let (top, bottom) = (start.row.min(end.row), start.row.max(end.row));
+        let (left, right) = (start.col.min(end.col), start.col.max(end.col));
+        
+        // (non-empty, text, numeric) counts for a row
+        let tally = |row: u32| {
+            let mut counts = (0usize, 0usize, 0usize);
+            for col in left..=right {
+                match self.get_cell(CellRef::new(row, col)).map(|c| &c.value) {
+                    None | Some(CellValue::Empty) => continue,
+                    Some(CellValue::Text(_)) => counts.1 += 1,
+                    Some(CellValue::Number(_)) => counts.2 += 1,
+                    Some(_) => {}
+                }
+                counts.0 += 1;
+            }
+            counts
+        };
+        
+        let no_header = HeaderDetection { has_header: false, header_row: None };
+        let Some((candidate, (filled, text, _))) = (top..=bottom)
+            .map(|row| (row, tally(row)))
+            .find(|(_, counts)| counts.0 > 0)
+        else {
+            return no_header;
+        };
+        if text * 2 <= filled {
+            return no_header;
+        }
+        
+        let (body_filled, body_numeric) = (candidate + 1..=bottom)
+            .map(tally)
+            .fold((0, 0), |(f, n), (filled, _, numeric)| (f + filled, n + numeric));
+        if body_filled == 0 || body_numeric * 2 <= body_filled {
+            return no_header;
+        }
+        
+        HeaderDetection { has_header: true, header_row: Some(candidate) }
+    }
+
+    /// Get column width (0 for hidden columns)
     pub fn get_col_width(&self, col: u32) -> f32 {
+        if self.hidden_cols.contains(&col) {
+            return 0.0;
+        }
         *self.col_widths.get(&col).unwrap_or(&self.default_col_width)
     }
 
-    /// Set column width
-    pub fn set_col_width(&mut self, col: u32, width: f32) {
-        if (width - self.default_col_width).abs() < 0.01 {
-            self.col_widths.remove(&col);
-        } else {
-            self.col_widths.insert(col, width);
+    /// Set column width, clamped to the grid's size limits
+    ///
+    /// NaN and infinite widths are rejected.
+    pub fn set_col_width(&mut self, col: u32, width: f32) -> Result<(), GridError> {
+        let width = self.normalize_size(width)?;
+        self.touch();
+        self.content_width.set(None);
+        store_size(&mut self.col_widths, col, width, self.default_col_width);
+        Ok(())
+    }
+
+    /// Set the width of every column in each range; nothing changes if any width is invalid
+    pub fn set_col_widths(&mut self, ranges: &[SizeRange]) -> Result<(), GridError> {
+        let sizes = ranges.iter()
+            .map(|range| self.size_limits.normalize(range.size))
+            .collect::<Result<Vec<_>, _>>()?;
+        for (range, &size) in ranges.iter().zip(&sizes) {
+            self.warn_if_clamped(range.size, size);
+        }
+        self.touch();
+        self.content_width.set(None);
+        for (range, size) in ranges.iter().zip(sizes) {
+            for col in range.start.min(range.end)..=range.start.max(range.end) {
+                store_size(&mut self.col_widths, col, size, self.default_col_width);
+            }
+        }
+        Ok(())
+    }
+
+    /// Normalize a width or height, warning if it had to be clamped
+    fn normalize_size(&mut self, size: f32) -> Result<f32, GridError> {
+        let normalized = self.size_limits.normalize(size)?;
+        self.warn_if_clamped(size, normalized);
+        Ok(normalized)
+    }
+
+    fn warn_if_clamped(&mut self, requested: f32, normalized: f32) {
+        if requested < self.size_limits.min || requested > self.size_limits.max {
+            self.warn(EngineWarning::new(WarningCode::SizeClamped, "Size was clamped to the allowed range")
+                .with_details(format!("requested {}, used {}", requested, normalized)));
         }
     }
 
-    /// Get row height
+    /// Put a column back to the default width
+    pub fn reset_col_width(&mut self, col: u32) {
+        self.touch();
+        self.content_width.set(None);
+        self.col_widths.remove(&col);
+    }
+
+    /// Get row height (0 for hidden rows)
     pub fn get_row_height(&self, row: u32) -> f32 {
+        if self.hidden_rows.contains(&row) {
+            return 0.0;
+        }
         *self.row_heights.get(&row).unwrap_or(&self.default_row_height)
     }
 
-    /// Set row height
-    pub fn set_row_height(&mut self, row: u32, height: f32) {
-        if (height - self.default_row_height).abs() < 0.01 {
-            self.row_heights.remove(&row);
+    /// Set row height, clamped to the grid's size limits
+    ///
+    /// NaN and infinite heights are rejected.
+    pub fn set_row_height(&mut self, row: u32, height: f32) -> Result<(), GridError> {
+        let height = self.normalize_size(height)?;
+        self.touch();
+        self.content_height.set(None);
+        store_size(&mut self.row_heights, row, height, self.default_row_height);
+        Ok(())
+    }
+
+    /// Put a row back to the default height
+    pub fn reset_row_height(&mut self, row: u32) {
+        self.touch();
+        self.content_height.set(None);
+        self.row_heights.remove(&row);
+    }
+
+    /// Change the allowed range for widths and heights
+    ///
+    /// Existing sizes are left as they are; the limits apply to later changes.
+    pub fn set_size_limits(&mut self, min: f32, max: f32) -> Result<(), GridError> {
+        if !(min.is_finite() && max.is_finite() && min > 0.0 && min <= max) {
+            return Err(GridError::InvalidSize(format!("invalid size limits {}..{}", min, max)));
+        }
+        self.size_limits = SizeLimits { min, max };
+        Ok(())
+    }
+
+    /// Hide or show a column; its width is kept for when it's shown again
+    pub fn set_col_hidden(&mut self, col: u32, hidden: bool) {
+        self.touch();
+        self.content_width.set(None);
+        if hidden {
+            self.hidden_cols.insert(col);
+        } else {
+            self.hidden_cols.remove(&col);
+        }
+    }
+
+    /// Hide or show a row; its height is kept for when it's shown again
+    pub fn set_row_hidden(&mut self, row: u32, hidden: bool) {
+        self.touch();
+        self.content_height.set(None);
+        if hidden {
+            self.hidden_rows.insert(row);
+        } else {
+            self.hidden_rows.remove(&row);
+        }
+    }
+
+    /// Total width of all columns in pixels, for sizing the horizontal scrollbar
+    pub fn total_content_width(&self) -> f64 {
+        cached_extent(&self.content_width, self.cols, || {
+            line_extent(self.cols, self.default_col_width, &self.col_widths, &self.hidden_cols)
+        })
+    }
+
+    /// Total height of all rows in pixels, for sizing the vertical scrollbar
+    pub fn total_content_height(&self) -> f64 {
+        cached_extent(&self.content_height, self.rows, || {
+            line_extent(self.rows, self.default_row_height, &self.row_heights, &self.hidden_rows)
+        })
+    }
+
+    /// Stored size (if not the default) and hidden flag of a row or column
+    pub(crate) fn line_layout(&self, axis: Axis, line: u32) -> (Option<f32>, bool) {
+        match axis {
+            Axis::Rows => (self.row_heights.get(&line).copied(), self.hidden_rows.contains(&line)),
+            Axis::Cols => (self.col_widths.get(&line).copied(), self.hidden_cols.contains(&line)),
+        }
+    }
+
+    /// Put back a row or column's layout from `line_layout`
+    pub(crate) fn set_line_layout(&mut self, axis: Axis, line: u32, size: Option<f32>, hidden: bool) {
+        let (sizes, hidden_lines) = match axis {
+            Axis::Rows => (&mut self.row_heights, &mut self.hidden_rows),
+            Axis::Cols => (&mut self.col_widths, &mut self.hidden_cols),
+        };
+        match size {
+            Some(size) => sizes.insert(line, size),
+            None => sizes.remove(&line),
+        };
+        if hidden {
+            hidden_lines.insert(line);
         } else {
-            self.row_heights.insert(row, height);
+            hidden_lines.remove(&line);
+        }
+        self.content_width.set(None);
+        self.content_height.set(None);
+        self.touch();
+    }
+
+    /// Insert `count` blank columns before `start`, growing the grid
+    ///
+    /// Cells, column widths, hidden columns and chart bindings move right,
+    /// and references into the moved columns are rewritten to follow them.
+    /// Fails without changing anything if the grid would outgrow its
+    /// dimension limits. Returns the cells whose values changed on
+    /// recalculation.
+    pub fn insert_cols(&mut self, start: u32, count: u32, formula_engine: &mut FormulaEngine) -> Result<Vec<CellRef>, GridError> {
+        if count == 0 {
+            return Ok(Vec::new());
+        }
+        self.check_growth(Axis::Cols, count)?;
+        formula_engine.clear_spills(self)?;
+        shift_keys_up(&mut self.columns, start, count);
+        shift_keys_up(&mut self.col_widths, start, count);
+        self.cols = self.cols.saturating_add(count);
+        self.hidden_cols = std::mem::take(&mut self.hidden_cols)
+            .into_iter()
+            .map(|col| if col >= start { col + count } else { col })
+            .collect();
+        self.charts.cols_inserted(start, count);
+        self.scenarios.lines_inserted(Axis::Cols, start, count, formula_engine);
+        self.insert_formula_lines(Axis::Cols, start, count, formula_engine);
+        self.insert_name_lines(Axis::Cols, start, count);
+        self.content_width.set(None);
+        self.touch();
+        
+        self.reload_formulas(formula_engine)
+    }
+
+    /// Delete `count` columns starting at `start`, moving later columns left and shrinking the grid
+    ///
+    /// Cells, column widths, hidden columns and chart bindings move left, and
+    /// references are rewritten to follow them as `delete_rows` does: a
+    /// reference that pointed only at deleted columns becomes `#REF!` in
+    /// its formula. The grid keeps at least one column. Returns the cells
+    /// whose formulas lost a reference and those whose values changed on
+    /// recalculation.
+    pub fn delete_cols(&mut self, start: u32, count: u32, formula_engine: &mut FormulaEngine) -> Result<Vec<CellRef>, GridError> {
+        let count = count.min(self.cols.saturating_sub(start));
+        if count == 0 {
+            return Ok(Vec::new());
+        }
+        formula_engine.clear_spills(self)?;
+        let end = start + count;
+        shift_keys_down(&mut self.columns, start, count);
+        self.columns.retain(|_, column| !column.is_empty());
+        self.cols = (self.cols - count).max(1);
+        shift_keys_down(&mut self.col_widths, start, count);
+        self.hidden_cols = std::mem::take(&mut self.hidden_cols)
+            .into_iter()
+            .filter(|col| !(start..end).contains(col))
+            .map(|col| if col >= end { col - count } else { col })
+            .collect();
+        self.charts.cols_deleted(start, count);
+        self.scenarios.lines_deleted(Axis::Cols, start, count, formula_engine);
+        let mut changed = self.delete_formula_lines(Axis::Cols, start, count, formula_engine);
+        self.delete_name_lines(Axis::Cols, start, count);
+        self.content_width.set(None);
+        self.touch();
+        
+        for cell in self.reload_formulas(formula_engine)? {
+            if !changed.contains(&cell) {
+                changed.push(cell);
+            }
+        }
+        Ok(changed)
+    }
+
+    /// Insert `count` blank rows before `start`, growing the grid
+    ///
+    /// Cells, row heights, hidden rows and chart bindings move down, and
+    /// references into the moved rows are rewritten to follow them. Fails
+    /// like `insert_cols` past the dimension limits. Returns the cells whose
+    /// values changed on recalculation.
+    pub fn insert_rows(&mut self, start: u32, count: u32, formula_engine: &mut FormulaEngine) -> Result<Vec<CellRef>, GridError> {
+        if count == 0 {
+            return Ok(Vec::new());
+        }
+        self.check_growth(Axis::Rows, count)?;
+        formula_engine.clear_spills(self)?;
+        for column in self.columns.values_mut() {
+            let rows: Vec<u32> = column.iter().map(|(row, _)| row).filter(|&row| row >= start).collect();
+            let moved: Vec<(u32, Cell)> = rows.into_iter()
+                .filter_map(|row| column.remove(row).map(|cell| (row, cell)))
+                .collect();
+            for (row, cell) in moved {
+                column.insert(row + count, cell);
+            }
+        }
+        self.rows = self.rows.saturating_add(count);
+        shift_keys_up(&mut self.row_heights, start, count);
+        self.hidden_rows = std::mem::take(&mut self.hidden_rows)
+            .into_iter()
+            .map(|row| if row >= start { row + count } else { row })
+            .collect();
+        self.charts.rows_inserted(start, count);
+        self.scenarios.lines_inserted(Axis::Rows, start, count, formula_engine);
+        self.insert_formula_lines(Axis::Rows, start, count, formula_engine);
+        self.insert_name_lines(Axis::Rows, start, count);
+        self.content_height.set(None);
+        self.touch();
+        
+        self.reload_formulas(formula_engine)
+    }
+
+    /// Delete `count` rows starting at `start`, moving later rows up and shrinking the grid
+    ///
+    /// Cells, row heights, hidden rows and chart bindings move up, and
+    /// references are rewritten to follow them. A range loses just its
+    /// deleted rows; a reference that pointed only at deleted rows becomes
+    /// `#REF!`, and the rest of its formula is kept, so `=IFERROR(A2,0)+A1`
+    /// reads `=IFERROR(#REF!,0)+A1` once row 2 goes. The grid keeps at least
+    /// one row. Returns the cells whose formulas lost a reference and those
+    /// whose values changed on recalculation.
+    pub fn delete_rows(&mut self, start: u32, count: u32, formula_engine: &mut FormulaEngine) -> Result<Vec<CellRef>, GridError> {
+        let count = count.min(self.rows.saturating_sub(start));
+        if count == 0 {
+            return Ok(Vec::new());
         }
+        formula_engine.clear_spills(self)?;
+        let end = start + count;
+        for column in self.columns.values_mut() {
+            let rows: Vec<u32> = column.iter().map(|(row, _)| row).filter(|&row| row >= start).collect();
+            let moved: Vec<(u32, Cell)> = rows.into_iter()
+                .filter_map(|row| column.remove(row).map(|cell| (row, cell)))
+                .filter(|(row, _)| *row >= end)
+                .collect();
+            for (row, cell) in moved {
+                column.insert(row - count, cell);
+            }
+        }
+        self.columns.retain(|_, column| !column.is_empty());
+        self.rows = (self.rows - count).max(1);
+        shift_keys_down(&mut self.row_heights, start, count);
+        self.hidden_rows = std::mem::take(&mut self.hidden_rows)
+            .into_iter()
+            .filter(|row| !(start..end).contains(row))
+            .map(|row| if row >= end { row - count } else { row })
+            .collect();
+        self.charts.rows_deleted(start, count);
+        self.scenarios.lines_deleted(Axis::Rows, start, count, formula_engine);
+        let mut changed = self.delete_formula_lines(Axis::Rows, start, count, formula_engine);
+        self.delete_name_lines(Axis::Rows, start, count);
+        self.content_height.set(None);
+        self.touch();
+        
+        for cell in self.reload_formulas(formula_engine)? {
+            if !changed.contains(&cell) {
+                changed.push(cell);
+            }
+        }
+        Ok(changed)
+    }
+
+    /// Fail if `count` more lines along `axis` would pass the dimension limits
+    ///
+    /// The error names the first line past the limit, as `expand_to_fit` does
+    /// for a write.
+    fn check_growth(&self, axis: Axis, count: u32) -> Result<(), GridError> {
+        let (extent, limit) = match axis {
+            Axis::Rows => (self.rows, self.dimension_limits.max_rows),
+            Axis::Cols => (self.cols, self.dimension_limits.max_cols),
+        };
+        if u64::from(extent) + u64::from(count) <= u64::from(limit) {
+            return Ok(());
+        }
+        let line = limit.max(extent);
+        let cell = match axis {
+            Axis::Rows => CellRef::new(line, 0),
+            Axis::Cols => CellRef::new(0, line),
+        };
+        Err(GridError::OutOfBounds { cell, rows: self.rows, cols: self.cols })
+    }
+
+    /// Re-register every formula and recalculate after rows or columns moved
+    ///
+    /// A full pass rather than re-registering only the moved and rewritten
+    /// formulas: the engine keys formulas, dependency edges, cycle marks and
+    /// spill anchors by cell, so everything at or past the edit has to be
+    /// rebuilt anyway, and the reference rewrite before this has already
+    /// parsed every stored formula. `load_formulas` skips cells marked
+    /// foreign, so they keep their frozen values.
+    fn reload_formulas(&mut self, formula_engine: &mut FormulaEngine) -> Result<Vec<CellRef>, GridError> {
+        formula_engine.load_formulas(self);
+        Ok(formula_engine.recalculate_all(self)?)
     }
 
     /// Apply a batch patch of updates
+    ///
+    /// Every target is bounds-checked before anything is written. With
+    /// `auto_expand`, the grid instead grows (once, to fit the furthest
+    /// target) as long as that stays within the dimension limits.
     pub fn apply_patch(&mut self, patch: GridPatch, formula_engine: &mut FormulaEngine) -> Result<Vec<CellRef>, GridError> {
+        let targets = patch.updates.iter().map(|update| CellRef::new(update.row, update.col));
+        if patch.auto_expand.unwrap_or(false) {
+            self.expand_to_fit(targets)?;
+        } else {
+            for cell_ref in targets {
+                self.check_bounds(cell_ref)?;
+            }
+        }
+        
         let mut affected = Vec::new();
         
         for update in patch.updates {
@@ -249,13 +1001,81 @@ impl Grid {
             
             if let Some(formula) = update.formula {
                 let parsed = formula_engine.parse(&formula)?;
-                formula_engine.register_formula(cell_ref, parsed.clone());
-                self.set_formula(cell_ref, parsed)?;
+                self.set_cell_formula(cell_ref, parsed, formula_engine)?;
             } else if let Some(value) = update.value {
-                self.set_value(cell_ref, CellValue::parse(&value))?;
+                self.enter_value(cell_ref, &value, formula_engine)?;
+            }
+        }
+        self.warn_empty_ranges(&affected, formula_engine);
+        
+        self.recalculate_cells(affected, formula_engine)
+    }
+
+    /// Paste a block of raw text values with its top-left corner at `top_left`
+    ///
+    /// Entries starting with `=` are treated as formulas. With
+    /// `options.infer_formats`, each pasted column gets a `number_format`
+    /// inferred from its values, unless the destination cell already has one.
+    pub fn paste_block(&mut self, top_left: CellRef, block: &[Vec<String>], options: PasteOptions, formula_engine: &mut FormulaEngine) -> Result<Vec<CellRef>, GridError> {
+        let mut affected = Vec::new();
+        
+        for (r, row_values) in block.iter().enumerate() {
+            for (c, raw) in row_values.iter().enumerate() {
+                let cell_ref = CellRef::new(top_left.row + r as u32, top_left.col + c as u32);
+                affected.push(cell_ref);
+                
+                if raw.starts_with('=') {
+                    let parsed = formula_engine.parse(raw)?;
+                    self.set_cell_formula(cell_ref, parsed, formula_engine)?;
+                } else {
+                    let value = if options.infer_formats { format_infer::parse_value(raw) } else { CellValue::parse(raw) };
+                    if let Some(details) = warnings::coercion_loss(raw, &value) {
+                        self.warn(EngineWarning::new(WarningCode::ValueCoerced, "Pasted text was converted to a number").at(cell_ref).with_details(details));
+                    }
+                    self.set_cell_value(cell_ref, value, formula_engine)?;
+                }
+            }
+        }
+        self.warn_empty_ranges(&affected, formula_engine);
+        
+        if options.infer_formats {
+            let width = block.iter().map(|r| r.len()).max().unwrap_or(0);
+            for c in 0..width {
+                let column_values = block.iter().filter_map(|r| r.get(c)).map(|s| s.as_str());
+                let Some(number_format) = format_infer::infer_column_format(column_values) else {
+                    continue;
+                };
+                for (r, row_values) in block.iter().enumerate() {
+                    let Some(raw) = row_values.get(c) else {
+                        continue;
+                    };
+                    if format_infer::classify(raw).is_none() {
+                        continue;
+                    }
+                    let cell_ref = CellRef::new(top_left.row + r as u32, top_left.col + c as u32);
+                    if !self.has_number_format(cell_ref) {
+                        self.set_format(cell_ref, CellFormat {
+                            number_format: Some(number_format.clone()),
+                            ..CellFormat::default()
+                        })?;
+                    }
+                }
+            }
+        }
+        
+        // Large pastes are where repetitive columns come from
+        let width = block.iter().map(|r| r.len()).max().unwrap_or(0) as u32;
+        for col in top_left.col..top_left.col + width {
+            if let Some(column) = self.columns.get_mut(&col) {
+                column.compact();
             }
         }
         
+        self.recalculate_cells(affected, formula_engine)
+    }
+
+    /// Recalculate the given cells and their dependents, returning everything touched
+    pub(crate) fn recalculate_cells(&mut self, mut affected: Vec<CellRef>, formula_engine: &mut FormulaEngine) -> Result<Vec<CellRef>, GridError> {
         // Collect cells to recalculate (copy to avoid borrow issues)
         let cells_to_recalc: Vec<CellRef> = affected.clone();
         
@@ -278,31 +1098,248 @@ impl Grid {
     }
 
     /// Import from JSON
+    ///
+    /// Blank cells a saved sheet may carry are dropped.
     pub fn from_json(json: &str) -> Result<Self, GridError> {
-        serde_json::from_str(json).map_err(|e| GridError::Serialization(e.to_string()))
+        let mut grid: Grid = serde_json::from_str(json).map_err(|e| GridError::Serialization(e.to_string()))?;
+        let blank: Vec<CellRef> = grid.columns.iter()
+            .flat_map(|(&col, column)| column.iter().filter(|(_, cell)| cell.is_blank()).map(move |(row, _)| CellRef::new(row, col)))
+            .collect();
+        for cell_ref in blank {
+            grid.remove_if_blank(cell_ref);
+        }
+        Ok(grid)
+    }
+
+    /// Run-length encode columns that are large and highly repetitive
+    ///
+    /// Storage is transparent to callers; columns switch back to plain maps on
+    /// their own once writes fragment their runs.
+    pub fn compact_columns(&mut self) {
+        for column in self.columns.values_mut() {
+            column.compact();
+        }
+    }
+
+    /// Rough heap footprint of cell storage in bytes
+    pub fn storage_bytes(&self) -> usize {
+        self.columns.values().map(ColumnStore::approx_heap_bytes).sum()
     }
 
-    /// Get total number of non-empty cells
+    /// Number of stored cells: those with a value, formula, format, style, note or validation
     pub fn cell_count(&self) -> usize {
         self.columns.values().map(|col| col.len()).sum()
     }
+
+    /// Last row and column holding a stored cell, if any
+    pub fn used_extent(&self) -> Option<(u32, u32)> {
+        let last_col = *self.columns.keys().max()?;
+        let last_row = self.columns.values().flat_map(|column| column.iter().map(|(row, _)| row)).max()?;
+        Some((last_row, last_col))
+    }
+
+    /// Shrink `rows`/`cols` to the used range plus a small margin
+    ///
+    /// Never grows the grid. Cells are untouched; sizes and hidden flags for
+    /// lines past the new edge are dropped. An empty grid keeps just the margin.
+    pub fn shrink_to_fit(&mut self) {
+        let (rows, cols) = match self.used_extent() {
+            Some((last_row, last_col)) => (last_row + 1 + SHRINK_MARGIN, last_col + 1 + SHRINK_MARGIN),
+            None => (SHRINK_MARGIN, SHRINK_MARGIN),
+        };
+        let rows = rows.min(self.rows);
+        let cols = cols.min(self.cols);
+        if (rows, cols) == (self.rows, self.cols) {
+            return;
+        }
+        self.rows = rows;
+        self.cols = cols;
+        self.row_heights.retain(|&row, _| row < rows);
+        self.col_widths.retain(|&col, _| col < cols);
+        self.hidden_rows.retain(|&row| row < rows);
+        self.hidden_cols.retain(|&col| col < cols);
+        self.touch();
+    }
+}
+
+/// Remove keys in `start..start + count` and move higher keys down by `count`
+///
+/// Done in two phases (drain, then reinsert in key order) so that moving a key
+/// onto one that hasn't been moved yet can't clobber it.
+fn shift_keys_down<V>(map: &mut HashMap<u32, V>, start: u32, count: u32) {
+    let end = start.saturating_add(count);
+    let keys: Vec<u32> = map.keys().copied().filter(|&k| k >= start).collect();
+    let mut moved: Vec<(u32, V)> = keys
+        .into_iter()
+        .filter_map(|k| map.remove(&k).map(|v| (k, v)))
+        .filter(|(k, _)| *k >= end)
+        .collect();
+    moved.sort_by_key(|(k, _)| *k);
+    for (k, v) in moved {
+        map.insert(k - count, v);
+    }
+}
+
+/// Move keys at or above `start` up by `count`
+fn shift_keys_up<V>(map: &mut HashMap<u32, V>, start: u32, count: u32) {
+    let keys: Vec<u32> = map.keys().copied().filter(|&k| k >= start).collect();
+    let moved: Vec<(u32, V)> = keys
+        .into_iter()
+        .filter_map(|k| map.remove(&k).map(|v| (k, v)))
+        .collect();
+    for (k, v) in moved {
+        map.insert(k + count, v);
+    }
+}
+
+/// Allowed range for column widths and row heights, in pixels
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct SizeLimits {
+    pub min: f32,
+    pub max: f32,
+}
+
+impl Default for SizeLimits {
+    fn default() -> Self {
+        Self { min: 4.0, max: 2000.0 }
+    }
+}
+
+/// Largest size auto-expanding writes may grow a grid to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DimensionLimits {
+    pub max_rows: u32,
+    pub max_cols: u32,
+}
+
+impl Default for DimensionLimits {
+    /// Excel's sheet size
+    fn default() -> Self {
+        Self { max_rows: 1_048_576, max_cols: 16_384 }
+    }
+}
+
+impl SizeLimits {
+    /// Clamp a size into range and round it to hundredths of a pixel
+    fn normalize(&self, size: f32) -> Result<f32, GridError> {
+        if !size.is_finite() {
+            return Err(GridError::InvalidSize(format!("{} is not a finite size", size)));
+        }
+        Ok((size.clamp(self.min, self.max) * 100.0).round() / 100.0)
+    }
+}
+
+/// The same size applied to columns `start..=end`
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct SizeRange {
+    pub start: u32,
+    pub end: u32,
+    pub size: f32,
+}
+
+/// Record a normalized size, dropping the entry when it matches the default
+fn store_size(sizes: &mut HashMap<u32, f32>, line: u32, size: f32, default: f32) {
+    // Both sides are rounded to hundredths, so compare at that resolution
+    if (size * 100.0).round() == (default * 100.0).round() {
+        sizes.remove(&line);
+    } else {
+        sizes.insert(line, size);
+    }
+}
+
+/// Reuse a cached extent if it was computed for the same line count
+fn cached_extent(cache: &std::cell::Cell<Option<(u32, f64)>>, count: u32, compute: impl FnOnce() -> f64) -> f64 {
+    if let Some((cached_count, extent)) = cache.get() {
+        if cached_count == count {
+            return extent;
+        }
+    }
+    let extent = compute();
+    cache.set(Some((count, extent)));
+    extent
+}
+
+/// Sum of `count` line sizes without visiting every line
+///
+/// Starts from `default * count` and corrects for custom sizes and hidden
+/// lines, so the cost is proportional to the number of non-default lines.
+fn line_extent(count: u32, default: f32, custom: &HashMap<u32, f32>, hidden: &BTreeSet<u32>) -> f64 {
+    let hidden_count = hidden.range(..count).count();
+    let deltas: f64 = custom.iter()
+        .filter(|(line, _)| **line < count && !hidden.contains(line))
+        .map(|(_, size)| (*size - default) as f64)
+        .sum();
+    default as f64 * (count as usize - hidden_count) as f64 + deltas
 }
 
 /// Simplified cell data for transfer to JS
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct CellData {
     pub row: u32,
     pub col: u32,
     pub value: String,
+    /// Type of the underlying value, so the UI can style without re-parsing
+    pub value_type: ValueType,
     pub formula: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub format: Option<crate::cell::CellFormat>,
+    /// The cell has a list of allowed values (drawn as a dropdown arrow)
+    #[serde(default)]
+    pub has_validation: bool,
+    /// The value is an error (drawn with a badge)
+    #[serde(default)]
+    pub is_error: bool,
+    /// The cell has a note (drawn as a corner triangle)
+    #[serde(default)]
+    pub has_note: bool,
+    /// The formula can't be evaluated here and its value is frozen (drawn with a badge)
+    #[serde(default)]
+    pub is_foreign: bool,
+    /// Number format inherited from the formula's operands, for when `format` sets none
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub implicit_format: Option<String>,
+}
+
+impl CellData {
+    pub fn from_cell(cell_ref: CellRef, cell: &Cell, formula_engine: &FormulaEngine) -> Self {
+        Self::with_options(cell_ref, cell, formula_engine, &PageOptions::default())
+    }
+
+    /// Like `from_cell`, leaving out the formula or formats when `options` says to
+    pub fn with_options(cell_ref: CellRef, cell: &Cell, formula_engine: &FormulaEngine, options: &PageOptions) -> Self {
+        Self {
+            row: cell_ref.row,
+            col: cell_ref.col,
+            value: cell.display(),
+            value_type: cell.value_type(),
+            formula: options.include_formulas.then(|| shown_formula(cell_ref, cell, formula_engine)).flatten(),
+            format: options.include_formats.then(|| cell.format.clone()).flatten(),
+            has_validation: cell.validation.is_some(),
+            is_error: matches!(cell.value, CellValue::Error(_)),
+            has_note: cell.note.is_some(),
+            is_foreign: cell.foreign,
+            implicit_format: options.include_formats.then(|| cell.implicit_format.clone()).flatten(),
+        }
+    }
+}
+
+/// The formula text shown for a cell: the engine's for live formulas, the
+/// stored text for foreign ones the engine doesn't hold
+fn shown_formula(cell_ref: CellRef, cell: &Cell, formula_engine: &FormulaEngine) -> Option<String> {
+    match cell.foreign {
+        true => cell.formula.clone(),
+        false => formula_engine.formula_at(cell_ref).map(|formula| formula.raw.clone()),
+    }
 }
 
 /// A batch update to apply to the grid
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct GridPatch {
     pub updates: Vec<CellUpdate>,
+    /// Grow the grid to fit out-of-bounds targets instead of failing;
+    /// unset means the caller's default (off for `Grid::apply_patch`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub auto_expand: Option<bool>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -315,26 +1352,75 @@ pub struct CellUpdate {
     pub formula: Option<String>,
 }
 
+/// Result of header-row detection for a range
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HeaderDetection {
+    pub has_header: bool,
+    /// Row index of the header, when there is one
+    pub header_row: Option<u32>,
+}
+
+/// A formula cell in `Grid::list_formulas`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FormulaListing {
+    #[serde(rename = "ref")]
+    pub reference: String,
+    pub formula: String,
+}
+
+/// Reading order when flattening a range into a series
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SeriesOrder {
+    RowMajor,
+    ColumnMajor,
+}
+
+/// Options for pasting a block of text into the grid
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct PasteOptions {
+    /// Infer a `number_format` per column from the pasted text
+    #[serde(default)]
+    pub infer_formats: bool,
+}
+
 /// A diff representing changes to the grid (for efficient UI updates)
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct GridDiff {
     pub cells: Vec<CellData>,
+    /// Regenerated data of charts bound to a range containing a changed cell
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub charts: Vec<ChartData>,
+    /// New grid size, when the edit grew the grid
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dimensions: Option<GridDimensions>,
+    /// Changed cells caught in a reference cycle (they hold `#CIRCULAR`)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub circular: Vec<CellRef>,
+    /// Adjustments the engine made to complete the edit
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub warnings: Vec<EngineWarning>,
+}
+
+/// Row and column count of a grid
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GridDimensions {
+    pub rows: u32,
+    pub cols: u32,
 }
 
 impl GridDiff {
-    pub fn from_cells(grid: &Grid, cells: &[CellRef]) -> Self {
+    pub fn from_cells(grid: &Grid, formula_engine: &FormulaEngine, cells: &[CellRef]) -> Self {
         Self {
             cells: cells.iter()
                 .filter_map(|cell_ref| {
-                    grid.get_cell(*cell_ref).map(|cell| CellData {
-                        row: cell_ref.row,
-                        col: cell_ref.col,
-                        value: cell.value.display(),
-                        formula: cell.formula.clone(),
-                        format: cell.format.clone(),
-                    })
+                    grid.get_cell(*cell_ref).map(|cell| CellData::from_cell(*cell_ref, cell, formula_engine))
                 })
                 .collect(),
+            charts: grid.charts_touching(cells),
+            dimensions: None,
+            circular: cells.iter().copied().filter(|cell| formula_engine.is_circular(*cell)).collect(),
+            warnings: Vec::new(),
         }
     }
 }
@@ -342,6 +1428,7 @@ impl GridDiff {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::test_support::set_at;
 
     #[test]
     fn test_grid_basics() {
@@ -381,4 +1468,873 @@ mod tests {
             CellValue::Number(42.0)
         );
     }
+
+    #[test]
+    fn test_paste_block_infers_formats() {
+        let mut grid = Grid::new(100, 10);
+        let mut engine = FormulaEngine::new();
+        
+        // Existing destination format must survive the paste
+        grid.set_format(CellRef::new(2, 1), CellFormat {
+            number_format: Some("0.000%".to_string()),
+            ..CellFormat::default()
+        }).unwrap();
+        
+        let block: Vec<Vec<String>> = [
+            ["Amount", "Rate", "Date", "Net"],
+            ["$1,234.00", "7.5%", "2024-01-31", "(500)"],
+            ["$5.50", "7%", "2024-02-01", "250"],
+        ]
+        .iter()
+        .map(|row| row.iter().map(|s| s.to_string()).collect())
+        .collect();
+        
+        grid.paste_block(CellRef::new(0, 0), &block, PasteOptions { infer_formats: true }, &mut engine).unwrap();
+        
+        let value = |row, col| grid.get_cell(CellRef::new(row, col)).unwrap().value.clone();
+        let number_format = |row, col| grid.get_cell(CellRef::new(row, col))
+            .and_then(|cell| cell.format.as_ref())
+            .and_then(|format| format.number_format.clone());
+        
+        assert_eq!(value(0, 0), CellValue::Text("Amount".to_string()));
+        assert_eq!(value(1, 0), CellValue::Number(1234.0));
+        assert_eq!(value(1, 1), CellValue::Number(0.075));
+        assert_eq!(value(1, 2), CellValue::Number(45322.0));
+        assert_eq!(value(1, 3), CellValue::Number(-500.0));
+        
+        assert_eq!(number_format(0, 0), None);
+        assert_eq!(number_format(1, 0).as_deref(), Some("$#,##0.00"));
+        assert_eq!(number_format(1, 1).as_deref(), Some("0.0%"));
+        assert_eq!(number_format(2, 1).as_deref(), Some("0.000%"));
+        assert_eq!(number_format(2, 2).as_deref(), Some("yyyy-mm-dd"));
+        assert_eq!(number_format(2, 3).as_deref(), Some("#,##0;(#,##0)"));
+        
+        // Pasted values show the way they were written
+        let shown = |row, col| grid.get_cell(CellRef::new(row, col)).unwrap().display();
+        assert_eq!(shown(1, 0), "$1,234.00");
+        assert_eq!(shown(1, 1), "7.5%");
+        assert_eq!(shown(2, 1), "7.000%");
+        assert_eq!(shown(1, 2), "2024-01-31");
+        assert_eq!(shown(2, 2), "2024-02-01");
+        assert_eq!(shown(1, 3), "(500)");
+    }
+
+    #[test]
+    fn test_paste_block_without_inference() {
+        let mut grid = Grid::new(100, 10);
+        let mut engine = FormulaEngine::new();
+        
+        let block = vec![vec!["$5.50".to_string(), "(500)".to_string()]];
+        grid.paste_block(CellRef::new(0, 0), &block, PasteOptions::default(), &mut engine).unwrap();
+        
+        let cell = grid.get_cell(CellRef::new(0, 0)).unwrap();
+        assert_eq!(cell.value, CellValue::Number(5.5));
+        assert!(cell.format.is_none());
+        assert_eq!(grid.get_cell(CellRef::new(0, 1)).unwrap().value, CellValue::Text("(500)".to_string()));
+    }
+
+    #[test]
+    fn test_cell_data_value_type() {
+        let mut grid = Grid::new(10, 10);
+        grid.set_value(CellRef::new(0, 0), CellValue::Number(1.5)).unwrap();
+        grid.set_value(CellRef::new(0, 1), CellValue::Text("abc".to_string())).unwrap();
+        grid.set_value(CellRef::new(0, 2), CellValue::Boolean(true)).unwrap();
+        grid.set_value(CellRef::new(0, 3), CellValue::Error("DIV/0".to_string())).unwrap();
+        
+        let engine = FormulaEngine::new();
+        let types: Vec<ValueType> = grid.get_range(0, 0, 0, 3, &engine).iter().map(|c| c.value_type).collect();
+        assert_eq!(types, vec![ValueType::Number, ValueType::Text, ValueType::Boolean, ValueType::Error]);
+        
+        let diff = GridDiff::from_cells(&grid, &engine, &[CellRef::new(0, 3), CellRef::new(0, 0)]);
+        assert_eq!(diff.cells[0].value_type, ValueType::Error);
+        assert_eq!(diff.cells[1].value_type, ValueType::Number);
+        
+        let json = serde_json::to_value(&diff.cells[0]).unwrap();
+        assert_eq!(json["value_type"], "error");
+    }
+
+    #[test]
+    fn test_value_type_follows_value_not_display() {
+        let mut grid = Grid::new(10, 10);
+        let mut engine = FormulaEngine::new();
+        set_at(&mut grid, &mut engine, 0, 0, "5");
+        set_at(&mut grid, &mut engine, 0, 1, "=A1>3");
+        grid.set_value(CellRef::new(0, 2), CellValue::Text("007".to_string())).unwrap();
+        grid.set_value(CellRef::new(0, 3), CellValue::Number(45322.0)).unwrap();
+        grid.set_format(CellRef::new(0, 3), CellFormat { number_format: Some("yyyy-mm-dd".to_string()), ..CellFormat::default() }).unwrap();
+        grid.set_format(CellRef::new(0, 4), CellFormat { number_format: Some("yyyy-mm-dd".to_string()), ..CellFormat::default() }).unwrap();
+        grid.set_format(CellRef::new(0, 0), CellFormat { number_format: Some("$#,##0".to_string()), ..CellFormat::default() }).unwrap();
+        
+        let types: Vec<ValueType> = grid.get_range(0, 0, 0, 4, &engine).iter().map(|c| c.value_type).collect();
+        assert_eq!(types, vec![ValueType::Number, ValueType::Boolean, ValueType::Text, ValueType::Date, ValueType::Empty]);
+        
+        let diff = GridDiff::from_cells(&grid, &engine, &[CellRef::new(0, 1), CellRef::new(0, 3)]);
+        assert_eq!(diff.cells[0].value_type, ValueType::Boolean);
+        assert_eq!(serde_json::to_value(&diff.cells[1]).unwrap()["value_type"], "date");
+        
+        // A transfer annotation only: saved sheets don't carry it
+        assert!(!grid.to_json().unwrap().contains("value_type"));
+    }
+
+    #[test]
+    fn test_transferred_values_are_formatted() {
+        let mut grid = Grid::new(10, 10);
+        let mut engine = FormulaEngine::new();
+        let number_format = |text: &str| CellFormat { number_format: Some(text.to_string()), ..Default::default() };
+        set_at(&mut grid, &mut engine, 0, 0, "0.5");
+        grid.set_format(CellRef::new(0, 0), number_format("0%")).unwrap();
+        set_at(&mut grid, &mut engine, 0, 1, "1234.5");
+        grid.set_format(CellRef::new(0, 1), number_format("$#,##0.00")).unwrap();
+        set_at(&mut grid, &mut engine, 0, 2, "=A1/3");
+        grid.set_format(CellRef::new(0, 2), number_format("0.00")).unwrap();
+
+        let values: Vec<String> = grid.get_range(0, 0, 0, 2, &engine).into_iter().map(|c| c.value).collect();
+        assert_eq!(values, ["50%", "$1,234.50", "0.17"]);
+        let diff = GridDiff::from_cells(&grid, &engine, &[CellRef::new(0, 1)]);
+        assert_eq!(diff.cells[0].value, "$1,234.50");
+        // The cell keeps the number, for formulas and editing
+        assert_eq!(grid.get_cell(CellRef::new(0, 1)).unwrap().value, CellValue::Number(1234.5));
+    }
+
+    #[test]
+    fn test_delete_cols_shifts_without_loss() {
+        let mut grid = Grid::new(10, 10);
+        for col in 0..8 {
+            grid.set_value(CellRef::new(0, col), CellValue::Number(col as f64)).unwrap();
+            grid.set_value(CellRef::new(3, col), CellValue::Number(100.0 + col as f64)).unwrap();
+        }
+        grid.set_col_width(4, 40.0).unwrap();
+        grid.set_col_width(5, 50.0).unwrap();
+        grid.set_col_width(6, 60.0).unwrap();
+        
+        grid.delete_cols(4, 1, &mut FormulaEngine::new()).unwrap();
+        
+        assert_eq!(grid.cell_count(), 14);
+        for col in 0..7 {
+            let original = if col < 4 { col } else { col + 1 };
+            assert_eq!(grid.get_cell(CellRef::new(0, col)).unwrap().value, CellValue::Number(original as f64));
+            assert_eq!(grid.get_cell(CellRef::new(3, col)).unwrap().value, CellValue::Number(100.0 + original as f64));
+        }
+        assert!(grid.get_cell(CellRef::new(0, 7)).is_none());
+        assert_eq!(grid.get_col_width(4), 50.0);
+        assert_eq!(grid.get_col_width(5), 60.0);
+        assert_eq!(grid.get_col_width(6), 100.0);
+    }
+
+    #[test]
+    fn test_mutations_bump_modified_at() {
+        let clock = Rc::new(crate::clock::ManualClock::new(1_000.0));
+        let mut grid = Grid::with_clock(10, 10, clock.clone());
+        assert_eq!(grid.properties().created_at, Some(1_000.0));
+        assert_eq!(grid.properties().modified_at, Some(1_000.0));
+        
+        clock.advance(500.0);
+        grid.set_value(CellRef::new(0, 0), CellValue::Number(1.0)).unwrap();
+        assert_eq!(grid.properties().modified_at, Some(1_500.0));
+        
+        clock.advance(500.0);
+        grid.set_col_width(2, 80.0).unwrap();
+        assert_eq!(grid.properties().modified_at, Some(2_000.0));
+        
+        // Reads and failed writes leave it alone
+        clock.advance(500.0);
+        grid.get_range(0, 0, 5, 5, &FormulaEngine::new());
+        assert!(grid.set_value(CellRef::new(50, 0), CellValue::Number(1.0)).is_err());
+        assert_eq!(grid.properties().modified_at, Some(2_000.0));
+        
+        grid.set_property("title", serde_json::json!("Budget")).unwrap();
+        assert_eq!(grid.properties().modified_at, Some(2_500.0));
+        assert_eq!(grid.properties().created_at, Some(1_000.0));
+        
+        grid.set_property("modified_at", serde_json::json!(42.0)).unwrap();
+        assert_eq!(grid.properties().modified_at, Some(42.0));
+    }
+
+    #[test]
+    fn test_properties_round_trip() {
+        let mut grid = Grid::new(10, 10);
+        grid.set_property("title", serde_json::json!("FY25 Plan")).unwrap();
+        grid.set_property("author", serde_json::json!("finance@ramp")).unwrap();
+        grid.set_property("schema", serde_json::json!("sheets/v2")).unwrap();
+        grid.set_property("tags", serde_json::json!(["budget", "draft"])).unwrap();
+        assert!(grid.set_property("title", serde_json::json!(7)).is_err());
+        
+        let restored = Grid::from_json(&grid.to_json().unwrap()).unwrap();
+        assert_eq!(restored.properties(), grid.properties());
+        assert_eq!(restored.properties().get("tags"), Some(serde_json::json!(["budget", "draft"])));
+        assert_eq!(restored.properties().get("missing"), None);
+    }
+
+    #[test]
+    fn test_loads_document_without_properties() {
+        let json = r#"{"rows":10,"cols":10,"columns":{"0":{"0":{"value":{"type":"Number","value":1.0}}}}}"#;
+        let grid = Grid::from_json(json).unwrap();
+        assert_eq!(grid.cell_count(), 1);
+        assert_eq!(grid.properties(), &DocumentProperties::default());
+        assert!(grid.view_state().is_none());
+    }
+
+    #[test]
+    fn test_shrink_to_fit() {
+        let mut grid = Grid::new(1_000_000, 1_000);
+        let mut engine = FormulaEngine::new();
+        set_at(&mut grid, &mut engine, 0, 0, "1");
+        set_at(&mut grid, &mut engine, 4, 2, "=A1*2");
+        grid.set_row_height(500, 40.0).unwrap();
+        grid.set_col_hidden(900, true);
+        
+        grid.shrink_to_fit();
+        assert_eq!((grid.rows, grid.cols), (5 + SHRINK_MARGIN, 3 + SHRINK_MARGIN));
+        assert_eq!(grid.cell_count(), 2);
+        assert_eq!(grid.get_row_height(500), grid.default_row_height);
+        assert_eq!(grid.total_content_height(), (5 + SHRINK_MARGIN) as f64 * grid.default_row_height as f64);
+        
+        // Formulas stay registered and live
+        set_at(&mut grid, &mut engine, 0, 0, "4");
+        assert_eq!(grid.get_cell(CellRef::new(4, 2)).unwrap().value, CellValue::Number(8.0));
+        
+        // Never grows, and an empty grid degenerates to the margin
+        let mut small = Grid::new(3, 3);
+        small.shrink_to_fit();
+        assert_eq!((small.rows, small.cols), (3, 3));
+        let mut empty = Grid::new(10_000, 500);
+        empty.shrink_to_fit();
+        assert_eq!((empty.rows, empty.cols), (SHRINK_MARGIN, SHRINK_MARGIN));
+    }
+
+    #[test]
+    fn test_view_state_round_trip() {
+        let mut grid = Grid::new(1_000, 100);
+        let mut viewport = Viewport::new(0, 0, 40, 12);
+        viewport.scroll(230.0, 500.0, 24.0, 100.0);
+        viewport.set_zoom(1.25);
+        viewport.frozen_rows = 1;
+        viewport.frozen_cols = 2;
+        grid.set_view_state(viewport);
+        
+        let restored = Grid::from_json(&grid.to_json().unwrap()).unwrap();
+        assert_eq!(restored.view_state(), Some(&viewport));
+        assert_eq!((viewport.start_row, viewport.start_col), (20, 2));
+    }
+
+    #[test]
+    fn test_series_with_gaps() {
+        let mut grid = Grid::new(10, 10);
+        // Horizontal: B1..E1 with a gap at D1 and text at E1
+        grid.set_value(CellRef::new(0, 1), CellValue::Number(1.0)).unwrap();
+        grid.set_value(CellRef::new(0, 2), CellValue::Number(2.0)).unwrap();
+        grid.set_value(CellRef::new(0, 4), CellValue::Text("n/a".to_string())).unwrap();
+        let horizontal = grid.series(CellRef::new(0, 1), CellRef::new(0, 4), SeriesOrder::RowMajor);
+        assert_eq!(horizontal, vec![Some(1.0), Some(2.0), None, None]);
+        
+        // Vertical: A3..A6 with a gap at A4
+        grid.set_value(CellRef::new(2, 0), CellValue::Number(10.0)).unwrap();
+        grid.set_value(CellRef::new(4, 0), CellValue::Number(30.0)).unwrap();
+        grid.set_value(CellRef::new(5, 0), CellValue::Number(40.0)).unwrap();
+        let vertical = grid.series(CellRef::new(2, 0), CellRef::new(5, 0), SeriesOrder::ColumnMajor);
+        assert_eq!(vertical, vec![Some(10.0), None, Some(30.0), Some(40.0)]);
+        
+        // 2x2 block read both ways
+        let block_rows = grid.series(CellRef::new(0, 1), CellRef::new(1, 2), SeriesOrder::RowMajor);
+        let block_cols = grid.series(CellRef::new(0, 1), CellRef::new(1, 2), SeriesOrder::ColumnMajor);
+        assert_eq!(block_rows, vec![Some(1.0), Some(2.0), None, None]);
+        assert_eq!(block_cols, vec![Some(1.0), None, Some(2.0), None]);
+    }
+
+    #[test]
+    fn test_detect_header() {
+        let mut grid = Grid::new(20, 10);
+        let rows: [[CellValue; 3]; 4] = [
+            [CellValue::Text("Month".into()), CellValue::Text("Revenue".into()), CellValue::Text("Cost".into())],
+            [CellValue::Number(45322.0), CellValue::Number(100.0), CellValue::Number(60.0)],
+            [CellValue::Number(45351.0), CellValue::Number(120.0), CellValue::Empty],
+            [CellValue::Number(45382.0), CellValue::Number(130.0), CellValue::Number(70.0)],
+        ];
+        for (r, row) in rows.iter().enumerate() {
+            for (c, value) in row.iter().enumerate() {
+                grid.set_value(CellRef::new(r as u32 + 1, c as u32), value.clone()).unwrap();
+            }
+        }
+        
+        // Leading blank row is skipped; the text row is the header
+        let detection = grid.detect_header(CellRef::new(0, 0), CellRef::new(4, 2));
+        assert_eq!(detection, HeaderDetection { has_header: true, header_row: Some(1) });
+        
+        // Only the numeric rows: no header
+        let detection = grid.detect_header(CellRef::new(2, 0), CellRef::new(4, 2));
+        assert_eq!(detection, HeaderDetection { has_header: false, header_row: None });
+        
+        // Empty range: no header
+        let detection = grid.detect_header(CellRef::new(10, 0), CellRef::new(12, 2));
+        assert!(!detection.has_header);
+    }
+
+    #[test]
+    fn test_notes_and_validation_survive_edits() {
+        let mut grid = Grid::new(10, 10);
+        let a1 = CellRef::new(0, 0);
+        grid.set_note(a1, Some("Check with finance".to_string())).unwrap();
+        grid.set_validation(a1, Some(vec!["Yes".to_string(), "No".to_string()])).unwrap();
+        grid.set_value(a1, CellValue::Text("Yes".to_string())).unwrap();
+        grid.set_value(a1, CellValue::Empty).unwrap();
+        
+        let data = CellData::from_cell(a1, grid.get_cell(a1).unwrap(), &FormulaEngine::new());
+        assert!(data.has_note && data.has_validation && !data.is_error);
+        
+        grid.set_note(a1, None).unwrap();
+        grid.set_validation(a1, None).unwrap();
+        assert!(grid.get_cell(a1).is_none());
+    }
+
+    #[test]
+    fn test_total_content_size() {
+        let mut grid = Grid::new(1_000_000, 50);
+        assert_eq!(grid.total_content_height(), 24.0 * 1_000_000.0);
+        assert_eq!(grid.total_content_width(), 100.0 * 50.0);
+        
+        grid.set_row_height(0, 40.0).unwrap();
+        grid.set_row_height(999_999, 12.0).unwrap();
+        grid.set_row_hidden(5, true);
+        assert_eq!(grid.total_content_height(), 24.0 * 999_999.0 + 16.0 - 12.0);
+        assert_eq!(grid.get_row_height(5), 0.0);
+        
+        // A hidden custom-height row contributes nothing, and comes back when shown
+        grid.set_row_height(7, 100.0).unwrap();
+        grid.set_row_hidden(7, true);
+        assert_eq!(grid.total_content_height(), 24.0 * 999_998.0 + 16.0 - 12.0);
+        grid.set_row_hidden(7, false);
+        assert_eq!(grid.total_content_height(), 24.0 * 999_998.0 + 100.0 + 16.0 - 12.0);
+        
+        grid.set_col_width(3, 250.0).unwrap();
+        grid.set_col_hidden(4, true);
+        assert_eq!(grid.total_content_width(), 100.0 * 48.0 + 250.0);
+        
+        // Growing the sheet invalidates the cache too
+        grid.cols = 60;
+        assert_eq!(grid.total_content_width(), 100.0 * 58.0 + 250.0);
+        
+        // Deleting shifts hidden and custom-width columns left and narrows the sheet
+        grid.delete_cols(0, 4, &mut FormulaEngine::new()).unwrap();
+        assert_eq!(grid.total_content_width(), 100.0 * 55.0);
+        assert_eq!(grid.get_col_width(0), 0.0);
+    }
+
+    #[test]
+    fn test_size_inputs_are_sanitized() {
+        let mut grid = Grid::new(100, 20);
+        assert!(matches!(grid.set_col_width(0, f32::NAN), Err(GridError::InvalidSize(_))));
+        assert!(grid.set_row_height(0, f32::INFINITY).is_err());
+        assert_eq!(grid.get_col_width(0), 100.0);
+        
+        grid.set_col_width(1, -50.0).unwrap();
+        grid.set_row_height(1, 1e9).unwrap();
+        assert_eq!(grid.get_col_width(1), 4.0);
+        assert_eq!(grid.get_row_height(1), 2000.0);
+        
+        // Within rounding of the default counts as the default
+        grid.set_col_width(2, 100.001).unwrap();
+        assert_eq!(grid.col_widths.get(&2), None);
+        
+        grid.set_size_limits(10.0, 500.0).unwrap();
+        grid.set_col_width(3, 1.0).unwrap();
+        assert_eq!(grid.get_col_width(3), 10.0);
+        assert!(grid.set_size_limits(10.0, f32::NAN).is_err());
+        assert!(grid.set_size_limits(0.0, 10.0).is_err());
+        
+        grid.reset_col_width(1);
+        grid.reset_row_height(1);
+        assert_eq!(grid.get_col_width(1), 100.0);
+        assert_eq!(grid.get_row_height(1), 24.0);
+    }
+
+    #[test]
+    fn test_set_col_widths_in_bulk() {
+        let mut grid = Grid::new(100, 20);
+        let ranges = [
+            SizeRange { start: 0, end: 2, size: 60.0 },
+            SizeRange { start: 5, end: 4, size: 3000.0 },
+        ];
+        grid.set_col_widths(&ranges).unwrap();
+        let widths: Vec<f32> = (0..7).map(|col| grid.get_col_width(col)).collect();
+        assert_eq!(widths, vec![60.0, 60.0, 60.0, 100.0, 2000.0, 2000.0, 100.0]);
+        
+        // One bad entry rejects the whole batch
+        let ranges = [
+            SizeRange { start: 10, end: 12, size: 60.0 },
+            SizeRange { start: 13, end: 13, size: f32::NAN },
+        ];
+        assert!(grid.set_col_widths(&ranges).is_err());
+        assert_eq!(grid.get_col_width(10), 100.0);
+    }
+
+    #[test]
+    fn test_compacted_column_is_transparent() {
+        let mut grid = Grid::new(10_000, 10);
+        let region = CellValue::Text("EMEA".to_string());
+        for row in 0..5_000 {
+            grid.set_value(CellRef::new(row, 2), region.clone()).unwrap();
+        }
+        let before = grid.storage_bytes();
+        grid.compact_columns();
+        assert!(grid.storage_bytes() < before / 100);
+        assert_eq!(grid.cell_count(), 5_000);
+
+        // Reads, writes and formats behave exactly as with map storage
+        grid.set_value(CellRef::new(2_500, 2), CellValue::Text("APAC".to_string())).unwrap();
+        grid.set_format(CellRef::new(10, 2), CellFormat { font_bold: Some(true), ..CellFormat::default() }).unwrap();
+        grid.set_value(CellRef::new(4_999, 2), CellValue::Empty).unwrap();
+        assert_eq!(grid.get_cell(CellRef::new(2_499, 2)).unwrap().value, region);
+        assert_eq!(grid.get_cell(CellRef::new(2_500, 2)).unwrap().value, CellValue::Text("APAC".to_string()));
+        assert_eq!(grid.get_cell(CellRef::new(10, 2)).unwrap().value, region);
+        assert!(grid.get_cell(CellRef::new(11, 2)).unwrap().format.is_none());
+        assert!(grid.get_cell(CellRef::new(4_999, 2)).is_none());
+        assert_eq!(grid.cell_count(), 4_999);
+
+        let restored = Grid::from_json(&grid.to_json().unwrap()).unwrap();
+        assert_eq!(restored.cell_count(), 4_999);
+        assert_eq!(restored.get_cell(CellRef::new(2_500, 2)).unwrap().value, CellValue::Text("APAC".to_string()));
+    }
+
+    fn shown_formula(grid: &Grid, engine: &FormulaEngine, cell: CellRef) -> Option<String> {
+        grid.get_range(cell.row, cell.col, cell.row, cell.col, engine).pop().and_then(|data| data.formula)
+    }
+
+    #[test]
+    fn test_value_overwrite_drops_formula() {
+        let mut grid = Grid::new(10, 10);
+        let mut engine = FormulaEngine::new();
+        let b1 = CellRef::new(0, 1);
+        set_at(&mut grid, &mut engine, 0, 0, "1");
+        set_at(&mut grid, &mut engine, 0, 1, "=A1*2");
+        assert_eq!(shown_formula(&grid, &engine, b1).as_deref(), Some("=A1*2"));
+        
+        set_at(&mut grid, &mut engine, 0, 1, "5");
+        assert!(engine.formula_at(b1).is_none());
+        assert_eq!(shown_formula(&grid, &engine, b1), None);
+        
+        // The old formula must not come back to life when its input changes
+        set_at(&mut grid, &mut engine, 0, 0, "3");
+        assert_eq!(grid.get_cell(b1).unwrap().value, CellValue::Number(5.0));
+    }
+
+    #[test]
+    fn test_patch_clear_drops_formula() {
+        let mut grid = Grid::new(10, 10);
+        let mut engine = FormulaEngine::new();
+        let b1 = CellRef::new(0, 1);
+        set_at(&mut grid, &mut engine, 0, 0, "1");
+        set_at(&mut grid, &mut engine, 0, 1, "=A1*2");
+        
+        set_at(&mut grid, &mut engine, 0, 1, "");
+        assert!(grid.get_cell(b1).is_none());
+        assert!(engine.formula_at(b1).is_none());
+        
+        set_at(&mut grid, &mut engine, 0, 0, "3");
+        assert!(grid.get_cell(b1).is_none());
+    }
+
+    #[test]
+    fn test_blank_cells_are_never_stored() {
+        let mut grid = Grid::new(10, 10);
+        let mut engine = FormulaEngine::new();
+        let (a1, b1) = (CellRef::new(0, 0), CellRef::new(0, 1));
+        let empty_formula = |row, col, formula: &str| GridPatch {
+            updates: vec![CellUpdate { row, col, value: None, formula: Some(formula.to_string()) }],
+            auto_expand: None,
+        };
+
+        // An empty value or formula clears, whatever the cell held
+        set_at(&mut grid, &mut engine, 0, 0, "");
+        grid.apply_patch(empty_formula(0, 1, ""), &mut engine).unwrap();
+        assert_eq!(grid.cell_count(), 0);
+        set_at(&mut grid, &mut engine, 0, 0, "5");
+        set_at(&mut grid, &mut engine, 0, 1, "=A1*2");
+        grid.apply_patch(empty_formula(0, 0, ""), &mut engine).unwrap();
+        grid.apply_patch(empty_formula(0, 1, "="), &mut engine).unwrap();
+        assert!(engine.formula_at(b1).is_none());
+        assert_eq!(grid.cell_count(), 0);
+
+        // A format that sets nothing isn't a format
+        grid.set_format(a1, CellFormat::default()).unwrap();
+        grid.apply_format_to_range(0, 0, 2, 2, CellFormat::default()).unwrap();
+        assert_eq!(grid.cell_count(), 0);
+
+        // Clearing the last attribute removes the cell
+        grid.set_note(a1, Some("check".to_string())).unwrap();
+        grid.set_note(a1, None).unwrap();
+        grid.set_value(b1, CellValue::Number(1.0)).unwrap();
+        grid.set_computed_value(b1, CellValue::Empty).unwrap();
+        assert_eq!(grid.cell_count(), 0);
+        assert_eq!(grid.used_extent(), None);
+
+        // Blank cells in a saved sheet don't survive loading
+        grid.columns.entry(4).or_default().insert(7, Cell::default());
+        grid.columns.entry(4).or_default().insert(8, Cell { format: Some(CellFormat::default()), ..Cell::default() });
+        grid.set_value(CellRef::new(2, 2), CellValue::Number(1.0)).unwrap();
+        let loaded = Grid::from_json(&grid.to_json().unwrap()).unwrap();
+        assert_eq!(loaded.cell_count(), 1);
+        assert_eq!(loaded.used_extent(), Some((2, 2)));
+    }
+
+    #[test]
+    fn test_imported_formulas_are_live() {
+        let mut grid = Grid::new(10, 10);
+        let mut engine = FormulaEngine::new();
+        let b1 = CellRef::new(0, 1);
+        set_at(&mut grid, &mut engine, 0, 0, "1");
+        set_at(&mut grid, &mut engine, 0, 1, "=A1*2");
+        set_at(&mut grid, &mut engine, 0, 2, "=A1+1");
+        
+        // Loading replaces whatever the engine held before
+        let mut imported = Grid::from_json(&grid.to_json().unwrap()).unwrap();
+        set_at(&mut grid, &mut engine, 0, 2, "7");
+        engine.load_formulas(&mut imported);
+        assert_eq!(shown_formula(&imported, &engine, b1).as_deref(), Some("=A1*2"));
+        assert_eq!(shown_formula(&imported, &engine, CellRef::new(0, 2)).as_deref(), Some("=A1+1"));
+        
+        set_at(&mut imported, &mut engine, 0, 0, "10");
+        assert_eq!(imported.get_cell(b1).unwrap().value, CellValue::Number(20.0));
+        assert_eq!(imported.get_cell(CellRef::new(0, 2)).unwrap().value, CellValue::Number(11.0));
+    }
+
+    #[test]
+    fn test_inserted_rows_regenerate_formula_text() {
+        let mut grid = Grid::new(10, 10);
+        let mut engine = FormulaEngine::new();
+        set_at(&mut grid, &mut engine, 0, 0, "100");
+        set_at(&mut grid, &mut engine, 1, 1, "5");
+        set_at(&mut grid, &mut engine, 2, 2, "=$A$1+B2");
+        set_at(&mut grid, &mut engine, 3, 2, "=sum(A$1:B2)");
+        
+        grid.insert_rows(0, 1, &mut engine).unwrap();
+        let c4 = CellRef::new(3, 2);
+        assert_eq!(grid.get_cell(c4).unwrap().formula.as_deref(), Some("=$A$2+B3"));
+        assert_eq!(engine.formula_at(c4).unwrap().raw, "=$A$2+B3");
+        assert_eq!(shown_formula(&grid, &engine, CellRef::new(4, 2)).as_deref(), Some("=sum(A$2:B3)"));
+        
+        // Still wired to the moved cells
+        set_at(&mut grid, &mut engine, 1, 0, "200");
+        assert_eq!(grid.get_cell(c4).unwrap().value, CellValue::Number(205.0));
+        
+        // Rows inserted below every reference leave the text alone
+        grid.insert_rows(5, 2, &mut engine).unwrap();
+        assert_eq!(shown_formula(&grid, &engine, c4).as_deref(), Some("=$A$2+B3"));
+    }
+
+    #[test]
+    fn test_inserted_and_deleted_rows_move_references() {
+        let mut grid = Grid::new(10, 10);
+        let mut engine = FormulaEngine::new();
+        for row in 0..6 {
+            set_at(&mut grid, &mut engine, row, 0, &(row + 1).to_string());
+        }
+        set_at(&mut grid, &mut engine, 7, 1, "=A5");
+        set_at(&mut grid, &mut engine, 8, 1, "=SUM(A2:A6)");
+        set_at(&mut grid, &mut engine, 9, 1, "=A1*2");
+
+        grid.insert_rows(2, 1, &mut engine).unwrap();
+        assert_eq!(grid.get_cell(CellRef::new(8, 1)).unwrap().formula.as_deref(), Some("=A6"));
+        assert_eq!(grid.get_cell(CellRef::new(8, 1)).unwrap().value, CellValue::Number(5.0));
+        assert_eq!(grid.rows, 11);
+
+        // Deleting the new row and A6 (once A5): =A6 loses its cell, the range just shrinks
+        let changed = grid.delete_rows(5, 1, &mut engine).unwrap();
+        grid.delete_rows(2, 1, &mut engine).unwrap();
+        assert!(changed.contains(&CellRef::new(8, 1)));
+        let cell = |row| grid.get_cell(CellRef::new(row, 1)).unwrap();
+        assert_eq!(cell(6).formula.as_deref(), Some("=#REF!"));
+        assert_eq!(cell(6).value, CellValue::Error("REF".to_string()));
+        assert_eq!(cell(7).formula.as_deref(), Some("=SUM(A2:A5)"));
+        assert_eq!(cell(7).value, CellValue::Number(2.0 + 3.0 + 4.0 + 6.0));
+        assert_eq!(cell(8).formula.as_deref(), Some("=A1*2"));
+        assert_eq!(grid.get_cell(CellRef::new(4, 0)).unwrap().value, CellValue::Number(6.0));
+        assert_eq!(grid.rows, 9);
+
+        // Live after the move; a range deleted outright is #REF too
+        set_at(&mut grid, &mut engine, 1, 0, "20");
+        assert_eq!(grid.get_cell(CellRef::new(7, 1)).unwrap().value, CellValue::Number(20.0 + 3.0 + 4.0 + 6.0));
+        grid.delete_rows(1, 4, &mut engine).unwrap();
+        assert_eq!(grid.get_cell(CellRef::new(3, 1)).unwrap().formula.as_deref(), Some("=SUM(#REF!)"));
+        assert_eq!(grid.get_cell(CellRef::new(3, 1)).unwrap().value, CellValue::Error("REF".to_string()));
+        assert_eq!(grid.delete_rows(20, 1, &mut engine).unwrap(), Vec::new());
+    }
+
+    #[test]
+    fn test_deleted_reference_keeps_the_rest_of_the_formula() {
+        let mut grid = Grid::new(10, 10);
+        let mut engine = FormulaEngine::new();
+        set_at(&mut grid, &mut engine, 0, 0, "1");
+        set_at(&mut grid, &mut engine, 1, 0, "2");
+        set_at(&mut grid, &mut engine, 0, 2, "=IFERROR(A2,0)+A1");
+        set_at(&mut grid, &mut engine, 0, 4, "=A2");
+
+        let changed = grid.delete_rows(1, 1, &mut engine).unwrap();
+        let c1 = grid.get_cell(CellRef::new(0, 2)).unwrap();
+        assert_eq!(c1.formula.as_deref(), Some("=IFERROR(#REF!,0)+A1"));
+        assert_eq!(c1.value, CellValue::Number(1.0));
+        let e1 = grid.get_cell(CellRef::new(0, 4)).unwrap();
+        assert_eq!(e1.formula.as_deref(), Some("=#REF!"));
+        assert_eq!(e1.value, CellValue::Error("REF".to_string()));
+        assert!(changed.contains(&CellRef::new(0, 2)) && changed.contains(&CellRef::new(0, 4)));
+
+        // Still live, and the text survives a save
+        set_at(&mut grid, &mut engine, 0, 0, "5");
+        assert_eq!(grid.get_cell(CellRef::new(0, 2)).unwrap().value, CellValue::Number(5.0));
+        let mut reloaded = Grid::from_json(&grid.to_json().unwrap()).unwrap();
+        let mut fresh = FormulaEngine::new();
+        fresh.load_formulas(&mut reloaded);
+        assert!(!reloaded.is_foreign(CellRef::new(0, 2)));
+        assert_eq!(fresh.formula_at(CellRef::new(0, 2)).unwrap().raw, "=IFERROR(#REF!,0)+A1");
+    }
+
+    #[test]
+    fn test_inserted_and_deleted_cols_move_references() {
+        let mut grid = Grid::new(10, 10);
+        let mut engine = FormulaEngine::new();
+        for col in 0..4 {
+            set_at(&mut grid, &mut engine, 0, col, &(col + 1).to_string());
+        }
+        set_at(&mut grid, &mut engine, 2, 0, "=B1");
+        set_at(&mut grid, &mut engine, 3, 0, "=SUM(A1:D1)");
+        set_at(&mut grid, &mut engine, 4, 0, "=$C$1*10");
+        let cells = grid.cell_count();
+
+        grid.insert_cols(1, 1, &mut engine).unwrap();
+        let formula = |grid: &Grid, row| grid.get_cell(CellRef::new(row, 0)).unwrap().formula.clone();
+        assert_eq!(formula(&grid, 2).as_deref(), Some("=C1"));
+        assert_eq!(formula(&grid, 3).as_deref(), Some("=SUM(A1:E1)"));
+        assert_eq!(formula(&grid, 4).as_deref(), Some("=$D$1*10"));
+        assert_eq!(grid.get_cell(CellRef::new(2, 0)).unwrap().value, CellValue::Number(2.0));
+        assert!(grid.get_cell(CellRef::new(0, 1)).is_none());
+        assert_eq!(grid.cell_count(), cells);
+        assert_eq!(grid.cols, 11);
+
+        // Deleting C (once B) leaves =C1 without a cell; the range shrinks
+        let changed = grid.delete_cols(2, 1, &mut engine).unwrap();
+        assert!(changed.contains(&CellRef::new(2, 0)));
+        assert_eq!(formula(&grid, 2).as_deref(), Some("=#REF!"));
+        assert_eq!(grid.get_cell(CellRef::new(2, 0)).unwrap().value, CellValue::Error("REF".to_string()));
+        assert_eq!(formula(&grid, 3).as_deref(), Some("=SUM(A1:D1)"));
+        assert_eq!(grid.get_cell(CellRef::new(3, 0)).unwrap().value, CellValue::Number(1.0 + 3.0 + 4.0));
+        assert_eq!(formula(&grid, 4).as_deref(), Some("=$C$1*10"));
+        assert_eq!(grid.get_cell(CellRef::new(4, 0)).unwrap().value, CellValue::Number(30.0));
+        assert_eq!(grid.cols, 10);
+    }
+
+    #[test]
+    fn test_iferror_around_deleted_col_still_evaluates() {
+        let mut grid = Grid::new(10, 10);
+        let mut engine = FormulaEngine::new();
+        set_at(&mut grid, &mut engine, 0, 0, "4");
+        set_at(&mut grid, &mut engine, 0, 1, "=1/0");
+        set_at(&mut grid, &mut engine, 0, 2, "7");
+        set_at(&mut grid, &mut engine, 1, 0, "=IFERROR(B1,-1)+C1");
+        set_at(&mut grid, &mut engine, 2, 0, "=SUM(B1:B1)*A1");
+        assert_eq!(grid.get_cell(CellRef::new(1, 0)).unwrap().value, CellValue::Number(6.0));
+
+        grid.delete_cols(1, 1, &mut engine).unwrap();
+        let a2 = grid.get_cell(CellRef::new(1, 0)).unwrap();
+        assert_eq!(a2.formula.as_deref(), Some("=IFERROR(#REF!,-1)+B1"));
+        assert_eq!(a2.value, CellValue::Number(6.0));
+        let a3 = grid.get_cell(CellRef::new(2, 0)).unwrap();
+        assert_eq!(a3.formula.as_deref(), Some("=SUM(#REF!)*A1"));
+        assert_eq!(a3.value, CellValue::Error("REF".to_string()));
+
+        // The surviving reference still follows its cell
+        set_at(&mut grid, &mut engine, 0, 1, "10");
+        assert_eq!(grid.get_cell(CellRef::new(1, 0)).unwrap().value, CellValue::Number(9.0));
+    }
+
+    #[test]
+    fn test_col_count_round_trips_through_insert_and_delete() {
+        let mut grid = Grid::new(5, 4);
+        let mut engine = FormulaEngine::new();
+        set_at(&mut grid, &mut engine, 0, 3, "4");
+
+        grid.insert_cols(1, 3, &mut engine).unwrap();
+        assert_eq!(grid.cols, 7);
+        grid.delete_cols(1, 3, &mut engine).unwrap();
+        assert_eq!(grid.cols, 4);
+        assert_eq!(grid.get_cell(CellRef::new(0, 3)).unwrap().value, CellValue::Number(4.0));
+
+        // Deleting past the edge stops at the last column; one column always remains
+        grid.delete_cols(2, 10, &mut engine).unwrap();
+        assert_eq!(grid.cols, 2);
+        grid.delete_cols(0, 5, &mut engine).unwrap();
+        assert_eq!(grid.cols, 1);
+        assert_eq!(grid.cell_count(), 0);
+        assert_eq!(grid.delete_cols(3, 1, &mut engine).unwrap(), Vec::new());
+    }
+
+    #[test]
+    fn test_formula_results_inherit_number_formats() {
+        let mut grid = Grid::new(10, 10);
+        let mut engine = FormulaEngine::new();
+        let number_format = |text: &str| CellFormat { number_format: Some(text.to_string()), ..Default::default() };
+        set_at(&mut grid, &mut engine, 1, 1, "$1,200.00");
+        grid.set_format(CellRef::new(1, 1), number_format("$#,##0.00")).unwrap();
+        set_at(&mut grid, &mut engine, 1, 2, "0.25");
+        grid.set_format(CellRef::new(1, 2), number_format("0%")).unwrap();
+        set_at(&mut grid, &mut engine, 2, 1, "2024-03-31");
+        set_at(&mut grid, &mut engine, 2, 2, "2024-01-01");
+
+        let (price_by_rate, days, due) = (CellRef::new(1, 3), CellRef::new(2, 3), CellRef::new(3, 3));
+        set_at(&mut grid, &mut engine, 1, 3, "=B2*C2");
+        set_at(&mut grid, &mut engine, 2, 3, "=B3-C3");
+        set_at(&mut grid, &mut engine, 3, 3, "=B3+30");
+        let diff = GridDiff::from_cells(&grid, &engine, &[price_by_rate, days, due]);
+        assert_eq!(diff.cells[0].implicit_format.as_deref(), Some("$#,##0.00"));
+        assert_eq!(diff.cells[1].implicit_format, None);
+        assert_eq!(diff.cells[1].value, "90");
+        assert_eq!(diff.cells[1].value_type, ValueType::Number);
+        assert_eq!(diff.cells[2].implicit_format.as_deref(), Some("yyyy-mm-dd"));
+        assert_eq!(diff.cells[2].value_type, ValueType::Date);
+
+        // The cell's own number format wins, and the implicit one follows formula edits
+        grid.set_format(price_by_rate, number_format("0.0")).unwrap();
+        assert_eq!(grid.get_cell(price_by_rate).unwrap().number_format(), Some("0.0"));
+        set_at(&mut grid, &mut engine, 1, 3, "=C2*2");
+        assert_eq!(grid.get_cell(price_by_rate).unwrap().implicit_format.as_deref(), Some("0%"));
+        set_at(&mut grid, &mut engine, 3, 3, "=B2+1");
+        let diff = GridDiff::from_cells(&grid, &engine, &[due]);
+        assert_eq!(diff.cells[0].implicit_format.as_deref(), Some("$#,##0.00"));
+        set_at(&mut grid, &mut engine, 3, 3, "7");
+        assert_eq!(grid.get_cell(due).unwrap().implicit_format, None);
+    }
+
+    fn value_update(row: u32, col: u32, value: &str) -> CellUpdate {
+        CellUpdate { row, col, value: Some(value.to_string()), formula: None }
+    }
+
+    #[test]
+    fn test_out_of_bounds_error_carries_dimensions() {
+        let mut grid = Grid::new(1000, 50);
+        let mut engine = FormulaEngine::new();
+        let patch = GridPatch { updates: vec![value_update(0, 0, "1"), value_update(1200, 49, "2")], auto_expand: None };
+        let err = grid.apply_patch(patch, &mut engine).unwrap_err();
+        assert!(matches!(err, GridError::OutOfBounds { cell, rows: 1000, cols: 50 } if cell == CellRef::new(1200, 49)));
+        assert_eq!(err.to_string(), "Cell reference out of bounds: AX1201 (grid is 1000x50)");
+        // Nothing is written when any target is out of range
+        assert!(grid.get_cell(CellRef::new(0, 0)).is_none());
+    }
+
+    #[test]
+    fn test_auto_expand_grows_once_to_fit() {
+        let mut grid = Grid::new(10, 5);
+        let mut engine = FormulaEngine::new();
+        let patch = GridPatch {
+            updates: vec![value_update(14, 0, "1"), value_update(11, 7, "2"), value_update(19, 1, "3")],
+            auto_expand: Some(true),
+        };
+        grid.apply_patch(patch, &mut engine).unwrap();
+        assert_eq!(grid.dimensions(), GridDimensions { rows: 20, cols: 8 });
+        assert_eq!(grid.get_cell(CellRef::new(19, 1)).unwrap().value, CellValue::Number(3.0));
+        
+        // In-bounds patches leave the size alone
+        let patch = GridPatch { updates: vec![value_update(0, 0, "4")], auto_expand: Some(true) };
+        grid.apply_patch(patch, &mut engine).unwrap();
+        assert_eq!(grid.dimensions(), GridDimensions { rows: 20, cols: 8 });
+    }
+
+    #[test]
+    fn test_auto_expand_refuses_past_dimension_limits() {
+        let mut grid = Grid::new(10, 5);
+        let mut engine = FormulaEngine::new();
+        grid.set_dimension_limits(100, 10).unwrap();
+        assert!(grid.set_dimension_limits(0, 10).is_err());
+        
+        let patch = GridPatch { updates: vec![value_update(50, 0, "1"), value_update(5, 10, "2")], auto_expand: Some(true) };
+        let err = grid.apply_patch(patch, &mut engine).unwrap_err();
+        assert!(matches!(err, GridError::OutOfBounds { cell, rows: 10, cols: 5 } if cell == CellRef::new(5, 10)));
+        assert_eq!(grid.dimensions(), GridDimensions { rows: 10, cols: 5 });
+        assert!(grid.get_cell(CellRef::new(50, 0)).is_none());
+        
+        let patch = GridPatch { updates: vec![value_update(99, 9, "1")], auto_expand: Some(true) };
+        grid.apply_patch(patch, &mut engine).unwrap();
+        assert_eq!(grid.dimensions(), GridDimensions { rows: 100, cols: 10 });
+    }
+
+    #[test]
+    fn test_inserts_refuse_past_dimension_limits() {
+        let mut grid = Grid::new(10, 5);
+        let mut engine = FormulaEngine::new();
+        grid.set_dimension_limits(12, 6).unwrap();
+        set_at(&mut grid, &mut engine, 0, 0, "1");
+        
+        let err = grid.insert_rows(0, 3, &mut engine).unwrap_err();
+        assert!(matches!(err, GridError::OutOfBounds { cell, rows: 10, cols: 5 } if cell == CellRef::new(12, 0)));
+        let err = grid.insert_cols(0, u32::MAX, &mut engine).unwrap_err();
+        assert!(matches!(err, GridError::OutOfBounds { cell, rows: 10, cols: 5 } if cell == CellRef::new(0, 6)));
+        assert_eq!(grid.dimensions(), GridDimensions { rows: 10, cols: 5 });
+        assert_eq!(grid.get_cell(CellRef::new(0, 0)).unwrap().value, CellValue::Number(1.0));
+        
+        // Filling up to the limits is fine
+        grid.insert_rows(0, 2, &mut engine).unwrap();
+        grid.insert_cols(0, 1, &mut engine).unwrap();
+        assert_eq!(grid.dimensions(), GridDimensions { rows: 12, cols: 6 });
+        assert_eq!(grid.get_cell(CellRef::new(2, 1)).unwrap().value, CellValue::Number(1.0));
+    }
+
+    #[test]
+    fn test_list_formulas_in_row_major_order() {
+        let mut grid = Grid::new(10, 10);
+        let mut engine = FormulaEngine::new();
+        set_at(&mut grid, &mut engine, 0, 0, "10");
+        set_at(&mut grid, &mut engine, 2, 0, "=A1*2");
+        set_at(&mut grid, &mut engine, 0, 3, "=SUM(A1:A3)");
+        set_at(&mut grid, &mut engine, 0, 1, "=A1+1");
+        set_at(&mut grid, &mut engine, 1, 1, "text");
+        set_at(&mut grid, &mut engine, 2, 0, "=A1*3");
+        
+        let listed: Vec<(String, String)> = grid.list_formulas().into_iter().map(|f| (f.reference, f.formula)).collect();
+        assert_eq!(listed, vec![
+            ("B1".to_string(), "=A1+1".to_string()),
+            ("D1".to_string(), "=SUM(A1:A3)".to_string()),
+            ("A3".to_string(), "=A1*3".to_string()),
+        ]);
+        let json = serde_json::to_string(&grid.list_formulas()[0]).unwrap();
+        assert_eq!(json, r#"{"ref":"B1","formula":"=A1+1"}"#);
+    }
+
+    #[test]
+    fn test_typed_dates_become_serials() {
+        // 2024-06-01T00:00:00Z, so a date without a year falls in 2024
+        let mut grid = Grid::with_clock(10, 5, Rc::new(crate::clock::ManualClock::new(1_717_200_000_000.0)));
+        let mut engine = FormulaEngine::new();
+        let number_format = |grid: &Grid, row| grid.get_cell(CellRef::new(row, 0)).unwrap().format.as_ref().and_then(|f| f.number_format.clone());
+        let value = |grid: &Grid, row| grid.get_cell(CellRef::new(row, 0)).unwrap().value.clone();
+
+        set_at(&mut grid, &mut engine, 0, 0, "1/5/2024");
+        set_at(&mut grid, &mut engine, 1, 0, "2024-01-05");
+        set_at(&mut grid, &mut engine, 2, 0, "Jan 5");
+        set_at(&mut grid, &mut engine, 3, 0, "1/x");
+        for row in 0..3 {
+            assert_eq!(value(&grid, row), CellValue::Number(45296.0));
+            assert_eq!(grid.get_cell(CellRef::new(row, 0)).unwrap().value_type(), ValueType::Date);
+        }
+        assert_eq!(number_format(&grid, 0).as_deref(), Some("m/d/yyyy"));
+        assert_eq!(number_format(&grid, 1).as_deref(), Some("yyyy-mm-dd"));
+        // Each shows the way it was typed, not as its serial
+        let shown = |grid: &Grid, row| grid.get_cell(CellRef::new(row, 0)).unwrap().display();
+        assert_eq!(shown(&grid, 0), "1/5/2024");
+        assert_eq!(shown(&grid, 1), "2024-01-05");
+        assert_eq!(shown(&grid, 2), "5-Jan");
+        assert_eq!(value(&grid, 3), CellValue::Text("1/x".to_string()));
+        assert_eq!(number_format(&grid, 3), None);
+
+        // Day-first order, and an existing number format is kept
+        grid.set_date_order(DateOrder::DayFirst);
+        grid.set_format(CellRef::new(4, 0), CellFormat { number_format: Some("yyyy/mm/dd".to_string()), ..CellFormat::default() }).unwrap();
+        set_at(&mut grid, &mut engine, 4, 0, "5/1/2024");
+        assert_eq!(value(&grid, 4), CellValue::Number(45296.0));
+        assert_eq!(number_format(&grid, 4).as_deref(), Some("yyyy/mm/dd"));
+        assert_eq!(shown(&grid, 4), "2024/01/05");
+        set_at(&mut grid, &mut engine, 6, 0, "5/1/2024");
+        assert_eq!(shown(&grid, 6), "5/1/2024");
+
+        // Dates feed arithmetic as serials
+        set_at(&mut grid, &mut engine, 5, 0, "=A1+30");
+        assert_eq!(value(&grid, 5), CellValue::Number(45326.0));
+        assert_eq!(Grid::from_json(&grid.to_json().unwrap()).unwrap().date_order(), DateOrder::DayFirst);
+    }
 }
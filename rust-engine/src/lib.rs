@@ -3,19 +3,68 @@
 //! This crate provides the core calculation engine for Ramp Sheets,
 //! including cell storage, formula evaluation, and dependency tracking.
 
+mod autosum;
 mod cell;
+mod chart;
+mod clipboard;
+mod clock;
+mod column;
+mod criteria;
+mod csv;
+mod describe;
+mod document;
+mod foreign;
+mod format_infer;
+mod format_inherit;
 mod formula;
+mod geometry;
 mod grid;
+mod paging;
+mod recalc;
+mod reference;
 mod renderer;
+mod scenario;
+mod schedule;
+mod spill;
+mod stats;
+mod style;
+#[cfg(test)]
+mod test_support;
+mod undo;
 mod viewport;
+mod warnings;
+mod what_if;
 
+use reference::Axis;
+use serde::Serialize;
+use undo::Edit;
 use wasm_bindgen::prelude::*;
 
-pub use cell::{Cell, CellValue, CellRef};
-pub use formula::{Formula, FormulaEngine, FormulaError};
-pub use grid::{Grid, GridDiff, GridPatch};
-pub use renderer::CanvasRenderer;
+pub use autosum::AutoSumSuggestion;
+pub use cell::{Cell, CellValue, CellRef, ValueType};
+pub use chart::{ChartBinding, ChartData, SeriesOrientation};
+pub use clipboard::{ClipboardCell, ClipboardPayload};
+pub use clock::{Clock, ManualClock, SystemClock};
+pub use csv::{CsvOptions, RowFilter};
+pub use describe::RangeDescription;
+pub use document::DocumentProperties;
+pub use format_infer::DateOrder;
+pub use format_inherit::FormatClass;
+pub use formula::{CalculationReport, Formula, FormulaEngine, FormulaError, GraphExportOptions, GraphFormat};
+pub use geometry::{CellRect, PaneGeometry, ScreenRect, SheetLayout, VisibleCells};
+pub use grid::{CellUpdate, DimensionLimits, FormulaListing, Grid, GridDiff, GridDimensions, GridPatch, HeaderDetection, PasteOptions, SeriesOrder, SizeLimits, SizeRange};
+pub use paging::{DenseBlock, DenseCell, PageOptions, RangePage};
+pub use recalc::{RecalcJob, RecalcProgress, RecalcStep};
+pub use reference::ReferenceMapping;
+pub use renderer::{CanvasRenderer, RenderStatus};
+pub use scenario::{Scenario, ScenarioCellSpec, ScenarioComparison};
+pub use schedule::{RenderReason, RenderScheduler, RenderScope};
+pub use stats::{EngineStats, FormulaWarning};
+pub use style::StyleSheet;
+pub use undo::UndoStack;
 pub use viewport::Viewport;
+pub use warnings::{EngineWarning, WarningCode};
+pub use what_if::{DataTable, GoalSeekResult};
 
 /// Initialize the WASM module with panic hooks for better error messages
 #[wasm_bindgen(start)]
@@ -31,6 +80,18 @@ pub struct SheetEngine {
     formula_engine: FormulaEngine,
     viewport: Viewport,
     renderer: Option<CanvasRenderer>,
+    /// Draws asked for since the last frame
+    render_schedule: RenderScheduler,
+    /// Cap on cells returned by one `get_viewport_cells` call
+    max_viewport_cells: usize,
+    /// Whether writes past the edge grow the grid when the caller doesn't say
+    auto_expand: bool,
+    /// Time-sliced recalculation started by `recalculate_async_begin`
+    recalc_job: Option<RecalcJob>,
+    /// Warnings raised by the most recent edit
+    last_warnings: Vec<EngineWarning>,
+    /// Edits `undo` and `redo` step through
+    history: UndoStack,
 }
 
 #[wasm_bindgen]
@@ -43,36 +104,86 @@ impl SheetEngine {
             formula_engine: FormulaEngine::new(),
             viewport: Viewport::new(0, 0, 100, 50),
             renderer: None,
+            render_schedule: RenderScheduler::new(),
+            max_viewport_cells: paging::DEFAULT_MAX_CELLS,
+            auto_expand: false,
+            recalc_job: None,
+            last_warnings: Vec::new(),
+            history: UndoStack::default(),
         }
     }
 
-    /// Attach a canvas element for rendering
+    /// Toggle strict blank-cell handling (blank operands error instead of acting as 0 / "")
+    #[wasm_bindgen]
+    pub fn set_strict_mode(&mut self, strict: bool) {
+        self.formula_engine.set_strict_mode(strict);
+    }
+
+    /// Toggle evaluating formulas with constants folded (values are unchanged; off by default)
+    #[wasm_bindgen]
+    pub fn set_simplify_formulas(&mut self, simplify: bool) {
+        self.formula_engine.set_simplify(simplify);
+    }
+
+    /// Attach a canvas element for rendering, replacing any attached before
+    ///
+    /// Attaching the canvas already in use does nothing. A different one,
+    /// or a new element under the same id after a remount, takes over and
+    /// the old canvas's context is released; if the new canvas can't be
+    /// set up, the old one stays attached.
     #[wasm_bindgen]
     pub fn attach_canvas(&mut self, canvas_id: &str) -> Result<(), JsValue> {
-        self.renderer = Some(CanvasRenderer::new(canvas_id)?);
+        if self.renderer.as_ref().is_some_and(|renderer| renderer.draws_to(canvas_id)) {
+            return Ok(());
+        }
+        let renderer = CanvasRenderer::new(canvas_id)?;
+        if let Some(old) = self.renderer.replace(renderer) {
+            old.release();
+        }
+        // A new canvas starts blank
+        self.render_schedule.request(RenderReason::Resize);
         Ok(())
     }
 
+    /// Stop drawing to the attached canvas and release its context, e.g. when the sheet unmounts
+    #[wasm_bindgen]
+    pub fn detach_canvas(&mut self) {
+        if let Some(renderer) = self.renderer.take() {
+            renderer.release();
+        }
+    }
+
+    /// Whether a canvas is attached and still in the document
+    #[wasm_bindgen]
+    pub fn is_canvas_attached(&self) -> bool {
+        self.renderer.as_ref().is_some_and(CanvasRenderer::is_connected)
+    }
+
     /// Set a cell value (raw value or formula string)
     #[wasm_bindgen]
     pub fn set_cell(&mut self, row: u32, col: u32, value: &str) -> Result<JsValue, JsValue> {
         let cell_ref = CellRef::new(row, col);
+        let before = self.grid.dimensions();
+        let prior = Edit::capture(&self.grid, &self.formula_engine, [cell_ref]);
+        if self.auto_expand {
+            self.grid.expand_to_fit(std::iter::once(cell_ref))?;
+        }
         
         // Check if it's a formula
         if value.starts_with('=') {
             let formula = self.formula_engine.parse(value)?;
-            self.grid.set_formula(cell_ref, formula)?;
+            self.grid.set_cell_formula(cell_ref, formula, &mut self.formula_engine)?;
+            self.grid.warn_empty_ranges(&[cell_ref], &self.formula_engine);
         } else {
-            let cell_value = CellValue::parse(value);
-            self.grid.set_value(cell_ref, cell_value)?;
+            self.grid.enter_value(cell_ref, value, &mut self.formula_engine)?;
         }
         
         // Recalculate affected cells
         let affected = self.formula_engine.recalculate(&mut self.grid, cell_ref)?;
+        self.history.record_typing(prior, self.grid.now_millis());
         
         // Return the diff for the UI
-        let diff = GridDiff::from_cells(&self.grid, &affected);
-        Ok(serde_wasm_bindgen::to_value(&diff)?)
+        Ok(serde_wasm_bindgen::to_value(&self.diff_after_write(&affected, before))?)
     }
 
     /// Get a cell's display value
@@ -85,52 +196,806 @@ impl SheetEngine {
         }
     }
 
-    /// Get cells in the current viewport for rendering
+    /// Get the first page of cells in the current viewport
+    ///
+    /// Returns `{ cells, next }`, or `{ dense, next }` with `dense` set. When
+    /// the viewport holds more than the cap, `next` is a token for
+    /// `get_viewport_cells_page`. `options` is an optional object with
+    /// `include_formulas` and `include_formats`, both true when left out.
     #[wasm_bindgen]
-    pub fn get_viewport_cells(&self) -> Result<JsValue, JsValue> {
-        let cells = self.grid.get_range(
-            self.viewport.start_row,
-            self.viewport.start_col,
-            self.viewport.end_row(),
-            self.viewport.end_col(),
+    pub fn get_viewport_cells(&self, dense: bool, options: JsValue) -> Result<JsValue, JsValue> {
+        let options: PageOptions = if options.is_undefined() || options.is_null() {
+            PageOptions::default()
+        } else {
+            serde_wasm_bindgen::from_value(options)?
+        };
+        let page = self.grid.get_range_page(
+            CellRef::new(self.viewport.start_row, self.viewport.start_col),
+            CellRef::new(self.viewport.end_row(), self.viewport.end_col()),
+            PageOptions { max_cells: self.max_viewport_cells, dense, ..options },
+            &self.formula_engine,
         );
+        Ok(serde_wasm_bindgen::to_value(&page)?)
+    }
+
+    /// Get the page after the one that returned `token`
+    ///
+    /// The token carries the first page's options, so later pages leave out
+    /// the same fields.
+    #[wasm_bindgen]
+    pub fn get_viewport_cells_page(&self, token: &str) -> Result<JsValue, JsValue> {
+        let page = self.grid.get_range_page_after(token, &self.formula_engine)?;
+        Ok(serde_wasm_bindgen::to_value(&page)?)
+    }
+
+    /// Every cell in view, split by frozen pane: `{ top_left, top, left, main, panes }`
+    ///
+    /// Laid out as the renderer draws, so hidden rows and columns and cells
+    /// scrolled out of view are left out. Without frozen panes only `main`
+    /// has cells. `panes` gives `cells_x` and `cells_y`, where the cells
+    /// start past the headers, and `split_x` and `split_y`, where the
+    /// scrolled panes start. Unlike `get_viewport_cells` this isn't paged.
+    #[wasm_bindgen]
+    pub fn get_visible_cells(&self) -> Result<JsValue, JsValue> {
+        Ok(serde_wasm_bindgen::to_value(&self.layout().visible_cells(&self.grid, &self.formula_engine))?)
+    }
+
+    /// Get the formula in a cell, for the formula bar when viewport reads leave formulas out
+    #[wasm_bindgen]
+    pub fn get_cell_formula(&self, row: u32, col: u32) -> Option<String> {
+        self.grid.get_cell_formula(CellRef::new(row, col), &self.formula_engine)
+    }
+
+    /// Trim the declared dimensions to the used range plus a small margin
+    #[wasm_bindgen]
+    pub fn shrink_to_fit(&mut self) {
+        self.grid.shrink_to_fit();
+    }
+
+    /// Set the cap on cells returned by one `get_viewport_cells` call
+    #[wasm_bindgen]
+    pub fn set_max_viewport_cells(&mut self, max_cells: usize) {
+        self.max_viewport_cells = max_cells.max(1);
+    }
+
+    /// Numeric series for a range (A1-style corners), with nulls for non-numeric cells
+    ///
+    /// `order` is `"row_major"` or `"column_major"`.
+    #[wasm_bindgen]
+    pub fn series_for_range(&self, start: &str, end: &str, order: &str) -> Result<JsValue, JsValue> {
+        let order: SeriesOrder = serde_wasm_bindgen::from_value(JsValue::from_str(order))?;
+        let series = self.grid.series(parse_a1(start)?, parse_a1(end)?, order);
+        let serializer = serde_wasm_bindgen::Serializer::new().serialize_missing_as_null(true);
+        Ok(series.serialize(&serializer)?)
+    }
+
+    /// Guess whether a range (A1-style corners) has a header row, and which row it is
+    #[wasm_bindgen]
+    pub fn detect_header(&self, start: &str, end: &str) -> Result<JsValue, JsValue> {
+        let detection = self.grid.detect_header(parse_a1(start)?, parse_a1(end)?);
+        Ok(serde_wasm_bindgen::to_value(&detection)?)
+    }
+
+    /// Formula and dependency counts, with warnings about formulas over huge
+    /// ranges, and the depth and size of the undo history
+    #[wasm_bindgen]
+    pub fn stats(&self) -> Result<JsValue, JsValue> {
+        let stats = EngineStats {
+            undo_depth: self.history.len(),
+            undo_bytes: self.history.bytes(),
+            ..self.formula_engine.stats()
+        };
+        Ok(serde_wasm_bindgen::to_value(&stats)?)
+    }
+
+    /// Warn about formulas whose ranges cover more than `cells` cells
+    #[wasm_bindgen]
+    pub fn set_large_formula_threshold(&mut self, cells: u32) {
+        self.formula_engine.set_large_formula_threshold(u64::from(cells));
+    }
+
+    /// Every formula in the sheet as `[{ ref, formula }]`, in row-major order
+    #[wasm_bindgen]
+    pub fn list_formulas(&self) -> Result<JsValue, JsValue> {
+        Ok(serde_wasm_bindgen::to_value(&self.grid.list_formulas())?)
+    }
+
+    /// A1 references of the formulas that would recalculate if the cell changed
+    ///
+    /// Nothing is modified; use it to preview the impact of an edit.
+    #[wasm_bindgen]
+    pub fn impact_of(&self, row: u32, col: u32) -> Result<JsValue, JsValue> {
+        let cells: Vec<String> = self.formula_engine.impact_of(CellRef::new(row, col)).iter().map(CellRef::to_a1).collect();
         Ok(serde_wasm_bindgen::to_value(&cells)?)
     }
 
+    /// The dependency graph as Graphviz DOT or JSON text, for drawing the model's structure
+    ///
+    /// `options` is an optional object with `format` (`"dot"`, the default,
+    /// or `"json"`), `range` (an A1-style range whose cells and everything
+    /// connected to them are exported) and `max_nodes`.
+    #[wasm_bindgen]
+    pub fn export_dependency_graph(&self, options: JsValue) -> Result<String, JsValue> {
+        let options: GraphExportOptions = if options.is_undefined() || options.is_null() {
+            GraphExportOptions::default()
+        } else {
+            serde_wasm_bindgen::from_value(options)?
+        };
+        Ok(self.formula_engine.export_graph(&self.grid, &options)?)
+    }
+
+    /// Retarget formula references for relocated source blocks
+    ///
+    /// `mappings_js` is an array of `{ from_start, from_end, to_start }`
+    /// objects. Returns the diff of rewritten and recalculated cells.
+    #[wasm_bindgen]
+    pub fn remap_references(&mut self, mappings_js: JsValue) -> Result<JsValue, JsValue> {
+        let mappings: Vec<ReferenceMapping> = serde_wasm_bindgen::from_value(mappings_js)?;
+        let affected = self.formula_engine.rewrite_references(&mut self.grid, &mappings)?;
+        self.history.clear_redo();
+        Ok(serde_wasm_bindgen::to_value(&self.diff(&affected))?)
+    }
+
+    /// Compact description of a range (A1-style corners) for grounding agent edits
+    #[wasm_bindgen]
+    pub fn describe_range(&self, start: &str, end: &str, max_samples: usize) -> Result<JsValue, JsValue> {
+        let description = self.grid.describe_range(parse_a1(start)?, parse_a1(end)?, max_samples, &self.formula_engine);
+        let serializer = serde_wasm_bindgen::Serializer::json_compatible();
+        Ok(description.serialize(&serializer)?)
+    }
+
+    /// Copy `start:end` with formulas and formats, as a JSON payload for `paste_internal`
+    ///
+    /// For copying within our own grids; other applications get values via
+    /// tab-separated text.
+    #[wasm_bindgen]
+    pub fn copy_internal(&self, start: &str, end: &str) -> Result<String, JsValue> {
+        Ok(self.grid.copy_internal(parse_a1(start)?, parse_a1(end)?)?.to_json()?)
+    }
+
+    /// Paste a `copy_internal` payload at `target`, moving relative references with it
+    #[wasm_bindgen]
+    pub fn paste_internal(&mut self, target: &str, payload: &str) -> Result<JsValue, JsValue> {
+        let payload = ClipboardPayload::from_json(payload)?;
+        let target = parse_a1(target)?;
+        let block = (0..payload.rows).flat_map(|row| (0..payload.cols).map(move |col| CellRef::new(target.row + row, target.col + col)));
+        let prior = Edit::capture(&self.grid, &self.formula_engine, block);
+        let affected = self.grid.paste_internal(target, &payload, &mut self.formula_engine)?;
+        self.history.record(prior);
+        let diff = self.diff(&affected);
+        Ok(serde_wasm_bindgen::to_value(&diff)?)
+    }
+
     /// Update viewport position (for scrolling)
     #[wasm_bindgen]
     pub fn set_viewport(&mut self, start_row: u32, start_col: u32, visible_rows: u32, visible_cols: u32) {
-        self.viewport = Viewport::new(start_row, start_col, visible_rows, visible_cols);
+        self.viewport = Viewport {
+            start_row,
+            start_col,
+            visible_rows,
+            visible_cols,
+            offset_x: 0.0,
+            offset_y: 0.0,
+            ..self.viewport
+        };
+        self.grid.set_view_state(self.viewport);
     }
 
-    /// Render the current viewport to the attached canvas
+    /// Set the zoom level (1.0 = 100%), clamped to 25%–400%
     #[wasm_bindgen]
-    pub fn render(&self) -> Result<(), JsValue> {
+    pub fn set_zoom(&mut self, zoom: f32) {
+        self.viewport.set_zoom(zoom);
+        self.grid.set_view_state(self.viewport);
+    }
+
+    /// Keep the first `rows` rows and `cols` columns in view while scrolling
+    #[wasm_bindgen]
+    pub fn freeze_panes(&mut self, rows: u32, cols: u32) {
+        self.viewport.frozen_rows = rows;
+        self.viewport.frozen_cols = cols;
+        self.grid.set_view_state(self.viewport);
+    }
+
+    /// Redraw gridlines and headers on the next render, e.g. after a theme change
+    #[wasm_bindgen]
+    pub fn invalidate_layout_cache(&self) {
         if let Some(ref renderer) = self.renderer {
-            renderer.render(&self.grid, &self.viewport)?;
+            renderer.invalidate_layout_cache();
         }
+    }
+
+    /// Canvas rectangle of a cell, for drawing an overlay over it
+    ///
+    /// Computed with the renderer's own layout: `{ x, y, width, height, clipped }`,
+    /// or undefined when the cell is hidden or out of view. `clipped` means
+    /// part of the cell is under a header or frozen pane or off the canvas.
+    #[wasm_bindgen]
+    pub fn get_cell_rect(&self, row: u32, col: u32) -> Result<JsValue, JsValue> {
+        Ok(serde_wasm_bindgen::to_value(&self.layout().cell_rect(row, col))?)
+    }
+
+    /// Canvas rectangle around the part of a range in view, e.g. `("B2", "D9")`
+    #[wasm_bindgen]
+    pub fn get_range_rect(&self, start: &str, end: &str) -> Result<JsValue, JsValue> {
+        let rect = self.layout().range_rect(parse_a1(start)?, parse_a1(end)?);
+        Ok(serde_wasm_bindgen::to_value(&rect)?)
+    }
+
+    /// Render the current viewport to the attached canvas
+    ///
+    /// Returns `"drawn"`, or `"no_canvas"` or `"canvas_detached"` when there
+    /// was nothing to draw to: a canvas removed from the document isn't
+    /// drawn to until it's back or another is attached.
+    #[wasm_bindgen]
+    pub fn render(&self) -> Result<JsValue, JsValue> {
+        let status = match &self.renderer {
+            None => RenderStatus::NoCanvas,
+            Some(renderer) if !renderer.is_connected() => RenderStatus::CanvasDetached,
+            Some(renderer) => {
+                renderer.render(&self.grid, &self.formula_engine, &self.viewport)?;
+                RenderStatus::Drawn
+            }
+        };
+        Ok(serde_wasm_bindgen::to_value(&status)?)
+    }
+
+    /// Ask for a draw on the next `render_if_needed`
+    ///
+    /// `reason` is one of `cells`, `format`, `scroll`, `zoom`, `resize` or
+    /// `layout`. Requests merge until the next draw: edits to cells redraw
+    /// only the cells written since then, and any other reason the whole canvas.
+    #[wasm_bindgen]
+    pub fn request_render(&mut self, reason: &str) -> Result<(), JsValue> {
+        let reason: RenderReason = serde_wasm_bindgen::from_value(JsValue::from_str(reason))?;
+        self.render_schedule.request(reason);
         Ok(())
     }
 
+    /// Draw whatever `request_render` asked for since the last draw, at most once
+    ///
+    /// Call from the host's `requestAnimationFrame` loop. Returns whether
+    /// anything was drawn; without a canvas in the document, requests wait.
+    #[wasm_bindgen]
+    pub fn render_if_needed(&mut self) -> Result<bool, JsValue> {
+        let Some(renderer) = self.renderer.as_ref().filter(|renderer| renderer.is_connected()) else {
+            return Ok(false);
+        };
+        let (grid, formula_engine, viewport) = (&self.grid, &self.formula_engine, &self.viewport);
+        self.render_schedule.run(|scope| match scope {
+            RenderScope::Full => renderer.render(grid, formula_engine, viewport),
+            RenderScope::Region(start, end) => renderer.render_region(grid, formula_engine, viewport, start, end),
+        })
+    }
+
     /// Apply a batch of cell updates (for AI agent patches)
     #[wasm_bindgen]
     pub fn apply_patch(&mut self, patch_js: JsValue) -> Result<JsValue, JsValue> {
-        let patch: GridPatch = serde_wasm_bindgen::from_value(patch_js)?;
+        let mut patch: GridPatch = serde_wasm_bindgen::from_value(patch_js)?;
+        patch.auto_expand.get_or_insert(self.auto_expand);
+        let before = self.grid.dimensions();
+        let targets = patch.updates.iter().map(|update| CellRef::new(update.row, update.col));
+        let prior = Edit::capture(&self.grid, &self.formula_engine, targets);
+        let affected = self.grid.apply_patch(patch, &mut self.formula_engine)?;
+        self.history.record_typing(prior, self.grid.now_millis());
+        Ok(serde_wasm_bindgen::to_value(&self.diff_after_write(&affected, before))?)
+    }
+
+    /// Default for patches and `set_cell` writes past the edge: grow the grid instead of failing
+    #[wasm_bindgen]
+    pub fn set_auto_expand(&mut self, auto_expand: bool) {
+        self.auto_expand = auto_expand;
+    }
+
+    /// Cap how far auto-expanding writes may grow the grid
+    #[wasm_bindgen]
+    pub fn set_max_dimensions(&mut self, max_rows: u32, max_cols: u32) -> Result<(), JsValue> {
+        self.grid.set_dimension_limits(max_rows, max_cols)?;
+        Ok(())
+    }
+
+    /// Paste tab-separated clipboard text with its top-left corner at (row, col)
+    #[wasm_bindgen]
+    pub fn paste_text(&mut self, row: u32, col: u32, text: &str, infer_formats: bool) -> Result<JsValue, JsValue> {
+        let block: Vec<Vec<String>> = text
+            .lines()
+            .map(|line| line.split('\t').map(|s| s.to_string()).collect())
+            .collect();
+        let options = PasteOptions { infer_formats };
+        let targets = block.iter().enumerate()
+            .flat_map(|(r, values)| (0..values.len()).map(move |c| CellRef::new(row + r as u32, col + c as u32)));
+        let prior = Edit::capture(&self.grid, &self.formula_engine, targets);
+        let affected = self.grid.paste_block(CellRef::new(row, col), &block, options, &mut self.formula_engine)?;
+        self.history.record(prior);
+        let diff = self.diff(&affected);
+        Ok(serde_wasm_bindgen::to_value(&diff)?)
+    }
+
+    /// Suggest an AutoSum formula for the active cell, or null if there's nothing to sum
+    #[wasm_bindgen]
+    pub fn suggest_autosum(&self, row: u32, col: u32) -> Result<JsValue, JsValue> {
+        match autosum::suggest(&self.grid, CellRef::new(row, col)) {
+            Some(suggestion) => Ok(serde_wasm_bindgen::to_value(&suggestion)?),
+            None => Ok(JsValue::NULL),
+        }
+    }
+
+    /// Write the suggested AutoSum formula into the active cell and recalculate
+    #[wasm_bindgen]
+    pub fn apply_autosum(&mut self, row: u32, col: u32) -> Result<JsValue, JsValue> {
+        let Some(suggestion) = autosum::suggest(&self.grid, CellRef::new(row, col)) else {
+            return Ok(JsValue::NULL);
+        };
+        let patch = GridPatch {
+            updates: vec![CellUpdate {
+                row,
+                col,
+                value: None,
+                formula: Some(suggestion.formula),
+            }],
+            auto_expand: None,
+        };
+        let prior = Edit::capture(&self.grid, &self.formula_engine, [CellRef::new(row, col)]);
         let affected = self.grid.apply_patch(patch, &mut self.formula_engine)?;
-        let diff = GridDiff::from_cells(&self.grid, &affected);
+        self.history.record(prior);
+        let diff = self.diff(&affected);
+        Ok(serde_wasm_bindgen::to_value(&diff)?)
+    }
+
+    /// Define (or replace) a named scenario from `[{ ref: "B3", value: "0.05" }, ...]`
+    #[wasm_bindgen]
+    pub fn define_scenario(&mut self, name: &str, cells_js: JsValue) -> Result<(), JsValue> {
+        let specs: Vec<ScenarioCellSpec> = serde_wasm_bindgen::from_value(cells_js)?;
+        self.grid.define_scenario(name, &specs)?;
+        Ok(())
+    }
+
+    /// Swap a scenario's values into its input cells
+    #[wasm_bindgen]
+    pub fn apply_scenario(&mut self, name: &str) -> Result<JsValue, JsValue> {
+        let affected = self.grid.apply_scenario(name, &mut self.formula_engine)?;
+        self.history.clear_redo();
+        let diff = self.diff(&affected);
+        Ok(serde_wasm_bindgen::to_value(&diff)?)
+    }
+
+    /// Restore the inputs that were in place before any scenario was applied
+    #[wasm_bindgen]
+    pub fn clear_scenario(&mut self) -> Result<JsValue, JsValue> {
+        let affected = self.grid.clear_scenario(&mut self.formula_engine)?;
+        self.history.clear_redo();
+        let diff = self.diff(&affected);
+        Ok(serde_wasm_bindgen::to_value(&diff)?)
+    }
+
+    /// List defined scenarios
+    #[wasm_bindgen]
+    pub fn list_scenarios(&self) -> Result<JsValue, JsValue> {
+        Ok(serde_wasm_bindgen::to_value(&self.grid.scenarios().list())?)
+    }
+
+    /// Compare output cells (A1-style refs) under two scenarios without changing the sheet
+    #[wasm_bindgen]
+    pub fn diff_scenarios(&mut self, a: &str, b: &str, output_cells_js: JsValue) -> Result<JsValue, JsValue> {
+        let refs: Vec<String> = serde_wasm_bindgen::from_value(output_cells_js)?;
+        let output_cells = refs.iter()
+            .map(|r| parse_a1(r))
+            .collect::<Result<Vec<_>, _>>()?;
+        let comparison = self.grid.diff_scenarios(a, b, &output_cells, &mut self.formula_engine)?;
+        Ok(serde_wasm_bindgen::to_value(&comparison)?)
+    }
+
+    /// Solve for the value of `changing_cell` that makes `target_cell` equal `target_value`
+    ///
+    /// Cells are A1-style references. The sheet is not modified.
+    #[wasm_bindgen]
+    pub fn goal_seek(&self, target_cell: &str, target_value: f64, changing_cell: &str) -> Result<JsValue, JsValue> {
+        let target = parse_a1(target_cell)?;
+        let changing = parse_a1(changing_cell)?;
+        let result = self.formula_engine.goal_seek(&self.grid, target, target_value, changing)?;
+        Ok(serde_wasm_bindgen::to_value(&result)?)
+    }
+
+    /// Compute a what-if data table for `formula_cell`
+    ///
+    /// Input cells are A1-style references; pass an empty string for one of
+    /// them to build a one-variable table. Values are arrays of raw inputs.
+    #[wasm_bindgen]
+    pub fn data_table(
+        &self,
+        formula_cell: &str,
+        row_input_cell: &str,
+        col_input_cell: &str,
+        row_values_js: JsValue,
+        col_values_js: JsValue,
+    ) -> Result<JsValue, JsValue> {
+        let formula_cell = parse_a1(formula_cell)?;
+        let row_input = Some(row_input_cell).filter(|r| !r.is_empty()).map(parse_a1).transpose()?;
+        let col_input = Some(col_input_cell).filter(|r| !r.is_empty()).map(parse_a1).transpose()?;
+        let parse_values = |js: JsValue| -> Result<Vec<CellValue>, JsValue> {
+            if js.is_null() || js.is_undefined() {
+                return Ok(Vec::new());
+            }
+            let raw: Vec<String> = serde_wasm_bindgen::from_value(js)?;
+            Ok(raw.iter().map(|v| CellValue::parse(v)).collect())
+        };
+        let table = self.formula_engine.data_table(
+            &self.grid,
+            formula_cell,
+            row_input,
+            col_input,
+            &parse_values(row_values_js)?,
+            &parse_values(col_values_js)?,
+        )?;
+        Ok(serde_wasm_bindgen::to_value(&table)?)
+    }
+
+    /// Set a document property (title, author, schema, timestamps, or a custom key)
+    #[wasm_bindgen]
+    pub fn set_property(&mut self, key: &str, value: JsValue) -> Result<(), JsValue> {
+        let value: serde_json::Value = serde_wasm_bindgen::from_value(value)?;
+        self.grid.set_property(key, value)?;
+        Ok(())
+    }
+
+    /// Get a document property, or undefined if unset
+    #[wasm_bindgen]
+    pub fn get_property(&self, key: &str) -> Result<JsValue, JsValue> {
+        match self.grid.properties().get(key) {
+            Some(value) => Ok(value.serialize(&serde_wasm_bindgen::Serializer::json_compatible())?),
+            None => Ok(JsValue::UNDEFINED),
+        }
+    }
+
+    /// Get all document properties as a plain object
+    #[wasm_bindgen]
+    pub fn get_all_properties(&self) -> Result<JsValue, JsValue> {
+        Ok(self.grid.properties().serialize(&serde_wasm_bindgen::Serializer::json_compatible())?)
+    }
+
+    /// Re-evaluate every formula without writing and report stale cached values
+    #[wasm_bindgen]
+    pub fn verify_calculations(&self) -> Result<JsValue, JsValue> {
+        let report = self.formula_engine.verify_calculations(&self.grid);
+        Ok(serde_wasm_bindgen::to_value(&report)?)
+    }
+
+    /// Recalculate the whole sheet in dependency order and return the corrected cells
+    #[wasm_bindgen]
+    pub fn repair_calculations(&mut self) -> Result<JsValue, JsValue> {
+        let changed = self.formula_engine.recalculate_all(&mut self.grid)?;
+        self.history.clear_redo();
+        let diff = self.diff(&changed);
+        Ok(serde_wasm_bindgen::to_value(&diff)?)
+    }
+
+    /// Start a time-sliced recalculation of the whole sheet, replacing any in progress
+    ///
+    /// Edits may keep arriving while it runs: the next step notices the sheet
+    /// changed and starts the pass over, so the result always matches a full
+    /// synchronous recalculation.
+    #[wasm_bindgen]
+    pub fn recalculate_async_begin(&mut self) {
+        self.recalc_job = Some(self.formula_engine.begin_recalculation(&self.grid));
+    }
+
+    /// Run the recalculation for about `budget_ms`; returns `{ done, progress, restarted, partial_diff }`
+    #[wasm_bindgen]
+    pub fn recalculate_async_step(&mut self, budget_ms: f64) -> Result<JsValue, JsValue> {
+        let Some(job) = self.recalc_job.as_mut() else {
+            return Err(JsValue::from_str("No recalculation in progress"));
+        };
+        let step = job.step(&mut self.formula_engine, &mut self.grid, budget_ms)?;
+        if step.done {
+            self.recalc_job = None;
+        }
+        let progress = RecalcProgress::new(step, &self.grid, &self.formula_engine);
+        Ok(serde_wasm_bindgen::to_value(&progress)?)
+    }
+
+    /// Stop the recalculation; cells it already evaluated keep their new values
+    #[wasm_bindgen]
+    pub fn recalculate_async_cancel(&mut self) {
+        self.recalc_job = None;
+    }
+
+    /// Insert `count` blank rows before `start`; returns the diff of recalculated cells
+    #[wasm_bindgen]
+    pub fn insert_rows(&mut self, start: u32, count: u32) -> Result<JsValue, JsValue> {
+        let before = self.grid.dimensions();
+        let changed = self.history.apply(Edit::insert_lines(Axis::Rows, start, count), &mut self.grid, &mut self.formula_engine)?;
+        Ok(serde_wasm_bindgen::to_value(&self.diff_after_write(&changed, before))?)
+    }
+
+    /// Delete `count` rows from `start`; returns the diff of cells that lost a reference or were recalculated
+    #[wasm_bindgen]
+    pub fn delete_rows(&mut self, start: u32, count: u32) -> Result<JsValue, JsValue> {
+        let before = self.grid.dimensions();
+        let edit = Edit::DeleteLines { axis: Axis::Rows, start, count };
+        let changed = self.history.apply(edit, &mut self.grid, &mut self.formula_engine)?;
+        Ok(serde_wasm_bindgen::to_value(&self.diff_after_write(&changed, before))?)
+    }
+
+    /// Insert `count` blank columns before `start`; returns the diff of recalculated cells
+    #[wasm_bindgen]
+    pub fn insert_cols(&mut self, start: u32, count: u32) -> Result<JsValue, JsValue> {
+        let before = self.grid.dimensions();
+        let changed = self.history.apply(Edit::insert_lines(Axis::Cols, start, count), &mut self.grid, &mut self.formula_engine)?;
+        Ok(serde_wasm_bindgen::to_value(&self.diff_after_write(&changed, before))?)
+    }
+
+    /// Delete `count` columns from `start`; returns the diff of cells that lost a reference or were recalculated
+    #[wasm_bindgen]
+    pub fn delete_cols(&mut self, start: u32, count: u32) -> Result<JsValue, JsValue> {
+        let before = self.grid.dimensions();
+        let edit = Edit::DeleteLines { axis: Axis::Cols, start, count };
+        let changed = self.history.apply(edit, &mut self.grid, &mut self.formula_engine)?;
+        Ok(serde_wasm_bindgen::to_value(&self.diff_after_write(&changed, before))?)
+    }
+
+    /// Revert the latest edit; returns the diff of the cells it changed, or null if there's nothing to undo
+    ///
+    /// Cell writes, pastes, formats, notes, validation and row and column
+    /// insertions and deletions can be undone.
+    #[wasm_bindgen]
+    pub fn undo(&mut self) -> Result<JsValue, JsValue> {
+        let before = self.grid.dimensions();
+        match self.history.undo(&mut self.grid, &mut self.formula_engine)? {
+            Some(changed) => Ok(serde_wasm_bindgen::to_value(&self.diff_after_write(&changed, before))?),
+            None => Ok(JsValue::NULL),
+        }
+    }
+
+    /// Make the latest undone edit again; returns the diff, or null if there's nothing to redo
+    #[wasm_bindgen]
+    pub fn redo(&mut self) -> Result<JsValue, JsValue> {
+        let before = self.grid.dimensions();
+        match self.history.redo(&mut self.grid, &mut self.formula_engine)? {
+            Some(changed) => Ok(serde_wasm_bindgen::to_value(&self.diff_after_write(&changed, before))?),
+            None => Ok(JsValue::NULL),
+        }
+    }
+
+    #[wasm_bindgen]
+    pub fn can_undo(&self) -> bool {
+        self.history.can_undo()
+    }
+
+    #[wasm_bindgen]
+    pub fn can_redo(&self) -> bool {
+        self.history.can_redo()
+    }
+
+    /// Keep at most `depth` edits to undo (100 by default)
+    #[wasm_bindgen]
+    pub fn set_undo_depth(&mut self, depth: usize) {
+        self.history.set_depth(depth);
+    }
+
+    /// Cap the undo history's memory: `entry_bytes` for one edit (a bigger
+    /// one clears the history) and `total_bytes` for all of them
+    #[wasm_bindgen]
+    pub fn set_undo_budget(&mut self, entry_bytes: usize, total_bytes: usize) {
+        self.history.set_byte_budget(entry_bytes, total_bytes);
+    }
+
+    /// Undo edits of one cell less than `millis` apart, up to `max_edits` of them, in one step
+    ///
+    /// Defaults to 1000ms and 100 edits; a `max_edits` of 1 turns this off.
+    #[wasm_bindgen]
+    pub fn set_undo_coalescing(&mut self, millis: f64, max_edits: usize) {
+        self.history.set_coalescing(millis, max_edits);
+    }
+
+    /// Name an A1-style range such as `B3` or `B3:B14`; returns the diff of cells recalculated
+    ///
+    /// The name is saved with the document. Formulas already using it follow
+    /// the new definition.
+    #[wasm_bindgen]
+    pub fn define_name(&mut self, name: &str, range: &str) -> Result<JsValue, JsValue> {
+        let (start, end) = range.split_once(':').unwrap_or((range, range));
+        let changed = self.formula_engine.define_name(&mut self.grid, name, parse_a1(start)?, parse_a1(end)?)?;
+        self.history.clear_redo();
+        Ok(serde_wasm_bindgen::to_value(&self.diff(&changed))?)
+    }
+
+    /// Forget a name; returns the diff of cells recalculated, or null if it wasn't defined
+    #[wasm_bindgen]
+    pub fn remove_name(&mut self, name: &str) -> Result<JsValue, JsValue> {
+        let Some(changed) = self.formula_engine.remove_name(&mut self.grid, name)? else {
+            return Ok(JsValue::NULL);
+        };
+        self.history.clear_redo();
+        Ok(serde_wasm_bindgen::to_value(&self.diff(&changed))?)
+    }
+
+    /// Bind chart `id` to an A1-style range such as `B1:D20`
+    ///
+    /// `orientation` is `"columns"` (one series per column) or `"rows"`.
+    /// Returns the chart's current data; later diffs carry it again whenever
+    /// a cell in the range changes.
+    #[wasm_bindgen]
+    pub fn add_chart_binding(&mut self, id: &str, range: &str, orientation: &str, has_header: bool) -> Result<JsValue, JsValue> {
+        let (start, end) = range.split_once(':').unwrap_or((range, range));
+        let orientation = match orientation {
+            "columns" => SeriesOrientation::Columns,
+            "rows" => SeriesOrientation::Rows,
+            other => return Err(JsValue::from_str(&format!("Invalid orientation: {}", other))),
+        };
+        let data = self.grid.add_chart_binding(id, parse_a1(start)?, parse_a1(end)?, orientation, has_header)?;
+        Ok(serde_wasm_bindgen::to_value(&data)?)
+    }
+
+    /// Remove a chart binding; returns false if there was none
+    #[wasm_bindgen]
+    pub fn remove_chart_binding(&mut self, id: &str) -> bool {
+        self.grid.remove_chart_binding(id)
+    }
+
+    /// Current series data for chart `id`, or null if it isn't bound
+    #[wasm_bindgen]
+    pub fn get_chart_data(&self, id: &str) -> Result<JsValue, JsValue> {
+        Ok(serde_wasm_bindgen::to_value(&self.grid.chart_data(id))?)
+    }
+
+    /// Define (or redefine) a named style; cells already using it are reformatted
+    #[wasm_bindgen]
+    pub fn define_style(&mut self, name: &str, format_js: JsValue) -> Result<JsValue, JsValue> {
+        let format: cell::CellFormat = serde_wasm_bindgen::from_value(format_js)?;
+        let changed = self.grid.define_style(name, format);
+        self.history.clear_redo();
+        let diff = self.diff(&changed);
+        Ok(serde_wasm_bindgen::to_value(&diff)?)
+    }
+
+    /// Give every cell in an A1-style range such as `A1:F1` a named style
+    #[wasm_bindgen]
+    pub fn apply_style(&mut self, range: &str, name: &str) -> Result<JsValue, JsValue> {
+        let (start, end) = range.split_once(':').unwrap_or((range, range));
+        let (start, end) = (parse_a1(start)?, parse_a1(end)?);
+        let range = (start.row.min(end.row)..=start.row.max(end.row))
+            .flat_map(|row| (start.col.min(end.col)..=start.col.max(end.col)).map(move |col| CellRef::new(row, col)));
+        let prior = Edit::capture(&self.grid, &self.formula_engine, range);
+        let changed = self.grid.apply_style_to_range(start, end, name)?;
+        self.history.record(prior);
+        let diff = self.diff(&changed);
         Ok(serde_wasm_bindgen::to_value(&diff)?)
     }
 
+    /// The sheet's styles as a JSON stylesheet
+    #[wasm_bindgen]
+    pub fn export_styles(&self) -> Result<String, JsValue> {
+        Ok(self.grid.export_styles()?)
+    }
+
+    /// Define every style of a stylesheet from `export_styles`
+    #[wasm_bindgen]
+    pub fn import_styles(&mut self, json: &str) -> Result<JsValue, JsValue> {
+        let changed = self.grid.import_styles(json)?;
+        self.history.clear_redo();
+        let diff = self.diff(&changed);
+        Ok(serde_wasm_bindgen::to_value(&diff)?)
+    }
+
+    /// How typed slashed dates like `1/5/2024` are read: `"month_first"` or `"day_first"`
+    #[wasm_bindgen]
+    pub fn set_date_order(&mut self, order: &str) -> Result<(), JsValue> {
+        let order: DateOrder = serde_wasm_bindgen::from_value(JsValue::from_str(order))?;
+        self.grid.set_date_order(order);
+        Ok(())
+    }
+
+    /// Run-length encode large repetitive columns; returns the approximate
+    /// cell storage size in bytes afterwards
+    #[wasm_bindgen]
+    pub fn compact_storage(&mut self) -> usize {
+        self.grid.compact_columns();
+        self.grid.storage_bytes()
+    }
+
+    /// Export the sheet as CSV, from A1 to the last populated cell
+    #[wasm_bindgen]
+    pub fn export_csv(&self) -> String {
+        self.grid.used_range_to_csv()
+    }
+
+    /// Export the rows of a range (A1-style corners) that pass `predicate` as CSV
+    ///
+    /// `predicate` is either an array of row indices or a formula template
+    /// such as `"=C{row}>1000"`. `options` is an optional `CsvOptions` object.
+    #[wasm_bindgen]
+    pub fn export_rows_csv(&self, start: &str, end: &str, predicate: JsValue, options: JsValue) -> Result<String, JsValue> {
+        let (start, end) = (parse_a1(start)?, parse_a1(end)?);
+        let filter: RowFilter = serde_wasm_bindgen::from_value(predicate)?;
+        let options: CsvOptions = if options.is_undefined() || options.is_null() {
+            CsvOptions::default()
+        } else {
+            serde_wasm_bindgen::from_value(options)?
+        };
+        let rows = filter.matching_rows(&self.grid, &self.formula_engine, start.row, end.row)?;
+        Ok(self.grid.rows_to_csv(start, end, rows, &options))
+    }
+
     /// Export grid data as JSON (for persistence)
     #[wasm_bindgen]
     pub fn export_json(&self) -> Result<String, JsValue> {
         self.grid.to_json().map_err(|e| JsValue::from_str(&e.to_string()))
     }
 
-    /// Import grid data from JSON
+    /// Import grid data from JSON, registering its formulas with the engine
+    ///
+    /// The saved scroll position, zoom and frozen panes are restored; older
+    /// files without them open at the top-left.
     #[wasm_bindgen]
     pub fn import_json(&mut self, json: &str) -> Result<(), JsValue> {
-        self.grid = Grid::from_json(json).map_err(|e| JsValue::from_str(&e.to_string()))?;
+        let mut grid = Grid::from_json(json).map_err(|e| JsValue::from_str(&e.to_string()))?;
+        self.formula_engine.load_formulas(&mut grid);
+        self.install_grid(grid);
+        Ok(())
+    }
+
+    /// Replace the sheet with the contents of a CSV file
+    ///
+    /// Numbers and booleans are typed; a first row of labels over numbers is
+    /// taken as a header and kept as text. With `infer_formats`, currency,
+    /// percent and date fields are read as numbers and their columns get a
+    /// matching number format, as `paste_text` does.
+    #[wasm_bindgen]
+    pub fn import_csv(&mut self, csv: &str, infer_formats: bool) -> Result<(), JsValue> {
+        let mut grid = Grid::from_csv_detecting_header(csv, infer_formats)?;
+        self.formula_engine.load_formulas(&mut grid);
+        self.install_grid(grid);
+        Ok(())
+    }
+
+    /// Import like `import_json`, then recalculate and report formulas whose saved value was wrong
+    ///
+    /// Returns `{ checked, mismatches: [{ cell, stored, recomputed }], volatile }`;
+    /// the sheet is left with the recalculated values.
+    #[wasm_bindgen]
+    pub fn import_json_verified(&mut self, json: &str) -> Result<JsValue, JsValue> {
+        let mut grid = Grid::from_json(json).map_err(|e| JsValue::from_str(&e.to_string()))?;
+        let report = self.formula_engine.load_verified(&mut grid)?;
+        self.install_grid(grid);
+        Ok(serde_wasm_bindgen::to_value(&report)?)
+    }
+
+    /// Bring back formulas marked foreign on import that this version can evaluate
+    #[wasm_bindgen]
+    pub fn reparse_foreign_formulas(&mut self) -> Result<JsValue, JsValue> {
+        let changed = self.grid.reparse_foreign_formulas(&mut self.formula_engine)?;
+        self.history.clear_redo();
+        let diff = self.diff(&changed);
+        Ok(serde_wasm_bindgen::to_value(&diff)?)
+    }
+
+    /// Warnings raised by the most recent edit: `[{ code, message, cell?, end?, details? }]`
+    ///
+    /// Edits that return a diff also carry these in its `warnings`; this is
+    /// how to see them after edits that return nothing, such as `apply_format`.
+    #[wasm_bindgen]
+    pub fn get_last_warnings(&self) -> Result<JsValue, JsValue> {
+        Ok(serde_wasm_bindgen::to_value(&self.last_warnings)?)
+    }
+
+    /// Attach a note to a cell; an empty string removes it
+    #[wasm_bindgen]
+    pub fn set_note(&mut self, row: u32, col: u32, note: &str) -> Result<(), JsValue> {
+        let note = (!note.is_empty()).then(|| note.to_string());
+        let prior = Edit::capture(&self.grid, &self.formula_engine, [CellRef::new(row, col)]);
+        self.grid.set_note(CellRef::new(row, col), note)?;
+        self.history.record(prior);
+        self.render_schedule.note_changed(&[CellRef::new(row, col)]);
+        Ok(())
+    }
+
+    /// Restrict a cell to a list of allowed values; an empty list removes the restriction
+    #[wasm_bindgen]
+    pub fn set_validation(&mut self, row: u32, col: u32, options_js: JsValue) -> Result<(), JsValue> {
+        let options: Vec<String> = serde_wasm_bindgen::from_value(options_js)?;
+        let prior = Edit::capture(&self.grid, &self.formula_engine, [CellRef::new(row, col)]);
+        self.grid.set_validation(CellRef::new(row, col), (!options.is_empty()).then_some(options))?;
+        self.history.record(prior);
+        self.render_schedule.note_changed(&[CellRef::new(row, col)]);
         Ok(())
     }
 
@@ -138,7 +1003,13 @@ impl SheetEngine {
     #[wasm_bindgen]
     pub fn apply_format(&mut self, start_row: u32, start_col: u32, end_row: u32, end_col: u32, format_js: JsValue) -> Result<(), JsValue> {
         let format: cell::CellFormat = serde_wasm_bindgen::from_value(format_js)?;
+        let (end_row, end_col) = (end_row.min(self.grid.rows - 1), end_col.min(self.grid.cols - 1));
+        let range = (start_row..=end_row).flat_map(|row| (start_col..=end_col).map(move |col| CellRef::new(row, col)));
+        let prior = Edit::capture(&self.grid, &self.formula_engine, range);
         self.grid.apply_format_to_range(start_row, start_col, end_row, end_col, format)?;
+        self.history.record(prior);
+        self.render_schedule.note_changed(&[CellRef::new(start_row, start_col), CellRef::new(end_row, end_col)]);
+        self.collect_warnings();
         Ok(())
     }
 
@@ -148,10 +1019,27 @@ impl SheetEngine {
         self.grid.get_col_width(col)
     }
 
-    /// Set column width
+    /// Set column width (clamped to the size limits; NaN/infinite widths are rejected)
+    #[wasm_bindgen]
+    pub fn set_col_width(&mut self, col: u32, width: f32) -> Result<(), JsValue> {
+        self.grid.set_col_width(col, width)?;
+        self.collect_warnings();
+        Ok(())
+    }
+
+    /// Set widths for several column ranges at once: `[{ start, end, size }, ...]`
     #[wasm_bindgen]
-    pub fn set_col_width(&mut self, col: u32, width: f32) {
-        self.grid.set_col_width(col, width);
+    pub fn set_col_widths(&mut self, ranges_js: JsValue) -> Result<(), JsValue> {
+        let ranges: Vec<SizeRange> = serde_wasm_bindgen::from_value(ranges_js)?;
+        self.grid.set_col_widths(&ranges)?;
+        self.collect_warnings();
+        Ok(())
+    }
+
+    /// Put a column back to the default width
+    #[wasm_bindgen]
+    pub fn reset_col_width(&mut self, col: u32) {
+        self.grid.reset_col_width(col);
     }
 
     /// Get row height
@@ -160,10 +1048,103 @@ impl SheetEngine {
         self.grid.get_row_height(row)
     }
 
-    /// Set row height
+    /// Set row height (clamped to the size limits; NaN/infinite heights are rejected)
+    #[wasm_bindgen]
+    pub fn set_row_height(&mut self, row: u32, height: f32) -> Result<(), JsValue> {
+        self.grid.set_row_height(row, height)?;
+        self.collect_warnings();
+        Ok(())
+    }
+
+    /// Put a row back to the default height
+    #[wasm_bindgen]
+    pub fn reset_row_height(&mut self, row: u32) {
+        self.grid.reset_row_height(row);
+    }
+
+    /// Change the allowed range for column widths and row heights
+    #[wasm_bindgen]
+    pub fn set_size_limits(&mut self, min: f32, max: f32) -> Result<(), JsValue> {
+        self.grid.set_size_limits(min, max)?;
+        Ok(())
+    }
+
+    /// Hide or show a row
+    #[wasm_bindgen]
+    pub fn set_row_hidden(&mut self, row: u32, hidden: bool) {
+        self.grid.set_row_hidden(row, hidden);
+    }
+
+    /// Hide or show a column
+    #[wasm_bindgen]
+    pub fn set_col_hidden(&mut self, col: u32, hidden: bool) {
+        self.grid.set_col_hidden(col, hidden);
+    }
+
+    /// Total height of all rows in pixels (for the vertical scrollbar)
+    #[wasm_bindgen]
+    pub fn total_content_height(&self) -> f64 {
+        self.grid.total_content_height()
+    }
+
+    /// Total width of all columns in pixels (for the horizontal scrollbar)
     #[wasm_bindgen]
-    pub fn set_row_height(&mut self, row: u32, height: f32) {
-        self.grid.set_row_height(row, height);
+    pub fn total_content_width(&self) -> f64 {
+        self.grid.total_content_width()
     }
 }
 
+impl SheetEngine {
+    /// Diff for a write, carrying the new dimensions if it grew the grid
+    fn diff_after_write(&mut self, affected: &[CellRef], before: GridDimensions) -> GridDiff {
+        let mut diff = self.diff(affected);
+        let after = self.grid.dimensions();
+        if after != before {
+            diff.dimensions = Some(after);
+        }
+        diff
+    }
+
+    /// Make a freshly loaded grid the sheet, restoring its saved view
+    ///
+    /// A time-sliced recalculation of the old sheet is dropped with it.
+    fn install_grid(&mut self, grid: Grid) {
+        let saved = grid.view_state().copied().unwrap_or_default();
+        self.viewport.restore(&saved);
+        self.grid = grid;
+        self.history.clear();
+        self.recalc_job = None;
+        self.collect_warnings();
+    }
+
+    /// Layout of the current view, sized to the attached canvas if there is one
+    fn layout(&self) -> SheetLayout {
+        let (width, height) = match &self.renderer {
+            Some(renderer) => {
+                let (width, height) = renderer.size();
+                (width as f64, height as f64)
+            }
+            None => (f64::INFINITY, f64::INFINITY),
+        };
+        SheetLayout::new(&self.grid, &self.viewport, width, height)
+    }
+
+    /// The diff for `cells`, carrying the warnings the edit raised
+    fn diff(&mut self, cells: &[CellRef]) -> GridDiff {
+        self.render_schedule.note_changed(cells);
+        let mut diff = GridDiff::from_cells(&self.grid, &self.formula_engine, cells);
+        diff.warnings = self.collect_warnings();
+        diff
+    }
+
+    /// Move the grid's pending warnings into `last_warnings`, returning a copy
+    fn collect_warnings(&mut self) -> Vec<EngineWarning> {
+        self.last_warnings = self.grid.take_warnings();
+        self.last_warnings.clone()
+    }
+}
+
+/// Parse an A1-style reference passed in from JS
+fn parse_a1(reference: &str) -> Result<CellRef, JsValue> {
+    CellRef::parse(reference).ok_or_else(|| JsValue::from_str(&format!("Invalid cell reference: {}", reference)))
+}
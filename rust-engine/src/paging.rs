@@ -0,0 +1,394 @@
+//! Paged transfer of cell ranges to JS
+//!
+//! Serializing a very large range in one call can stall a frame, so reads
+//! from JS are capped at `PageOptions::max_cells`. A page that hits the cap
+//! carries a continuation token for fetching the rest. Cells are always read
+//! in column-major order (top to bottom, then left to right), matching the
+//! grid's columnar storage. The renderer reads ranges directly and isn't paged.
+//!
+//! A page can leave out formulas and formats, which are most of the payload
+//! on formula-heavy sheets and aren't needed to scroll. They're skipped
+//! while the cells are read, not cleared afterwards, so a lean page costs
+//! less to build as well as to send.
+
+use crate::cell::{is_false, CellFormat, CellRef, ValueType};
+use crate::formula::FormulaEngine;
+use crate::grid::{CellData, Grid, GridError};
+use serde::{Deserialize, Serialize};
+
+/// Default cap on the cells returned in one page
+pub const DEFAULT_MAX_CELLS: usize = 10_000;
+
+fn default_max_cells() -> usize { DEFAULT_MAX_CELLS }
+
+fn default_true() -> bool { true }
+
+/// How a range is split into pages
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PageOptions {
+    /// Most entries in one page (at least 1)
+    #[serde(default = "default_max_cells")]
+    pub max_cells: usize,
+    /// Return every position in a positional array instead of populated cells with coordinates
+    #[serde(default)]
+    pub dense: bool,
+    /// Fill in `formula`; scrolling only needs values, and the formula bar
+    /// can fetch its one formula with `get_cell_formula`
+    #[serde(default = "default_true")]
+    pub include_formulas: bool,
+    /// Fill in `format` and `implicit_format`
+    #[serde(default = "default_true")]
+    pub include_formats: bool,
+}
+
+impl Default for PageOptions {
+    fn default() -> Self {
+        Self { max_cells: DEFAULT_MAX_CELLS, dense: false, include_formulas: true, include_formats: true }
+    }
+}
+
+/// One page of a range read
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct RangePage {
+    /// Populated cells (sparse encoding)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub cells: Vec<CellData>,
+    /// Every position in the page (dense encoding)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dense: Option<DenseBlock>,
+    /// Token for the next page, when the range isn't exhausted
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub next: Option<String>,
+}
+
+/// A run of consecutive positions in column-major order
+///
+/// Entry `i` is at column-major index `first + i` of the range starting at
+/// (`start_row`, `start_col`) with `rows` rows; empty positions are `null`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DenseBlock {
+    pub start_row: u32,
+    pub start_col: u32,
+    pub rows: u32,
+    pub first: u64,
+    pub cells: Vec<Option<DenseCell>>,
+}
+
+impl DenseBlock {
+    /// Position of entry `index`
+    pub fn cell_ref(&self, index: usize) -> CellRef {
+        let position = self.first + index as u64;
+        CellRef::new(
+            self.start_row + (position % self.rows as u64) as u32,
+            self.start_col + (position / self.rows as u64) as u32,
+        )
+    }
+
+    /// Decode into the equivalent sparse cells
+    pub fn to_cells(&self) -> Vec<CellData> {
+        self.cells.iter()
+            .enumerate()
+            .filter_map(|(index, cell)| Some(cell.clone()?.with_position(self.cell_ref(index))))
+            .collect()
+    }
+}
+
+/// `CellData` without its coordinates, which are implied by position
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DenseCell {
+    pub value: String,
+    pub value_type: ValueType,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub formula: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub format: Option<CellFormat>,
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub has_validation: bool,
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub is_error: bool,
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub has_note: bool,
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub is_foreign: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub implicit_format: Option<String>,
+}
+
+impl DenseCell {
+    fn with_position(self, cell_ref: CellRef) -> CellData {
+        CellData {
+            row: cell_ref.row,
+            col: cell_ref.col,
+            value: self.value,
+            value_type: self.value_type,
+            formula: self.formula,
+            format: self.format,
+            has_validation: self.has_validation,
+            is_error: self.is_error,
+            has_note: self.has_note,
+            is_foreign: self.is_foreign,
+            implicit_format: self.implicit_format,
+        }
+    }
+}
+
+impl From<CellData> for DenseCell {
+    fn from(data: CellData) -> Self {
+        Self {
+            value: data.value,
+            value_type: data.value_type,
+            formula: data.formula,
+            format: data.format,
+            has_validation: data.has_validation,
+            is_error: data.is_error,
+            has_note: data.has_note,
+            is_foreign: data.is_foreign,
+            implicit_format: data.implicit_format,
+        }
+    }
+}
+
+/// Everything needed to carry on reading a range
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Continuation {
+    start: CellRef,
+    end: CellRef,
+    /// First position not yet returned
+    resume: CellRef,
+    options: PageOptions,
+}
+
+impl Continuation {
+    fn encode(&self) -> String {
+        format!(
+            "{}.{}.{}.{}.{}.{}.{}.{}.{}.{}",
+            self.start.row, self.start.col, self.end.row, self.end.col,
+            self.resume.row, self.resume.col, self.options.max_cells, u8::from(self.options.dense),
+            u8::from(self.options.include_formulas), u8::from(self.options.include_formats),
+        )
+    }
+
+    fn decode(token: &str) -> Option<Self> {
+        let parts: Vec<u64> = token.split('.').map(|part| part.parse().ok()).collect::<Option<_>>()?;
+        let &[start_row, start_col, end_row, end_col, resume_row, resume_col, max_cells, dense, formulas, formats] = parts.as_slice() else {
+            return None;
+        };
+        let cell = |row: u64, col: u64| Some(CellRef::new(row.try_into().ok()?, col.try_into().ok()?));
+        Some(Self {
+            start: cell(start_row, start_col)?,
+            end: cell(end_row, end_col)?,
+            resume: cell(resume_row, resume_col)?,
+            options: PageOptions {
+                max_cells: max_cells.try_into().ok()?,
+                dense: dense != 0,
+                include_formulas: formulas != 0,
+                include_formats: formats != 0,
+            },
+        })
+    }
+}
+
+impl Grid {
+    /// First page of the range between `start` and `end` (inclusive)
+    pub fn get_range_page(&self, start: CellRef, end: CellRef, options: PageOptions, formula_engine: &FormulaEngine) -> RangePage {
+        let end = CellRef::new(end.row.min(self.rows - 1), end.col.min(self.cols - 1));
+        self.read_page(Continuation { start, end, resume: start, options }, formula_engine)
+    }
+
+    /// The page following the one that returned `token`
+    pub fn get_range_page_after(&self, token: &str, formula_engine: &FormulaEngine) -> Result<RangePage, GridError> {
+        let continuation = Continuation::decode(token)
+            .filter(|c| c.end.row < self.rows && c.end.col < self.cols)
+            .ok_or_else(|| GridError::Pagination(format!("invalid page token '{}'", token)))?;
+        Ok(self.read_page(continuation, formula_engine))
+    }
+
+    fn read_page(&self, continuation: Continuation, formula_engine: &FormulaEngine) -> RangePage {
+        let Continuation { start, end, resume, options } = continuation;
+        if start.row > end.row || start.col > end.col {
+            return RangePage::default();
+        }
+        let max_cells = options.max_cells.max(1);
+        let next = |resume: CellRef| Some(Continuation { resume, ..continuation }.encode());
+
+        if options.dense {
+            let rows = end.row - start.row + 1;
+            let total = (end.col - start.col + 1) as u64 * rows as u64;
+            let first = (resume.col - start.col) as u64 * rows as u64 + (resume.row - start.row) as u64;
+            let mut block = DenseBlock { start_row: start.row, start_col: start.col, rows, first, cells: Vec::new() };
+            let count = (total - first).min(max_cells as u64) as usize;
+            block.cells = (0..count)
+                .map(|index| {
+                    let cell_ref = block.cell_ref(index);
+                    self.get_cell(cell_ref).map(|cell| CellData::with_options(cell_ref, cell, formula_engine, &options).into())
+                })
+                .collect();
+            let after = first + count as u64;
+            return RangePage {
+                cells: Vec::new(),
+                next: if after < total { next(block.cell_ref(count)) } else { None },
+                dense: Some(block),
+            };
+        }
+
+        let mut page = RangePage::default();
+        for col in resume.col..=end.col {
+            let first_row = if col == resume.col { resume.row } else { start.row };
+            let mut rows: Vec<u32> = self.column_rows(col)
+                .filter(|row| (first_row..=end.row).contains(row))
+                .collect();
+            rows.sort_unstable();
+            for row in rows {
+                let cell_ref = CellRef::new(row, col);
+                if page.cells.len() == max_cells {
+                    page.next = next(cell_ref);
+                    return page;
+                }
+                if let Some(cell) = self.get_cell(cell_ref) {
+                    page.cells.push(CellData::with_options(cell_ref, cell, formula_engine, &options));
+                }
+            }
+        }
+        page
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cell::CellValue;
+
+    /// Cells in B2:D5 at a scattered set of positions
+    fn fixture() -> Grid {
+        let mut grid = Grid::new(20, 10);
+        for (row, col) in [(1, 1), (4, 1), (2, 2), (3, 2), (4, 2), (1, 3), (3, 3)] {
+            grid.set_value(CellRef::new(row, col), CellValue::Number((row * 10 + col) as f64)).unwrap();
+        }
+        // Outside the range
+        grid.set_value(CellRef::new(0, 2), CellValue::Number(-1.0)).unwrap();
+        grid
+    }
+
+    fn read_all(grid: &Grid, engine: &FormulaEngine, options: PageOptions) -> Vec<RangePage> {
+        let mut pages = vec![grid.get_range_page(CellRef::new(1, 1), CellRef::new(4, 3), options, engine)];
+        while let Some(token) = pages.last().unwrap().next.clone() {
+            pages.push(grid.get_range_page_after(&token, engine).unwrap());
+        }
+        pages
+    }
+
+    fn positions(cells: &[CellData]) -> Vec<(u32, u32)> {
+        cells.iter().map(|cell| (cell.row, cell.col)).collect()
+    }
+
+    #[test]
+    fn test_pages_follow_column_major_order() {
+        let grid = fixture();
+        let engine = FormulaEngine::new();
+        let pages = read_all(&grid, &engine, PageOptions { max_cells: 3, ..Default::default() });
+
+        let per_page: Vec<Vec<(u32, u32)>> = pages.iter().map(|page| positions(&page.cells)).collect();
+        assert_eq!(per_page, vec![
+            vec![(1, 1), (4, 1), (2, 2)],
+            vec![(3, 2), (4, 2), (1, 3)],
+            vec![(3, 3)],
+        ]);
+        assert!(pages.last().unwrap().next.is_none());
+
+        // A range that fits comes back whole, with no token
+        let whole = grid.get_range_page(CellRef::new(1, 1), CellRef::new(4, 3), PageOptions::default(), &engine);
+        assert_eq!(whole.cells.len(), 7);
+        assert!(whole.next.is_none());
+
+        assert!(grid.get_range_page_after("not-a-token", &engine).is_err());
+        assert!(grid.get_range_page_after("0.0.99.99.0.0.3.0", &engine).is_err());
+    }
+
+    /// A `cols` x `rows` block of formula cells at A1, each formatted as currency
+    fn formula_block(rows: u32, cols: u32) -> (Grid, FormulaEngine) {
+        let mut grid = Grid::new(rows + 1, cols);
+        let mut engine = FormulaEngine::new();
+        for col in 0..cols {
+            grid.set_value(CellRef::new(rows, col), CellValue::Number(col as f64)).unwrap();
+            for row in 0..rows {
+                let cell_ref = CellRef::new(row, col);
+                let formula = engine.parse(&format!("=ROUND({}*1.0825+{}, 2)", CellRef::new(rows, col).to_a1(), row)).unwrap();
+                grid.set_cell_formula(cell_ref, formula, &mut engine).unwrap();
+                grid.set_format(cell_ref, CellFormat { number_format: Some("$#,##0.00".to_string()), ..Default::default() }).unwrap();
+            }
+        }
+        engine.recalculate_all(&mut grid).unwrap();
+        (grid, engine)
+    }
+
+    #[test]
+    fn test_pages_can_leave_out_formulas_and_formats() {
+        let (grid, engine) = formula_block(4, 3);
+        let lean = PageOptions { max_cells: 5, include_formulas: false, include_formats: false, ..Default::default() };
+        let pages = read_all(&grid, &engine, lean);
+        let cells: Vec<&CellData> = pages.iter().flat_map(|page| &page.cells).collect();
+        assert_eq!(cells.len(), 8);
+        assert!(cells.iter().all(|cell| cell.formula.is_none() && cell.format.is_none()));
+
+        // Values are the same either way, and the defaults keep everything
+        let full = read_all(&grid, &engine, PageOptions { max_cells: 5, ..Default::default() });
+        let full: Vec<&CellData> = full.iter().flat_map(|page| &page.cells).collect();
+        assert_eq!(cells.iter().map(|cell| &cell.value).collect::<Vec<_>>(), full.iter().map(|cell| &cell.value).collect::<Vec<_>>());
+        assert_eq!(full[0].formula.as_deref(), Some("=ROUND(B5*1.0825+1, 2)"));
+        assert!(full[0].format.is_some());
+        assert_eq!(grid.get_cell_formula(CellRef::new(2, 0), &engine).as_deref(), Some("=ROUND(A5*1.0825+2, 2)"));
+        assert_eq!(grid.get_cell_formula(CellRef::new(4, 1), &engine), None);
+
+        let dense = read_all(&grid, &engine, PageOptions { dense: true, ..lean });
+        assert!(dense.iter().flat_map(|page| page.dense.as_ref().unwrap().to_cells()).all(|cell| cell.formula.is_none()));
+
+        // Options left out of JSON default to everything
+        let options: PageOptions = serde_json::from_str("{\"include_formulas\":false}").unwrap();
+        assert_eq!(options, PageOptions { include_formulas: false, ..Default::default() });
+    }
+
+    /// A 60x40 viewport of formula cells read whole and lean
+    #[test]
+    #[ignore]
+    fn bench_viewport_of_formula_cells() {
+        const READS: u32 = 200;
+        let (grid, engine) = formula_block(60, 40);
+        let measure = |options: PageOptions| {
+            let start = std::time::Instant::now();
+            let mut bytes = 0;
+            for _ in 0..READS {
+                let page = grid.get_range_page(CellRef::new(0, 0), CellRef::new(59, 39), options, &engine);
+                bytes = serde_json::to_string(&page).unwrap().len();
+            }
+            (bytes, start.elapsed() / READS)
+        };
+        let (full_bytes, full_time) = measure(PageOptions::default());
+        let (lean_bytes, lean_time) = measure(PageOptions { include_formulas: false, include_formats: false, ..Default::default() });
+        eprintln!(
+            "2400 formula cells: full {} bytes in {:?}, lean {} bytes in {:?}",
+            full_bytes, full_time, lean_bytes, lean_time,
+        );
+        assert!(lean_bytes < full_bytes);
+    }
+
+    #[test]
+    fn test_dense_pages_round_trip() {
+        let mut grid = fixture();
+        grid.set_note(CellRef::new(3, 3), Some("check".to_string())).unwrap();
+        let engine = FormulaEngine::new();
+        let sparse: Vec<CellData> = read_all(&grid, &engine, PageOptions::default()).remove(0).cells;
+
+        let pages = read_all(&grid, &engine, PageOptions { max_cells: 5, dense: true, ..Default::default() });
+        // 12 positions in pages of 5
+        let lengths: Vec<usize> = pages.iter().map(|page| page.dense.as_ref().unwrap().cells.len()).collect();
+        assert_eq!(lengths, vec![5, 5, 2]);
+
+        let decoded: Vec<CellData> = pages.iter().flat_map(|page| page.dense.as_ref().unwrap().to_cells()).collect();
+        assert_eq!(decoded, sparse);
+
+        // Survives JSON, and empty positions are bare nulls
+        let json = serde_json::to_string(&pages[0]).unwrap();
+        assert!(json.contains("null") && !json.contains("\"row\""));
+        assert_eq!(serde_json::from_str::<RangePage>(&json).unwrap(), pages[0]);
+    }
+}
@@ -0,0 +1,228 @@
+//! Time-sliced full recalculation
+//!
+//! A full pass over a large document can block the thread for seconds. A
+//! `RecalcJob` runs the same pass as `FormulaEngine::recalculate_all` in
+//! steps bounded by a time budget, so the caller can yield (and report
+//! progress) between them. Values are committed as each formula is
+//! evaluated, so dropping a job part-way leaves the cells already done
+//! up to date.
+//!
+//! Edits between steps invalidate the work list: if the grid changed since
+//! the last step, the next step rebuilds the dependency order and starts
+//! over from the first formula. That keeps the final state identical to a
+//! synchronous recalculation of the edited sheet.
+
+use crate::cell::CellRef;
+use crate::formula::{FormulaEngine, FormulaError};
+use crate::grid::{Grid, GridDiff};
+use serde::{Deserialize, Serialize};
+
+/// A full recalculation in progress
+#[derive(Debug, Clone)]
+pub struct RecalcJob {
+    /// Cells in dependency order
+    order: Vec<CellRef>,
+    /// Index into `order` of the next cell to evaluate
+    next: usize,
+    /// Grid revision after the last step, to notice edits made in between
+    revision: u64,
+    /// Times the work list was rebuilt because of an edit
+    restarts: u32,
+}
+
+/// Outcome of one `RecalcJob::step`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RecalcStep {
+    pub done: bool,
+    /// Share of the work list evaluated so far, from 0 to 1
+    pub progress: f64,
+    /// Cells whose value changed during this step
+    pub changed: Vec<CellRef>,
+    /// Whether an edit since the previous step made the job start over
+    pub restarted: bool,
+}
+
+/// A step's outcome as reported to JS, with the changed cells as a diff
+#[derive(Debug, Clone, Serialize)]
+pub struct RecalcProgress {
+    pub done: bool,
+    pub progress: f64,
+    pub restarted: bool,
+    pub partial_diff: GridDiff,
+}
+
+impl RecalcProgress {
+    pub fn new(step: RecalcStep, grid: &Grid, formula_engine: &FormulaEngine) -> Self {
+        Self {
+            done: step.done,
+            progress: step.progress,
+            restarted: step.restarted,
+            partial_diff: GridDiff::from_cells(grid, formula_engine, &step.changed),
+        }
+    }
+}
+
+impl FormulaEngine {
+    /// Prepare a time-sliced recalculation of every formula
+    pub fn begin_recalculation(&mut self, grid: &Grid) -> RecalcJob {
+        RecalcJob {
+            order: self.calculation_order(),
+            next: 0,
+            revision: grid.revision(),
+            restarts: 0,
+        }
+    }
+}
+
+impl RecalcJob {
+    /// Evaluate formulas until `budget_ms` of the grid's clock has passed
+    ///
+    /// At least one formula is evaluated per step, so a job always finishes.
+    pub fn step(&mut self, formula_engine: &mut FormulaEngine, grid: &mut Grid, budget_ms: f64) -> Result<RecalcStep, FormulaError> {
+        let restarted = grid.revision() != self.revision;
+        if restarted {
+            self.order = formula_engine.calculation_order();
+            self.next = 0;
+            self.restarts += 1;
+        }
+
+        let started = grid.now_millis();
+        let mut changed = Vec::new();
+        while self.next < self.order.len() {
+            changed.extend(formula_engine.recalculate_cell(grid, self.order[self.next])?);
+            self.next += 1;
+            if grid.now_millis() - started >= budget_ms {
+                break;
+            }
+        }
+        self.revision = grid.revision();
+
+        Ok(RecalcStep {
+            done: self.is_done(),
+            progress: self.progress(),
+            changed,
+            restarted,
+        })
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.next >= self.order.len()
+    }
+
+    /// Share of the work list evaluated so far, from 0 to 1
+    pub fn progress(&self) -> f64 {
+        if self.order.is_empty() {
+            1.0
+        } else {
+            self.next as f64 / self.order.len() as f64
+        }
+    }
+
+    /// Times an edit made the job start over
+    pub fn restarts(&self) -> u32 {
+        self.restarts
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cell::CellValue;
+    use crate::clock::Clock;
+    use crate::test_support::set_at;
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    /// A clock that moves forward 1ms every time it's read
+    #[derive(Debug, Default)]
+    struct TickingClock {
+        now: Cell<f64>,
+    }
+
+    impl Clock for TickingClock {
+        fn now_millis(&self) -> f64 {
+            self.now.set(self.now.get() + 1.0);
+            self.now.get()
+        }
+    }
+
+    /// A chain of 40 formulas in column B, each building on the one above
+    fn chain() -> (Grid, FormulaEngine) {
+        let mut grid = Grid::with_clock(50, 5, Rc::new(TickingClock::default()));
+        let mut engine = FormulaEngine::new();
+        set_at(&mut grid, &mut engine, 0, 0, "1");
+        set_at(&mut grid, &mut engine, 0, 1, "=A1*2");
+        for row in 1..40 {
+            set_at(&mut grid, &mut engine, row, 1, &format!("=B{}+A1", row));
+        }
+        (grid, engine)
+    }
+
+    /// Change A1 without recalculating, leaving every formula stale
+    fn make_stale(grid: &mut Grid, value: f64) {
+        grid.get_cell_mut(CellRef::new(0, 0)).unwrap().value = CellValue::Number(value);
+    }
+
+    fn column_b(grid: &Grid) -> Vec<CellValue> {
+        (0..40).map(|row| grid.get_cell(CellRef::new(row, 1)).unwrap().value.clone()).collect()
+    }
+
+    #[test]
+    fn test_steps_match_synchronous_recalculation() {
+        let (mut grid, mut engine) = chain();
+        make_stale(&mut grid, 5.0);
+        let mut expected = grid.clone();
+        let mut sync_engine = FormulaEngine::new();
+        sync_engine.load_formulas(&mut expected);
+        sync_engine.recalculate_all(&mut expected).unwrap();
+
+        let mut job = engine.begin_recalculation(&grid);
+        let mut steps = Vec::new();
+        loop {
+            let step = job.step(&mut engine, &mut grid, 10.0).unwrap();
+            assert!(!step.restarted);
+            let done = step.done;
+            steps.push(step);
+            if done {
+                break;
+            }
+        }
+        assert!(steps.len() > 1);
+        assert!(steps.windows(2).all(|pair| pair[0].progress < pair[1].progress));
+        assert_eq!(steps.last().unwrap().progress, 1.0);
+        assert_eq!(steps.iter().map(|step| step.changed.len()).sum::<usize>(), 40);
+        assert_eq!(column_b(&grid), column_b(&expected));
+        assert_eq!(grid.get_cell(CellRef::new(39, 1)).unwrap().value, CellValue::Number(205.0));
+    }
+
+    #[test]
+    fn test_cancelled_job_keeps_computed_cells() {
+        let (mut grid, mut engine) = chain();
+        make_stale(&mut grid, 5.0);
+        let mut job = engine.begin_recalculation(&grid);
+        let step = job.step(&mut engine, &mut grid, 10.0).unwrap();
+        assert!(!step.done && step.progress > 0.0);
+        drop(job);
+
+        // The cells evaluated so far are committed; the rest are still stale
+        let values = column_b(&grid);
+        assert_eq!(values[0], CellValue::Number(10.0));
+        assert_eq!(values[39], CellValue::Number(41.0));
+        assert!(step.changed.contains(&CellRef::new(0, 1)));
+    }
+
+    #[test]
+    fn test_edit_between_steps_restarts() {
+        let (mut grid, mut engine) = chain();
+        make_stale(&mut grid, 5.0);
+        let mut job = engine.begin_recalculation(&grid);
+        job.step(&mut engine, &mut grid, 10.0).unwrap();
+
+        set_at(&mut grid, &mut engine, 0, 0, "3");
+        let step = job.step(&mut engine, &mut grid, 10.0).unwrap();
+        assert!(step.restarted);
+        assert_eq!(job.restarts(), 1);
+        while !job.step(&mut engine, &mut grid, 10.0).unwrap().done {}
+        assert_eq!(grid.get_cell(CellRef::new(39, 1)).unwrap().value, CellValue::Number(123.0));
+    }
+}
@@ -0,0 +1,673 @@
+//! Absolute (`$`) anchors on A1 references, and formula text regenerated from the AST
+//!
+//! Structural edits such as inserting rows move the cells a formula points
+//! at. Rather than patch the typed text, the references are adjusted in the
+//! parsed expression and the formula is written back out, keeping each
+//! reference's anchors and each function name as the user typed it.
+//!
+//! The same machinery retargets formulas in bulk when a block of source
+//! data is relocated: see `FormulaEngine::rewrite_references`.
+
+use crate::cell::CellRef;
+use crate::formula::{BinaryOp, FormulaEngine, FormulaError, FormulaNode, UnaryOp};
+use crate::grid::Grid;
+use serde::{Deserialize, Serialize};
+
+/// Rows or columns, for edits that insert or delete whole lines
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Axis {
+    Rows,
+    Cols,
+}
+
+impl Axis {
+    /// The part of `cell` that counts along this axis
+    pub(crate) fn line(self, cell: &mut CellRef) -> &mut u32 {
+        match self {
+            Axis::Rows => &mut cell.row,
+            Axis::Cols => &mut cell.col,
+        }
+    }
+}
+
+/// Which parts of a reference are absolute
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Anchor {
+    /// `$` before the column letters
+    pub col: bool,
+    /// `$` before the row number
+    pub row: bool,
+}
+
+impl Anchor {
+    pub fn is_relative(&self) -> bool {
+        !self.col && !self.row
+    }
+}
+
+/// Parse a reference that may carry `$` anchors, such as `$A$1`, `A$1` or `$A1`
+pub(crate) fn parse_anchored(s: &str) -> Option<(CellRef, Anchor)> {
+    let s = s.trim();
+    let (col_absolute, rest) = match s.strip_prefix('$') {
+        Some(rest) => (true, rest),
+        None => (false, s),
+    };
+    let letters = rest.chars().take_while(|c| c.is_ascii_alphabetic()).count();
+    let (letters, rest) = rest.split_at(letters);
+    let (row_absolute, digits) = match rest.strip_prefix('$') {
+        Some(digits) => (true, digits),
+        None => (false, rest),
+    };
+    if letters.is_empty() || digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let cell = CellRef::parse(&format!("{}{}", letters, digits))?;
+    Some((cell, Anchor { col: col_absolute, row: row_absolute }))
+}
+
+fn anchored_a1(cell: CellRef, anchor: Anchor) -> String {
+    format!(
+        "{}{}{}{}",
+        if anchor.col { "$" } else { "" },
+        CellRef::col_to_letter(cell.col),
+        if anchor.row { "$" } else { "" },
+        cell.row + 1,
+    )
+}
+
+/// Retarget references into the block `from_start:from_end` to the same-shaped block at `to_start`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReferenceMapping {
+    pub from_start: CellRef,
+    pub from_end: CellRef,
+    pub to_start: CellRef,
+}
+
+impl ReferenceMapping {
+    /// Source corners, top-left first
+    fn source(&self) -> (CellRef, CellRef) {
+        (
+            CellRef::new(self.from_start.row.min(self.from_end.row), self.from_start.col.min(self.from_end.col)),
+            CellRef::new(self.from_start.row.max(self.from_end.row), self.from_start.col.max(self.from_end.col)),
+        )
+    }
+
+    fn contains(&self, cell: CellRef) -> bool {
+        let (start, end) = self.source();
+        (start.row..=end.row).contains(&cell.row) && (start.col..=end.col).contains(&cell.col)
+    }
+
+    fn translate(&self, cell: CellRef) -> CellRef {
+        let (start, _) = self.source();
+        CellRef::new(cell.row - start.row + self.to_start.row, cell.col - start.col + self.to_start.col)
+    }
+
+    /// Destination corners, top-left first
+    fn destination(&self) -> (CellRef, CellRef) {
+        let (start, end) = self.source();
+        (self.translate(start), self.translate(end))
+    }
+}
+
+/// Reject mappings that reach past the grid or whose sources overlap, so every reference has at most one target
+fn check_mappings(mappings: &[ReferenceMapping], grid: &Grid) -> Result<(), FormulaError> {
+    for (i, mapping) in mappings.iter().enumerate() {
+        let (start, end) = mapping.source();
+        let (to_start, to_end) = mapping.destination();
+        if end.row >= grid.rows || end.col >= grid.cols || to_end.row >= grid.rows || to_end.col >= grid.cols {
+            return Err(FormulaError::InvalidMapping(format!(
+                "{}:{} -> {} is outside the grid",
+                start.to_a1(), end.to_a1(), to_start.to_a1(),
+            )));
+        }
+        for other in &mappings[i + 1..] {
+            let (other_start, other_end) = other.source();
+            if start.row <= other_end.row && other_start.row <= end.row && start.col <= other_end.col && other_start.col <= end.col {
+                return Err(FormulaError::InvalidMapping(format!(
+                    "{}:{} overlaps {}:{}",
+                    start.to_a1(), end.to_a1(), other_start.to_a1(), other_end.to_a1(),
+                )));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Binding strength, loosest first
+fn precedence(node: &FormulaNode) -> u8 {
+    match node {
+        FormulaNode::BinaryOp { op, .. } => match op {
+            BinaryOp::Eq | BinaryOp::Ne | BinaryOp::Lt | BinaryOp::Le | BinaryOp::Gt | BinaryOp::Ge => 1,
+            BinaryOp::Concat => 2,
+            BinaryOp::Add | BinaryOp::Sub => 3,
+            BinaryOp::Mul | BinaryOp::Div => 4,
+            BinaryOp::Pow => 5,
+        },
+        FormulaNode::UnaryOp { .. } => 6,
+        _ => 7,
+    }
+}
+
+fn operator(op: BinaryOp) -> &'static str {
+    match op {
+        BinaryOp::Add => "+",
+        BinaryOp::Sub => "-",
+        BinaryOp::Mul => "*",
+        BinaryOp::Div => "/",
+        BinaryOp::Pow => "^",
+        BinaryOp::Eq => "=",
+        BinaryOp::Ne => "<>",
+        BinaryOp::Lt => "<",
+        BinaryOp::Le => "<=",
+        BinaryOp::Gt => ">",
+        BinaryOp::Ge => ">=",
+        BinaryOp::Concat => "&",
+    }
+}
+
+impl FormulaNode {
+    /// The formula as A1 text, starting with `=`
+    pub fn to_formula_string(&self) -> String {
+        format!("={}", self.expression_text())
+    }
+
+    fn expression_text(&self) -> String {
+        match self {
+            FormulaNode::Number { value } => value.to_string(),
+            FormulaNode::Text { value } => format!("\"{}\"", value.replace('"', "\"\"")),
+            FormulaNode::Boolean { value } => if *value { "TRUE" } else { "FALSE" }.to_string(),
+            FormulaNode::CellRef { name: Some(name), .. } | FormulaNode::Range { name: Some(name), .. } => name.clone(),
+            FormulaNode::CellRef { cell, anchor, .. } => anchored_a1(*cell, *anchor),
+            FormulaNode::Range { start, end, start_anchor, end_anchor, .. } => {
+                format!("{}:{}", anchored_a1(*start, *start_anchor), anchored_a1(*end, *end_anchor))
+            }
+            FormulaNode::BinaryOp { op, left, right } => {
+                let own = precedence(self);
+                // An equal-precedence operand needs parentheses on the side the operator doesn't group
+                // from: the right for left-associative operators, the left for `^`
+                let right_associative = matches!(op, BinaryOp::Pow);
+                let left = left.operand_text(precedence(left) < own || (right_associative && precedence(left) == own));
+                let right = right.operand_text(precedence(right) < own || (!right_associative && precedence(right) == own));
+                format!("{}{}{}", left, operator(*op), right)
+            }
+            FormulaNode::UnaryOp { op, operand } => {
+                let inner = operand.operand_text(precedence(operand) < precedence(self));
+                match op {
+                    UnaryOp::Neg => format!("-{}", inner),
+                    UnaryOp::Percent => format!("{}%", inner),
+                }
+            }
+            FormulaNode::Function { name, args, written_name } => {
+                let args: Vec<String> = args.iter().map(FormulaNode::expression_text).collect();
+                format!("{}({})", written_name.as_deref().unwrap_or(name), args.join(","))
+            }
+            FormulaNode::RefError => "#REF!".to_string(),
+        }
+    }
+
+    fn operand_text(&self, parenthesize: bool) -> String {
+        if parenthesize {
+            format!("({})", self.expression_text())
+        } else {
+            self.expression_text()
+        }
+    }
+
+    /// Move references at or after line `start` of `axis` on by `count` lines
+    ///
+    /// Absolute references move too: anchors only pin a reference when it's
+    /// copied, not when the cell it names is pushed elsewhere.
+    pub(crate) fn insert_lines(&mut self, axis: Axis, start: u32, count: u32) {
+        let shift = |cell: &mut CellRef| {
+            let line = axis.line(cell);
+            if *line >= start {
+                *line = line.saturating_add(count);
+            }
+        };
+        match self {
+            FormulaNode::CellRef { cell, .. } => shift(cell),
+            FormulaNode::Range { start: first, end: last, .. } => {
+                shift(first);
+                shift(last);
+            }
+            FormulaNode::BinaryOp { left, right, .. } => {
+                left.insert_lines(axis, start, count);
+                right.insert_lines(axis, start, count);
+            }
+            FormulaNode::UnaryOp { operand, .. } => operand.insert_lines(axis, start, count),
+            FormulaNode::Function { args, .. } => {
+                for arg in args {
+                    arg.insert_lines(axis, start, count);
+                }
+            }
+            FormulaNode::Number { .. } | FormulaNode::Text { .. } | FormulaNode::Boolean { .. } | FormulaNode::RefError => {}
+        }
+    }
+
+    /// Close up references after lines `start..start + count` of `axis` were deleted
+    ///
+    /// References past the gap move back, and a range loses just its deleted
+    /// lines. A reference that pointed only at deleted lines becomes
+    /// `#REF!`, leaving the rest of the expression as it was. Returns
+    /// whether any reference did.
+    pub(crate) fn delete_lines(&mut self, axis: Axis, start: u32, count: u32) -> bool {
+        let end = start.saturating_add(count);
+        let shift = |line: u32| if line >= end { line - count } else { line };
+        let kept = match self {
+            FormulaNode::CellRef { cell, .. } => {
+                let line = axis.line(cell);
+                let kept = !(start..end).contains(line);
+                if kept {
+                    *line = shift(*line);
+                }
+                kept
+            }
+            FormulaNode::Range { start: first, end: last, .. } => {
+                let (first, last) = (axis.line(first), axis.line(last));
+                let (low, high) = if *first <= *last { (first, last) } else { (last, first) };
+                // The first and last lines that survive, if any do
+                let new_low = if (start..end).contains(low) { end } else { *low };
+                let new_high = match (start..end).contains(high) {
+                    true => start.checked_sub(1),
+                    false => Some(*high),
+                };
+                match new_high {
+                    Some(new_high) if new_low <= new_high => {
+                        *low = shift(new_low);
+                        *high = shift(new_high);
+                        true
+                    }
+                    _ => false,
+                }
+            }
+            FormulaNode::BinaryOp { left, right, .. } => {
+                return left.delete_lines(axis, start, count) | right.delete_lines(axis, start, count);
+            }
+            FormulaNode::UnaryOp { operand, .. } => return operand.delete_lines(axis, start, count),
+            FormulaNode::Function { args, .. } => {
+                return args.iter_mut().fold(false, |cut, arg| arg.delete_lines(axis, start, count) | cut);
+            }
+            FormulaNode::Number { .. } | FormulaNode::Text { .. } | FormulaNode::Boolean { .. } | FormulaNode::RefError => true,
+        };
+        if !kept {
+            *self = FormulaNode::RefError;
+        }
+        !kept
+    }
+
+    /// Whether the expression uses the named range `key`, given upper-cased
+    pub(crate) fn uses_name(&self, key: &str) -> bool {
+        match self {
+            FormulaNode::CellRef { name, .. } | FormulaNode::Range { name, .. } => {
+                name.as_ref().is_some_and(|name| name.to_uppercase() == key)
+            }
+            FormulaNode::BinaryOp { left, right, .. } => left.uses_name(key) || right.uses_name(key),
+            FormulaNode::UnaryOp { operand, .. } => operand.uses_name(key),
+            FormulaNode::Function { args, .. } => args.iter().any(|arg| arg.uses_name(key)),
+            FormulaNode::Number { .. } | FormulaNode::Text { .. } | FormulaNode::Boolean { .. } | FormulaNode::RefError => false,
+        }
+    }
+
+    /// Move relative references by `rows` and `cols`, as copying the formula that far does
+    ///
+    /// Anchored (`$`) parts stay put. Returns false if a reference would
+    /// leave a grid of `limit` rows by columns; the node is then partly moved
+    /// and should be discarded.
+    pub(crate) fn offset(&mut self, rows: i64, cols: i64, limit: CellRef) -> bool {
+        let offset = |cell: &mut CellRef, anchor: Anchor| {
+            let row = if anchor.row { i64::from(cell.row) } else { i64::from(cell.row) + rows };
+            let col = if anchor.col { i64::from(cell.col) } else { i64::from(cell.col) + cols };
+            if !(0..i64::from(limit.row)).contains(&row) || !(0..i64::from(limit.col)).contains(&col) {
+                return false;
+            }
+            *cell = CellRef::new(row as u32, col as u32);
+            true
+        };
+        match self {
+            FormulaNode::CellRef { cell, anchor, .. } => offset(cell, *anchor),
+            FormulaNode::Range { start, end, start_anchor, end_anchor, .. } => offset(start, *start_anchor) && offset(end, *end_anchor),
+            FormulaNode::BinaryOp { left, right, .. } => left.offset(rows, cols, limit) && right.offset(rows, cols, limit),
+            FormulaNode::UnaryOp { operand, .. } => operand.offset(rows, cols, limit),
+            FormulaNode::Function { args, .. } => args.iter_mut().all(|arg| arg.offset(rows, cols, limit)),
+            FormulaNode::Number { .. } | FormulaNode::Text { .. } | FormulaNode::Boolean { .. } | FormulaNode::RefError => true,
+        }
+    }
+
+    /// Apply `mappings` to every reference, returning whether any changed
+    ///
+    /// A range moves only when it lies wholly inside one mapping's source;
+    /// one that's merely partly covered keeps its corners, since moving just
+    /// one of them would silently change what the range covers. A named
+    /// range is left alone: it's written as its name, which stays put.
+    pub(crate) fn remap(&mut self, mappings: &[ReferenceMapping]) -> bool {
+        match self {
+            FormulaNode::CellRef { name: Some(_), .. } | FormulaNode::Range { name: Some(_), .. } => false,
+            FormulaNode::CellRef { cell, .. } => match mappings.iter().find(|mapping| mapping.contains(*cell)) {
+                Some(mapping) => {
+                    *cell = mapping.translate(*cell);
+                    true
+                }
+                None => false,
+            },
+            FormulaNode::Range { start, end, .. } => {
+                match mappings.iter().find(|mapping| mapping.contains(*start) && mapping.contains(*end)) {
+                    Some(mapping) => {
+                        *start = mapping.translate(*start);
+                        *end = mapping.translate(*end);
+                        true
+                    }
+                    None => false,
+                }
+            }
+            FormulaNode::BinaryOp { left, right, .. } => left.remap(mappings) | right.remap(mappings),
+            FormulaNode::UnaryOp { operand, .. } => operand.remap(mappings),
+            FormulaNode::Function { args, .. } => args.iter_mut().fold(false, |changed, arg| arg.remap(mappings) | changed),
+            FormulaNode::Number { .. } | FormulaNode::Text { .. } | FormulaNode::Boolean { .. } | FormulaNode::RefError => false,
+        }
+    }
+}
+
+impl FormulaEngine {
+    /// Retarget references in every registered formula, then recalculate once
+    ///
+    /// References are rewritten in the parsed expression, so text that merely
+    /// looks like a reference (inside a string literal, say) is untouched.
+    /// Rewritten formulas get new text in their cells and new dependencies.
+    /// All mappings are checked before anything changes. Returns the cells
+    /// rewritten or recalculated.
+    pub fn rewrite_references(&mut self, grid: &mut Grid, mappings: &[ReferenceMapping]) -> Result<Vec<CellRef>, FormulaError> {
+        check_mappings(mappings, grid)?;
+
+        let mut cells: Vec<CellRef> = self.formulas.keys().copied().collect();
+        cells.sort_by_key(|cell| (cell.row, cell.col));
+        let mut rewritten = Vec::new();
+        for cell in cells {
+            let Some(formula) = self.formula_at(cell) else {
+                continue;
+            };
+            let mut ast = formula.ast.clone();
+            if !ast.remap(mappings) {
+                continue;
+            }
+            let formula = self.parse(&ast.to_formula_string())?;
+            if let Some(stored) = grid.get_cell_mut(cell) {
+                stored.formula = Some(formula.raw.clone());
+            }
+            self.register_formula(cell, formula);
+            rewritten.push(cell);
+        }
+        if rewritten.is_empty() {
+            return Ok(rewritten);
+        }
+        grid.touch();
+        self.recalculate_from(grid, &rewritten)
+    }
+}
+
+impl Grid {
+    /// Move named ranges after lines of `axis` were inserted at `start`
+    pub(crate) fn insert_name_lines(&mut self, axis: Axis, start: u32, count: u32) {
+        self.move_names(|range| {
+            range.insert_lines(axis, start, count);
+            true
+        });
+    }
+
+    /// Close up named ranges after lines `start..start + count` of `axis` were deleted
+    ///
+    /// A name whose range was deleted entirely is forgotten, as the formulas
+    /// that used it now read `#REF!` in its place.
+    pub(crate) fn delete_name_lines(&mut self, axis: Axis, start: u32, count: u32) {
+        self.move_names(|range| !range.delete_lines(axis, start, count));
+    }
+
+    /// Apply `change` to each named range as a range reference, forgetting names it rejects
+    fn move_names(&mut self, mut change: impl FnMut(&mut FormulaNode) -> bool) {
+        self.names.retain(|_, (first, last)| {
+            let anchor = Anchor::default();
+            let mut range = FormulaNode::Range { start: *first, end: *last, start_anchor: anchor, end_anchor: anchor, name: None };
+            let kept = change(&mut range);
+            if let FormulaNode::Range { start, end, .. } = range {
+                (*first, *last) = (start, end);
+            }
+            kept
+        });
+    }
+
+    /// Rewrite every stored formula after lines of `axis` were inserted at `start`
+    ///
+    /// Formulas that fail to parse are left alone. The engine isn't touched;
+    /// callers reload it from the rewritten text.
+    pub(crate) fn insert_formula_lines(&mut self, axis: Axis, start: u32, count: u32, formula_engine: &FormulaEngine) {
+        for (cell_ref, text) in self.stored_formulas() {
+            let Ok(mut formula) = formula_engine.parse(&text) else {
+                continue;
+            };
+            formula.ast.insert_lines(axis, start, count);
+            if let Some(cell) = self.get_cell_mut(cell_ref) {
+                cell.formula = Some(formula.ast.to_formula_string());
+            }
+        }
+    }
+
+    /// Rewrite every stored formula after lines `start..start + count` of `axis` were deleted
+    ///
+    /// The cells must already have moved back. A reference left pointing
+    /// only at deleted lines becomes `#REF!` in the formula text, and the
+    /// rest of the formula is kept; the cells where that happened are
+    /// returned. As with `insert_formula_lines`, callers reload the engine
+    /// afterwards.
+    pub(crate) fn delete_formula_lines(&mut self, axis: Axis, start: u32, count: u32, formula_engine: &FormulaEngine) -> Vec<CellRef> {
+        let mut cut = Vec::new();
+        for (cell_ref, text) in self.stored_formulas() {
+            let Ok(mut formula) = formula_engine.parse(&text) else {
+                continue;
+            };
+            if formula.ast.delete_lines(axis, start, count) {
+                cut.push(cell_ref);
+            }
+            if let Some(cell) = self.get_cell_mut(cell_ref) {
+                cell.formula = Some(formula.ast.to_formula_string());
+            }
+        }
+        cut
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cell::CellValue;
+    use crate::test_support::set;
+
+    fn round_trip(text: &str) -> String {
+        FormulaEngine::new().parse(text).unwrap().ast.to_formula_string()
+    }
+
+    #[test]
+    fn test_parse_anchored() {
+        let a1 = CellRef::new(0, 0);
+        assert_eq!(parse_anchored("$A$1"), Some((a1, Anchor { col: true, row: true })));
+        assert_eq!(parse_anchored("A$1"), Some((a1, Anchor { col: false, row: true })));
+        assert_eq!(parse_anchored("$a1"), Some((a1, Anchor { col: true, row: false })));
+        assert_eq!(parse_anchored("B2"), Some((CellRef::new(1, 1), Anchor::default())));
+        assert_eq!(parse_anchored("$$A1"), None);
+        assert_eq!(parse_anchored("A$"), None);
+        assert_eq!(parse_anchored("$A$1+B2"), None);
+    }
+
+    #[test]
+    fn test_anchor_combinations_round_trip_and_copy() {
+        let limit = CellRef::new(100, 100);
+        for (text, copied) in [("$A$1", "$A$1"), ("A$1", "B$1"), ("$A1", "$A3"), ("A1", "B3")] {
+            let (cell, anchor) = parse_anchored(text).unwrap();
+            assert_eq!(anchored_a1(cell, anchor), text);
+            // Copied two rows down and one column right, only relative parts move
+            let mut node = FormulaNode::CellRef { cell, anchor, name: None };
+            assert!(node.offset(2, 1, limit));
+            assert_eq!(node.to_formula_string(), format!("={}", copied));
+        }
+    }
+
+    #[test]
+    fn test_formula_text_round_trips() {
+        assert_eq!(round_trip("=$A$1+B2"), "=$A$1+B2");
+        assert_eq!(round_trip("=sum($B$1:B10)*2"), "=sum($B$1:B10)*2");
+        assert_eq!(round_trip("=A1-B1-C1"), "=A1-B1-C1");
+        assert_eq!(round_trip("=IF(A1>=10,\"big\",FALSE)"), "=IF(A1>=10,\"big\",FALSE)");
+        assert_eq!(round_trip("=A1 * 0.5"), "=A1*0.5");
+        assert_eq!(round_trip("=IFERROR(#ref!,0)+A1"), "=IFERROR(#REF!,0)+A1");
+    }
+
+    #[test]
+    fn test_parentheses_follow_precedence() {
+        let cell = |row| Box::new(FormulaNode::CellRef { cell: CellRef::new(row, 0), anchor: Anchor::default(), name: None });
+        let sum = FormulaNode::BinaryOp { op: BinaryOp::Sub, left: cell(0), right: cell(1) };
+        let node = FormulaNode::BinaryOp { op: BinaryOp::Sub, left: cell(2), right: Box::new(sum.clone()) };
+        assert_eq!(node.to_formula_string(), "=A3-(A1-A2)");
+        let node = FormulaNode::BinaryOp { op: BinaryOp::Mul, left: Box::new(sum.clone()), right: cell(2) };
+        assert_eq!(node.to_formula_string(), "=(A1-A2)*A3");
+        let node = FormulaNode::UnaryOp { op: UnaryOp::Neg, operand: Box::new(sum) };
+        assert_eq!(node.to_formula_string(), "=-(A1-A2)");
+    }
+
+    fn cell(a1: &str) -> CellRef {
+        CellRef::parse(a1).unwrap()
+    }
+
+    fn value(grid: &Grid, a1: &str) -> CellValue {
+        grid.get_cell(cell(a1)).unwrap().value.clone()
+    }
+
+    fn formula(grid: &Grid, a1: &str) -> String {
+        grid.get_cell(cell(a1)).unwrap().formula.clone().unwrap()
+    }
+
+    /// Old data in A1:A4, its relocated copy in D1:D4, and formulas over the old block
+    fn relocated() -> (Grid, FormulaEngine) {
+        let mut grid = Grid::new(20, 10);
+        let mut engine = FormulaEngine::new();
+        for row in 1..=4 {
+            set(&mut grid, &mut engine, &format!("A{}", row), &row.to_string());
+            set(&mut grid, &mut engine, &format!("D{}", row), &(row * 10).to_string());
+        }
+        set(&mut grid, &mut engine, "F1", "=SUM(A1:A4)");
+        set(&mut grid, &mut engine, "F2", "=SUM($A$1:A6)");
+        set(&mut grid, &mut engine, "F3", "=A2*2");
+        set(&mut grid, &mut engine, "F4", "=IF(A1>0,\"A1\",A1)");
+        set(&mut grid, &mut engine, "F5", "=F1+F3");
+        (grid, engine)
+    }
+
+    fn to_d() -> ReferenceMapping {
+        ReferenceMapping { from_start: cell("A1"), from_end: cell("A4"), to_start: cell("D1") }
+    }
+
+    #[test]
+    fn test_rewrite_references() {
+        let (mut grid, mut engine) = relocated();
+        engine.rewrite_references(&mut grid, &[to_d()]).unwrap();
+
+        assert_eq!(formula(&grid, "F1"), "=SUM(D1:D4)");
+        assert_eq!(formula(&grid, "F3"), "=D2*2");
+        assert_eq!(value(&grid, "F1"), CellValue::Number(100.0));
+        assert_eq!(value(&grid, "F3"), CellValue::Number(40.0));
+        // A range only partly inside the source keeps its corners
+        assert_eq!(formula(&grid, "F2"), "=SUM($A$1:A6)");
+        assert_eq!(value(&grid, "F2"), CellValue::Number(10.0));
+        // Text that looks like a reference isn't one
+        assert_eq!(formula(&grid, "F4"), "=IF(D1>0,\"A1\",D1)");
+        assert_eq!(value(&grid, "F4"), CellValue::Text("A1".to_string()));
+
+        // Dependencies follow the new text
+        assert!(engine.depends_on(cell("F3"), cell("D2")));
+        assert!(!engine.depends_on(cell("F3"), cell("A2")));
+        set(&mut grid, &mut engine, "A2", "100");
+        assert_eq!(value(&grid, "F3"), CellValue::Number(40.0));
+    }
+
+    #[test]
+    fn test_rewrite_recalculates_once() {
+        let (mut grid, mut engine) = relocated();
+        let affected = engine.rewrite_references(&mut grid, &[to_d()]).unwrap();
+
+        // F5 depends on two rewritten formulas but is evaluated once, after both
+        let mut sorted = affected.clone();
+        sorted.sort_by_key(|cell| (cell.row, cell.col));
+        assert_eq!(sorted, vec![cell("F1"), cell("F3"), cell("F4"), cell("F5")]);
+        assert!(affected.iter().position(|c| *c == cell("F5")) > affected.iter().position(|c| *c == cell("F3")));
+        assert_eq!(value(&grid, "F5"), CellValue::Number(140.0));
+
+        // Nothing left to retarget
+        assert!(engine.rewrite_references(&mut grid, &[to_d()]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_conflicting_mappings_are_rejected() {
+        let (mut grid, mut engine) = relocated();
+        let overlapping = ReferenceMapping { from_start: cell("B5"), from_end: cell("A3"), to_start: cell("H1") };
+        assert!(matches!(
+            engine.rewrite_references(&mut grid, &[to_d(), overlapping]),
+            Err(FormulaError::InvalidMapping(_))
+        ));
+        let off_grid = ReferenceMapping { from_start: cell("A1"), from_end: cell("A4"), to_start: cell("A18") };
+        assert!(engine.rewrite_references(&mut grid, &[off_grid]).is_err());
+
+        // Nothing was rewritten
+        assert_eq!(formula(&grid, "F1"), "=SUM(A1:A4)");
+        assert_eq!(value(&grid, "F1"), CellValue::Number(10.0));
+    }
+    #[test]
+    fn test_names_follow_inserted_and_deleted_lines() {
+        let mut grid = Grid::new(20, 10);
+        let mut engine = FormulaEngine::new();
+        set(&mut grid, &mut engine, "B3", "1200");
+        set(&mut grid, &mut engine, "B4", "300");
+        set(&mut grid, &mut engine, "B5", "50");
+        engine.define_name(&mut grid, "Revenue", cell("B3"), cell("B3")).unwrap();
+        engine.define_name(&mut grid, "Costs", cell("B4"), cell("B5")).unwrap();
+        set(&mut grid, &mut engine, "C1", "=Revenue*2");
+
+        grid.insert_rows(0, 2, &mut engine).unwrap();
+        assert_eq!(engine.named_range("Revenue"), Some((cell("B5"), cell("B5"))));
+        assert_eq!(engine.named_range("Costs"), Some((cell("B6"), cell("B7"))));
+        assert_eq!(value(&grid, "C3"), CellValue::Number(2400.0));
+        // The formula still reads by name
+        assert_eq!(formula(&grid, "C3"), "=Revenue*2");
+        // Names still resolve in formulas entered after the edit
+        set(&mut grid, &mut engine, "D1", "=SUM(Costs)");
+        assert_eq!(value(&grid, "D1"), CellValue::Number(350.0));
+
+        // Deleting all of a name's cells forgets it; a partial delete shrinks it
+        grid.delete_rows(4, 2, &mut engine).unwrap();
+        assert_eq!(engine.named_range("Revenue"), None);
+        assert_eq!(engine.named_range("Costs"), Some((cell("B5"), cell("B5"))));
+        assert_eq!(formula(&grid, "C3"), "=#REF!*2");
+        assert_eq!(value(&grid, "C3"), CellValue::Error("REF".to_string()));
+        assert_eq!(formula(&grid, "D1"), "=SUM(Costs)");
+        assert_eq!(value(&grid, "D1"), CellValue::Number(50.0));
+    }
+
+    #[test]
+    fn test_names_are_saved_with_the_grid() {
+        let mut grid = Grid::new(20, 10);
+        let mut engine = FormulaEngine::new();
+        set(&mut grid, &mut engine, "B3", "1200");
+        engine.define_name(&mut grid, "Revenue", cell("B3"), cell("B3")).unwrap();
+        set(&mut grid, &mut engine, "C1", "=Revenue*2");
+
+        let mut reloaded = Grid::from_json(&grid.to_json().unwrap()).unwrap();
+        let mut fresh = FormulaEngine::new();
+        fresh.load_formulas(&mut reloaded);
+        assert!(!reloaded.is_foreign(cell("C1")));
+        assert_eq!(fresh.named_range("revenue"), Some((cell("B3"), cell("B3"))));
+        set(&mut reloaded, &mut fresh, "B3", "10");
+        assert_eq!(value(&reloaded, "C1"), CellValue::Number(20.0));
+
+        // Loading another document drops the names of this one
+        let mut other = Grid::new(5, 5);
+        fresh.load_formulas(&mut other);
+        assert_eq!(fresh.named_range("Revenue"), None);
+        assert!(fresh.parse("=Revenue*2").is_err());
+    }
+}
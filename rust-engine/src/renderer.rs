@@ -1,9 +1,127 @@
 //! Canvas rendering engine using WebGL
 
-use crate::grid::Grid;
+use crate::cell::{CellRef, HorizontalAlign, ValueType};
+use crate::formula::FormulaEngine;
+use crate::geometry::{CellRect, SheetLayout};
+use crate::grid::{CellData, Grid};
 use crate::viewport::Viewport;
+use std::hash::{DefaultHasher, Hash, Hasher};
 use wasm_bindgen::prelude::*;
-use web_sys::{HtmlCanvasElement, WebGl2RenderingContext};
+use serde::Serialize;
+use web_sys::{HtmlCanvasElement, WebGl2RenderingContext, WebglLoseContext};
+
+/// Leg length of the note triangle at 100% zoom
+const NOTE_TRIANGLE_SIZE: f64 = 6.0;
+/// Width of the validation dropdown arrow at 100% zoom
+const DROPDOWN_ARROW_WIDTH: f64 = 8.0;
+/// Radius of the error badge at 100% zoom
+const ERROR_BADGE_RADIUS: f64 = 3.0;
+
+/// Note marker: a right triangle filling the cell's top-right corner
+///
+/// Scales with zoom but never takes more than half the cell in either direction.
+pub fn note_triangle(rect: CellRect, zoom: f64) -> [(f64, f64); 3] {
+    let size = (NOTE_TRIANGLE_SIZE * zoom).min(rect.width / 2.0).min(rect.height / 2.0);
+    let right = rect.x + rect.width;
+    [(right - size, rect.y), (right, rect.y), (right, rect.y + size)]
+}
+
+/// Validation marker: a downward arrow centered vertically at the cell's right edge
+pub fn dropdown_arrow(rect: CellRect, zoom: f64) -> [(f64, f64); 3] {
+    let width = (DROPDOWN_ARROW_WIDTH * zoom).min(rect.width / 2.0).min(rect.height);
+    let padding = 4.0 * zoom;
+    let right = rect.x + rect.width - padding;
+    let middle = rect.y + rect.height / 2.0;
+    [
+        (right - width, middle - width / 4.0),
+        (right, middle - width / 4.0),
+        (right - width / 2.0, middle + width / 4.0),
+    ]
+}
+
+/// Error marker: center and radius of a dot in the cell's top-left corner
+pub fn error_badge(rect: CellRect, zoom: f64) -> (f64, f64, f64) {
+    let radius = (ERROR_BADGE_RADIUS * zoom).min(rect.width / 4.0).min(rect.height / 4.0);
+    let inset = radius + zoom;
+    (rect.x + inset, rect.y + inset, radius)
+}
+
+/// How a cell's text is aligned: its own `align_h` if set, otherwise by value type
+///
+/// As in Excel, numbers and dates sit on the right, booleans and errors in
+/// the middle and text on the left.
+pub fn text_alignment(cell: &CellData) -> HorizontalAlign {
+    match cell.format.as_ref().and_then(|format| format.align_h) {
+        Some(align) => align,
+        None => match cell.value_type {
+            ValueType::Number | ValueType::Date => HorizontalAlign::Right,
+            ValueType::Boolean | ValueType::Error => HorizontalAlign::Center,
+            ValueType::Text | ValueType::Empty => HorizontalAlign::Left,
+        },
+    }
+}
+
+/// Where to draw text in a cell: the x coordinate and the matching canvas `textAlign`
+pub fn text_anchor(rect: CellRect, align: HorizontalAlign, padding: f64) -> (f64, &'static str) {
+    match align {
+        HorizontalAlign::Left => (rect.x + padding, "left"),
+        HorizontalAlign::Center => (rect.x + rect.width / 2.0, "center"),
+        HorizontalAlign::Right => (rect.x + rect.width - padding, "right"),
+    }
+}
+
+/// Hash of everything the gridlines and headers are drawn from
+///
+/// Covers the canvas size, the scroll position, zoom and frozen panes, and
+/// the sizes of the visible rows and columns, but not cell contents. Colors and fonts
+/// aren't inputs; changing them needs `CanvasRenderer::invalidate_layout_cache`.
+pub fn layout_hash(grid: &Grid, viewport: &Viewport, width: u32, height: u32) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    (width, height).hash(&mut hasher);
+    (viewport.start_row, viewport.start_col, viewport.visible_rows, viewport.visible_cols).hash(&mut hasher);
+    (viewport.frozen_rows, viewport.frozen_cols).hash(&mut hasher);
+    for value in [viewport.offset_x, viewport.offset_y, viewport.zoom] {
+        value.to_bits().hash(&mut hasher);
+    }
+    for col in (0..viewport.frozen_cols).chain(viewport.start_col..viewport.end_col()) {
+        grid.get_col_width(col).to_bits().hash(&mut hasher);
+    }
+    for row in (0..viewport.frozen_rows).chain(viewport.start_row..viewport.end_row()) {
+        grid.get_row_height(row).to_bits().hash(&mut hasher);
+    }
+    hasher.finish()
+}
+
+/// Each cell in view with where it's drawn and the part of that left uncovered
+///
+/// `CanvasRenderer` draws exactly these positions; `SheetLayout::cell_rect`
+/// reports the same ones to overlays.
+pub fn cell_placements<'a>(layout: &SheetLayout, cells: &'a [CellData]) -> Vec<(&'a CellData, CellRect, CellRect)> {
+    cells
+        .iter()
+        .filter_map(|cell| layout.placement(cell.row, cell.col).map(|(rect, visible)| (cell, rect, visible)))
+        .collect()
+}
+
+/// The cells in view, frozen panes included
+fn viewport_cells(grid: &Grid, formula_engine: &FormulaEngine, layout: &SheetLayout) -> Vec<CellData> {
+    layout
+        .blocks()
+        .into_iter()
+        .flat_map(|(start, end)| grid.get_range(start.row, start.col, end.row, end.col, formula_engine))
+        .collect()
+}
+
+/// What a call to `render` did
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RenderStatus {
+    Drawn,
+    /// No canvas is attached
+    NoCanvas,
+    /// The attached canvas was removed from the document, so nothing was drawn
+    CanvasDetached,
+}
 
 /// Canvas renderer using WebGL for high-performance rendering
 pub struct CanvasRenderer {
@@ -11,6 +129,12 @@ pub struct CanvasRenderer {
     gl: WebGl2RenderingContext,
     width: u32,
     height: u32,
+    /// Offscreen gridlines, drawn under the cells
+    grid_layer: HtmlCanvasElement,
+    /// Offscreen header band, drawn over the cells
+    header_layer: HtmlCanvasElement,
+    /// `layout_hash` the layers were drawn for; `None` forces a redraw
+    layer_hash: std::cell::Cell<Option<u64>>,
 }
 
 impl CanvasRenderer {
@@ -35,84 +159,182 @@ impl CanvasRenderer {
         gl.viewport(0, 0, width as i32, height as i32);
         gl.clear_color(1.0, 1.0, 1.0, 1.0);
         
+        let offscreen = || -> Result<HtmlCanvasElement, JsValue> {
+            let layer = document.create_element("canvas")?.dyn_into::<HtmlCanvasElement>()?;
+            layer.set_width(width);
+            layer.set_height(height);
+            Ok(layer)
+        };
+        
         Ok(Self {
+            grid_layer: offscreen()?,
+            header_layer: offscreen()?,
             canvas,
             gl,
             width,
             height,
+            layer_hash: std::cell::Cell::new(None),
         })
     }
 
+    /// Whether this renderer draws to the canvas with id `canvas_id`
+    pub fn draws_to(&self, canvas_id: &str) -> bool {
+        self.canvas.id() == canvas_id && self.is_connected()
+    }
+
+    /// Whether the canvas is still in the document
+    pub fn is_connected(&self) -> bool {
+        self.canvas.is_connected()
+    }
+
+    /// Give up the canvas's WebGL context, so the browser can reclaim it
+    ///
+    /// Browsers cap the live WebGL contexts per page and only free one when
+    /// its canvas is garbage collected, so a remounted sheet would otherwise
+    /// hold on to every context it ever made. The context is lost where the
+    /// browser has `WEBGL_lose_context`, and left to the collector elsewhere.
+    pub fn release(self) {
+        if let Ok(Some(extension)) = self.gl.get_extension("WEBGL_lose_context") {
+            extension.unchecked_into::<WebglLoseContext>().lose_context();
+        }
+    }
+
+    /// Canvas size in pixels
+    pub fn size(&self) -> (u32, u32) {
+        (self.width, self.height)
+    }
+
+    /// Redraw the gridlines and headers on the next render
+    ///
+    /// For changes `layout_hash` doesn't see, such as a new theme.
+    pub fn invalidate_layout_cache(&self) {
+        self.layer_hash.set(None);
+    }
+
     /// Render the current viewport to the canvas
-    pub fn render(&self, grid: &Grid, viewport: &Viewport) -> Result<(), JsValue> {
+    pub fn render(&self, grid: &Grid, formula_engine: &FormulaEngine, viewport: &Viewport) -> Result<(), JsValue> {
         // Clear the canvas
         self.gl.clear(WebGl2RenderingContext::COLOR_BUFFER_BIT);
         
-        // Get cells in viewport
-        let cells = grid.get_range(
-            viewport.start_row,
-            viewport.start_col,
-            viewport.end_row(),
-            viewport.end_col(),
-        );
+        let layout = SheetLayout::new(grid, viewport, self.width as f64, self.height as f64);
+        let cells = viewport_cells(grid, formula_engine, &layout);
         
         // For now, we'll use 2D canvas for text rendering
         // In a full implementation, this would use WebGL shaders for grid lines
         // and a texture atlas for text
-        let ctx_2d = self.canvas
+        let ctx_2d = self.context_2d()?;
+        self.draw_area(&ctx_2d, grid, viewport, &layout, &cells, CellRect {
+            x: 0.0,
+            y: 0.0,
+            width: self.width as f64,
+            height: self.height as f64,
+        })
+    }
+
+    /// Redraw only the cells between `start` and `end`, leaving the rest of the canvas as it is
+    ///
+    /// For edits that don't move the layout; the canvas must already show
+    /// this viewport.
+    pub fn render_region(
+        &self,
+        grid: &Grid,
+        formula_engine: &FormulaEngine,
+        viewport: &Viewport,
+        start: CellRef,
+        end: CellRef,
+    ) -> Result<(), JsValue> {
+        let layout = SheetLayout::new(grid, viewport, self.width as f64, self.height as f64);
+        let Some(area) = layout.range_rect(start, end) else {
+            return Ok(());
+        };
+        // Neighbours under the same rectangle, such as a frozen pane over it, are drawn again too
+        let overlaps = |rect: CellRect| {
+            rect.x < area.x + area.width && area.x < rect.x + rect.width && rect.y < area.y + area.height && area.y < rect.y + rect.height
+        };
+        let mut cells = viewport_cells(grid, formula_engine, &layout);
+        cells.retain(|cell| layout.placement(cell.row, cell.col).is_some_and(|(_, visible)| overlaps(visible)));
+        
+        let ctx_2d = self.context_2d()?;
+        ctx_2d.save();
+        ctx_2d.begin_path();
+        ctx_2d.rect(area.x, area.y, area.width, area.height);
+        ctx_2d.clip();
+        let area = CellRect { x: area.x, y: area.y, width: area.width, height: area.height };
+        let drawn = self.draw_area(&ctx_2d, grid, viewport, &layout, &cells, area);
+        ctx_2d.restore();
+        drawn
+    }
+
+    fn context_2d(&self) -> Result<web_sys::CanvasRenderingContext2d, JsValue> {
+        Ok(self.canvas
             .get_context("2d")?
             .ok_or("2D context not available")?
-            .dyn_into::<web_sys::CanvasRenderingContext2d>()?;
-        
+            .dyn_into::<web_sys::CanvasRenderingContext2d>()?)
+    }
+
+    /// Clear a rectangle of the canvas and draw the sheet into it
+    fn draw_area(
+        &self,
+        ctx_2d: &web_sys::CanvasRenderingContext2d,
+        grid: &Grid,
+        viewport: &Viewport,
+        layout: &SheetLayout,
+        cells: &[CellData],
+        area: CellRect,
+    ) -> Result<(), JsValue> {
         // Clear
         ctx_2d.set_fill_style_str("#ffffff");
-        ctx_2d.fill_rect(0.0, 0.0, self.width as f64, self.height as f64);
+        ctx_2d.fill_rect(area.x, area.y, area.width, area.height);
         
-        // Draw grid lines
-        self.draw_grid_lines(&ctx_2d, grid, viewport)?;
+        // Gridlines and headers only change with the layout, so they're
+        // blitted from offscreen layers unless it moved
+        self.update_layers(grid, viewport, layout)?;
+        ctx_2d.draw_image_with_html_canvas_element(&self.grid_layer, 0.0, 0.0)?;
         
         // Draw cells
-        self.draw_cells(&ctx_2d, grid, viewport, &cells)?;
+        self.draw_cells(ctx_2d, layout, cells)?;
         
-        // Draw headers
-        self.draw_headers(&ctx_2d, grid, viewport)?;
+        ctx_2d.draw_image_with_html_canvas_element(&self.header_layer, 0.0, 0.0)?;
         
         Ok(())
     }
 
+    /// Redraw the offscreen layers if the layout changed since they were drawn
+    fn update_layers(&self, grid: &Grid, viewport: &Viewport, layout: &SheetLayout) -> Result<(), JsValue> {
+        let hash = layout_hash(grid, viewport, self.width, self.height);
+        if self.layer_hash.get() == Some(hash) {
+            return Ok(());
+        }
+        let grid_ctx = layer_context(&self.grid_layer, self.width, self.height)?;
+        self.draw_grid_lines(&grid_ctx, layout)?;
+        let header_ctx = layer_context(&self.header_layer, self.width, self.height)?;
+        self.draw_headers(&header_ctx, layout)?;
+        self.layer_hash.set(Some(hash));
+        Ok(())
+    }
+
     fn draw_grid_lines(
         &self,
         ctx: &web_sys::CanvasRenderingContext2d,
-        grid: &Grid,
-        viewport: &Viewport,
+        layout: &SheetLayout,
     ) -> Result<(), JsValue> {
-        let zoom = viewport.zoom as f64;
-        let header_width = 50.0 * zoom;
-        let header_height = 24.0 * zoom;
-        
         ctx.set_stroke_style_str("#e0e0e0");
         ctx.set_line_width(1.0);
         
-        // Vertical lines (columns)
-        let mut x = header_width - (viewport.offset_x as f64 * zoom);
-        for col in viewport.start_col..viewport.end_col() {
-            let col_width = grid.get_col_width(col) as f64 * zoom;
+        // Vertical lines (columns), skipping edges under a frozen pane
+        for col in layout.cols().filter(|col| col.start >= col.visible_start) {
             ctx.begin_path();
-            ctx.move_to(x, 0.0);
-            ctx.line_to(x, self.height as f64);
+            ctx.move_to(col.start, 0.0);
+            ctx.line_to(col.start, self.height as f64);
             ctx.stroke();
-            x += col_width;
         }
         
         // Horizontal lines (rows)
-        let mut y = header_height - (viewport.offset_y as f64 * zoom);
-        for row in viewport.start_row..viewport.end_row() {
-            let row_height = grid.get_row_height(row) as f64 * zoom;
+        for row in layout.rows().filter(|row| row.start >= row.visible_start) {
             ctx.begin_path();
-            ctx.move_to(0.0, y);
-            ctx.line_to(self.width as f64, y);
+            ctx.move_to(0.0, row.start);
+            ctx.line_to(self.width as f64, row.start);
             ctx.stroke();
-            y += row_height;
         }
         
         Ok(())
@@ -121,45 +343,30 @@ impl CanvasRenderer {
     fn draw_cells(
         &self,
         ctx: &web_sys::CanvasRenderingContext2d,
-        grid: &Grid,
-        viewport: &Viewport,
-        cells: &[crate::grid::CellData],
+        layout: &SheetLayout,
+        cells: &[CellData],
     ) -> Result<(), JsValue> {
-        let zoom = viewport.zoom as f64;
-        let header_width = 50.0 * zoom;
-        let header_height = 24.0 * zoom;
+        let zoom = layout.zoom();
         
         ctx.set_fill_style_str("#1a1a1a");
         let font_size = (13.0 * zoom).max(8.0);
         ctx.set_font(&format!("{}px -apple-system, BlinkMacSystemFont, sans-serif", font_size));
         ctx.set_text_baseline("middle");
         
-        for cell in cells {
-            // Calculate cell position
-            let mut x = header_width - (viewport.offset_x as f64 * zoom);
-            for col in viewport.start_col..cell.col {
-                x += grid.get_col_width(col) as f64 * zoom;
-            }
-            
-            let mut y = header_height - (viewport.offset_y as f64 * zoom);
-            for row in viewport.start_row..cell.row {
-                y += grid.get_row_height(row) as f64 * zoom;
-            }
-            
-            let cell_width = grid.get_col_width(cell.col) as f64 * zoom;
-            let cell_height = grid.get_row_height(cell.row) as f64 * zoom;
-            
+        for (cell, rect, visible) in cell_placements(layout, cells) {
             // Draw cell text with padding
             let padding = 4.0 * zoom;
-            ctx.set_text_align("left");
+            let (text_x, text_align) = text_anchor(rect, text_alignment(cell), padding);
+            ctx.set_text_align(text_align);
             
-            // Clip to cell bounds
+            // Clip to the part of the cell not under a header or frozen pane
             ctx.save();
             ctx.begin_path();
-            ctx.rect(x, y, cell_width, cell_height);
+            ctx.rect(visible.x, visible.y, visible.width, visible.height);
             ctx.clip();
             
-            ctx.fill_text(&cell.value, x + padding, y + cell_height / 2.0)?;
+            ctx.fill_text(&cell.value, text_x, rect.y + rect.height / 2.0)?;
+            self.draw_decorations(ctx, cell, rect, zoom)?;
             
             ctx.restore();
         }
@@ -167,15 +374,41 @@ impl CanvasRenderer {
         Ok(())
     }
 
+    /// Draw corner glyphs for validation lists, errors and notes
+    fn draw_decorations(
+        &self,
+        ctx: &web_sys::CanvasRenderingContext2d,
+        cell: &CellData,
+        rect: CellRect,
+        zoom: f64,
+    ) -> Result<(), JsValue> {
+        if cell.has_validation {
+            fill_polygon(ctx, &dropdown_arrow(rect, zoom), "#5f6368");
+        }
+        if cell.is_error {
+            let (cx, cy, radius) = error_badge(rect, zoom);
+            ctx.set_fill_style_str("#d93025");
+            ctx.begin_path();
+            ctx.arc(cx, cy, radius, 0.0, std::f64::consts::TAU)?;
+            ctx.fill();
+        }
+        if cell.has_note {
+            fill_polygon(ctx, &note_triangle(rect, zoom), "#f9ab00");
+        }
+        
+        // Restore the text color for the next cell
+        ctx.set_fill_style_str("#1a1a1a");
+        Ok(())
+    }
+
     fn draw_headers(
         &self,
         ctx: &web_sys::CanvasRenderingContext2d,
-        grid: &Grid,
-        viewport: &Viewport,
+        layout: &SheetLayout,
     ) -> Result<(), JsValue> {
-        let zoom = viewport.zoom as f64;
-        let header_width = 50.0 * zoom;
-        let header_height = 24.0 * zoom;
+        let zoom = layout.zoom();
+        let header_width = layout.header_width();
+        let header_height = layout.header_height();
         
         // Header background
         ctx.set_fill_style_str("#f8f9fa");
@@ -193,22 +426,28 @@ impl CanvasRenderer {
         ctx.set_text_align("center");
         ctx.set_text_baseline("middle");
         
-        let mut x = header_width - (viewport.offset_x as f64 * zoom);
-        for col in viewport.start_col..viewport.end_col() {
-            let col_width = grid.get_col_width(col) as f64 * zoom;
-            let label = crate::cell::CellRef::col_to_letter(col);
-            ctx.fill_text(&label, x + col_width / 2.0, header_height / 2.0)?;
-            x += col_width;
+        // Labels are clipped like their cells, so a column sliding under a
+        // frozen pane doesn't draw its label over the frozen ones
+        for col in layout.cols() {
+            let label = crate::cell::CellRef::col_to_letter(col.index);
+            ctx.save();
+            ctx.begin_path();
+            ctx.rect(col.visible_start, 0.0, col.visible_end - col.visible_start, header_height);
+            ctx.clip();
+            ctx.fill_text(&label, col.start + col.size / 2.0, header_height / 2.0)?;
+            ctx.restore();
         }
         
         // Row headers
         ctx.set_text_align("center");
-        let mut y = header_height - (viewport.offset_y as f64 * zoom);
-        for row in viewport.start_row..viewport.end_row() {
-            let row_height = grid.get_row_height(row) as f64 * zoom;
-            let label = (row + 1).to_string();
-            ctx.fill_text(&label, header_width / 2.0, y + row_height / 2.0)?;
-            y += row_height;
+        for row in layout.rows() {
+            let label = (row.index + 1).to_string();
+            ctx.save();
+            ctx.begin_path();
+            ctx.rect(0.0, row.visible_start, header_width, row.visible_end - row.visible_start);
+            ctx.clip();
+            ctx.fill_text(&label, header_width / 2.0, row.start + row.size / 2.0)?;
+            ctx.restore();
         }
         
         // Header borders
@@ -237,6 +476,204 @@ impl CanvasRenderer {
         self.width = width;
         self.height = height;
         self.gl.viewport(0, 0, width as i32, height as i32);
+        for layer in [&self.grid_layer, &self.header_layer] {
+            layer.set_width(width);
+            layer.set_height(height);
+        }
+        self.invalidate_layout_cache();
+    }
+}
+
+/// The 2D context of an offscreen layer, cleared to transparent
+fn layer_context(layer: &HtmlCanvasElement, width: u32, height: u32) -> Result<web_sys::CanvasRenderingContext2d, JsValue> {
+    let ctx = layer
+        .get_context("2d")?
+        .ok_or("2D context not available")?
+        .dyn_into::<web_sys::CanvasRenderingContext2d>()?;
+    ctx.clear_rect(0.0, 0.0, width as f64, height as f64);
+    Ok(ctx)
+}
+
+fn fill_polygon(ctx: &web_sys::CanvasRenderingContext2d, points: &[(f64, f64)], color: &str) {
+    let Some((&(x0, y0), rest)) = points.split_first() else {
+        return;
+    };
+    ctx.set_fill_style_str(color);
+    ctx.begin_path();
+    ctx.move_to(x0, y0);
+    for &(x, y) in rest {
+        ctx.line_to(x, y);
     }
+    ctx.close_path();
+    ctx.fill();
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cell::{CellFormat, CellRef, CellValue};
+
+    const RECT: CellRect = CellRect { x: 50.0, y: 24.0, width: 100.0, height: 24.0 };
+
+    #[test]
+    fn test_note_triangle_geometry() {
+        assert_eq!(note_triangle(RECT, 1.0), [(144.0, 24.0), (150.0, 24.0), (150.0, 30.0)]);
+        assert_eq!(note_triangle(RECT, 2.0), [(138.0, 24.0), (150.0, 24.0), (150.0, 36.0)]);
+        
+        // Capped at half the cell when zoomed in on a short row
+        let short = CellRect { height: 10.0, ..RECT };
+        assert_eq!(note_triangle(short, 4.0), [(145.0, 24.0), (150.0, 24.0), (150.0, 29.0)]);
+    }
+
+    #[test]
+    fn test_geometry_stays_finite_after_bad_sizes() {
+        let mut grid = crate::grid::Grid::new(10, 10);
+        let _ = grid.set_col_width(0, f32::NAN);
+        let _ = grid.set_row_height(0, f32::NEG_INFINITY);
+        grid.set_col_width(1, -1.0).unwrap();
+        for col in 0..2 {
+            let rect = CellRect {
+                x: 50.0,
+                y: 24.0,
+                width: grid.get_col_width(col) as f64,
+                height: grid.get_row_height(0) as f64,
+            };
+            let (cx, cy, r) = error_badge(rect, 1.0);
+            assert!(note_triangle(rect, 1.0).iter().chain(&dropdown_arrow(rect, 1.0)).all(|(x, y)| x.is_finite() && y.is_finite()));
+            assert!(cx.is_finite() && cy.is_finite() && r.is_finite());
+        }
+    }
+
+    #[test]
+    fn test_other_decorations_stay_inside_cell() {
+        for zoom in [0.5, 1.0, 3.0] {
+            for (x, y) in dropdown_arrow(RECT, zoom) {
+                assert!(x > RECT.x && x < RECT.x + RECT.width, "zoom {}", zoom);
+                assert!(y > RECT.y && y < RECT.y + RECT.height, "zoom {}", zoom);
+            }
+            let (cx, cy, r) = error_badge(RECT, zoom);
+            assert!(cx - r >= RECT.x && cy - r >= RECT.y, "zoom {}", zoom);
+        }
+    }
+
+    #[test]
+    fn test_boolean_cells_center_unless_aligned() {
+        let mut grid = Grid::new(10, 10);
+        let engine = FormulaEngine::new();
+        grid.set_value(CellRef::new(0, 0), CellValue::parse("true")).unwrap();
+        grid.set_value(CellRef::new(0, 1), CellValue::parse("yes")).unwrap();
+        grid.set_value(CellRef::new(0, 2), CellValue::parse("FALSE")).unwrap();
+        grid.set_format(CellRef::new(0, 2), CellFormat {
+            align_h: Some(HorizontalAlign::Right),
+            ..CellFormat::default()
+        }).unwrap();
+        let cells = grid.get_range(0, 0, 0, 2, &engine);
+        let aligns: Vec<HorizontalAlign> = cells.iter().map(text_alignment).collect();
+        assert_eq!(aligns, vec![HorizontalAlign::Center, HorizontalAlign::Left, HorizontalAlign::Right]);
+
+        assert_eq!(text_anchor(RECT, HorizontalAlign::Left, 4.0), (54.0, "left"));
+        assert_eq!(text_anchor(RECT, HorizontalAlign::Center, 4.0), (100.0, "center"));
+        assert_eq!(text_anchor(RECT, HorizontalAlign::Right, 4.0), (146.0, "right"));
+    }
+
+    #[test]
+    fn test_numbers_right_align_unless_aligned() {
+        let mut grid = Grid::new(10, 10);
+        let engine = FormulaEngine::new();
+        grid.set_value(CellRef::new(0, 0), CellValue::Number(1234.5)).unwrap();
+        grid.set_value(CellRef::new(0, 1), CellValue::parse("Revenue")).unwrap();
+        grid.set_value(CellRef::new(0, 2), CellValue::Number(42.0)).unwrap();
+        grid.set_format(CellRef::new(0, 2), CellFormat {
+            align_h: Some(HorizontalAlign::Left),
+            ..CellFormat::default()
+        }).unwrap();
+        grid.set_value(CellRef::new(0, 3), CellValue::Error("DIV/0".to_string())).unwrap();
+        let cells = grid.get_range(0, 0, 0, 3, &engine);
+        let xs: Vec<(f64, &str)> = cells.iter().map(|cell| text_anchor(RECT, text_alignment(cell), 4.0)).collect();
+        assert_eq!(xs, vec![(146.0, "right"), (54.0, "left"), (54.0, "left"), (100.0, "center")]);
+    }
+
+    #[test]
+    fn test_cell_rects_match_drawn_positions() {
+        let mut grid = Grid::new(60, 20);
+        let engine = FormulaEngine::new();
+        for row in 0..40 {
+            for col in 0..15 {
+                grid.set_value(CellRef::new(row, col), CellValue::Number((row * col) as f64)).unwrap();
+            }
+        }
+        grid.set_col_width(2, 160.0).unwrap();
+        grid.set_row_height(7, 40.0).unwrap();
+        grid.set_row_hidden(8, true);
+        grid.set_col_hidden(4, true);
+
+        for zoom in [0.5, 1.0, 1.75] {
+            for (start_row, start_col, offset_x, offset_y) in [(0, 0, 0.0, 0.0), (5, 3, 17.0, 9.0)] {
+                for (frozen_rows, frozen_cols) in [(0, 0), (2, 1)] {
+                    let mut viewport = Viewport::new(start_row, start_col, 25, 10);
+                    viewport.set_zoom(zoom);
+                    viewport.offset_x = offset_x;
+                    viewport.offset_y = offset_y;
+                    viewport.frozen_rows = frozen_rows;
+                    viewport.frozen_cols = frozen_cols;
+                    let layout = SheetLayout::new(&grid, &viewport, 900.0, 500.0);
+                    let cells: Vec<CellData> = layout
+                        .blocks()
+                        .into_iter()
+                        .flat_map(|(start, end)| grid.get_range(start.row, start.col, end.row, end.col, &engine))
+                        .collect();
+                    let drawn = cell_placements(&layout, &cells);
+                    let config = format!("zoom {} at {},{} frozen {},{}", zoom, start_row, start_col, frozen_rows, frozen_cols);
+                    assert!(drawn.len() > 20, "{}", config);
+
+                    for (cell, rect, visible) in &drawn {
+                        let reported = layout.cell_rect(cell.row, cell.col).unwrap();
+                        assert_eq!(CellRect { x: reported.x, y: reported.y, width: reported.width, height: reported.height }, *rect, "{}", config);
+                        assert_eq!(reported.clipped, visible != rect, "{}", config);
+                        if frozen_rows == 0 && frozen_cols == 0 {
+                            // Without panes, cells sit where the renderer always put them
+                            let zoom = zoom as f64;
+                            let x = (start_col..cell.col).map(|col| grid.get_col_width(col) as f64 * zoom).sum::<f64>();
+                            let y = (start_row..cell.row).map(|row| grid.get_row_height(row) as f64 * zoom).sum::<f64>();
+                            assert_eq!(rect.x, 50.0 * zoom - offset_x as f64 * zoom + x, "{}", config);
+                            assert_eq!(rect.y, 24.0 * zoom - offset_y as f64 * zoom + y, "{}", config);
+                        }
+                    }
+                    // Every other cell is reported as out of view
+                    for row in 0..40 {
+                        for col in 0..15 {
+                            let is_drawn = drawn.iter().any(|(cell, _, _)| (cell.row, cell.col) == (row, col));
+                            assert_eq!(layout.cell_rect(row, col).is_some(), is_drawn, "{} {},{}", config, row, col);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_layout_hash_tracks_geometry_only() {
+        let mut grid = Grid::new(100, 30);
+        let viewport = Viewport::new(0, 0, 20, 10);
+        let base = layout_hash(&grid, &viewport, 800, 600);
+        assert_eq!(layout_hash(&grid, &viewport, 800, 600), base);
+
+        // Cell contents and sizes outside the viewport don't matter
+        grid.set_value(CellRef::new(2, 2), CellValue::Number(1.0)).unwrap();
+        grid.set_col_width(15, 200.0).unwrap();
+        grid.set_row_height(50, 40.0).unwrap();
+        assert_eq!(layout_hash(&grid, &viewport, 800, 600), base);
+
+        assert_ne!(layout_hash(&grid, &viewport, 1024, 600), base);
+        assert_ne!(layout_hash(&grid, &Viewport { start_row: 1, ..viewport }, 800, 600), base);
+        assert_ne!(layout_hash(&grid, &Viewport { offset_x: 3.0, ..viewport }, 800, 600), base);
+        assert_ne!(layout_hash(&grid, &Viewport { zoom: 1.25, ..viewport }, 800, 600), base);
+        assert_ne!(layout_hash(&grid, &Viewport { frozen_rows: 1, ..viewport }, 800, 600), base);
+
+        grid.set_col_width(3, 140.0).unwrap();
+        let widened = layout_hash(&grid, &viewport, 800, 600);
+        assert_ne!(widened, base);
+        grid.set_row_height(4, 30.0).unwrap();
+        assert_ne!(layout_hash(&grid, &viewport, 800, 600), widened);
+    }
+}
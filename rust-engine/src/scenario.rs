@@ -0,0 +1,409 @@
+//! Scenario manager: named sets of alternative input values
+//!
+//! A scenario swaps values into designated input cells (e.g. growth rate,
+//! headcount) so a model can be flipped between "base / upside / downside".
+//! The original inputs are captured the first time a scenario touches them,
+//! so switching between scenarios and back to the base is lossless.
+
+use crate::cell::{CellRef, CellValue};
+use crate::formula::FormulaEngine;
+use crate::grid::{Grid, GridError};
+use crate::reference::Axis;
+use indexmap::IndexMap;
+use serde::{Deserialize, Serialize};
+
+/// A named set of input values
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Scenario {
+    pub name: String,
+    pub inputs: Vec<ScenarioInput>,
+}
+
+/// One input cell's value within a scenario
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScenarioInput {
+    pub cell: CellRef,
+    pub value: CellValue,
+}
+
+/// Input cell spec as sent from JS: `{ ref: "B3", value: "0.05" }`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScenarioCellSpec {
+    #[serde(rename = "ref")]
+    pub reference: String,
+    pub value: String,
+}
+
+/// What an input cell held before any scenario was applied
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct SavedInput {
+    cell: CellRef,
+    value: CellValue,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    formula: Option<String>,
+}
+
+/// Scenarios defined on a sheet, plus the state needed to undo them
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ScenarioStore {
+    #[serde(default)]
+    scenarios: IndexMap<String, Scenario>,
+    /// Name of the scenario currently swapped in, if any
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    active: Option<String>,
+    /// Original contents of every input cell a scenario has overwritten
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    baseline: Vec<SavedInput>,
+}
+
+impl ScenarioStore {
+    pub fn list(&self) -> Vec<&Scenario> {
+        self.scenarios.values().collect()
+    }
+
+    pub fn active(&self) -> Option<&str> {
+        self.active.as_deref()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.scenarios.is_empty()
+    }
+
+    /// Move input cells and saved formulas after lines of `axis` were inserted at `start`
+    pub(crate) fn lines_inserted(&mut self, axis: Axis, start: u32, count: u32, formula_engine: &FormulaEngine) {
+        let shift = |cell: &mut CellRef| {
+            let line = axis.line(cell);
+            if *line >= start {
+                *line = line.saturating_add(count);
+            }
+        };
+        for input in self.scenarios.values_mut().flat_map(|scenario| scenario.inputs.iter_mut()) {
+            shift(&mut input.cell);
+        }
+        for saved in &mut self.baseline {
+            shift(&mut saved.cell);
+            rewrite_formula(&mut saved.formula, formula_engine, |ast| {
+                ast.insert_lines(axis, start, count);
+            });
+        }
+    }
+
+    /// Move input cells and saved formulas after lines `start..start + count` of `axis` were deleted
+    ///
+    /// Inputs in the deleted lines are dropped from their scenarios, and so
+    /// are their saved originals, as there's no cell left to restore.
+    pub(crate) fn lines_deleted(&mut self, axis: Axis, start: u32, count: u32, formula_engine: &FormulaEngine) {
+        let end = start.saturating_add(count);
+        // Moves a surviving cell back, or returns false for a deleted one
+        let shift = |cell: &mut CellRef| {
+            let line = axis.line(cell);
+            if (start..end).contains(line) {
+                return false;
+            }
+            if *line >= end {
+                *line -= count;
+            }
+            true
+        };
+        for scenario in self.scenarios.values_mut() {
+            scenario.inputs.retain_mut(|input| shift(&mut input.cell));
+        }
+        self.baseline.retain_mut(|saved| shift(&mut saved.cell));
+        for saved in &mut self.baseline {
+            rewrite_formula(&mut saved.formula, formula_engine, |ast| {
+                ast.delete_lines(axis, start, count);
+            });
+        }
+    }
+}
+
+/// Apply `change` to a saved formula's references, leaving text that doesn't parse alone
+fn rewrite_formula(formula: &mut Option<String>, formula_engine: &FormulaEngine, change: impl FnOnce(&mut crate::formula::FormulaNode)) {
+    let Some(parsed) = formula.as_deref().and_then(|text| formula_engine.parse(text).ok()) else {
+        return;
+    };
+    let mut ast = parsed.ast;
+    change(&mut ast);
+    *formula = Some(ast.to_formula_string());
+}
+
+/// Output cell values under two scenarios, side by side
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScenarioComparison {
+    pub a: String,
+    pub b: String,
+    pub outputs: Vec<ScenarioOutput>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScenarioOutput {
+    pub cell: CellRef,
+    pub a: CellValue,
+    pub b: CellValue,
+}
+
+impl Grid {
+    /// The sheet's scenarios
+    pub fn scenarios(&self) -> &ScenarioStore {
+        &self.scenarios
+    }
+
+    /// Define (or replace) a named scenario
+    pub fn define_scenario(&mut self, name: &str, specs: &[ScenarioCellSpec]) -> Result<(), GridError> {
+        let mut inputs = Vec::with_capacity(specs.len());
+        for spec in specs {
+            let cell = CellRef::parse(&spec.reference)
+                .ok_or_else(|| GridError::Scenario(format!("invalid cell reference '{}'", spec.reference)))?;
+            self.check_bounds(cell)?;
+            inputs.push(ScenarioInput { cell, value: CellValue::parse(&spec.value) });
+        }
+        self.scenarios.scenarios.insert(name.to_string(), Scenario { name: name.to_string(), inputs });
+        self.touch();
+        Ok(())
+    }
+
+    /// Swap a scenario's values into its input cells and recalculate
+    ///
+    /// Inputs overwritten by a previously applied scenario but not by this
+    /// one are put back to their original contents first.
+    pub fn apply_scenario(&mut self, name: &str, formula_engine: &mut FormulaEngine) -> Result<Vec<CellRef>, GridError> {
+        let scenario = self.scenarios.scenarios.get(name)
+            .cloned()
+            .ok_or_else(|| GridError::Scenario(format!("unknown scenario '{}'", name)))?;
+
+        for input in &scenario.inputs {
+            if !self.scenarios.baseline.iter().any(|saved| saved.cell == input.cell) {
+                let saved = self.saved_input(input.cell);
+                self.scenarios.baseline.push(saved);
+            }
+        }
+
+        let mut affected = Vec::new();
+        for saved in self.scenarios.baseline.clone() {
+            if !scenario.inputs.iter().any(|input| input.cell == saved.cell) {
+                self.restore_input(&saved, formula_engine)?;
+                affected.push(saved.cell);
+            }
+        }
+        for input in &scenario.inputs {
+            self.set_cell_value(input.cell, input.value.clone(), formula_engine)?;
+            affected.push(input.cell);
+        }
+
+        self.scenarios.active = Some(name.to_string());
+        self.recalculate_cells(affected, formula_engine)
+    }
+
+    /// Put every input back to its pre-scenario contents and recalculate
+    ///
+    /// Every input is attempted even if one fails; those that fail keep
+    /// their saved contents, for a later clear to try again.
+    pub fn clear_scenario(&mut self, formula_engine: &mut FormulaEngine) -> Result<Vec<CellRef>, GridError> {
+        let baseline = std::mem::take(&mut self.scenarios.baseline);
+        self.scenarios.active = None;
+
+        let affected: Vec<CellRef> = baseline.iter().map(|saved| saved.cell).collect();
+        let failed = self.restore_inputs(&baseline, formula_engine);
+        let changed = self.recalculate_cells(affected, formula_engine)?;
+        if let Err((error, unrestored)) = failed {
+            self.scenarios.baseline = unrestored;
+            return Err(error);
+        }
+        Ok(changed)
+    }
+
+    /// Compare output cells under scenarios `a` and `b`
+    ///
+    /// Each scenario is applied and recalculated in turn. The sheet is always
+    /// put back the way it was afterwards, even if applying or evaluating fails.
+    pub fn diff_scenarios(&mut self, a: &str, b: &str, output_cells: &[CellRef], formula_engine: &mut FormulaEngine) -> Result<ScenarioComparison, GridError> {
+        let store = self.scenarios.clone();
+        let mut touched: Vec<CellRef> = store.baseline.iter().map(|saved| saved.cell).collect();
+        for name in [a, b] {
+            if let Some(scenario) = store.scenarios.get(name) {
+                touched.extend(scenario.inputs.iter().map(|input| input.cell));
+            }
+        }
+        touched.sort_by_key(|cell| (cell.row, cell.col));
+        touched.dedup();
+        let snapshot: Vec<SavedInput> = touched.iter().map(|&cell| self.saved_input(cell)).collect();
+
+        let result = (|| {
+            self.apply_scenario(a, formula_engine)?;
+            let values_a = self.output_values(output_cells);
+            self.apply_scenario(b, formula_engine)?;
+            let values_b = self.output_values(output_cells);
+            Ok(ScenarioComparison {
+                a: a.to_string(),
+                b: b.to_string(),
+                outputs: output_cells.iter()
+                    .zip(values_a.into_iter().zip(values_b))
+                    .map(|(&cell, (a, b))| ScenarioOutput { cell, a, b })
+                    .collect(),
+            })
+        })();
+
+        self.scenarios = store;
+        let restored = self.restore_inputs(&snapshot, formula_engine);
+        self.recalculate_cells(touched, formula_engine)?;
+
+        restored.map_err(|(error, _)| error)?;
+        result
+    }
+
+    fn output_values(&self, cells: &[CellRef]) -> Vec<CellValue> {
+        cells.iter()
+            .map(|&cell| self.get_cell(cell).map(|c| c.value.clone()).unwrap_or_default())
+            .collect()
+    }
+
+    fn saved_input(&self, cell: CellRef) -> SavedInput {
+        match self.get_cell(cell) {
+            Some(existing) => SavedInput {
+                cell,
+                value: existing.value.clone(),
+                formula: existing.formula.clone(),
+            },
+            None => SavedInput { cell, value: CellValue::Empty, formula: None },
+        }
+    }
+
+    /// Restore every input in `saved`, carrying on past failures
+    ///
+    /// On failure, returns an error naming each input that couldn't be
+    /// restored, along with those inputs.
+    fn restore_inputs(&mut self, saved: &[SavedInput], formula_engine: &mut FormulaEngine) -> Result<(), (GridError, Vec<SavedInput>)> {
+        let mut failures = Vec::new();
+        let mut unrestored = Vec::new();
+        for input in saved {
+            if let Err(e) = self.restore_input(input, formula_engine) {
+                failures.push(format!("{}: {}", input.cell.to_a1(), e));
+                unrestored.push(input.clone());
+            }
+        }
+        if failures.is_empty() {
+            return Ok(());
+        }
+        Err((GridError::Scenario(format!("couldn't restore {}", failures.join("; "))), unrestored))
+    }
+
+    fn restore_input(&mut self, saved: &SavedInput, formula_engine: &mut FormulaEngine) -> Result<(), GridError> {
+        match &saved.formula {
+            Some(formula) => {
+                let parsed = formula_engine.parse(formula)?;
+                self.set_cell_formula(saved.cell, parsed, formula_engine)?;
+            }
+            None => self.set_cell_value(saved.cell, saved.value.clone(), formula_engine)?,
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::grid::{CellUpdate, GridPatch};
+
+    fn spec(reference: &str, value: &str) -> ScenarioCellSpec {
+        ScenarioCellSpec { reference: reference.to_string(), value: value.to_string() }
+    }
+
+    fn value(grid: &Grid, a1: &str) -> CellValue {
+        grid.get_cell(CellRef::parse(a1).unwrap()).map(|c| c.value.clone()).unwrap_or_default()
+    }
+
+    /// A1 = growth, A2 = base, A3 = "=A2+A2*A1"
+    fn model() -> (Grid, FormulaEngine) {
+        let mut grid = Grid::new(10, 10);
+        let mut engine = FormulaEngine::new();
+        let patch = GridPatch {
+            updates: vec![
+                CellUpdate { row: 0, col: 0, value: Some("0.1".to_string()), formula: None },
+                CellUpdate { row: 1, col: 0, value: Some("100".to_string()), formula: None },
+                CellUpdate { row: 2, col: 0, value: None, formula: Some("=A2+A2*A1".to_string()) },
+            ],
+            auto_expand: None,
+        };
+        grid.apply_patch(patch, &mut engine).unwrap();
+        grid.define_scenario("upside", &[spec("A1", "0.5")]).unwrap();
+        grid.define_scenario("downside", &[spec("A1", "-0.5"), spec("A2", "50")]).unwrap();
+        (grid, engine)
+    }
+
+    #[test]
+    fn test_apply_switch_and_clear() {
+        let (mut grid, mut engine) = model();
+        assert_eq!(value(&grid, "A3"), CellValue::Number(110.0));
+
+        grid.apply_scenario("downside", &mut engine).unwrap();
+        assert_eq!(value(&grid, "A3"), CellValue::Number(25.0));
+        assert_eq!(grid.scenarios().active(), Some("downside"));
+
+        // A2 isn't part of "upside", so it goes back to its original value
+        grid.apply_scenario("upside", &mut engine).unwrap();
+        assert_eq!(value(&grid, "A2"), CellValue::Number(100.0));
+        assert_eq!(value(&grid, "A3"), CellValue::Number(150.0));
+
+        grid.clear_scenario(&mut engine).unwrap();
+        assert_eq!(value(&grid, "A1"), CellValue::Number(0.1));
+        assert_eq!(value(&grid, "A2"), CellValue::Number(100.0));
+        assert_eq!(value(&grid, "A3"), CellValue::Number(110.0));
+        assert_eq!(grid.scenarios().active(), None);
+
+        assert!(grid.apply_scenario("missing", &mut engine).is_err());
+    }
+
+    #[test]
+    fn test_diff_scenarios_restores_sheet() {
+        let (mut grid, mut engine) = model();
+        let a3 = CellRef::parse("A3").unwrap();
+
+        let comparison = grid.diff_scenarios("upside", "downside", &[a3], &mut engine).unwrap();
+        assert_eq!(comparison.outputs.len(), 1);
+        assert_eq!(comparison.outputs[0].a, CellValue::Number(150.0));
+        assert_eq!(comparison.outputs[0].b, CellValue::Number(25.0));
+
+        assert_eq!(value(&grid, "A1"), CellValue::Number(0.1));
+        assert_eq!(value(&grid, "A3"), CellValue::Number(110.0));
+        assert_eq!(grid.scenarios().active(), None);
+
+        // A failing comparison still leaves the sheet untouched
+        assert!(grid.diff_scenarios("upside", "missing", &[a3], &mut engine).is_err());
+        assert_eq!(value(&grid, "A1"), CellValue::Number(0.1));
+        assert_eq!(value(&grid, "A3"), CellValue::Number(110.0));
+    }
+
+    #[test]
+    fn test_inputs_follow_inserted_and_deleted_rows() {
+        let (mut grid, mut engine) = model();
+        grid.apply_scenario("downside", &mut engine).unwrap();
+
+        // The inputs and the formula all move down a row
+        grid.insert_rows(0, 1, &mut engine).unwrap();
+        assert_eq!(value(&grid, "A4"), CellValue::Number(25.0));
+        grid.clear_scenario(&mut engine).unwrap();
+        assert_eq!(value(&grid, "A1"), CellValue::Empty);
+        assert_eq!(value(&grid, "A2"), CellValue::Number(0.1));
+        assert_eq!(value(&grid, "A3"), CellValue::Number(100.0));
+        assert_eq!(value(&grid, "A4"), CellValue::Number(110.0));
+
+        // Deleting A2's row drops it from the scenario; A3 becomes A2
+        grid.delete_rows(1, 1, &mut engine).unwrap();
+        grid.apply_scenario("downside", &mut engine).unwrap();
+        assert_eq!(value(&grid, "A1"), CellValue::Empty);
+        assert_eq!(value(&grid, "A2"), CellValue::Number(50.0));
+        grid.clear_scenario(&mut engine).unwrap();
+        assert_eq!(value(&grid, "A2"), CellValue::Number(100.0));
+    }
+
+    #[test]
+    fn test_scenarios_serialize_with_grid() {
+        let (mut grid, mut engine) = model();
+        grid.apply_scenario("upside", &mut engine).unwrap();
+
+        let restored = Grid::from_json(&grid.to_json().unwrap()).unwrap();
+        let names: Vec<&str> = restored.scenarios().list().iter().map(|s| s.name.as_str()).collect();
+        assert_eq!(names, vec!["upside", "downside"]);
+        assert_eq!(restored.scenarios().active(), Some("upside"));
+    }
+}
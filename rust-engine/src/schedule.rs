@@ -0,0 +1,175 @@
+//! One render per animation frame, however many edits asked for one
+//!
+//! The host used to call `render()` after every edit and every scroll
+//! event, drawing several times a frame during a drag-scroll. Instead it
+//! now reports why the sheet needs drawing with `request_render` and calls
+//! `render_if_needed` from its `requestAnimationFrame` loop. Requests made
+//! between frames are merged: edits to cells only redraw the block around
+//! the cells written since the last draw, and anything that moves the
+//! layout redraws the whole canvas.
+
+use crate::cell::CellRef;
+use serde::Deserialize;
+
+/// Why the sheet needs drawing again
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RenderReason {
+    /// Cell values changed
+    Cells,
+    /// Cell formats, notes or validation changed
+    Format,
+    /// The viewport scrolled
+    Scroll,
+    Zoom,
+    /// The canvas was resized
+    Resize,
+    /// Row heights, column widths, hidden lines, frozen panes or the grid's size changed
+    Layout,
+}
+
+impl RenderReason {
+    /// Whether only the written cells need drawing again
+    fn is_local(self) -> bool {
+        matches!(self, RenderReason::Cells | RenderReason::Format)
+    }
+}
+
+/// What one draw covers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RenderScope {
+    /// The block between two corners; the rest of the canvas is up to date
+    Region(CellRef, CellRef),
+    Full,
+}
+
+/// Render requests waiting for the next frame
+#[derive(Debug, Clone, Default)]
+pub struct RenderScheduler {
+    /// Whether a draw was asked for since the last one
+    requested: bool,
+    /// Whether any of those requests needs the whole canvas drawn
+    full: bool,
+    /// Corners of the block written since the last draw
+    changed: Option<(CellRef, CellRef)>,
+}
+
+impl RenderScheduler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Remember cells written, for the next local redraw
+    pub fn note_changed(&mut self, cells: &[CellRef]) {
+        for &cell in cells {
+            self.changed = Some(match self.changed {
+                Some((start, end)) => (
+                    CellRef::new(start.row.min(cell.row), start.col.min(cell.col)),
+                    CellRef::new(end.row.max(cell.row), end.col.max(cell.col)),
+                ),
+                None => (cell, cell),
+            });
+        }
+    }
+
+    /// Ask for a draw on the next frame
+    pub fn request(&mut self, reason: RenderReason) {
+        self.requested = true;
+        self.full |= !reason.is_local();
+    }
+
+    /// Whether a draw is waiting
+    pub fn is_pending(&self) -> bool {
+        self.requested
+    }
+
+    /// What the next draw covers, if one was requested
+    ///
+    /// Local reasons with no cells written since the last draw redraw
+    /// everything, as what changed isn't known.
+    pub fn scope(&self) -> Option<RenderScope> {
+        if !self.requested {
+            return None;
+        }
+        Some(match (self.full, self.changed) {
+            (false, Some((start, end))) => RenderScope::Region(start, end),
+            _ => RenderScope::Full,
+        })
+    }
+
+    /// Draw once if anything was requested, then clear the requests
+    ///
+    /// Returns whether `draw` ran. When it fails the requests are kept, so
+    /// the next frame tries again.
+    pub fn run<E>(&mut self, draw: impl FnOnce(RenderScope) -> Result<(), E>) -> Result<bool, E> {
+        let Some(scope) = self.scope() else {
+            return Ok(false);
+        };
+        draw(scope)?;
+        *self = Self::default();
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Run a frame, recording what was drawn
+    fn frame(scheduler: &mut RenderScheduler, drawn: &mut Vec<RenderScope>) -> bool {
+        scheduler
+            .run(|scope| {
+                drawn.push(scope);
+                Ok::<(), ()>(())
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn test_requests_coalesce_into_one_draw() {
+        let mut scheduler = RenderScheduler::new();
+        let mut drawn = Vec::new();
+        assert!(!frame(&mut scheduler, &mut drawn));
+
+        scheduler.note_changed(&[CellRef::new(4, 2)]);
+        scheduler.request(RenderReason::Cells);
+        scheduler.note_changed(&[CellRef::new(1, 3), CellRef::new(6, 1)]);
+        scheduler.request(RenderReason::Cells);
+        scheduler.request(RenderReason::Format);
+        assert!(frame(&mut scheduler, &mut drawn));
+        assert!(!frame(&mut scheduler, &mut drawn));
+        assert_eq!(drawn, vec![RenderScope::Region(CellRef::new(1, 1), CellRef::new(6, 3))]);
+
+        // The next region starts afresh
+        scheduler.note_changed(&[CellRef::new(0, 0)]);
+        scheduler.request(RenderReason::Cells);
+        frame(&mut scheduler, &mut drawn);
+        assert_eq!(drawn[1], RenderScope::Region(CellRef::new(0, 0), CellRef::new(0, 0)));
+
+        // A failed draw is retried on the next frame
+        scheduler.request(RenderReason::Resize);
+        assert!(scheduler.run(|_| Err("lost context")).is_err());
+        assert!(frame(&mut scheduler, &mut drawn));
+        assert_eq!(drawn.len(), 3);
+    }
+
+    #[test]
+    fn test_layout_reasons_escalate_to_full() {
+        let mut drawn = Vec::new();
+        for reasons in [[RenderReason::Scroll, RenderReason::Cells], [RenderReason::Cells, RenderReason::Scroll]] {
+            let mut scheduler = RenderScheduler::new();
+            scheduler.note_changed(&[CellRef::new(2, 2)]);
+            for reason in reasons {
+                scheduler.request(reason);
+            }
+            frame(&mut scheduler, &mut drawn);
+        }
+        assert_eq!(drawn, vec![RenderScope::Full, RenderScope::Full]);
+
+        // Cells said to have changed, but none written: draw everything
+        let mut scheduler = RenderScheduler::new();
+        scheduler.request(RenderReason::Cells);
+        frame(&mut scheduler, &mut drawn);
+        assert_eq!(drawn[2], RenderScope::Full);
+    }
+}
@@ -0,0 +1,456 @@
+//! Array results that spill into neighbouring cells
+//!
+//! A formula such as `=TEXTSPLIT(A1, ",")` or `=A1:A3*2` evaluates to a 2D
+//! array. Operators applied to ranges work element-wise, broadcasting single
+//! values across the array. The formula cell (the anchor) shows the top-left
+//! element and the rest of the array is written into the cells below and to
+//! the right of it. If any of
+//! those cells already holds data, nothing spills and the anchor shows
+//! `#SPILL!` until the obstruction is cleared.
+//!
+//! Spilled values belong to their anchor, not to the cells they sit in.
+//! Structural edits (inserting or deleting rows and columns) empty them
+//! before moving anything and spill every anchor again from its new
+//! position, so a spill split by the edit either fits its new area or
+//! turns its anchor into `#SPILL!`.
+
+use crate::cell::{CellRef, CellValue};
+use crate::formula::{BinaryOp, FormulaEngine, FormulaError, FormulaNode};
+use crate::grid::{Grid, GridError};
+use std::collections::HashSet;
+
+/// The area an anchor spills into, and the cells it currently fills
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Spill {
+    rows: u32,
+    cols: u32,
+    /// Cells holding spilled values (never the anchor itself)
+    owned: Vec<CellRef>,
+}
+
+impl Spill {
+    fn covers(&self, anchor: CellRef, cell: CellRef) -> bool {
+        cell != anchor
+            && (anchor.row..anchor.row.saturating_add(self.rows)).contains(&cell.row)
+            && (anchor.col..anchor.col.saturating_add(self.cols)).contains(&cell.col)
+    }
+}
+
+/// One side of an element-wise operation
+enum Operand {
+    Scalar(CellValue),
+    Array(Vec<Vec<CellValue>>),
+}
+
+impl Operand {
+    /// Element at `(row, col)`; scalars broadcast, ragged arrays read as `#N/A`
+    fn at(&self, row: usize, col: usize) -> CellValue {
+        match self {
+            Operand::Scalar(value) => value.clone(),
+            Operand::Array(array) => array.get(row)
+                .and_then(|r| r.get(col))
+                .cloned()
+                .unwrap_or_else(|| CellValue::Error("N/A".to_string())),
+        }
+    }
+}
+
+/// (rows, cols) of an array, with ragged rows counting at their widest
+fn shape(array: &[Vec<CellValue>]) -> (usize, usize) {
+    (array.len(), array.iter().map(Vec::len).max().unwrap_or(0))
+}
+
+/// Whether an expression evaluates to an array rather than a single value
+pub(crate) fn is_array_expr(node: &FormulaNode) -> bool {
+    match node {
+        FormulaNode::Range { .. } => true,
+        FormulaNode::Function { name, .. } => name == "TEXTSPLIT",
+        FormulaNode::BinaryOp { left, right, .. } => is_array_expr(left) || is_array_expr(right),
+        FormulaNode::UnaryOp { operand, .. } => is_array_expr(operand),
+        _ => false,
+    }
+}
+
+impl FormulaEngine {
+    /// Evaluate an expression whose result is an array
+    ///
+    /// Returns `None` for expressions that produce a single value. Errors in
+    /// individual elements become error values; only a shape mismatch between
+    /// two arrays fails the whole evaluation.
+    pub(crate) fn evaluate_array(&self, node: &FormulaNode, grid: &Grid) -> Option<Result<Vec<Vec<CellValue>>, FormulaError>> {
+        if !is_array_expr(node) {
+            return None;
+        }
+        Some(match node {
+            FormulaNode::Range { start, end, .. } => Ok(self.collect_range_cells(*start, *end, grid)),
+            FormulaNode::Function { args, .. } => self.fn_textsplit(args, grid),
+            FormulaNode::BinaryOp { op, left, right } => self.broadcast(*op, left, right, grid),
+            FormulaNode::UnaryOp { op, operand } => self.operand(operand, grid).map(|operand| {
+                let Operand::Array(array) = operand else {
+                    unreachable!("operand of an array expression is an array");
+                };
+                array.into_iter()
+                    .map(|row| {
+                        row.into_iter()
+                            .map(|value| self.evaluate_unary_op(*op, value).unwrap_or_else(|e| error_value(&e)))
+                            .collect()
+                    })
+                    .collect()
+            }),
+            _ => unreachable!("is_array_expr only accepts ranges, array functions and operators"),
+        })
+    }
+
+    fn operand(&self, node: &FormulaNode, grid: &Grid) -> Result<Operand, FormulaError> {
+        match self.evaluate_array(node, grid) {
+            Some(array) => Ok(Operand::Array(array?)),
+            None => Ok(Operand::Scalar(self.evaluate(node, grid)?)),
+        }
+    }
+
+    /// Apply `op` element-wise, broadcasting a single value across an array
+    fn broadcast(&self, op: BinaryOp, left: &FormulaNode, right: &FormulaNode, grid: &Grid) -> Result<Vec<Vec<CellValue>>, FormulaError> {
+        let left = self.operand(left, grid)?;
+        let right = self.operand(right, grid)?;
+        let (rows, cols) = match (&left, &right) {
+            (Operand::Array(a), Operand::Array(b)) => {
+                if shape(a) != shape(b) {
+                    return Err(FormulaError::ShapeMismatch(format!(
+                        "{}x{} array and {}x{} array", shape(a).0, shape(a).1, shape(b).0, shape(b).1
+                    )));
+                }
+                shape(a)
+            }
+            (Operand::Array(a), _) | (_, Operand::Array(a)) => shape(a),
+            _ => (1, 1),
+        };
+        Ok((0..rows)
+            .map(|row| {
+                (0..cols)
+                    .map(|col| {
+                        self.evaluate_binary_op(op, left.at(row, col), right.at(row, col))
+                            .unwrap_or_else(|e| error_value(&e))
+                    })
+                    .collect()
+            })
+            .collect())
+    }
+
+    /// Cells currently filled by the array formula at `anchor`
+    pub fn spilled_cells(&self, anchor: CellRef) -> &[CellRef] {
+        self.spills.get(&anchor).map(|spill| spill.owned.as_slice()).unwrap_or(&[])
+    }
+
+    /// Note a direct edit to `cell`, returning the anchors whose spill area covers it
+    ///
+    /// An edited cell no longer belongs to any spill, so re-spilling those
+    /// anchors will see it as an obstruction (or fill it again once cleared).
+    pub(crate) fn release_spill_cell(&mut self, cell: CellRef) -> Vec<CellRef> {
+        let mut anchors = Vec::new();
+        for (&anchor, spill) in self.spills.iter_mut() {
+            if spill.covers(anchor, cell) {
+                spill.owned.retain(|&owned| owned != cell);
+                anchors.push(anchor);
+            }
+        }
+        anchors
+    }
+
+    /// Empty every spilled cell and forget all spill areas
+    ///
+    /// Anchors keep their formulas; recalculating them spills again.
+    pub(crate) fn clear_spills(&mut self, grid: &mut Grid) -> Result<(), GridError> {
+        for (_, spill) in self.spills.drain() {
+            for cell in spill.owned {
+                grid.set_value(cell, CellValue::Empty)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Empty the cells `anchor` spilled into and forget its spill area
+    ///
+    /// For an anchor that no longer holds a formula. Returns the emptied cells.
+    pub(crate) fn drop_spill(&mut self, grid: &mut Grid, anchor: CellRef) -> Result<Vec<CellRef>, GridError> {
+        let Some(spill) = self.spills.remove(&anchor) else {
+            return Ok(Vec::new());
+        };
+        for &cell in &spill.owned {
+            grid.set_value(cell, CellValue::Empty)?;
+        }
+        Ok(spill.owned)
+    }
+
+    /// Every cell currently holding a spilled value
+    pub(crate) fn spilled_cell_set(&self) -> HashSet<CellRef> {
+        self.spills.values().flat_map(|spill| spill.owned.iter().copied()).collect()
+    }
+
+    /// Write an array result into the grid starting at `anchor`
+    ///
+    /// Clears whatever the anchor spilled previously. Short rows are padded
+    /// with `#N/A`. Returns the cells, other than the anchor, whose contents
+    /// changed.
+    pub(crate) fn spill(&mut self, grid: &mut Grid, anchor: CellRef, array: Vec<Vec<CellValue>>) -> Result<Vec<CellRef>, FormulaError> {
+        let rows = array.len() as u32;
+        let cols = array.iter().map(Vec::len).max().unwrap_or(0) as u32;
+
+        let mut changed = Vec::new();
+        if let Some(previous) = self.spills.remove(&anchor) {
+            for cell in previous.owned {
+                grid.set_value(cell, CellValue::Empty)?;
+                changed.push(cell);
+            }
+        }
+
+        let targets: Vec<CellRef> = (0..rows)
+            .flat_map(|r| (0..cols).map(move |c| (r, c)))
+            .filter(|&offset| offset != (0, 0))
+            .map(|(r, c)| CellRef::new(anchor.row + r, anchor.col + c))
+            .collect();
+        let blocked = targets.iter().any(|&cell| {
+            grid.check_bounds(cell).is_err()
+                || self.depends_on(anchor, cell)
+                || grid.get_cell(cell).is_some_and(|c| c.formula.is_some() || c.value != CellValue::Empty)
+        });
+
+        let mut spill = Spill { rows, cols, owned: Vec::new() };
+        let top_left = if blocked {
+            CellValue::Error("SPILL".to_string())
+        } else {
+            for &cell in &targets {
+                let value = array.get((cell.row - anchor.row) as usize)
+                    .and_then(|row| row.get((cell.col - anchor.col) as usize))
+                    .cloned()
+                    .unwrap_or_else(|| CellValue::Error("N/A".to_string()));
+                grid.set_value(cell, value)?;
+                spill.owned.push(cell);
+                if !changed.contains(&cell) {
+                    changed.push(cell);
+                }
+            }
+            array.first().and_then(|row| row.first()).cloned().unwrap_or_default()
+        };
+
+        grid.set_computed_value(anchor, top_left)?;
+        self.spills.insert(anchor, spill);
+        Ok(changed)
+    }
+}
+
+fn error_value(error: &FormulaError) -> CellValue {
+    CellValue::Error(error.error_code().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::set;
+
+    fn value(grid: &Grid, a1: &str) -> CellValue {
+        grid.get_cell(CellRef::parse(a1).unwrap()).map(|c| c.value.clone()).unwrap_or_default()
+    }
+
+    fn text(s: &str) -> CellValue {
+        CellValue::Text(s.to_string())
+    }
+
+    /// Check the spill registry against the grid
+    ///
+    /// Every anchor holds a formula; a blocked anchor owns nothing; an
+    /// unblocked one owns exactly its area; and no cell is owned twice.
+    fn assert_spill_consistency(grid: &Grid, engine: &FormulaEngine) {
+        let mut seen = Vec::new();
+        for (&anchor, spill) in &engine.spills {
+            assert!(engine.formula_at(anchor).is_some(), "anchor {} has no formula", anchor.to_a1());
+            let blocked = grid.get_cell(anchor).is_some_and(|c| c.value == CellValue::Error("SPILL".to_string()));
+            if blocked {
+                assert!(spill.owned.is_empty(), "blocked anchor {} owns cells", anchor.to_a1());
+                continue;
+            }
+            let area = (spill.rows * spill.cols).saturating_sub(1) as usize;
+            assert_eq!(spill.owned.len(), area, "anchor {} owns part of its area", anchor.to_a1());
+            for &cell in &spill.owned {
+                assert!(spill.covers(anchor, cell), "{} lies outside {}'s area", cell.to_a1(), anchor.to_a1());
+                assert!(grid.get_cell(cell).is_none_or(|c| c.formula.is_none()), "{} is spilled over a formula", cell.to_a1());
+                assert!(!seen.contains(&cell), "{} is owned twice", cell.to_a1());
+                seen.push(cell);
+            }
+        }
+    }
+
+    /// Values down column `col` from row 1 to `rows`
+    fn column(grid: &Grid, col: &str, rows: u32) -> Vec<CellValue> {
+        (1..=rows).map(|row| value(grid, &format!("{}{}", col, row))).collect()
+    }
+
+    #[test]
+    fn test_spill_follows_source_changes() {
+        let mut grid = Grid::new(10, 10);
+        let mut engine = FormulaEngine::new();
+        set(&mut grid, &mut engine, "A1", "a,b,c");
+        set(&mut grid, &mut engine, "A2", "=TEXTSPLIT(A1, \",\")");
+        assert_eq!(engine.spilled_cells(CellRef::parse("A2").unwrap()).len(), 2);
+
+        // A shorter result clears the cells it no longer covers
+        set(&mut grid, &mut engine, "A1", "x,y");
+        assert_eq!(value(&grid, "A2"), text("x"));
+        assert_eq!(value(&grid, "B2"), text("y"));
+        assert_eq!(value(&grid, "C2"), CellValue::Empty);
+    }
+
+    #[test]
+    fn test_blocked_spill_recovers_when_cleared() {
+        let mut grid = Grid::new(10, 10);
+        let mut engine = FormulaEngine::new();
+        set(&mut grid, &mut engine, "C1", "keep");
+        set(&mut grid, &mut engine, "A1", "=TEXTSPLIT(\"a,b,c\", \",\")");
+        assert_eq!(value(&grid, "A1"), CellValue::Error("SPILL".to_string()));
+        assert_eq!(value(&grid, "B1"), CellValue::Empty);
+        assert_eq!(value(&grid, "C1"), text("keep"));
+
+        set(&mut grid, &mut engine, "C1", "");
+        assert_eq!(value(&grid, "A1"), text("a"));
+        assert_eq!(value(&grid, "C1"), text("c"));
+
+        // Typing over a spilled cell blocks the spill again
+        set(&mut grid, &mut engine, "B1", "mine");
+        assert_eq!(value(&grid, "A1"), CellValue::Error("SPILL".to_string()));
+        assert_eq!(value(&grid, "B1"), text("mine"));
+        assert_eq!(value(&grid, "C1"), CellValue::Empty);
+    }
+
+    #[test]
+    fn test_overwritten_anchor_takes_its_spill_along() {
+        let mut grid = Grid::new(10, 10);
+        let mut engine = FormulaEngine::new();
+        set(&mut grid, &mut engine, "A1", "=TEXTSPLIT(\"a,b,c\", \",\")");
+        set(&mut grid, &mut engine, "D1", "=C1&\"!\"");
+        set(&mut grid, &mut engine, "A1", "x");
+        assert_spill_consistency(&grid, &engine);
+        assert_eq!(["A1", "B1", "C1", "D1"].map(|a1| value(&grid, a1)), [text("x"), CellValue::Empty, CellValue::Empty, text("!")]);
+        assert!(engine.spills.is_empty());
+    }
+
+    #[test]
+    fn test_range_times_scalar_spills() {
+        let mut grid = Grid::new(10, 10);
+        let mut engine = FormulaEngine::new();
+        set(&mut grid, &mut engine, "A1", "1");
+        set(&mut grid, &mut engine, "A2", "2");
+        set(&mut grid, &mut engine, "A3", "3");
+        set(&mut grid, &mut engine, "B1", "=A1:A3*2");
+        let column: Vec<CellValue> = ["B1", "B2", "B3"].iter().map(|a1| value(&grid, a1)).collect();
+        assert_eq!(column, [2.0, 4.0, 6.0].map(CellValue::Number));
+
+        // Equal shapes combine element-wise; errors stay in their element
+        set(&mut grid, &mut engine, "D1", "1");
+        set(&mut grid, &mut engine, "D2", "0");
+        set(&mut grid, &mut engine, "D3", "1");
+        set(&mut grid, &mut engine, "C1", "=A1:A3/D1:D3");
+        let column: Vec<CellValue> = ["C1", "C2", "C3"].iter().map(|a1| value(&grid, a1)).collect();
+        assert_eq!(column, vec![CellValue::Number(1.0), CellValue::Error("DIV/0".to_string()), CellValue::Number(3.0)]);
+
+        // Ranges inside aggregate functions are consumed, not spilled
+        set(&mut grid, &mut engine, "E1", "=SUM(A1:A3*2)");
+        assert_eq!(value(&grid, "E1"), CellValue::Number(12.0));
+        assert_eq!(value(&grid, "E2"), CellValue::Empty);
+    }
+
+    #[test]
+    fn test_shape_mismatch_is_value_error() {
+        let mut grid = Grid::new(10, 10);
+        let mut engine = FormulaEngine::new();
+        set(&mut grid, &mut engine, "D1", "=A1:A3+B1:B2");
+        assert_eq!(value(&grid, "D1"), CellValue::Error("VALUE".to_string()));
+        assert!(engine.spilled_cells(CellRef::parse("D1").unwrap()).is_empty());
+    }
+
+    #[test]
+    fn test_spill_past_grid_edge_is_blocked() {
+        let mut grid = Grid::new(10, 2);
+        let mut engine = FormulaEngine::new();
+        set(&mut grid, &mut engine, "A1", "=TEXTSPLIT(\"a,b,c\", \",\")");
+        assert_eq!(value(&grid, "A1"), CellValue::Error("SPILL".to_string()));
+    }
+
+    #[test]
+    fn test_dependents_of_spilled_cells_recalculate() {
+        let mut grid = Grid::new(10, 10);
+        let mut engine = FormulaEngine::new();
+        set(&mut grid, &mut engine, "A1", "1,2");
+        set(&mut grid, &mut engine, "D1", "=B2*10");
+        set(&mut grid, &mut engine, "A2", "=TEXTSPLIT(A1, \",\")");
+        assert_eq!(value(&grid, "D1"), CellValue::Number(20.0));
+
+        set(&mut grid, &mut engine, "A1", "1,5");
+        assert_eq!(value(&grid, "D1"), CellValue::Number(50.0));
+    }
+
+    #[test]
+    fn test_insert_through_spill_respills() {
+        let mut grid = Grid::new(10, 10);
+        let mut engine = FormulaEngine::new();
+        set(&mut grid, &mut engine, "B1", "=TEXTSPLIT(\"a;b;c\", \",\", \";\")");
+        set(&mut grid, &mut engine, "C2", "=B2&\"!\"");
+        assert_spill_consistency(&grid, &engine);
+
+        // A row inserted inside the spill doesn't leave a moved copy behind
+        grid.insert_rows(1, 1, &mut engine).unwrap();
+        assert_spill_consistency(&grid, &engine);
+        assert_eq!(column(&grid, "B", 5), vec![text("a"), text("b"), text("c"), CellValue::Empty, CellValue::Empty]);
+        assert_eq!(value(&grid, "C3"), text("c!"));
+
+        // Columns inserted before the anchor move the whole spill
+        grid.insert_cols(0, 2, &mut engine).unwrap();
+        assert_spill_consistency(&grid, &engine);
+        assert_eq!(column(&grid, "D", 4), vec![text("a"), text("b"), text("c"), CellValue::Empty]);
+        assert_eq!(column(&grid, "B", 3), vec![CellValue::Empty; 3]);
+        assert_eq!(engine.spilled_cells(CellRef::parse("D1").unwrap()).len(), 2);
+    }
+
+    #[test]
+    fn test_deleting_anchor_clears_its_spill() {
+        let mut grid = Grid::new(10, 10);
+        let mut engine = FormulaEngine::new();
+        set(&mut grid, &mut engine, "A2", "=TEXTSPLIT(\"a,b,c\", \",\")");
+        set(&mut grid, &mut engine, "A3", "=TEXTSPLIT(\"x;y\", \",\", \";\")");
+        assert_spill_consistency(&grid, &engine);
+
+        grid.delete_rows(1, 1, &mut engine).unwrap();
+        assert_spill_consistency(&grid, &engine);
+        assert_eq!(["A1", "B1", "C1", "B2", "C2"].map(|a1| value(&grid, a1)), [CellValue::Empty, CellValue::Empty, CellValue::Empty, CellValue::Empty, CellValue::Empty]);
+        assert_eq!(column(&grid, "A", 3), vec![CellValue::Empty, text("x"), text("y")]);
+
+        grid.delete_cols(0, 1, &mut engine).unwrap();
+        assert_spill_consistency(&grid, &engine);
+        assert!(engine.spills.is_empty());
+        assert!(column(&grid, "A", 3).iter().all(|value| *value == CellValue::Empty));
+    }
+
+    #[test]
+    fn test_split_spill_reanchors_or_blocks() {
+        let mut grid = Grid::new(10, 10);
+        let mut engine = FormulaEngine::new();
+        set(&mut grid, &mut engine, "A1", "=TEXTSPLIT(\"a,b,c\", \",\")");
+        set(&mut grid, &mut engine, "A3", "=TEXTSPLIT(\"x;y\", \",\", \";\")");
+        set(&mut grid, &mut engine, "B2", "keep");
+        assert_spill_consistency(&grid, &engine);
+
+        // Deleting a column inside the spill re-spills it in full
+        grid.delete_cols(1, 1, &mut engine).unwrap();
+        assert_spill_consistency(&grid, &engine);
+        assert_eq!(["A1", "B1", "C1", "D1"].map(|a1| value(&grid, a1)), [text("a"), text("b"), text("c"), CellValue::Empty]);
+
+        // Data moved into a spill's area blocks it rather than being overwritten
+        set(&mut grid, &mut engine, "A5", "keep");
+        grid.delete_rows(3, 1, &mut engine).unwrap();
+        assert_spill_consistency(&grid, &engine);
+        assert_eq!(column(&grid, "A", 4), vec![text("a"), CellValue::Empty, CellValue::Error("SPILL".to_string()), text("keep")]);
+        assert!(engine.spilled_cells(CellRef::parse("A3").unwrap()).is_empty());
+
+        set(&mut grid, &mut engine, "A4", "");
+        assert_spill_consistency(&grid, &engine);
+        assert_eq!(column(&grid, "A", 4), vec![text("a"), CellValue::Empty, text("x"), text("y")]);
+    }
+}
@@ -0,0 +1,138 @@
+//! Engine statistics and warnings about expensive formulas
+//!
+//! A formula over a huge range (`SUM(A1:Z100000)`) is valid but can cost
+//! seconds to register and evaluate. Rather than spend that time silently,
+//! the engine records a warning for every formula whose references cover
+//! more cells than a threshold, so the host can show a "this formula is
+//! slow" hint. Sizes are counted from the ranges as written, not from the
+//! dependency list they expand to.
+
+use crate::cell::CellRef;
+use crate::formula::{Formula, FormulaEngine, FormulaNode};
+use serde::{Deserialize, Serialize};
+
+/// Default cell count above which a formula gets a warning
+pub const DEFAULT_LARGE_FORMULA_CELLS: u64 = 100_000;
+
+/// Something about a formula the host may want to flag
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum FormulaWarning {
+    /// The formula's references cover `referenced_cells` cells, more than `threshold`
+    LargeRange { cell: CellRef, referenced_cells: u64, threshold: u64 },
+}
+
+/// A snapshot of the engine's workload
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EngineStats {
+    pub formulas: usize,
+    /// Edges in the dependency graph
+    pub dependencies: usize,
+    /// Current warnings, in row-major order of their cells
+    pub warnings: Vec<FormulaWarning>,
+    /// Edits that can be undone (filled in by `SheetEngine::stats`)
+    #[serde(default)]
+    pub undo_depth: usize,
+    /// Approximate memory those edits take, in bytes
+    #[serde(default)]
+    pub undo_bytes: usize,
+}
+
+impl FormulaNode {
+    /// Cells covered by the node's references, counting each range by its area
+    pub(crate) fn referenced_cells(&self) -> u64 {
+        match self {
+            FormulaNode::CellRef { .. } => 1,
+            FormulaNode::Range { start, end, .. } => {
+                let rows = u64::from(start.row.abs_diff(end.row)) + 1;
+                let cols = u64::from(start.col.abs_diff(end.col)) + 1;
+                rows * cols
+            }
+            FormulaNode::BinaryOp { left, right, .. } => left.referenced_cells() + right.referenced_cells(),
+            FormulaNode::UnaryOp { operand, .. } => operand.referenced_cells(),
+            FormulaNode::Function { args, .. } => args.iter().map(FormulaNode::referenced_cells).sum(),
+            FormulaNode::Number { .. } | FormulaNode::Text { .. } | FormulaNode::Boolean { .. } | FormulaNode::RefError => 0,
+        }
+    }
+}
+
+impl FormulaEngine {
+    /// Counts of formulas and dependencies, with any warnings
+    pub fn stats(&self) -> EngineStats {
+        let mut warnings: Vec<(CellRef, FormulaWarning)> = self.large_formulas.iter()
+            .map(|(&cell, &referenced_cells)| {
+                (cell, FormulaWarning::LargeRange { cell, referenced_cells, threshold: self.large_formula_cells })
+            })
+            .collect();
+        warnings.sort_by_key(|(cell, _)| (cell.row, cell.col));
+        EngineStats {
+            formulas: self.formulas.len(),
+            dependencies: self.dep_graph.edge_count(),
+            warnings: warnings.into_iter().map(|(_, warning)| warning).collect(),
+            undo_depth: 0,
+            undo_bytes: 0,
+        }
+    }
+
+    /// Warn about formulas covering more than `cells` cells
+    ///
+    /// Applies to formulas registered from now on.
+    pub fn set_large_formula_threshold(&mut self, cells: u64) {
+        self.large_formula_cells = cells;
+    }
+
+    /// Record or clear the size warning for a formula being registered
+    pub(crate) fn check_formula_size(&mut self, cell: CellRef, formula: &Formula) {
+        let referenced_cells = formula.ast.referenced_cells();
+        if referenced_cells > self.large_formula_cells {
+            self.large_formulas.insert(cell, referenced_cells);
+        } else {
+            self.large_formulas.remove(&cell);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::grid::{CellUpdate, Grid, GridPatch};
+
+    fn set_formula(grid: &mut Grid, engine: &mut FormulaEngine, row: u32, col: u32, formula: &str) {
+        let update = CellUpdate { row, col, value: None, formula: Some(formula.to_string()) };
+        grid.apply_patch(GridPatch { updates: vec![update], auto_expand: None }, engine).unwrap();
+    }
+
+    #[test]
+    fn test_oversized_range_warns() {
+        let mut grid = Grid::new(2_000, 10);
+        let mut engine = FormulaEngine::new();
+        engine.set_large_formula_threshold(1_000);
+        set_formula(&mut grid, &mut engine, 0, 5, "=SUM(A1:B600)");
+        set_formula(&mut grid, &mut engine, 1, 5, "=SUM(A1:A10)+A11");
+        set_formula(&mut grid, &mut engine, 2, 5, "=SUM(A1:A600, B1:B600)");
+
+        let stats = engine.stats();
+        assert_eq!(stats.formulas, 3);
+        assert_eq!(stats.warnings, vec![
+            FormulaWarning::LargeRange { cell: CellRef::new(0, 5), referenced_cells: 1_200, threshold: 1_000 },
+            FormulaWarning::LargeRange { cell: CellRef::new(2, 5), referenced_cells: 1_200, threshold: 1_000 },
+        ]);
+        let json = serde_json::to_string(&stats.warnings[0]).unwrap();
+        assert!(json.contains(r#""kind":"large_range""#));
+
+        // Replacing the formula clears its warning
+        set_formula(&mut grid, &mut engine, 0, 5, "=SUM(A1:A5)");
+        let update = CellUpdate { row: 2, col: 5, value: Some("1".to_string()), formula: None };
+        grid.apply_patch(GridPatch { updates: vec![update], auto_expand: None }, &mut engine).unwrap();
+        assert!(engine.stats().warnings.is_empty());
+    }
+
+    #[test]
+    fn test_size_counts_ranges_as_written() {
+        let engine = FormulaEngine::new();
+        let size = |text: &str| engine.parse(text).unwrap().ast.referenced_cells();
+        assert_eq!(size("=A1+B2*2"), 2);
+        assert_eq!(size("=SUM(B10:A1)"), 20);
+        assert_eq!(size("=SUM(A1:Z10000)"), 260_000);
+    }
+}
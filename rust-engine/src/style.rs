@@ -0,0 +1,198 @@
+//! Named cell styles, shareable between documents as a stylesheet
+//!
+//! A style is a `CellFormat` stored under a name. Cells that reference a
+//! style keep the formatting set on them directly as overrides, and their
+//! `format` is the style with the overrides merged over it. Redefining a
+//! style rewrites the format of every cell using it, so readers of
+//! `Cell::format` never need to look styles up.
+
+use crate::cell::{Cell, CellFormat, CellRef, CellValue};
+use crate::grid::{Grid, GridError};
+use indexmap::IndexMap;
+use serde::{Deserialize, Serialize};
+
+/// Styles defined on a sheet, in definition order
+///
+/// Serializes as a plain `{ name: format }` object, which is also the
+/// stylesheet format of `Grid::export_styles`.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct StyleSheet {
+    styles: IndexMap<String, CellFormat>,
+}
+
+impl StyleSheet {
+    pub fn get(&self, name: &str) -> Option<&CellFormat> {
+        self.styles.get(name)
+    }
+
+    pub fn names(&self) -> Vec<&str> {
+        self.styles.keys().map(String::as_str).collect()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.styles.is_empty()
+    }
+}
+
+/// Recompute a styled cell's format from its style and overrides
+///
+/// A style missing from the sheet contributes nothing.
+pub(crate) fn restyle(cell: &mut Cell, style: Option<&CellFormat>) {
+    let mut format = style.cloned().unwrap_or_default();
+    if let Some(overrides) = &cell.format_overrides {
+        format.merge(overrides.clone());
+    }
+    cell.format = Some(format);
+}
+
+impl Grid {
+    /// The sheet's named styles
+    pub fn styles(&self) -> &StyleSheet {
+        &self.styles
+    }
+
+    /// Define (or redefine) style `name`, returning the cells whose format it changed
+    pub fn define_style(&mut self, name: &str, format: CellFormat) -> Vec<CellRef> {
+        self.styles.styles.insert(name.to_string(), format);
+        let cells = self.cells_with_style(name);
+        let style = self.styles.styles.get(name);
+        for &cell_ref in &cells {
+            if let Some(cell) = self.columns.get_mut(&cell_ref.col).and_then(|column| column.get_mut(cell_ref.row)) {
+                restyle(cell, style);
+            }
+        }
+        self.touch();
+        cells
+    }
+
+    /// Give every cell between `start` and `end` style `name`
+    ///
+    /// Like applying a cell style in Excel, this drops formatting previously
+    /// set on the cells; formatting set afterwards overrides the style.
+    pub fn apply_style_to_range(&mut self, start: CellRef, end: CellRef, name: &str) -> Result<Vec<CellRef>, GridError> {
+        self.check_bounds(start)?;
+        self.check_bounds(end)?;
+        let style = self.styles.styles.get(name).ok_or_else(|| GridError::UnknownStyle(name.to_string()))?;
+        let mut cells = Vec::new();
+        for col in start.col.min(end.col)..=start.col.max(end.col) {
+            let column = self.columns.entry(col).or_default();
+            for row in start.row.min(end.row)..=start.row.max(end.row) {
+                let cell = column.get_or_insert_with(row, || Cell::new(CellValue::Empty));
+                cell.style = Some(name.to_string());
+                cell.format_overrides = None;
+                restyle(cell, Some(style));
+                cells.push(CellRef::new(row, col));
+            }
+        }
+        self.touch();
+        Ok(cells)
+    }
+
+    /// The style table as JSON, for sharing with other documents
+    pub fn export_styles(&self) -> Result<String, GridError> {
+        serde_json::to_string(&self.styles).map_err(|e| GridError::Serialization(e.to_string()))
+    }
+
+    /// Define every style in an exported stylesheet
+    ///
+    /// Styles with the same name are replaced and styles only this sheet
+    /// has are kept. Returns the cells whose format changed.
+    pub fn import_styles(&mut self, json: &str) -> Result<Vec<CellRef>, GridError> {
+        let sheet: StyleSheet = serde_json::from_str(json).map_err(|e| GridError::Serialization(e.to_string()))?;
+        let mut cells = Vec::new();
+        for (name, format) in sheet.styles {
+            cells.extend(self.define_style(&name, format));
+        }
+        Ok(cells)
+    }
+
+    fn cells_with_style(&self, name: &str) -> Vec<CellRef> {
+        let mut cells: Vec<CellRef> = self.columns.iter()
+            .flat_map(|(&col, column)| {
+                column.iter()
+                    .filter(|(_, cell)| cell.style.as_deref() == Some(name))
+                    .map(move |(row, _)| CellRef::new(row, col))
+            })
+            .collect();
+        cells.sort_by_key(|cell| (cell.row, cell.col));
+        cells
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cell::HorizontalAlign;
+
+    fn header() -> CellFormat {
+        CellFormat { font_bold: Some(true), bg_color: Some("#1f4e79".to_string()), ..Default::default() }
+    }
+
+    fn format_at(grid: &Grid, a1: &str) -> CellFormat {
+        grid.get_cell(CellRef::parse(a1).unwrap()).and_then(|cell| cell.format.clone()).unwrap_or_default()
+    }
+
+    #[test]
+    fn test_overrides_win_over_style() {
+        let mut grid = Grid::new(10, 10);
+        grid.define_style("header", header());
+        grid.set_format(CellRef::new(0, 0), CellFormat { font_italic: Some(true), ..Default::default() }).unwrap();
+        let cells = grid.apply_style_to_range(CellRef::new(0, 0), CellRef::new(0, 2), "header").unwrap();
+        assert_eq!(cells.len(), 3);
+        // Applying the style dropped A1's italic
+        assert_eq!(format_at(&grid, "A1"), header());
+
+        grid.set_format(CellRef::new(0, 1), CellFormat { bg_color: Some("#ffffff".to_string()), align_h: Some(HorizontalAlign::Right), ..Default::default() }).unwrap();
+        let b1 = format_at(&grid, "B1");
+        assert_eq!(b1.font_bold, Some(true));
+        assert_eq!(b1.bg_color.as_deref(), Some("#ffffff"));
+        assert_eq!(b1.align_h, Some(HorizontalAlign::Right));
+
+        assert!(matches!(
+            grid.apply_style_to_range(CellRef::new(1, 0), CellRef::new(1, 0), "missing"),
+            Err(GridError::UnknownStyle(_))
+        ));
+    }
+
+    #[test]
+    fn test_redefinition_updates_cells() {
+        let mut grid = Grid::new(10, 10);
+        grid.define_style("header", header());
+        grid.apply_style_to_range(CellRef::new(0, 0), CellRef::new(0, 1), "header").unwrap();
+        grid.set_format(CellRef::new(0, 1), CellFormat { bg_color: Some("#ffffff".to_string()), ..Default::default() }).unwrap();
+        let revision = grid.revision();
+
+        let changed = grid.define_style("header", CellFormat { font_bold: Some(false), bg_color: Some("#000000".to_string()), ..Default::default() });
+        assert_eq!(changed, vec![CellRef::new(0, 0), CellRef::new(0, 1)]);
+        assert!(grid.revision() > revision);
+        assert_eq!(format_at(&grid, "A1").bg_color.as_deref(), Some("#000000"));
+        assert_eq!(format_at(&grid, "B1").font_bold, Some(false));
+        assert_eq!(format_at(&grid, "B1").bg_color.as_deref(), Some("#ffffff"));
+
+        // The style reference survives a save and reload
+        let mut reloaded = Grid::from_json(&grid.to_json().unwrap()).unwrap();
+        reloaded.define_style("header", header());
+        assert_eq!(format_at(&reloaded, "A1"), header());
+    }
+
+    #[test]
+    fn test_import_styles_into_other_document() {
+        let mut template = Grid::new(10, 10);
+        template.define_style("header", header());
+        template.define_style("currency", CellFormat { number_format: Some("$#,##0.00".to_string()), ..Default::default() });
+        let stylesheet = template.export_styles().unwrap();
+
+        let mut report = Grid::new(10, 10);
+        report.define_style("header", CellFormat { font_italic: Some(true), ..Default::default() });
+        report.define_style("note", CellFormat { font_size: Some(9.0), ..Default::default() });
+        report.apply_style_to_range(CellRef::new(0, 0), CellRef::new(0, 0), "header").unwrap();
+
+        let changed = report.import_styles(&stylesheet).unwrap();
+        assert_eq!(changed, vec![CellRef::new(0, 0)]);
+        assert_eq!(report.styles().names(), vec!["header", "note", "currency"]);
+        assert_eq!(format_at(&report, "A1"), header());
+
+        assert!(matches!(report.import_styles("not json"), Err(GridError::Serialization(_))));
+    }
+}
@@ -0,0 +1,23 @@
+//! Helpers shared by the unit tests
+
+use crate::cell::CellRef;
+use crate::formula::FormulaEngine;
+use crate::grid::{CellUpdate, Grid, GridPatch};
+
+/// Enter `input` into `a1` as a one-cell patch, the way the UI does, returning the changed cells
+///
+/// Input starting with `=` is a formula; anything else is typed as a value.
+pub(crate) fn set(grid: &mut Grid, engine: &mut FormulaEngine, a1: &str, input: &str) -> Vec<CellRef> {
+    let cell = CellRef::parse(a1).unwrap();
+    set_at(grid, engine, cell.row, cell.col, input)
+}
+
+/// Like `set`, for the cell at `row` and `col`
+pub(crate) fn set_at(grid: &mut Grid, engine: &mut FormulaEngine, row: u32, col: u32, input: &str) -> Vec<CellRef> {
+    let update = if input.starts_with('=') {
+        CellUpdate { row, col, value: None, formula: Some(input.to_string()) }
+    } else {
+        CellUpdate { row, col, value: Some(input.to_string()), formula: None }
+    };
+    grid.apply_patch(GridPatch { updates: vec![update], auto_expand: None }, engine).unwrap()
+}
@@ -0,0 +1,763 @@
+//! Undo and redo of edits
+//!
+//! Each recorded edit is stored as its inverse: the `Edit` that puts the
+//! sheet back the way it was. Cell edits keep the prior contents of the
+//! cells they touch (value, formula, format, note and validation); an
+//! insertion of rows or columns is undone by deleting them again, and a
+//! deletion by inserting blank lines and putting back the deleted cells,
+//! their sizes, every formula the deletion rewrote and the named ranges,
+//! scenario inputs and chart bindings it moved or dropped. Applying an inverse
+//! yields the inverse of that, which is what redo applies.
+//!
+//! Spilled values aren't kept: recalculation spills them again. The grid
+//! doesn't shrink back when an undone edit had grown it.
+//!
+//! History is bounded in memory as well as in depth. Snapshots of more than
+//! `PACK_THRESHOLD_CELLS` cells are kept serialized, which for typical cells
+//! is several times smaller than the cells themselves. An entry is sized
+//! by its serialized snapshot; one over the per-entry budget can't be
+//! undone and clears the history, and the oldest entries are dropped while
+//! the total is over budget. Keystroke-by-keystroke edits of one cell are
+//! coalesced into a single entry, which undoes to the state before the
+//! first of them.
+
+use crate::cell::{Cell, CellRef, CellValue};
+use crate::chart::ChartStore;
+use crate::formula::FormulaEngine;
+use crate::grid::{Grid, GridError};
+use crate::reference::Axis;
+use crate::scenario::ScenarioStore;
+use std::collections::{BTreeMap, VecDeque};
+
+/// Default for how many edits `UndoStack` keeps
+pub const DEFAULT_UNDO_DEPTH: usize = 100;
+
+/// Default cap on the size of one entry, in bytes
+pub const DEFAULT_UNDO_ENTRY_BYTES: usize = 16 * 1024 * 1024;
+
+/// Default cap on the size of all entries together, in bytes
+pub const DEFAULT_UNDO_TOTAL_BYTES: usize = 64 * 1024 * 1024;
+
+/// Default time between edits of one cell for them to share an entry
+pub const DEFAULT_COALESCE_MILLIS: f64 = 1000.0;
+
+/// Default cap on the edits one coalesced entry covers
+pub const DEFAULT_COALESCE_EDITS: usize = 100;
+
+/// Snapshots of more cells than this are kept serialized
+const PACK_THRESHOLD_CELLS: usize = 256;
+
+/// A cell's contents to put back, `None` for a blank cell
+type CellState = (CellRef, Option<Cell>);
+
+/// Cell contents kept by an edit, serialized when there are many
+#[derive(Debug, Clone)]
+pub(crate) enum Snapshot {
+    Cells(Vec<CellState>),
+    Packed(Box<[u8]>),
+}
+
+impl Snapshot {
+    fn new(states: Vec<CellState>) -> Self {
+        if states.len() > PACK_THRESHOLD_CELLS {
+            if let Ok(packed) = serde_json::to_vec(&states) {
+                return Snapshot::Packed(packed.into_boxed_slice());
+            }
+        }
+        Snapshot::Cells(states)
+    }
+
+    fn is_empty(&self) -> bool {
+        match self {
+            Snapshot::Cells(states) => states.is_empty(),
+            Snapshot::Packed(_) => false,
+        }
+    }
+
+    /// Serialized size in bytes
+    fn bytes(&self) -> usize {
+        match self {
+            Snapshot::Cells(states) => serde_json::to_vec(states).map_or(0, |bytes| bytes.len()),
+            Snapshot::Packed(packed) => packed.len(),
+        }
+    }
+
+    /// The one cell kept, if there's exactly one
+    fn single_cell(&self) -> Option<CellRef> {
+        match self {
+            Snapshot::Cells(states) if states.len() == 1 => Some(states[0].0),
+            _ => None,
+        }
+    }
+
+    fn into_states(self) -> Vec<CellState> {
+        match self {
+            Snapshot::Cells(states) => states,
+            // Only ever unpacks what `new` packed
+            Snapshot::Packed(packed) => serde_json::from_slice(&packed).unwrap_or_default(),
+        }
+    }
+}
+
+/// The sheet's references to cells kept outside them, which deleting lines rewrites
+#[derive(Debug, Clone)]
+pub(crate) struct Anchors {
+    names: BTreeMap<String, (CellRef, CellRef)>,
+    scenarios: ScenarioStore,
+    charts: ChartStore,
+}
+
+impl Anchors {
+    fn capture(grid: &Grid) -> Self {
+        Self { names: grid.names.clone(), scenarios: grid.scenarios.clone(), charts: grid.charts.clone() }
+    }
+
+    /// Serialized size in bytes
+    fn bytes(&self) -> usize {
+        [
+            serde_json::to_vec(&self.names).map_or(0, |bytes| bytes.len()),
+            serde_json::to_vec(&self.scenarios).map_or(0, |bytes| bytes.len()),
+            serde_json::to_vec(&self.charts).map_or(0, |bytes| bytes.len()),
+        ]
+        .iter()
+        .sum()
+    }
+}
+
+/// A reversible change to the sheet
+#[derive(Debug, Clone)]
+pub(crate) enum Edit {
+    /// Put cells back to the given contents
+    Cells(Snapshot),
+    /// Delete `count` lines from `start`
+    DeleteLines { axis: Axis, start: u32, count: u32 },
+    /// Insert `count` blank lines at `start`, then restore their layouts and cells
+    InsertLines {
+        axis: Axis,
+        start: u32,
+        count: u32,
+        /// Each line's stored size and hidden flag
+        layouts: Vec<(u32, Option<f32>, bool)>,
+        cells: Snapshot,
+        /// Names, scenarios and charts as they were before the lines were deleted
+        anchors: Option<Box<Anchors>>,
+    },
+}
+
+impl Edit {
+    /// The edit putting `cells` back as they are now
+    pub(crate) fn capture(grid: &Grid, formula_engine: &FormulaEngine, cells: impl IntoIterator<Item = CellRef>) -> Self {
+        Edit::Cells(Snapshot::new(cell_states(grid, formula_engine, cells)))
+    }
+
+    /// The edit inserting `count` blank lines at `start`
+    pub(crate) fn insert_lines(axis: Axis, start: u32, count: u32) -> Self {
+        Edit::InsertLines { axis, start, count, layouts: Vec::new(), cells: Snapshot::Cells(Vec::new()), anchors: None }
+    }
+
+    /// Whether applying the edit would change nothing
+    fn is_empty(&self) -> bool {
+        match self {
+            Edit::Cells(cells) => cells.is_empty(),
+            Edit::DeleteLines { count, .. } | Edit::InsertLines { count, .. } => *count == 0,
+        }
+    }
+
+    /// Approximate size in bytes
+    fn bytes(&self) -> usize {
+        std::mem::size_of::<Edit>() + match self {
+            Edit::Cells(cells) => cells.bytes(),
+            Edit::DeleteLines { .. } => 0,
+            Edit::InsertLines { layouts, cells, anchors, .. } => {
+                layouts.len() * std::mem::size_of::<(u32, Option<f32>, bool)>()
+                    + cells.bytes()
+                    + anchors.as_ref().map_or(0, |anchors| anchors.bytes())
+            }
+        }
+    }
+
+    /// Make the change, returning the edit that reverts it and the cells it changed
+    pub(crate) fn apply(self, grid: &mut Grid, formula_engine: &mut FormulaEngine) -> Result<(Edit, Vec<CellRef>), GridError> {
+        match self {
+            Edit::Cells(cells) => {
+                let cells = cells.into_states();
+                let inverse = Edit::capture(grid, formula_engine, cells.iter().map(|(cell_ref, _)| *cell_ref));
+                let targets: Vec<CellRef> = cells.iter().map(|(cell_ref, _)| *cell_ref).collect();
+                for (cell_ref, cell) in cells {
+                    grid.restore_cell(cell_ref, cell, formula_engine)?;
+                }
+                Ok((inverse, grid.recalculate_cells(targets, formula_engine)?))
+            }
+            Edit::DeleteLines { axis, start, count } => {
+                let extent = match axis {
+                    Axis::Rows => grid.rows,
+                    Axis::Cols => grid.cols,
+                };
+                let count = count.min(extent.saturating_sub(start));
+                let end = start + count;
+                let along = |cell: &CellRef| match axis {
+                    Axis::Rows => cell.row,
+                    Axis::Cols => cell.col,
+                };
+                // The deleted cells, and every formula, since deletion may rewrite any of them
+                let mut kept: Vec<CellRef> = grid.columns.iter()
+                    .flat_map(|(&col, column)| {
+                        column.iter().filter_map(move |(row, cell)| {
+                            let cell_ref = CellRef::new(row, col);
+                            (cell.formula.is_some() || (start..end).contains(&along(&cell_ref))).then_some(cell_ref)
+                        })
+                    })
+                    .collect();
+                kept.sort_by_key(|cell| (cell.row, cell.col));
+                let cells = Snapshot::new(cell_states(grid, formula_engine, kept));
+                let layouts = (start..end)
+                    .map(|line| {
+                        let (size, hidden) = grid.line_layout(axis, line);
+                        (line, size, hidden)
+                    })
+                    .collect();
+                let anchors = Some(Box::new(Anchors::capture(grid)));
+                let changed = match axis {
+                    Axis::Rows => grid.delete_rows(start, count, formula_engine)?,
+                    Axis::Cols => grid.delete_cols(start, count, formula_engine)?,
+                };
+                Ok((Edit::InsertLines { axis, start, count, layouts, cells, anchors }, changed))
+            }
+            Edit::InsertLines { axis, start, count, layouts, cells, anchors } => {
+                let mut changed = match axis {
+                    Axis::Rows => grid.insert_rows(start, count, formula_engine)?,
+                    Axis::Cols => grid.insert_cols(start, count, formula_engine)?,
+                };
+                for (line, size, hidden) in layouts {
+                    grid.set_line_layout(axis, line, size, hidden);
+                }
+                // Names go back first, so restored formulas using them parse
+                let renamed = anchors.is_some_and(|anchors| {
+                    let Anchors { names, scenarios, charts } = *anchors;
+                    grid.scenarios = scenarios;
+                    grid.charts = charts;
+                    let renamed = grid.names != names;
+                    grid.names = names;
+                    formula_engine.named_ranges = grid.names.clone().into_iter().collect();
+                    renamed
+                });
+                if !cells.is_empty() || renamed {
+                    formula_engine.clear_spills(grid)?;
+                    for (cell_ref, cell) in cells.into_states() {
+                        grid.restore_cell(cell_ref, cell, formula_engine)?;
+                        changed.push(cell_ref);
+                    }
+                    formula_engine.load_formulas(grid);
+                    changed.extend(formula_engine.recalculate_all(grid)?);
+                }
+                Ok((Edit::DeleteLines { axis, start, count }, changed))
+            }
+        }
+    }
+}
+
+/// Current contents of `cells`, with spilled values left out
+fn cell_states(grid: &Grid, formula_engine: &FormulaEngine, cells: impl IntoIterator<Item = CellRef>) -> Vec<CellState> {
+    let spilled = formula_engine.spilled_cell_set();
+    cells.into_iter()
+        .map(|cell_ref| {
+            let cell = grid.get_cell(cell_ref).cloned().map(|mut cell| {
+                if spilled.contains(&cell_ref) {
+                    cell.value = CellValue::Empty;
+                }
+                cell
+            });
+            (cell_ref, cell.filter(|cell| !cell.is_blank()))
+        })
+        .collect()
+}
+
+/// A recorded edit's inverse, with what's needed to budget and coalesce it
+#[derive(Debug, Clone)]
+struct Entry {
+    inverse: Edit,
+    bytes: usize,
+    /// For an edit of one cell by `record_typing`: the cell, when it was last
+    /// edited and how many edits the entry covers
+    typing: Option<(CellRef, f64, usize)>,
+}
+
+impl Entry {
+    fn new(inverse: Edit) -> Self {
+        Self { bytes: inverse.bytes(), inverse, typing: None }
+    }
+}
+
+/// Edits that can be undone, and undone edits that can be redone
+#[derive(Debug, Clone)]
+pub struct UndoStack {
+    /// Inverses of past edits, oldest first
+    undo: VecDeque<Entry>,
+    /// Inverses of undone edits, most recently undone last
+    redo: Vec<Entry>,
+    depth: usize,
+    entry_bytes: usize,
+    total_bytes: usize,
+    coalesce_millis: f64,
+    coalesce_edits: usize,
+}
+
+impl Default for UndoStack {
+    fn default() -> Self {
+        Self::new(DEFAULT_UNDO_DEPTH)
+    }
+}
+
+impl UndoStack {
+    /// A stack keeping the last `depth` edits, within the default byte budget
+    pub fn new(depth: usize) -> Self {
+        Self {
+            undo: VecDeque::new(),
+            redo: Vec::new(),
+            depth,
+            entry_bytes: DEFAULT_UNDO_ENTRY_BYTES,
+            total_bytes: DEFAULT_UNDO_TOTAL_BYTES,
+            coalesce_millis: DEFAULT_COALESCE_MILLIS,
+            coalesce_edits: DEFAULT_COALESCE_EDITS,
+        }
+    }
+
+    /// Keep at most `depth` edits, forgetting the oldest ones past it
+    pub fn set_depth(&mut self, depth: usize) {
+        self.depth = depth;
+        self.trim();
+    }
+
+    /// Cap one entry at `entry_bytes` and all of them at `total_bytes`, forgetting the oldest past it
+    pub fn set_byte_budget(&mut self, entry_bytes: usize, total_bytes: usize) {
+        self.entry_bytes = entry_bytes;
+        self.total_bytes = total_bytes;
+        self.trim();
+    }
+
+    /// Coalesce edits of one cell made less than `millis` apart, up to `max_edits` per entry
+    ///
+    /// A `max_edits` of 1 or less turns coalescing off.
+    pub fn set_coalescing(&mut self, millis: f64, max_edits: usize) {
+        self.coalesce_millis = millis;
+        self.coalesce_edits = max_edits;
+    }
+
+    pub fn can_undo(&self) -> bool {
+        !self.undo.is_empty()
+    }
+
+    pub fn can_redo(&self) -> bool {
+        !self.redo.is_empty()
+    }
+
+    /// How many edits can be undone
+    pub fn len(&self) -> usize {
+        self.undo.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.undo.is_empty()
+    }
+
+    /// Approximate size of the edits that can be undone, in bytes
+    pub fn bytes(&self) -> usize {
+        self.undo.iter().map(|entry| entry.bytes).sum()
+    }
+
+    /// Forget all history, as when a different sheet is loaded
+    pub fn clear(&mut self) {
+        self.undo.clear();
+        self.redo.clear();
+    }
+
+    /// Forget what could be redone, after a change to the sheet that isn't recorded
+    ///
+    /// Redoing an edit over such a change would put back contents from
+    /// before it, so the change ends the redo chain as a recorded edit would.
+    pub(crate) fn clear_redo(&mut self) {
+        self.redo.clear();
+    }
+
+    /// Remember how to revert a new edit; anything undone can no longer be redone
+    pub(crate) fn record(&mut self, inverse: Edit) {
+        if inverse.is_empty() {
+            return;
+        }
+        self.redo.clear();
+        self.push_undo(Entry::new(inverse));
+    }
+
+    /// Like `record`, but folds an edit of the cell the previous typed edit
+    /// changed into that entry, when it follows within the coalescing window
+    ///
+    /// The entry keeps the first edit's inverse, so it undoes all of them.
+    pub(crate) fn record_typing(&mut self, inverse: Edit, now_millis: f64) {
+        let Edit::Cells(cells) = &inverse else {
+            return self.record(inverse);
+        };
+        let Some(cell) = cells.single_cell() else {
+            return self.record(inverse);
+        };
+        if self.redo.is_empty() {
+            if let Some(Entry { typing: Some((last_cell, last, edits)), .. }) = self.undo.back_mut() {
+                let in_window = now_millis - *last < self.coalesce_millis && *edits < self.coalesce_edits;
+                if *last_cell == cell && in_window {
+                    *last = now_millis;
+                    *edits += 1;
+                    return;
+                }
+            }
+        }
+        self.redo.clear();
+        self.push_undo(Entry { typing: Some((cell, now_millis, 1)), ..Entry::new(inverse) });
+    }
+
+    /// Apply `edit` and remember how to revert it, returning the changed cells
+    pub(crate) fn apply(&mut self, edit: Edit, grid: &mut Grid, formula_engine: &mut FormulaEngine) -> Result<Vec<CellRef>, GridError> {
+        let (inverse, changed) = edit.apply(grid, formula_engine)?;
+        self.record(inverse);
+        Ok(changed)
+    }
+
+    /// Revert the latest edit, returning the changed cells, or `None` if there's none
+    pub fn undo(&mut self, grid: &mut Grid, formula_engine: &mut FormulaEngine) -> Result<Option<Vec<CellRef>>, GridError> {
+        let Some(entry) = self.undo.pop_back() else {
+            return Ok(None);
+        };
+        let (inverse, changed) = entry.inverse.apply(grid, formula_engine)?;
+        self.redo.push(Entry::new(inverse));
+        Ok(Some(changed))
+    }
+
+    /// Make the latest undone edit again, returning the changed cells, or `None` if there's none
+    pub fn redo(&mut self, grid: &mut Grid, formula_engine: &mut FormulaEngine) -> Result<Option<Vec<CellRef>>, GridError> {
+        let Some(entry) = self.redo.pop() else {
+            return Ok(None);
+        };
+        let (inverse, changed) = entry.inverse.apply(grid, formula_engine)?;
+        self.push_undo(Entry::new(inverse));
+        Ok(Some(changed))
+    }
+
+    fn push_undo(&mut self, entry: Entry) {
+        // Older entries undo to states before this edit, so without it they're useless too
+        if entry.bytes > self.entry_bytes {
+            self.undo.clear();
+            return;
+        }
+        self.undo.push_back(entry);
+        self.trim();
+    }
+
+    /// Drop the oldest entries past the depth or the total byte budget
+    fn trim(&mut self) {
+        let mut bytes = self.bytes();
+        while self.undo.len() > self.depth || bytes > self.total_bytes {
+            let Some(oldest) = self.undo.pop_front() else {
+                break;
+            };
+            bytes -= oldest.bytes;
+        }
+    }
+}
+
+impl Grid {
+    /// Put a cell back exactly as captured, registering any formula it holds
+    fn restore_cell(&mut self, cell_ref: CellRef, cell: Option<Cell>, formula_engine: &mut FormulaEngine) -> Result<(), GridError> {
+        self.set_cell_value(cell_ref, CellValue::Empty, formula_engine)?;
+        if let Some(column) = self.columns.get_mut(&cell_ref.col) {
+            column.remove(cell_ref.row);
+            if column.is_empty() {
+                self.columns.remove(&cell_ref.col);
+            }
+        }
+        let Some(cell) = cell else {
+            return Ok(());
+        };
+        let formula = cell.formula.clone().filter(|_| !cell.foreign);
+        self.columns.entry(cell_ref.col).or_default().insert(cell_ref.row, cell);
+        if let Some(text) = formula {
+            match formula_engine.parse_supported(&text) {
+                Ok(formula) => formula_engine.register_formula(cell_ref, formula),
+                Err(reason) => self.mark_foreign(cell_ref, reason),
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cell::CellFormat;
+    use crate::chart::SeriesOrientation;
+    use crate::clock::ManualClock;
+    use crate::scenario::ScenarioCellSpec;
+    use crate::test_support;
+    use std::rc::Rc;
+
+    /// Type `input` into `a1` the way `SheetEngine::apply_patch` does, recording it
+    fn set(history: &mut UndoStack, grid: &mut Grid, engine: &mut FormulaEngine, a1: &str, input: &str) {
+        let prior = Edit::capture(grid, engine, [CellRef::parse(a1).unwrap()]);
+        test_support::set(grid, engine, a1, input);
+        history.record(prior);
+    }
+
+    fn value(grid: &Grid, a1: &str) -> CellValue {
+        grid.get_cell(CellRef::parse(a1).unwrap()).map(|cell| cell.value.clone()).unwrap_or(CellValue::Empty)
+    }
+
+    #[test]
+    fn test_undo_and_redo_a_value() {
+        let (mut grid, mut engine, mut history) = (Grid::new(10, 10), FormulaEngine::new(), UndoStack::default());
+        set(&mut history, &mut grid, &mut engine, "A1", "5");
+        set(&mut history, &mut grid, &mut engine, "B1", "=A1*2");
+        set(&mut history, &mut grid, &mut engine, "A1", "7");
+        assert_eq!(value(&grid, "B1"), CellValue::Number(14.0));
+
+        let changed = history.undo(&mut grid, &mut engine).unwrap().unwrap();
+        assert!(changed.contains(&CellRef::new(0, 1)));
+        assert_eq!(value(&grid, "A1"), CellValue::Number(5.0));
+        assert_eq!(value(&grid, "B1"), CellValue::Number(10.0));
+        assert!(history.can_redo());
+
+        history.redo(&mut grid, &mut engine).unwrap().unwrap();
+        assert_eq!(value(&grid, "A1"), CellValue::Number(7.0));
+        assert_eq!(value(&grid, "B1"), CellValue::Number(14.0));
+        assert!(!history.can_redo());
+
+        // Undoing a formula takes its cell back to blank and stops it tracking A1
+        history.undo(&mut grid, &mut engine).unwrap();
+        history.undo(&mut grid, &mut engine).unwrap();
+        assert_eq!(value(&grid, "B1"), CellValue::Empty);
+        assert!(engine.formula_at(CellRef::new(0, 1)).is_none());
+        history.undo(&mut grid, &mut engine).unwrap();
+        assert_eq!(value(&grid, "A1"), CellValue::Empty);
+        assert_eq!(history.undo(&mut grid, &mut engine).unwrap(), None);
+    }
+
+    #[test]
+    fn test_new_edit_clears_redo() {
+        let (mut grid, mut engine, mut history) = (Grid::new(10, 10), FormulaEngine::new(), UndoStack::default());
+        set(&mut history, &mut grid, &mut engine, "A1", "1");
+        set(&mut history, &mut grid, &mut engine, "A1", "2");
+        history.undo(&mut grid, &mut engine).unwrap();
+        set(&mut history, &mut grid, &mut engine, "A1", "3");
+        assert!(!history.can_redo());
+        assert_eq!(history.redo(&mut grid, &mut engine).unwrap(), None);
+        history.undo(&mut grid, &mut engine).unwrap();
+        assert_eq!(value(&grid, "A1"), CellValue::Number(1.0));
+    }
+
+    #[test]
+    fn test_undo_style() {
+        let (mut grid, mut engine, mut history) = (Grid::new(10, 10), FormulaEngine::new(), UndoStack::default());
+        set(&mut history, &mut grid, &mut engine, "A1", "1");
+        grid.define_style("Total", CellFormat { font_bold: Some(true), ..CellFormat::default() });
+        let (start, end) = (CellRef::new(0, 0), CellRef::new(1, 0));
+        let prior = Edit::capture(&grid, &engine, [start, end]);
+        grid.apply_style_to_range(start, end, "Total").unwrap();
+        history.record(prior);
+
+        // The styled range undoes to its unstyled cells
+        history.undo(&mut grid, &mut engine).unwrap();
+        assert_eq!(grid.get_cell(start).unwrap().style, None);
+        assert!(grid.get_cell(end).is_none());
+        assert_eq!(value(&grid, "A1"), CellValue::Number(1.0));
+        history.redo(&mut grid, &mut engine).unwrap();
+        assert_eq!(grid.get_cell(end).unwrap().style.as_deref(), Some("Total"));
+    }
+
+    #[test]
+    fn test_depth_caps_history() {
+        let (mut grid, mut engine, mut history) = (Grid::new(10, 10), FormulaEngine::new(), UndoStack::new(2));
+        for n in 1..=4 {
+            set(&mut history, &mut grid, &mut engine, "A1", &n.to_string());
+        }
+        history.undo(&mut grid, &mut engine).unwrap();
+        history.undo(&mut grid, &mut engine).unwrap();
+        assert!(!history.can_undo());
+        assert_eq!(value(&grid, "A1"), CellValue::Number(2.0));
+
+        history.set_depth(1);
+        assert!(!history.can_undo());
+    }
+
+    #[test]
+    fn test_undo_format() {
+        let (mut grid, mut engine, mut history) = (Grid::new(10, 10), FormulaEngine::new(), UndoStack::default());
+        set(&mut history, &mut grid, &mut engine, "A1", "0.5");
+        let prior = Edit::capture(&grid, &engine, [CellRef::new(0, 0), CellRef::new(1, 0)]);
+        let format = CellFormat { number_format: Some("0%".to_string()), ..Default::default() };
+        grid.apply_format_to_range(0, 0, 1, 0, format).unwrap();
+        history.record(prior);
+        assert_eq!(grid.get_cell(CellRef::new(0, 0)).unwrap().display(), "50%");
+
+        history.undo(&mut grid, &mut engine).unwrap();
+        assert_eq!(grid.get_cell(CellRef::new(0, 0)).unwrap().format, None);
+        assert!(grid.get_cell(CellRef::new(1, 0)).is_none());
+        assert_eq!(value(&grid, "A1"), CellValue::Number(0.5));
+        history.redo(&mut grid, &mut engine).unwrap();
+        assert_eq!(grid.get_cell(CellRef::new(0, 0)).unwrap().display(), "50%");
+    }
+
+    #[test]
+    fn test_undo_row_edits() {
+        let (mut grid, mut engine, mut history) = (Grid::new(10, 10), FormulaEngine::new(), UndoStack::default());
+        set(&mut history, &mut grid, &mut engine, "A1", "1");
+        set(&mut history, &mut grid, &mut engine, "A2", "2");
+        set(&mut history, &mut grid, &mut engine, "A3", "3");
+        set(&mut history, &mut grid, &mut engine, "B1", "=A2*10");
+        set(&mut history, &mut grid, &mut engine, "B2", "=SUM(A1:A3)");
+        grid.set_row_height(1, 40.0).unwrap();
+
+        history.apply(Edit::DeleteLines { axis: Axis::Rows, start: 1, count: 1 }, &mut grid, &mut engine).unwrap();
+        assert_eq!(value(&grid, "B1"), CellValue::Error("REF".to_string()));
+        assert_eq!(value(&grid, "A2"), CellValue::Number(3.0));
+
+        // The deleted row comes back with its size, and formulas lose their #REF
+        history.undo(&mut grid, &mut engine).unwrap();
+        assert_eq!(value(&grid, "A2"), CellValue::Number(2.0));
+        assert_eq!(value(&grid, "B1"), CellValue::Number(20.0));
+        assert_eq!(value(&grid, "B2"), CellValue::Number(6.0));
+        assert_eq!(engine.formula_at(CellRef::new(0, 1)).unwrap().raw, "=A2*10");
+        assert_eq!(grid.line_layout(Axis::Rows, 1), (Some(40.0), false));
+
+        history.redo(&mut grid, &mut engine).unwrap();
+        assert_eq!(value(&grid, "A2"), CellValue::Number(3.0));
+
+        history.undo(&mut grid, &mut engine).unwrap();
+        history.apply(Edit::insert_lines(Axis::Rows, 0, 2), &mut grid, &mut engine).unwrap();
+        assert_eq!(value(&grid, "A3"), CellValue::Number(1.0));
+        assert_eq!(value(&grid, "B4"), CellValue::Number(6.0));
+        history.undo(&mut grid, &mut engine).unwrap();
+        assert_eq!(value(&grid, "A1"), CellValue::Number(1.0));
+        assert_eq!(engine.formula_at(CellRef::new(1, 1)).unwrap().raw, "=SUM(A1:A3)");
+    }
+
+    #[test]
+    fn test_undo_row_delete_restores_names_scenarios_and_charts() {
+        let (mut grid, mut engine, mut history) = (Grid::new(10, 10), FormulaEngine::new(), UndoStack::default());
+        set(&mut history, &mut grid, &mut engine, "B3", "1200");
+        set(&mut history, &mut grid, &mut engine, "B4", "5");
+        engine.define_name(&mut grid, "Revenue", CellRef::new(2, 1), CellRef::new(2, 1)).unwrap();
+        set(&mut history, &mut grid, &mut engine, "C1", "=Revenue*2");
+        grid.define_scenario("upside", &[ScenarioCellSpec { reference: "B3".to_string(), value: "1500".to_string() }]).unwrap();
+        grid.add_chart_binding("sales", CellRef::new(2, 1), CellRef::new(3, 1), SeriesOrientation::Columns, false).unwrap();
+
+        history.apply(Edit::DeleteLines { axis: Axis::Rows, start: 2, count: 1 }, &mut grid, &mut engine).unwrap();
+        assert_eq!(engine.named_range("Revenue"), None);
+        assert_eq!(value(&grid, "C1"), CellValue::Error("REF".to_string()));
+
+        history.undo(&mut grid, &mut engine).unwrap();
+        assert_eq!(value(&grid, "B3"), CellValue::Number(1200.0));
+        assert_eq!(engine.named_range("Revenue"), Some((CellRef::new(2, 1), CellRef::new(2, 1))));
+        assert!(!grid.is_foreign(CellRef::new(0, 2)));
+        assert_eq!(engine.formula_at(CellRef::new(0, 2)).unwrap().raw, "=Revenue*2");
+        assert_eq!(value(&grid, "C1"), CellValue::Number(2400.0));
+        let chart = grid.charts.get("sales").unwrap();
+        assert_eq!((chart.start, chart.end), (CellRef::new(2, 1), CellRef::new(3, 1)));
+
+        // The scenario writes to B3 again, and the name follows it
+        grid.apply_scenario("upside", &mut engine).unwrap();
+        assert_eq!(value(&grid, "C1"), CellValue::Number(3000.0));
+        grid.clear_scenario(&mut engine).unwrap();
+
+        // Redoing the delete drops them all again
+        history.redo(&mut grid, &mut engine).unwrap();
+        assert_eq!(engine.named_range("Revenue"), None);
+        assert_eq!(value(&grid, "C1"), CellValue::Error("REF".to_string()));
+        let chart = grid.charts.get("sales").unwrap();
+        assert_eq!((chart.start, chart.end), (CellRef::new(2, 1), CellRef::new(2, 1)));
+    }
+
+    /// Type `input` into `a1` `millis` after the previous keystroke, as `SheetEngine::set_cell` records it
+    fn type_into(history: &mut UndoStack, grid: &mut Grid, engine: &mut FormulaEngine, clock: &ManualClock, millis: f64, a1: &str, input: &str) {
+        clock.advance(millis);
+        let cell_ref = CellRef::parse(a1).unwrap();
+        let prior = Edit::capture(grid, engine, [cell_ref]);
+        grid.enter_value(cell_ref, input, engine).unwrap();
+        history.record_typing(prior, grid.now_millis());
+    }
+
+    #[test]
+    fn test_typing_is_coalesced() {
+        let clock = Rc::new(ManualClock::new(0.0));
+        let (mut grid, mut engine, mut history) = (Grid::with_clock(10, 10, clock.clone()), FormulaEngine::new(), UndoStack::default());
+        set(&mut history, &mut grid, &mut engine, "A1", "draft");
+        for input in ["h", "he", "hel"] {
+            type_into(&mut history, &mut grid, &mut engine, &clock, 200.0, "A1", input);
+        }
+        // A pause, or another cell, starts a new entry
+        type_into(&mut history, &mut grid, &mut engine, &clock, 1500.0, "A1", "hell");
+        type_into(&mut history, &mut grid, &mut engine, &clock, 100.0, "A1", "hello");
+        type_into(&mut history, &mut grid, &mut engine, &clock, 100.0, "B1", "x");
+        assert_eq!(history.len(), 4);
+
+        history.undo(&mut grid, &mut engine).unwrap();
+        history.undo(&mut grid, &mut engine).unwrap();
+        assert_eq!(value(&grid, "A1"), CellValue::Text("hel".to_string()));
+        // The coalesced entry goes back to before its first keystroke
+        history.undo(&mut grid, &mut engine).unwrap();
+        assert_eq!(value(&grid, "A1"), CellValue::Text("draft".to_string()));
+        history.redo(&mut grid, &mut engine).unwrap();
+        assert_eq!(value(&grid, "A1"), CellValue::Text("hel".to_string()));
+
+        // After an undo, typing never joins an older entry
+        history.undo(&mut grid, &mut engine).unwrap();
+        type_into(&mut history, &mut grid, &mut engine, &clock, 100.0, "A1", "d");
+        assert_eq!(history.len(), 2);
+
+        // A cap on edits per entry splits long runs
+        history.set_coalescing(1000.0, 2);
+        for input in ["a", "ab", "abc"] {
+            type_into(&mut history, &mut grid, &mut engine, &clock, 100.0, "C1", input);
+        }
+        assert_eq!(history.len(), 4);
+    }
+
+    #[test]
+    fn test_byte_budget_evicts_oldest() {
+        let (mut grid, mut engine, mut history) = (Grid::new(10, 10), FormulaEngine::new(), UndoStack::default());
+        set(&mut history, &mut grid, &mut engine, "A1", "0");
+        history.clear();
+        set(&mut history, &mut grid, &mut engine, "A1", "1");
+        let entry = history.bytes();
+        // Room for two entries of this size
+        history.set_byte_budget(entry * 10, entry * 2 + entry / 2);
+        for n in 2..=4 {
+            set(&mut history, &mut grid, &mut engine, "A1", &n.to_string());
+        }
+        assert_eq!(history.len(), 2);
+        assert!(history.bytes() <= entry * 2 + entry / 2);
+        history.undo(&mut grid, &mut engine).unwrap();
+        history.undo(&mut grid, &mut engine).unwrap();
+        assert_eq!(value(&grid, "A1"), CellValue::Number(2.0));
+        assert!(!history.can_undo());
+    }
+
+    #[test]
+    fn test_large_edits_are_packed() {
+        let (mut grid, mut engine, mut history) = (Grid::new(1000, 2), FormulaEngine::new(), UndoStack::default());
+        let column: Vec<CellRef> = (0..1000).map(|row| CellRef::new(row, 0)).collect();
+        for &cell in &column {
+            grid.enter_value(cell, &cell.row.to_string(), &mut engine).unwrap();
+        }
+        let prior = Edit::capture(&grid, &engine, column.iter().copied());
+        assert!(matches!(&prior, Edit::Cells(Snapshot::Packed(_))));
+        for &cell in &column {
+            grid.enter_value(cell, "", &mut engine).unwrap();
+        }
+        history.record(prior);
+        history.undo(&mut grid, &mut engine).unwrap();
+        assert_eq!(value(&grid, "A1000"), CellValue::Number(999.0));
+
+        // An edit over the per-entry budget can't be undone, and neither can anything before it
+        set(&mut history, &mut grid, &mut engine, "B1", "x");
+        history.set_byte_budget(history.bytes() - 1, usize::MAX);
+        let prior = Edit::capture(&grid, &engine, column.iter().copied());
+        history.record(prior);
+        assert!(!history.can_undo());
+    }
+}
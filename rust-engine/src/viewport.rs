@@ -3,7 +3,7 @@
 use serde::{Deserialize, Serialize};
 
 /// Represents the visible area of the grid
-#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
 pub struct Viewport {
     /// First visible row
     pub start_row: u32,
@@ -19,6 +19,12 @@ pub struct Viewport {
     pub offset_y: f32,
     /// Current zoom level (1.0 = 100%)
     pub zoom: f32,
+    /// Rows kept in view at the top while scrolling
+    #[serde(default)]
+    pub frozen_rows: u32,
+    /// Columns kept in view at the left while scrolling
+    #[serde(default)]
+    pub frozen_cols: u32,
 }
 
 impl Viewport {
@@ -31,9 +37,24 @@ impl Viewport {
             offset_x: 0.0,
             offset_y: 0.0,
             zoom: 1.0,
+            frozen_rows: 0,
+            frozen_cols: 0,
         }
     }
 
+    /// Take the scroll position, zoom and frozen panes from a saved viewport
+    ///
+    /// The visible row and column counts are kept, since they depend on the
+    /// size of the canvas rather than the document.
+    pub fn restore(&mut self, saved: &Viewport) {
+        *self = Viewport {
+            visible_rows: self.visible_rows,
+            visible_cols: self.visible_cols,
+            ..*saved
+        };
+        self.set_zoom(saved.zoom);
+    }
+
     /// Calculate the end row (exclusive)
     pub fn end_row(&self) -> u32 {
         self.start_row + self.visible_rows
@@ -143,6 +164,20 @@ mod tests {
         assert_eq!(vp.offset_x, 50.0);
     }
 
+    #[test]
+    fn test_restore_keeps_visible_size() {
+        let mut saved = Viewport::new(120, 7, 40, 12);
+        saved.scroll(10.0, 5.0, 24.0, 100.0);
+        saved.set_zoom(1.5);
+        saved.frozen_rows = 1;
+
+        let mut vp = Viewport::new(0, 0, 50, 20);
+        vp.restore(&saved);
+        assert_eq!((vp.start_row, vp.start_col, vp.offset_x, vp.offset_y), (120, 7, 10.0, 5.0));
+        assert_eq!((vp.zoom, vp.frozen_rows, vp.frozen_cols), (1.5, 1, 0));
+        assert_eq!((vp.visible_rows, vp.visible_cols), (50, 20));
+    }
+
     #[test]
     fn test_zoom() {
         let mut vp = Viewport::new(0, 0, 50, 20);
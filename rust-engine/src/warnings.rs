@@ -0,0 +1,241 @@
+//! Warnings about edits that succeeded only after the engine adjusted them
+//!
+//! Errors stop an edit; warnings don't. A pasted `007` is stored as the
+//! number 7, a font size of 1000 is clamped to 409 and a formula may sum a
+//! range that has nothing in it yet. Each of these still succeeds, but the
+//! host may want to tell the user, so the grid records a warning for every
+//! adjustment. `SheetEngine` drains them after each edit into the returned
+//! diff and keeps them for `get_last_warnings`.
+
+use crate::cell::{CellFormat, CellRef, CellValue};
+use crate::formula::{FormulaEngine, FormulaNode};
+use crate::grid::Grid;
+use serde::{Deserialize, Serialize};
+
+/// Largest font size a format may set, in points (Excel's limit)
+pub const MAX_FONT_SIZE: f32 = 409.0;
+
+/// Smallest font size a format may set, in points
+pub const MIN_FONT_SIZE: f32 = 1.0;
+
+/// What kind of adjustment a warning reports
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WarningCode {
+    /// Imported text was stored as a value that doesn't read back the same
+    ValueCoerced,
+    /// A format property was outside its allowed range and was clamped or dropped
+    FormatClamped,
+    /// A formula references a range with no values in it
+    EmptyRange,
+    /// A column width or row height was clamped to the size limits
+    SizeClamped,
+    /// A loaded formula can't be evaluated, so its cached value was kept
+    ForeignFormula,
+}
+
+/// A non-fatal problem found while applying an edit
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EngineWarning {
+    pub code: WarningCode,
+    pub message: String,
+    /// The cell concerned, or the first cell of the range concerned
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cell: Option<CellRef>,
+    /// The last cell of the range concerned, when it is more than one cell
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub end: Option<CellRef>,
+    /// The input and what was made of it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub details: Option<String>,
+}
+
+impl EngineWarning {
+    pub fn new(code: WarningCode, message: impl Into<String>) -> Self {
+        Self { code, message: message.into(), cell: None, end: None, details: None }
+    }
+
+    pub fn at(mut self, cell: CellRef) -> Self {
+        self.cell = Some(cell);
+        self
+    }
+
+    /// Attach the range `start..=end`, or just `start` when they're the same cell
+    pub fn over(mut self, start: CellRef, end: CellRef) -> Self {
+        self.cell = Some(start);
+        self.end = (start != end).then_some(end);
+        self
+    }
+
+    pub fn with_details(mut self, details: impl Into<String>) -> Self {
+        self.details = Some(details.into());
+        self
+    }
+}
+
+/// Why storing `raw` as `value` loses something, if it does
+///
+/// Leading zeros (`007`, typically an ID) and digits past the 15 an f64
+/// holds don't survive the conversion to a number.
+pub(crate) fn coercion_loss(raw: &str, value: &CellValue) -> Option<String> {
+    let CellValue::Number(n) = value else {
+        return None;
+    };
+    let text = raw.trim();
+    let unsigned = text.trim_start_matches(['-', '+']);
+    let integer_part = unsigned.split('.').next().unwrap_or("");
+    if integer_part.len() > 1 && integer_part.starts_with('0') && integer_part.bytes().all(|b| b.is_ascii_digit()) {
+        return Some(format!("'{}' was stored as {} and lost its leading zeros", text, n));
+    }
+    let mantissa = unsigned.split(['e', 'E']).next().unwrap_or("");
+    let digits: String = mantissa.chars().filter(char::is_ascii_digit).collect();
+    let mut significant = digits.trim_start_matches('0');
+    if mantissa.contains('.') {
+        significant = significant.trim_end_matches('0');
+    }
+    if significant.len() > 15 {
+        return Some(format!("'{}' has more than 15 significant digits and was stored as {}", text, n));
+    }
+    None
+}
+
+/// Clamp a format's font size into range, describing what changed
+pub(crate) fn clamp_format(format: &mut CellFormat) -> Option<String> {
+    let size = format.font_size?;
+    if !size.is_finite() {
+        format.font_size = None;
+        return Some(format!("font size {} is not a number and was ignored", size));
+    }
+    let clamped = size.clamp(MIN_FONT_SIZE, MAX_FONT_SIZE);
+    if clamped == size {
+        return None;
+    }
+    format.font_size = Some(clamped);
+    Some(format!("font size {} was clamped to {}", size, clamped))
+}
+
+/// Every range written in a formula, in the order they appear
+fn ranges(node: &FormulaNode, out: &mut Vec<(CellRef, CellRef)>) {
+    match node {
+        FormulaNode::Range { start, end, .. } => out.push((*start, *end)),
+        FormulaNode::BinaryOp { left, right, .. } => {
+            ranges(left, out);
+            ranges(right, out);
+        }
+        FormulaNode::UnaryOp { operand, .. } => ranges(operand, out),
+        FormulaNode::Function { args, .. } => args.iter().for_each(|arg| ranges(arg, out)),
+        FormulaNode::CellRef { .. }
+        | FormulaNode::Number { .. }
+        | FormulaNode::Text { .. }
+        | FormulaNode::Boolean { .. }
+        | FormulaNode::RefError => {}
+    }
+}
+
+impl Grid {
+    /// Record a warning for the edit in progress
+    pub(crate) fn warn(&mut self, warning: EngineWarning) {
+        self.warnings.push(warning);
+    }
+
+    /// Warnings recorded since the last call, oldest first
+    pub fn take_warnings(&mut self) -> Vec<EngineWarning> {
+        std::mem::take(&mut self.warnings)
+    }
+
+    /// Warn about each range referenced by the formulas in `cells` that holds nothing
+    ///
+    /// Run once a whole edit is written, so a formula pasted above its data
+    /// isn't flagged. Cells holding a formula count as filled.
+    pub(crate) fn warn_empty_ranges(&mut self, cells: &[CellRef], formula_engine: &FormulaEngine) {
+        for &cell_ref in cells {
+            let Some(formula) = formula_engine.formula_at(cell_ref) else {
+                continue;
+            };
+            let mut found = Vec::new();
+            ranges(&formula.ast, &mut found);
+            for (start, end) in found {
+                if self.range_is_empty(start, end) {
+                    self.warn(EngineWarning::new(WarningCode::EmptyRange, format!("{} references an empty range", cell_ref.to_a1()))
+                        .over(start, end)
+                        .with_details(format!("{}:{} in {}", start.to_a1(), end.to_a1(), formula.raw)));
+                }
+            }
+        }
+    }
+
+    fn range_is_empty(&self, start: CellRef, end: CellRef) -> bool {
+        let rows = start.row.min(end.row)..=start.row.max(end.row);
+        (start.col.min(end.col)..=start.col.max(end.col)).all(|col| {
+            self.columns.get(&col).is_none_or(|column| {
+                column.iter().all(|(row, cell)| {
+                    !rows.contains(&row) || (cell.value == CellValue::Empty && cell.formula.is_none())
+                })
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::grid::PasteOptions;
+
+    #[test]
+    fn test_coercing_paste_warns_and_succeeds() {
+        let mut grid = Grid::new(10, 10);
+        let mut engine = FormulaEngine::new();
+        let block = vec![
+            vec!["id".to_string(), "amount".to_string()],
+            vec!["00123".to_string(), "12345678901234567890".to_string()],
+            vec!["0.5".to_string(), "1.2500".to_string()],
+        ];
+        grid.paste_block(CellRef::new(0, 0), &block, PasteOptions::default(), &mut engine).unwrap();
+
+        assert_eq!(grid.get_cell(CellRef::new(1, 0)).unwrap().value, CellValue::Number(123.0));
+        let warnings = grid.take_warnings();
+        assert_eq!(warnings.len(), 2);
+        assert!(warnings.iter().all(|w| w.code == WarningCode::ValueCoerced));
+        assert_eq!(warnings[0].cell, Some(CellRef::new(1, 0)));
+        assert_eq!(warnings[1].cell, Some(CellRef::new(1, 1)));
+        assert!(warnings[0].details.as_deref().unwrap().contains("00123"));
+        assert!(grid.take_warnings().is_empty());
+    }
+
+    #[test]
+    fn test_clamped_format_warns_once_per_range() {
+        let mut grid = Grid::new(10, 10);
+        grid.apply_format_to_range(0, 0, 2, 1, CellFormat { font_size: Some(1000.0), ..Default::default() }).unwrap();
+
+        let format = grid.get_cell(CellRef::new(2, 1)).unwrap().format.clone().unwrap();
+        assert_eq!(format.font_size, Some(MAX_FONT_SIZE));
+        let warnings = grid.take_warnings();
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(warnings[0].code, WarningCode::FormatClamped);
+        assert_eq!((warnings[0].cell, warnings[0].end), (Some(CellRef::new(0, 0)), Some(CellRef::new(2, 1))));
+
+        grid.set_format(CellRef::new(5, 5), CellFormat { font_size: Some(11.0), ..Default::default() }).unwrap();
+        assert!(grid.take_warnings().is_empty());
+    }
+
+    #[test]
+    fn test_empty_range_and_size_warnings() {
+        let mut grid = Grid::new(10, 10);
+        let mut engine = FormulaEngine::new();
+        // The formula is pasted above its data, so only C1:C3 is empty
+        let block = vec![
+            vec!["=SUM(A2:A3,C1:C3)".to_string()],
+            vec!["1".to_string()],
+        ];
+        grid.paste_block(CellRef::new(0, 0), &block, PasteOptions::default(), &mut engine).unwrap();
+        let warnings = grid.take_warnings();
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(warnings[0].code, WarningCode::EmptyRange);
+        assert_eq!(warnings[0].cell, Some(CellRef::new(0, 2)));
+
+        grid.set_col_width(0, 1_000_000.0).unwrap();
+        assert_eq!(grid.take_warnings()[0].code, WarningCode::SizeClamped);
+        grid.set_col_width(0, 80.0).unwrap();
+        assert!(grid.take_warnings().is_empty());
+    }
+}
@@ -0,0 +1,318 @@
+//! What-if analysis built on override evaluation
+//!
+//! These tools evaluate the model many times with hypothetical inputs via
+//! `FormulaEngine::evaluate_with_overrides`, never mutating the grid.
+
+use crate::cell::{CellRef, CellValue};
+use crate::formula::{Formula, FormulaEngine, FormulaError};
+use crate::grid::Grid;
+use serde::{Deserialize, Serialize};
+use std::cell::Cell;
+use std::collections::HashMap;
+
+/// Iteration cap for the Newton phase of goal seek
+const NEWTON_ITERATIONS: usize = 50;
+/// Iteration cap for the bisection phase of goal seek
+const BISECTION_ITERATIONS: usize = 200;
+
+/// Outcome of a successful goal seek
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct GoalSeekResult {
+    /// Input value found for the changing cell
+    pub value: f64,
+    /// Target cell's value with that input
+    pub achieved: f64,
+    /// Model evaluations used
+    pub iterations: usize,
+}
+
+/// Results of a what-if data table
+///
+/// `results[i][j]` is the formula's value for `row_values[i]` and
+/// `col_values[j]`. In a one-variable table the missing dimension has length 1.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DataTable {
+    pub row_values: Vec<CellValue>,
+    pub col_values: Vec<CellValue>,
+    pub results: Vec<Vec<CellValue>>,
+}
+
+impl FormulaEngine {
+    /// Find the value of `changing_cell` that makes `target_cell` equal `target_value`
+    ///
+    /// Tries Newton's method (with a numerical derivative) from the changing
+    /// cell's current value, then falls back to bracketing the root and
+    /// bisecting. `target_cell` must be a formula that depends, directly or
+    /// transitively, on `changing_cell`.
+    pub fn goal_seek(
+        &self,
+        grid: &Grid,
+        target_cell: CellRef,
+        target_value: f64,
+        changing_cell: CellRef,
+    ) -> Result<GoalSeekResult, FormulaError> {
+        let formula = self.formula_at(target_cell)
+            .ok_or_else(|| FormulaError::GoalSeek(format!("{} does not contain a formula", target_cell)))?;
+        if !self.depends_on(target_cell, changing_cell) {
+            return Err(FormulaError::GoalSeek(format!("{} does not depend on {}", target_cell, changing_cell)));
+        }
+
+        // Iterate towards a tight tolerance, but accept the best point seen if
+        // rounding keeps us from reaching it
+        let scale = target_value.abs().max(1.0);
+        let tolerance = 1e-12 * scale;
+        let acceptable = 1e-9 * scale;
+        let evaluations = Cell::new(0);
+        let best: Cell<Option<(f64, f64)>> = Cell::new(None);
+        let f = |x: f64| -> Option<f64> {
+            evaluations.set(evaluations.get() + 1);
+            let y = self.evaluate_at(formula, grid, changing_cell, x)
+                .ok()
+                .map(|y| y - target_value)
+                .filter(|y| y.is_finite())?;
+            if best.get().is_none_or(|(_, best_y)| y.abs() < best_y.abs()) {
+                best.set(Some((x, y)));
+            }
+            Some(y)
+        };
+
+        let start = grid.get_cell(changing_cell)
+            .and_then(|c| c.value.to_number())
+            .unwrap_or(0.0);
+        let mut x = start;
+        let mut fx = f(x);
+
+        // Newton's method, remembering any sign change seen along the way
+        let mut bracket = None;
+        for _ in 0..NEWTON_ITERATIONS {
+            let Some(y) = fx else {
+                break;
+            };
+            if y.abs() <= tolerance {
+                return Ok(GoalSeekResult { value: x, achieved: y + target_value, iterations: evaluations.get() });
+            }
+            let h = (x.abs() * 1e-6).max(1e-6);
+            let (Some(y_plus), Some(y_minus)) = (f(x + h), f(x - h)) else {
+                break;
+            };
+            let slope = (y_plus - y_minus) / (2.0 * h);
+            if slope == 0.0 || !slope.is_finite() {
+                break;
+            }
+            let next = x - y / slope;
+            let f_next = f(next);
+            if let Some(y_next) = f_next {
+                if y_next.signum() != y.signum() {
+                    bracket = Some((x, y, next));
+                }
+            }
+            x = next;
+            fx = f_next;
+        }
+
+        // Expand outward from the start looking for a sign change
+        if bracket.is_none() {
+            if let Some(y0) = f(start) {
+                let mut step = 1.0_f64.max(start.abs() * 0.1);
+                'search: while step < 1e12 {
+                    for candidate in [start + step, start - step] {
+                        if let Some(y) = f(candidate) {
+                            if y.signum() != y0.signum() {
+                                bracket = Some((start, y0, candidate));
+                                break 'search;
+                            }
+                        }
+                    }
+                    step *= 2.0;
+                }
+            }
+        }
+
+        if let Some((mut lo, mut f_lo, mut hi)) = bracket {
+            for _ in 0..BISECTION_ITERATIONS {
+                let mid = (lo + hi) / 2.0;
+                let Some(f_mid) = f(mid) else {
+                    break;
+                };
+                if f_mid.abs() <= tolerance {
+                    return Ok(GoalSeekResult { value: mid, achieved: f_mid + target_value, iterations: evaluations.get() });
+                }
+                if mid == lo || mid == hi {
+                    break;
+                }
+                if f_mid.signum() == f_lo.signum() {
+                    lo = mid;
+                    f_lo = f_mid;
+                } else {
+                    hi = mid;
+                }
+            }
+        }
+
+        match best.get() {
+            Some((x, y)) if y.abs() <= acceptable => {
+                Ok(GoalSeekResult { value: x, achieved: y + target_value, iterations: evaluations.get() })
+            }
+            _ => Err(FormulaError::GoalSeek(format!("no solution found after {} evaluations", evaluations.get()))),
+        }
+    }
+
+    /// Compute a what-if table (Excel's Data Table) for `formula_cell`
+    ///
+    /// Each result is the formula's value with `row_input` set to the row's
+    /// value and `col_input` set to the column's value. Pass `None` for one of
+    /// the inputs (and no values for it) to get a one-variable table.
+    pub fn data_table(
+        &self,
+        grid: &Grid,
+        formula_cell: CellRef,
+        row_input: Option<CellRef>,
+        col_input: Option<CellRef>,
+        row_values: &[CellValue],
+        col_values: &[CellValue],
+    ) -> Result<DataTable, FormulaError> {
+        let formula = self.formula_at(formula_cell)
+            .ok_or_else(|| FormulaError::InvalidRef(format!("{} does not contain a formula", formula_cell)))?;
+        if row_input.is_none() && col_input.is_none() {
+            return Err(FormulaError::InvalidRef("data table needs a row or column input cell".to_string()));
+        }
+
+        // A missing input contributes a single pass-through row/column
+        let rows: Vec<Option<&CellValue>> = match row_input {
+            Some(_) => row_values.iter().map(Some).collect(),
+            None => vec![None],
+        };
+        let cols: Vec<Option<&CellValue>> = match col_input {
+            Some(_) => col_values.iter().map(Some).collect(),
+            None => vec![None],
+        };
+
+        let mut results = Vec::with_capacity(rows.len());
+        for row_value in &rows {
+            let mut result_row = Vec::with_capacity(cols.len());
+            for col_value in &cols {
+                let mut overrides = HashMap::new();
+                if let (Some(cell), Some(value)) = (row_input, row_value) {
+                    overrides.insert(cell, (*value).clone());
+                }
+                if let (Some(cell), Some(value)) = (col_input, col_value) {
+                    overrides.insert(cell, (*value).clone());
+                }
+                let value = self.evaluate_with_overrides(formula, grid, overrides)
+                    .unwrap_or_else(|e| CellValue::Error(e.error_code().to_string()));
+                result_row.push(value);
+            }
+            results.push(result_row);
+        }
+
+        Ok(DataTable {
+            row_values: row_values.to_vec(),
+            col_values: col_values.to_vec(),
+            results,
+        })
+    }
+
+    /// Evaluate a formula as a number with one input cell overridden
+    fn evaluate_at(&self, formula: &Formula, grid: &Grid, input: CellRef, x: f64) -> Result<f64, FormulaError> {
+        let overrides = HashMap::from([(input, CellValue::Number(x))]);
+        self.evaluate_with_overrides(formula, grid, overrides)?
+            .to_number()
+            .ok_or_else(|| FormulaError::TypeError {
+                expected: "number".to_string(),
+                got: "non-numeric".to_string(),
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::grid::{CellUpdate, GridPatch};
+
+    fn model(formula: &str) -> (Grid, FormulaEngine) {
+        let mut grid = Grid::new(10, 10);
+        let mut engine = FormulaEngine::new();
+        let patch = GridPatch {
+            updates: vec![
+                CellUpdate { row: 0, col: 0, value: Some("1".to_string()), formula: None },
+                CellUpdate { row: 0, col: 1, value: None, formula: Some(formula.to_string()) },
+            ],
+            auto_expand: None,
+        };
+        grid.apply_patch(patch, &mut engine).unwrap();
+        (grid, engine)
+    }
+
+    #[test]
+    fn test_goal_seek_linear() {
+        let (grid, engine) = model("=A1*3+4");
+        let result = engine.goal_seek(&grid, CellRef::new(0, 1), 19.0, CellRef::new(0, 0)).unwrap();
+        assert!((result.value - 5.0).abs() < 1e-12, "{:?}", result);
+        assert!((result.achieved - 19.0).abs() < 1e-12, "{:?}", result);
+
+        // The grid itself is untouched
+        assert_eq!(grid.get_cell(CellRef::new(0, 0)).unwrap().value, CellValue::Number(1.0));
+    }
+
+    #[test]
+    fn test_goal_seek_unreachable_target() {
+        let (grid, engine) = model("=A1*A1");
+        let result = engine.goal_seek(&grid, CellRef::new(0, 1), -1.0, CellRef::new(0, 0));
+        assert!(matches!(result, Err(FormulaError::GoalSeek(_))));
+    }
+
+    #[test]
+    fn test_goal_seek_requires_dependency() {
+        let (grid, engine) = model("=A1*3+4");
+        let result = engine.goal_seek(&grid, CellRef::new(0, 1), 19.0, CellRef::new(5, 5));
+        assert!(matches!(result, Err(FormulaError::GoalSeek(_))));
+    }
+
+    #[test]
+    fn test_data_table_one_variable() {
+        let (grid, engine) = model("=A1*3+4");
+        let inputs = [1.0, 2.0, 10.0].map(CellValue::Number);
+        let table = engine.data_table(&grid, CellRef::new(0, 1), Some(CellRef::new(0, 0)), None, &inputs, &[]).unwrap();
+        assert_eq!(table.results, vec![
+            vec![CellValue::Number(7.0)],
+            vec![CellValue::Number(10.0)],
+            vec![CellValue::Number(34.0)],
+        ]);
+    }
+
+    #[test]
+    fn test_data_table_two_variables() {
+        let mut grid = Grid::new(10, 10);
+        let mut engine = FormulaEngine::new();
+        let patch = GridPatch {
+            updates: vec![
+                CellUpdate { row: 0, col: 0, value: Some("100".to_string()), formula: None },
+                CellUpdate { row: 1, col: 0, value: Some("0.1".to_string()), formula: None },
+                CellUpdate { row: 2, col: 0, value: None, formula: Some("=A1+A1*A2".to_string()) },
+            ],
+            auto_expand: None,
+        };
+        grid.apply_patch(patch, &mut engine).unwrap();
+
+        let prices = [100.0, 200.0].map(CellValue::Number);
+        let rates = [0.0, 0.5, 1.0].map(CellValue::Number);
+        let table = engine.data_table(
+            &grid,
+            CellRef::new(2, 0),
+            Some(CellRef::new(0, 0)),
+            Some(CellRef::new(1, 0)),
+            &prices,
+            &rates,
+        ).unwrap();
+
+        let expected: Vec<Vec<CellValue>> = vec![
+            vec![100.0, 150.0, 200.0],
+            vec![200.0, 300.0, 400.0],
+        ]
+        .into_iter()
+        .map(|row| row.into_iter().map(CellValue::Number).collect())
+        .collect();
+        assert_eq!(table.results, expected);
+        assert_eq!(grid.get_cell(CellRef::new(2, 0)).unwrap().value, CellValue::Number(110.0));
+    }
+}
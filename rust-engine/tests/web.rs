@@ -0,0 +1,200 @@
+//! Tests of the JS-facing API, run in a browser with `wasm-pack test`
+
+#![cfg(target_arch = "wasm32")]
+
+use ramp_sheets_engine::{GridDiff, GridDimensions, RangePage, SheetEngine, VisibleCells};
+use wasm_bindgen::{JsCast, JsValue};
+use web_sys::{HtmlCanvasElement, WebGl2RenderingContext};
+use wasm_bindgen_test::*;
+
+wasm_bindgen_test_configure!(run_in_browser);
+
+fn sheet() -> SheetEngine {
+    let mut engine = SheetEngine::new(20, 10);
+    engine.set_cell(0, 0, "12.5").unwrap();
+    engine.set_cell(1, 0, "=A1*2").unwrap();
+    engine
+}
+
+fn page(value: JsValue) -> RangePage {
+    serde_wasm_bindgen::from_value(value).unwrap()
+}
+
+#[wasm_bindgen_test]
+fn viewport_cells_default_to_everything() {
+    let engine = sheet();
+    let omitted = page(engine.get_viewport_cells(false, JsValue::UNDEFINED).unwrap());
+    let null = page(engine.get_viewport_cells(false, JsValue::NULL).unwrap());
+    let explicit = js_sys::JSON::parse(r#"{"include_formulas":true,"include_formats":true}"#).unwrap();
+    let explicit = page(engine.get_viewport_cells(false, explicit).unwrap());
+    assert_eq!(omitted, null);
+    assert_eq!(omitted, explicit);
+    assert_eq!(omitted.cells[1].formula.as_deref(), Some("=A1*2"));
+    assert_eq!(omitted.cells[1].value, "25");
+}
+
+#[wasm_bindgen_test]
+fn dependency_graph_options_are_optional() {
+    let engine = sheet();
+    let dot = engine.export_dependency_graph(JsValue::UNDEFINED).unwrap();
+    assert!(dot.starts_with("digraph dependencies {"));
+    assert!(dot.contains("\"A1\" -> \"A2\";"));
+
+    let options = js_sys::JSON::parse(r#"{"format":"json","range":"A2"}"#).unwrap();
+    let json: serde_json::Value = serde_json::from_str(&engine.export_dependency_graph(options).unwrap()).unwrap();
+    assert_eq!(json["nodes"].as_array().unwrap().len(), 2);
+    assert_eq!(json["truncated"], false);
+
+    let bad = js_sys::JSON::parse(r#"{"range":"nowhere"}"#).unwrap();
+    assert!(engine.export_dependency_graph(bad).is_err());
+}
+
+#[wasm_bindgen_test]
+fn lean_viewport_cells_leave_formulas_to_get_cell_formula() {
+    let engine = sheet();
+    let options = js_sys::JSON::parse(r#"{"include_formulas":false}"#).unwrap();
+    let lean = page(engine.get_viewport_cells(false, options).unwrap());
+    assert_eq!(lean.cells[1].formula, None);
+    assert_eq!(lean.cells[1].value, "25");
+    assert_eq!(engine.get_cell_formula(1, 0).as_deref(), Some("=A1*2"));
+    assert_eq!(engine.get_cell_formula(0, 0), None);
+}
+
+#[wasm_bindgen_test]
+fn visible_cells_split_by_pane() {
+    let mut engine = sheet();
+    let all: VisibleCells = serde_wasm_bindgen::from_value(engine.get_visible_cells().unwrap()).unwrap();
+    assert_eq!(all.main.len(), 2);
+    assert!(all.top.is_empty());
+
+    engine.freeze_panes(1, 0);
+    let split: VisibleCells = serde_wasm_bindgen::from_value(engine.get_visible_cells().unwrap()).unwrap();
+    assert_eq!(split.top[0].value, "12.5");
+    assert_eq!(split.main[0].value, "25");
+    assert!(split.panes.split_y > split.panes.cells_y);
+}
+
+#[wasm_bindgen_test]
+fn undo_reverts_set_cell() {
+    let mut engine = sheet();
+    engine.set_cell(0, 0, "20").unwrap();
+    assert!(!engine.undo().unwrap().is_null());
+    assert_eq!(engine.get_cell_formula(1, 0).as_deref(), Some("=A1*2"));
+    assert!(engine.can_redo());
+    assert!(!engine.redo().unwrap().is_null());
+    assert!(!engine.can_redo());
+
+    engine.undo().unwrap();
+    engine.undo().unwrap();
+    engine.undo().unwrap();
+    assert!(!engine.can_undo());
+    assert!(engine.undo().unwrap().is_null());
+}
+
+/// Make an edit and undo it, leaving it to be redone
+fn undone_edit(engine: &mut SheetEngine) {
+    engine.set_cell(2, 0, "1").unwrap();
+    engine.undo().unwrap();
+    assert!(engine.can_redo());
+}
+
+#[wasm_bindgen_test]
+fn unrecorded_changes_end_the_redo_chain() {
+    let mut engine = sheet();
+    let scenario = js_sys::JSON::parse(r#"[{"ref":"A1","value":"30"}]"#).unwrap();
+    engine.define_scenario("upside", scenario).unwrap();
+
+    undone_edit(&mut engine);
+    engine.apply_scenario("upside").unwrap();
+    assert!(!engine.can_redo());
+
+    undone_edit(&mut engine);
+    engine.clear_scenario().unwrap();
+    assert!(!engine.can_redo());
+
+    undone_edit(&mut engine);
+    engine.define_name("Rate", "A1").unwrap();
+    assert!(!engine.can_redo());
+
+    undone_edit(&mut engine);
+    engine.define_style("Total", js_sys::JSON::parse(r#"{"font_bold":true}"#).unwrap()).unwrap();
+    assert!(!engine.can_redo());
+}
+
+#[wasm_bindgen_test]
+fn structural_edits_report_new_dimensions() {
+    let mut engine = sheet();
+    let diff: GridDiff = serde_wasm_bindgen::from_value(engine.insert_rows(1, 3).unwrap()).unwrap();
+    assert_eq!(diff.dimensions, Some(GridDimensions { rows: 23, cols: 10 }));
+    let diff: GridDiff = serde_wasm_bindgen::from_value(engine.delete_cols(2, 2).unwrap()).unwrap();
+    assert_eq!(diff.dimensions, Some(GridDimensions { rows: 23, cols: 8 }));
+}
+
+/// A canvas with `id` added to the page
+fn mount_canvas(id: &str) -> HtmlCanvasElement {
+    let document = web_sys::window().unwrap().document().unwrap();
+    let canvas: HtmlCanvasElement = document.create_element("canvas").unwrap().dyn_into().unwrap();
+    canvas.set_id(id);
+    canvas.set_width(400);
+    canvas.set_height(300);
+    document.body().unwrap().append_child(&canvas).unwrap();
+    canvas
+}
+
+fn status(engine: &SheetEngine) -> String {
+    engine.render().unwrap().as_string().unwrap()
+}
+
+fn context(canvas: &HtmlCanvasElement) -> WebGl2RenderingContext {
+    canvas.get_context("webgl2").unwrap().unwrap().dyn_into().unwrap()
+}
+
+#[wasm_bindgen_test]
+fn canvas_detach_and_reattach() {
+    let mut engine = sheet();
+    assert_eq!(status(&engine), "no_canvas");
+    assert!(!engine.is_canvas_attached());
+
+    let first = mount_canvas("sheet-lifecycle");
+    engine.attach_canvas("sheet-lifecycle").unwrap();
+    assert!(engine.is_canvas_attached());
+    assert_eq!(status(&engine), "drawn");
+
+    // The component unmounts: the canvas leaves the page but the engine still holds it
+    first.remove();
+    assert!(!engine.is_canvas_attached());
+    assert_eq!(status(&engine), "canvas_detached");
+    engine.request_render("cells").unwrap();
+    assert!(!engine.render_if_needed().unwrap());
+
+    // It remounts with a fresh canvas under the same id; the old context is released
+    let second = mount_canvas("sheet-lifecycle");
+    engine.attach_canvas("sheet-lifecycle").unwrap();
+    assert!(context(&first).is_context_lost());
+    assert_eq!(status(&engine), "drawn");
+    assert!(engine.render_if_needed().unwrap());
+
+    engine.detach_canvas();
+    assert!(context(&second).is_context_lost());
+    assert!(!engine.is_canvas_attached());
+    assert_eq!(status(&engine), "no_canvas");
+    engine.detach_canvas();
+    second.remove();
+}
+
+#[wasm_bindgen_test]
+fn attaching_twice_keeps_the_canvas() {
+    let mut engine = sheet();
+    let canvas = mount_canvas("sheet-double-attach");
+    engine.attach_canvas("sheet-double-attach").unwrap();
+    engine.attach_canvas("sheet-double-attach").unwrap();
+    assert!(!context(&canvas).is_context_lost());
+    assert_eq!(status(&engine), "drawn");
+
+    // A canvas that isn't there leaves the current one attached
+    assert!(engine.attach_canvas("no-such-canvas").is_err());
+    assert_eq!(status(&engine), "drawn");
+
+    engine.detach_canvas();
+    canvas.remove();
+}